@@ -0,0 +1,293 @@
+use super::*;
+
+use std::collections::HashMap;
+use tracing::info;
+
+/// Assembles programs into BrainFuck.
+///
+/// The assembler owns the state of a single assembly: the numbers given to
+/// basic blocks, the table of labels, the layout of the program's memory, and
+/// the cells allocated on the tape after the registers, stack, heap, and call
+/// stack. Nothing is shared between
+/// assemblers, so programs can be assembled concurrently, and assembling the
+/// same program twice gives the same BrainFuck.
+///
+/// ```rust
+/// use basm::{Assembler, Program};
+///
+/// let program = Program::parse("putint 5").unwrap();
+/// let bf = Assembler::new().assemble(&program);
+/// assert_eq!(bf, program.assemble());
+/// ```
+#[derive(Debug, Clone)]
+pub struct Assembler {
+    /// The basic block number of each label in the program being assembled.
+    labels: HashMap<Symbol, usize>,
+    /// Where the stack, heap, and call stack of the program being assembled live.
+    layout: Layout,
+    /// The next free cell on the tape.
+    next_global: StaticLocation,
+    /// The instructions marked so far, if a source map is being built.
+    sources: Option<Vec<SourceLocation>>,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Self {
+            labels: HashMap::new(),
+            layout: Layout::default(),
+            next_global: Layout::default().globals(),
+            sources: None,
+        }
+    }
+
+    /// Allocate `size` cells on the tape that no other allocation will use.
+    pub fn global_alloc(&mut self, size: usize) -> StaticLocation {
+        let result = self.next_global;
+        self.next_global = self.next_global.off(size as i64);
+        result
+    }
+
+    /// Allocate a table with room for `data_cells` values.
+    pub fn allocate_table(&mut self, data_cells: usize) -> Table {
+        let base = self.global_alloc(4 + 2 * data_cells);
+        info!("Allocated table {}", base);
+        Table::new(data_cells, base)
+    }
+
+    /// Allocate a null terminated string, and return its address along with
+    /// the code that writes it to the tape.
+    pub fn allocate_string(&mut self, string: &str) -> (StaticLocation, String) {
+        // Allocate the size of the string
+        let result_addr = self.global_alloc(string.len() + 1);
+        // Write the string to the global
+        let mut result_code = String::new();
+        for (i, ch) in string.chars().enumerate() {
+            let ch = ch as u8;
+            let cell = result_addr.off(i as i64);
+            result_code.push_str(&cell.set_const(ch as u64));
+        }
+        (result_addr, result_code)
+    }
+
+    /// The memory layout of the program being assembled.
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// The basic block number of a label in the program being assembled.
+    pub fn label_number(&self, label: &Symbol) -> Option<usize> {
+        self.labels.get(label).copied()
+    }
+
+    /// Assemble a program into BrainFuck.
+    ///
+    /// Panics if the program doesn't fit on its tape; use
+    /// [`Assembler::try_assemble`] to get an error instead.
+    pub fn assemble(&mut self, program: &Program) -> String {
+        self.try_assemble(program)
+            .unwrap_or_else(|e| panic!("Failed to assemble program: {e}"))
+    }
+
+    /// Assemble a program into BrainFuck, checking that its stack, heap,
+    /// call stack, and everything the assembler allocates fit on its tape.
+    pub fn try_assemble(&mut self, program: &Program) -> Result<String, LayoutError> {
+        self.layout = *program.layout();
+        self.next_global = self.layout.globals();
+        // Basic blocks are numbered in program order, starting at 1
+        self.labels = program
+            .ops()
+            .iter()
+            .enumerate()
+            .filter_map(|(i, op)| match op {
+                Op::Label(label, _) => Some((label.clone(), i + 1)),
+                _ => None,
+            })
+            .collect();
+
+        let cases: Vec<String> = program
+            .ops()
+            .iter()
+            .enumerate()
+            .map(|(i, op)| op.assemble(program.source(i), self))
+            .collect();
+
+        self.layout.check(self.next_global.address())?;
+
+        // The data section sits at the bottom of the stack, below everything pushed
+        let mut data = String::new();
+        for (i, value) in program.data().iter().enumerate() {
+            if *value != 0 {
+                data += &self.layout.stack().cell(i + 1).set_const(*value);
+            }
+        }
+        if !program.data().is_empty() {
+            data += &SP.set_const(program.data().len() as u64);
+        }
+
+        // Add a while loop while the basic block is not 0
+        Ok(data
+            + &CURRENT_BASIC_BLOCK.set_const(1)
+            + &NEXT_BASIC_BLOCK.set_const(1)
+            + &while_on(
+                &NEXT_BASIC_BLOCK,
+                NEXT_BASIC_BLOCK.inc()
+                    // // Debugging
+                    // + &TRASH.putmsg("Current basic block is: ")
+                    // + &CURRENT_BASIC_BLOCK.putint()
+                    // + &TRASH.putmsg("\n")
+                    + &switch(&cases)
+                    + &CURRENT_BASIC_BLOCK.set_from(NEXT_BASIC_BLOCK),
+            ))
+    }
+
+    /// Assemble a program into BrainFuck, along with a map from the
+    /// BrainFuck back to the instructions in the source.
+    pub fn assemble_with_source_map(
+        &mut self,
+        program: &Program,
+    ) -> Result<(String, SourceMap), LayoutError> {
+        self.sources = Some(vec![]);
+        let marked = self.try_assemble(program);
+        let sources = self.sources.take().unwrap_or_default();
+        Ok(SourceMap::extract(&marked?, sources))
+    }
+
+    /// Assemble the ops in a basic block, marking the code for each of them.
+    pub(super) fn assemble_basic_block(&mut self, bb: &BasicBlock) -> String {
+        let mut result = String::new();
+        for (i, op) in bb.ops().iter().enumerate() {
+            result += &self.mark(bb.source(i), op.assemble(&self.layout));
+        }
+        result
+    }
+
+    /// Mark the code generated for an instruction, if a source map is being built.
+    pub(super) fn mark(&mut self, source: Option<&SourceLocation>, code: String) -> String {
+        match (&mut self.sources, source) {
+            (Some(sources), Some(source)) => {
+                sources.push(source.clone());
+                SourceMap::mark(sources.len() - 1, code)
+            }
+            _ => code,
+        }
+    }
+}
+
+/// Run the case for the current basic block, numbered from 1.
+///
+/// The count starts as the number of cases after the current one, plus one.
+/// Each case decrements it, and only goes on to the cases before it if it
+/// isn't zero yet, so finding a block takes a step for each case after it,
+/// instead of comparing the block number against every block in the program.
+///
+/// ```text
+/// count- flag=1 count[ <the earlier cases> flag=0 count=0 ] flag[ flag- <case> ]
+/// ```
+///
+/// The earlier cases leave the count at zero, so they can share one flag, and
+/// the code for the cases stays in program order.
+fn switch(cases: &[String]) -> String {
+    let count = DISPATCH_COUNT;
+    let flag = DISPATCH_FLAG;
+    set_block_number(count, cases.len() + 1)
+        + &DISPATCH_TEMP.set_from(CURRENT_BASIC_BLOCK)
+        + &while_on(&DISPATCH_TEMP, DISPATCH_TEMP.dec() + &count.dec())
+        + &cases.iter().fold(String::new(), |earlier, case| {
+            count.dec()
+                + &flag.set_const(1)
+                + &while_on(&count, earlier + &flag.zero() + &count.zero())
+                + &while_on(&flag, flag.dec() + case)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = r#"
+    main:
+        call greet
+        quit
+    greet:
+        putchar 'h'
+        putchar 'i'
+        ret
+    "#;
+
+    #[test]
+    fn test_assembly_is_deterministic() {
+        let program = parse(SOURCE).unwrap();
+        let first = program.assemble();
+        // Assembling an unrelated program must not affect the numbering
+        parse("other:\n putchar 'x'\n jmp other").unwrap().assemble();
+        assert_eq!(first, program.assemble());
+        assert_eq!(first, parse(SOURCE).unwrap().assemble());
+        assert_eq!(interpret_with_input(first, "", 1).unwrap(), "hi");
+    }
+
+    #[test]
+    fn test_concurrent_assembly() {
+        let expected = parse(SOURCE).unwrap().assemble();
+        let handles: Vec<_> = (0..4)
+            .map(|_| std::thread::spawn(|| parse(SOURCE).unwrap().assemble()))
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_labels_are_numbered_in_program_order() {
+        let program = parse(SOURCE).unwrap();
+        let mut asm = Assembler::new();
+        asm.assemble(&program);
+        assert_eq!(asm.label_number(&Symbol::from("main")), Some(1));
+        assert_eq!(asm.label_number(&Symbol::from("greet")), Some(4));
+        assert_eq!(asm.label_number(&Symbol::from("missing")), None);
+    }
+
+    #[test]
+    fn test_many_basic_blocks() {
+        // Jump backwards through every label, printing at every fiftieth one
+        let source = |labels: usize| {
+            let mut source = format!("main:\n    jmp l{}\n", labels - 1);
+            for i in 0..labels {
+                source += &format!("l{i}:\n");
+                if i % 50 == 0 {
+                    source += &format!("    putint {}\n    putchar ' '\n", i / 50);
+                }
+                source += &match i {
+                    0 => "    quit\n".to_string(),
+                    _ => format!("    jmp l{}\n", i - 1),
+                };
+            }
+            parse(&source).unwrap().assemble()
+        };
+
+        let (small, large) = (source(150), source(300));
+        assert_eq!(
+            interpret_with_input(large.clone(), "", 2).unwrap(),
+            "5 4 3 2 1 0 "
+        );
+        // The dispatcher grows with the number of blocks, not its square
+        assert!(large.len() < small.len() * 5 / 2);
+    }
+
+    #[test]
+    fn test_global_alloc() {
+        let mut asm = Assembler::new();
+        let a = asm.global_alloc(2);
+        let b = asm.global_alloc(1);
+        assert_eq!(a, Layout::default().globals());
+        assert_eq!(b, a.off(2));
+        // A fresh assembler starts allocating from the beginning again
+        assert_eq!(Assembler::new().global_alloc(1), a);
+    }
+}
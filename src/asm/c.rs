@@ -0,0 +1,900 @@
+use super::*;
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+use tracing::info;
+
+/// Compiles programs straight to C, without going through BrainFuck.
+///
+/// Registers become global variables, the stack, heap, and call stack become
+/// arrays indexed by their logical indices, and the basic block dispatcher
+/// becomes a `switch`. Every instruction loads its operands into the same
+/// temporaries as its BrainFuck does, so the output of the two backends can
+/// be compared directly. There are two exceptions: dumps show the assembler's
+/// scratch cells with whatever the C last left in them, which isn't what the
+/// BrainFuck leaves there, and dividing by zero stops the program with an
+/// error instead of running forever.
+struct CWriter {
+    /// The basic block number of each label.
+    labels: HashMap<Symbol, usize>,
+    /// The cells outside of the registers that the program uses.
+    globals: BTreeSet<usize>,
+    /// The number of bits in a cell.
+    bits: u32,
+    /// The sizes of the stack, heap, and call stack arrays.
+    layout: Layout,
+}
+
+impl CWriter {
+    fn new(program: &Program, cell_bytes: u8) -> Self {
+        let labels = program
+            .ops()
+            .iter()
+            .enumerate()
+            .filter_map(|(i, op)| match op {
+                Op::Label(label, _) => Some((label.clone(), i + 1)),
+                _ => None,
+            })
+            .collect();
+        Self {
+            labels,
+            globals: BTreeSet::new(),
+            bits: cell_bytes as u32 * 8,
+            layout: *program.layout(),
+        }
+    }
+
+    fn label_number(&self, label: &Symbol) -> usize {
+        *self
+            .labels
+            .get(label)
+            .unwrap_or_else(|| panic!("Unknown basic block {label}"))
+    }
+
+    /// The variable that holds a cell on the tape.
+    fn var(&mut self, loc: StaticLocation) -> String {
+        let addr = loc.address();
+        if (1..=REGISTER_COUNT).contains(&addr) {
+            register_name(addr - 1).to_string()
+        } else if let Some(index) = self.layout.stack().index_of(loc) {
+            // Data labels name stack cells directly
+            format!("stack[{index}]")
+        } else {
+            self.globals.insert(addr);
+            format!("cell_{addr}")
+        }
+    }
+
+    fn location(&mut self, loc: &DynamicLocation) -> String {
+        match loc {
+            DynamicLocation::Static(loc) => self.var(*loc),
+            DynamicLocation::DerefStack(loc) => format!("stack[{}]", self.var(*loc)),
+            DynamicLocation::DerefHeap(loc) => format!("heap[{}]", self.var(*loc)),
+            DynamicLocation::Frame(offset) => format!("stack[(cell)(FP + {offset})]"),
+        }
+    }
+
+    fn operand(&mut self, op: &Operand) -> String {
+        match op {
+            Operand::Immediate(n) => self.immediate(*n),
+            Operand::Location(loc) => self.location(loc),
+        }
+    }
+
+    /// An immediate, wrapped around to fit in a cell like `set_const` does.
+    fn immediate(&self, n: u64) -> String {
+        (n & (u64::MAX >> (64 - self.bits))).to_string()
+    }
+
+    /// Load the operands of a binary operation into T0 and T1 like the
+    /// BrainFuck does, and store `expr` in the destination.
+    fn binop(
+        &mut self,
+        lhs: &Operand,
+        rhs: &Operand,
+        dest: &DynamicLocation,
+        expr: &str,
+    ) -> String {
+        let (lhs, rhs, dest) = (self.operand(lhs), self.operand(rhs), self.location(dest));
+        format!("T0 = {lhs}; T1 = {rhs}; {dest} = {expr};")
+    }
+
+    fn unop(&mut self, src: &Operand, dest: &DynamicLocation, expr: &str) -> String {
+        let (src, dest) = (self.operand(src), self.location(dest));
+        format!("T0 = {src}; {dest} = {expr};")
+    }
+
+    /// A wide operand, put together from its bytes like the BrainFuck stores it.
+    fn wide_operand(&mut self, op: &WideOperand) -> String {
+        match op {
+            WideOperand::Register(reg) => format!("WIDE({reg})"),
+            WideOperand::Immediate(n) => format!("{}u", n & 0xffff_ffff),
+            WideOperand::Narrow(loc) => format!("(wide){}", self.location(loc)),
+        }
+    }
+
+    fn wide_binop(
+        &mut self,
+        lhs: &WideOperand,
+        rhs: &WideOperand,
+        dest: &WideLocation,
+        op: &str,
+    ) -> String {
+        let (lhs, rhs) = (self.wide_operand(lhs), self.wide_operand(rhs));
+        format!("SET_WIDE({dest}, {lhs} {op} {rhs});")
+    }
+
+    /// The ops inside an `if` or `while` block, on one line.
+    fn block(&mut self, ops: &[BasicBlockOp]) -> String {
+        let ops: Vec<_> = ops.iter().map(|op| self.basic_block_op(op)).collect();
+        ops.join(" ")
+    }
+
+    fn basic_block_op(&mut self, op: &BasicBlockOp) -> String {
+        use BasicBlockOp::*;
+        use Operand::*;
+        match op {
+            Push(op) => format!(
+                "PUSH_TEMP = {}; SP++; stack[SP] = PUSH_TEMP;",
+                self.operand(op)
+            ),
+            Pop(None) => "SP--;".to_string(),
+            Pop(Some(loc)) => format!("{} = stack[SP]; SP--;", self.location(loc)),
+
+            If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let cond = self.location(cond);
+                let then = self.block(then);
+                if otherwise.is_empty() {
+                    format!("if ({cond}) {{ {then} }}")
+                } else {
+                    format!(
+                        "if ({cond}) {{ {then} }} else {{ {} }}",
+                        self.block(otherwise)
+                    )
+                }
+            }
+            While { cond, body } => {
+                let cond = self.location(cond);
+                format!("while ({cond}) {{ {} }}", self.block(body))
+            }
+
+            Enter { locals } => {
+                "PUSH_TEMP = FP; SP++; stack[SP] = PUSH_TEMP; FP = SP;".to_string()
+                    + &" SP++; stack[SP] = 0;".repeat(*locals)
+            }
+            Leave { args, result } => {
+                let leave = format!("SP = FP; FP = stack[SP]; SP--; SP -= {args};");
+                match result {
+                    None => leave,
+                    Some(result) => format!(
+                        "FRAME_RESULT = {}; {leave} PUSH_TEMP = FRAME_RESULT; SP++; stack[SP] = PUSH_TEMP;",
+                        self.operand(result)
+                    ),
+                }
+            }
+
+            HexDump => "dump(1);".to_string(),
+            DecimalDump => "dump(0);".to_string(),
+
+            Log(msg, locations) => {
+                let mut result = format!("fputs({}, stdout);", c_string(msg));
+                for location in locations {
+                    let name = c_string(&format!("{location} = "));
+                    let loc = self.location(location);
+                    write!(
+                        result,
+                        " fputs({name}, stdout); putint({loc}); putchar('\\n');"
+                    )
+                    .unwrap();
+                }
+                result
+            }
+
+            Inc(loc, amount) => {
+                let amount = self.immediate(amount.unwrap_or(1));
+                format!("{} += {amount};", self.location(loc))
+            }
+            Dec(loc, amount) => {
+                let amount = self.immediate(amount.unwrap_or(1));
+                format!("{} -= {amount};", self.location(loc))
+            }
+
+            GetAddr {
+                src,
+                dest,
+                offset,
+                negative,
+            } => {
+                let dest = self.location(dest);
+                let sign = if *negative { '-' } else { '+' };
+                match (src, offset) {
+                    (DynamicLocation::Static(loc), None) => format!("{dest} = {};", loc.address()),
+                    (DynamicLocation::Static(loc), Some(Immediate(n))) => {
+                        let addr = if *negative {
+                            loc.address() as i64 - *n as i64
+                        } else {
+                            loc.address() as i64 + *n as i64
+                        };
+                        format!("{dest} = {};", self.immediate(addr as u64))
+                    }
+                    // The BrainFuck always adds a location to a static address
+                    (DynamicLocation::Static(loc), Some(Location(offset))) => {
+                        let offset = self.location(offset);
+                        format!("{dest} = {}; {dest} = {dest} + {offset};", loc.address())
+                    }
+                    (
+                        DynamicLocation::DerefStack(loc) | DynamicLocation::DerefHeap(loc),
+                        offset,
+                    ) => {
+                        let loc = self.var(*loc);
+                        match offset {
+                            None => format!("{dest} = {loc};"),
+                            Some(offset) => {
+                                let offset = self.operand(offset);
+                                format!("{dest} = {loc}; {dest} = {dest} {sign} {offset};")
+                            }
+                        }
+                    }
+                    (DynamicLocation::Frame(frame), offset) => match offset {
+                        None => format!("{dest} = FP + {frame};"),
+                        Some(offset) => {
+                            let offset = self.operand(offset);
+                            format!("{dest} = FP + {frame}; {dest} = {dest} {sign} {offset};")
+                        }
+                    },
+                }
+            }
+
+            Set { src, dest } => format!("{} = {};", self.location(dest), self.operand(src)),
+
+            GetChar(None) => "getch();".to_string(),
+            GetChar(Some(loc)) => format!("{} = getch();", self.location(loc)),
+            PutChar(op) => format!("putchar({});", self.operand(op)),
+            PutInt(op) => format!("putint({});", self.operand(op)),
+            PutSInt(op) => format!("putsint({});", self.operand(op)),
+            WidePutInt(reg) => format!("printf(\"%u\", WIDE({reg}));"),
+            WideSet { src, dest } => format!("SET_WIDE({dest}, {});", self.wide_operand(src)),
+            WideAdd { lhs, rhs, dest } => self.wide_binop(lhs, rhs, dest, "+"),
+            WideSub { lhs, rhs, dest } => self.wide_binop(lhs, rhs, dest, "-"),
+            WideMul { lhs, rhs, dest } => self.wide_binop(lhs, rhs, dest, "*"),
+            GetInt(loc) => format!("{} = getint();", self.location(loc)),
+
+            Add { lhs, rhs, dest } => self.binop(lhs, rhs, dest, "T0 + T1"),
+            Sub { lhs, rhs, dest } => self.binop(lhs, rhs, dest, "T0 - T1"),
+            Mul { lhs, rhs, dest } => self.binop(lhs, rhs, dest, "T0 * T1"),
+            // The BrainFuck never finishes dividing by zero, so stop the program instead
+            Div { lhs, rhs, dest } => self.binop(lhs, rhs, dest, "T1 ? T0 / T1 : divide_by_zero()"),
+            // The remainder of dividing by zero is the dividend
+            Mod { lhs, rhs, dest } => self.binop(lhs, rhs, dest, "T1 ? T0 % T1 : T0"),
+            IMul { lhs, rhs, dest } => self.binop(lhs, rhs, dest, "T0 * T1"),
+            IDiv { lhs, rhs, dest } => self.binop(
+                lhs,
+                rhs,
+                dest,
+                "T1 ? (cell)((long long)(scell)T0 / (scell)T1) : divide_by_zero()",
+            ),
+            Neg { src, dest } => self.unop(src, dest, "-T0"),
+            Eq { lhs, rhs, dest } => self.binop(lhs, rhs, dest, "T0 == T1"),
+            Ne { lhs, rhs, dest } => self.binop(lhs, rhs, dest, "T0 != T1"),
+            Lt { lhs, rhs, dest } => self.binop(lhs, rhs, dest, "T0 < T1"),
+            ILt { lhs, rhs, dest } => self.binop(lhs, rhs, dest, "(scell)T0 < (scell)T1"),
+            Le { lhs, rhs, dest } => self.binop(lhs, rhs, dest, "T0 <= T1"),
+            Gt { lhs, rhs, dest } => self.binop(lhs, rhs, dest, "T0 > T1"),
+            Ge { lhs, rhs, dest } => self.binop(lhs, rhs, dest, "T0 >= T1"),
+            And { lhs, rhs, dest } => self.binop(lhs, rhs, dest, "T0 & T1"),
+            Or { lhs, rhs, dest } => self.binop(lhs, rhs, dest, "T0 | T1"),
+            Xor { lhs, rhs, dest } => self.binop(lhs, rhs, dest, "T0 ^ T1"),
+            Not { src, dest } => self.unop(src, dest, "~T0"),
+            // Shifting by the width of a cell or more shifts every bit out
+            Shl { lhs, rhs, dest } => {
+                let expr = format!("T1 < {} ? T0 << T1 : 0", self.bits);
+                self.binop(lhs, rhs, dest, &expr)
+            }
+            Shr { lhs, rhs, dest } => {
+                let expr = format!("T1 < {} ? T0 >> T1 : 0", self.bits);
+                self.binop(lhs, rhs, dest, &expr)
+            }
+
+            Alloc { size, dest } => {
+                let (size, dest) = (self.operand(size), self.location(dest));
+                format!("T0 = {size}; {dest} = alloc(T0);")
+            }
+            Free(ptr) => format!("T0 = {}; heap[(cell)(T0 - 1)] = 0;", self.operand(ptr)),
+        }
+    }
+
+    fn basic_block(&mut self, bb: &BasicBlock, code: &mut String) {
+        for (i, op) in bb.ops().iter().enumerate() {
+            if let Some(source) = bb.source(i) {
+                writeln!(code, "            /* {} */", c_comment(&source.instruction)).unwrap();
+            }
+            writeln!(code, "            {}", self.basic_block_op(op)).unwrap();
+        }
+    }
+
+    fn op(&mut self, op: &Op, code: &mut String) {
+        let line = match op {
+            Op::BasicBlock(bb) | Op::Label(_, bb) => return self.basic_block(bb, code),
+            Op::Quit => "NEXT_BASIC_BLOCK = 0;".to_string(),
+            Op::Jmp(label, _) => format!("NEXT_BASIC_BLOCK = {};", self.label_number(label)),
+            Op::Call(label, _) => format!(
+                "CALL_SP++; call_stack[CALL_SP] = NEXT_BASIC_BLOCK; NEXT_BASIC_BLOCK = {};",
+                self.label_number(label)
+            ),
+            Op::Return => "NEXT_BASIC_BLOCK = call_stack[CALL_SP]; CALL_SP--;".to_string(),
+            Op::JmpIf(loc, label, _) => format!(
+                "JMP_TEMP = {}; if (JMP_TEMP) NEXT_BASIC_BLOCK = {};",
+                self.location(loc),
+                self.label_number(label)
+            ),
+        };
+        writeln!(code, "            {line}").unwrap();
+    }
+
+    fn program(mut self, program: &Program) -> String {
+        let mut cases = String::new();
+        for (i, op) in program.ops().iter().enumerate() {
+            match op {
+                Op::Label(label, _) => writeln!(cases, "        case {}: /* {label} */", i + 1),
+                _ => writeln!(cases, "        case {}:", i + 1),
+            }
+            .unwrap();
+            if let Some(source) = program.source(i) {
+                writeln!(
+                    cases,
+                    "            /* {} */",
+                    c_comment(&source.instruction)
+                )
+                .unwrap();
+            }
+            self.op(op, &mut cases);
+            cases.push_str("            break;\n");
+        }
+
+        let (cell, scell) = match self.bits {
+            8 => ("unsigned char", "signed char"),
+            16 => ("unsigned short", "short"),
+            _ => ("unsigned int", "int"),
+        };
+        let mut c = String::new();
+        c.push_str("#include <stdio.h>\n");
+        c.push_str("#include <stdlib.h>\n\n");
+        writeln!(c, "typedef {cell} cell;").unwrap();
+        writeln!(c, "typedef {scell} scell;").unwrap();
+        c.push_str("typedef unsigned int wide;\n\n");
+        // Wide registers are kept a byte per cell, like the BrainFuck keeps them
+        c.push_str(
+            "#define WIDE(r) ((wide)r##_0 | (wide)r##_1 << 8 | (wide)r##_2 << 16 | (wide)r##_3 << 24)\n",
+        );
+        c.push_str(
+            "#define SET_WIDE(r, x) (wide_temp = (x), r##_0 = wide_temp & 0xff, \\\n    \
+             r##_1 = wide_temp >> 8 & 0xff, r##_2 = wide_temp >> 16 & 0xff, r##_3 = wide_temp >> 24)\n\n",
+        );
+        writeln!(c, "#define STACK_SIZE {}", self.layout.stack_size).unwrap();
+        writeln!(c, "#define HEAP_SIZE {}", self.layout.heap_size).unwrap();
+        writeln!(
+            c,
+            "#define CALL_STACK_SIZE {}\n",
+            self.layout.call_stack_size
+        )
+        .unwrap();
+        c.push_str("static cell stack[STACK_SIZE];\n");
+        c.push_str("static cell heap[HEAP_SIZE];\n");
+        c.push_str("static cell call_stack[CALL_STACK_SIZE];\n");
+        c.push_str("static wide wide_temp;\n\n");
+        for name in REGISTER_NAMES {
+            writeln!(c, "static cell {name};").unwrap();
+        }
+        for addr in &self.globals {
+            writeln!(c, "static cell cell_{addr};").unwrap();
+        }
+        c.push('\n');
+        c.push_str(&self.runtime());
+        c.push_str("int main() {\n");
+        for (i, value) in program.data().iter().enumerate() {
+            if *value != 0 {
+                writeln!(c, "    stack[{}] = {};", i + 1, self.immediate(*value)).unwrap();
+            }
+        }
+        if !program.data().is_empty() {
+            writeln!(c, "    SP = {};", program.data().len()).unwrap();
+        }
+        c.push_str("    CURRENT_BASIC_BLOCK = 1;\n");
+        c.push_str("    NEXT_BASIC_BLOCK = 1;\n");
+        c.push_str("    while (NEXT_BASIC_BLOCK) {\n");
+        c.push_str("        NEXT_BASIC_BLOCK++;\n");
+        c.push_str("        switch (CURRENT_BASIC_BLOCK) {\n");
+        c.push_str(&cases);
+        c.push_str("        }\n");
+        c.push_str("        CURRENT_BASIC_BLOCK = NEXT_BASIC_BLOCK;\n");
+        c.push_str("    }\n");
+        c.push_str("    return 0;\n");
+        c.push_str("}\n");
+        c
+    }
+
+    /// The functions the compiled instructions call.
+    fn runtime(&self) -> String {
+        // Dumps show the registers and the start of the stack where they are on the tape
+        let mut dump_tape = String::new();
+        for (i, name) in REGISTER_NAMES.iter().enumerate() {
+            writeln!(dump_tape, "    tape[{}] = {name};", i + 1).unwrap();
+        }
+        let first = self.layout.stack().cell(0).address();
+        format!(
+            r#"static void putint(cell x) {{
+    printf("%u", (unsigned)x);
+}}
+
+static cell divide_by_zero(void) {{
+    fflush(stdout);
+    fputs("Division by zero\n", stderr);
+    exit(1);
+}}
+
+static void putsint(cell x) {{
+    printf("%d", (int)(scell)x);
+}}
+
+static cell getch(void) {{
+    int ch = getchar();
+    return ch == EOF ? 0 : ch;
+}}
+
+/* Skip whitespace, then read an optional minus sign and digits, consuming the character after them */
+static cell getint(void) {{
+    cell c = getch(), n = 0;
+    int negative;
+    while (c == ' ' || c == '\t' || c == '\n' || c == '\r') {{
+        c = getch();
+    }}
+    negative = c == '-';
+    if (negative) {{
+        c = getch();
+    }}
+    while (c >= '0' && c <= '9') {{
+        n = n * 10 + (c - '0');
+        c = getch();
+    }}
+    return negative ? -n : n;
+}}
+
+/* First fit allocation, with a header of the size and whether it is in use before each block */
+static cell alloc(cell size) {{
+    cell block = 0;
+    while (block < HP && (heap[(cell)(block + 1)] || heap[block] < size)) {{
+        block = block + heap[block] + 2;
+    }}
+    if (block >= HP) {{
+        block = HP;
+        heap[block] = size;
+        HP = HP + size + 2;
+    }}
+    heap[(cell)(block + 1)] = 1;
+    return block + 2;
+}}
+
+static void dump(int hex) {{
+    cell tape[0x100] = {{0}};
+{dump_tape}    for (int i = 0; i < STACK_SIZE && {first} + 2 * i < 0x100; i++) {{
+        tape[{first} + 2 * i] = stack[i];
+    }}
+    for (int i = 0; i < 0x100; i++) {{
+        if (i % 16 == 0) {{
+            printf("%03d-%03d: ", i, i + 15);
+        }}
+        printf(hex ? "%02x " : "%3d ", (unsigned)tape[i]);
+        if ((i + 1) % 16 == 0) {{
+            printf("\n");
+        }}
+    }}
+}}
+
+"#
+        )
+    }
+}
+
+/// Quote a string as a C string literal.
+fn c_string(s: &str) -> String {
+    let mut result = String::from('"');
+    for byte in s.bytes() {
+        match byte {
+            b'"' | b'\\' => {
+                result.push('\\');
+                result.push(byte as char);
+            }
+            b' '..=b'~' => result.push(byte as char),
+            _ => write!(result, "\\{byte:03o}").unwrap(),
+        }
+    }
+    result.push('"');
+    result
+}
+
+/// Make text safe to put in a C comment.
+fn c_comment(s: &str) -> String {
+    s.replace("*/", "* /")
+}
+
+impl Program {
+    /// Compile the program straight to C, with cells of `cell_bytes` bytes.
+    ///
+    /// This is much faster to run than the BrainFuck compiled to C, and prints
+    /// the same output, so it is useful for checking the logic of a program.
+    pub fn assemble_to_c(&self, cell_bytes: u8) -> String {
+        info!("Compiling assembly straight to C...");
+        CWriter::new(self, cell_bytes).program(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write as _;
+    use std::process::{Command, Stdio};
+
+    /// Compile C with `gcc` and run it with the given input.
+    fn run_c(c: &str, input: &str, name: &str) -> String {
+        let dir = std::env::temp_dir();
+        let source = dir.join(format!("basm_c_test_{name}_{}.c", std::process::id()));
+        let exe = dir.join(format!("basm_c_test_{name}_{}", std::process::id()));
+        std::fs::write(&source, c).unwrap();
+        let status = Command::new("gcc")
+            .arg("-o")
+            .arg(&exe)
+            .arg(&source)
+            .status()
+            .unwrap();
+        assert!(status.success(), "{c}");
+        let mut child = Command::new(&exe)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+        std::fs::remove_file(source).unwrap();
+        std::fs::remove_file(exe).unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    /// Check that the program prints the same thing compiled straight to C as through BrainFuck.
+    fn assert_same_output(source: &str, input: &str, name: &str) {
+        let program = parse(source).unwrap();
+        for cell_bytes in [1, 2] {
+            let expected = interpret_with_input(program.assemble(), input, cell_bytes).unwrap();
+            let c = program.assemble_to_c(cell_bytes);
+            let name = format!("{name}{cell_bytes}");
+            assert_eq!(run_c(&c, input, &name), expected, "{source}");
+        }
+    }
+
+    #[test]
+    fn test_c_structure() {
+        let program = parse("main:\n    R0 = 5\n    putint R0\n    jmp main\n").unwrap();
+        let c = program.assemble_to_c(2);
+        assert!(c.contains("typedef unsigned short cell;"));
+        assert!(c.contains("static cell R0;"));
+        assert!(c.contains("switch (CURRENT_BASIC_BLOCK)"));
+        assert!(c.contains("case 1: /* main */"));
+        assert!(c.contains("/* putint R0 */\n            putint(R0);"));
+        assert!(c.contains("NEXT_BASIC_BLOCK = 1;"));
+        // There is no BrainFuck tape
+        assert!(!c.contains("ptr"));
+    }
+
+    #[test]
+    fn test_c_arithmetic() {
+        assert_same_output(
+            r#"
+            main:
+                R0 = 200
+                R1 = 100
+                R2 add R0, R1
+                R3 sub R1, R0
+                R4 mul R0, R1
+                R5 div R0, 7
+                R6 mod R0, 7
+                R8 mod R0, 0
+                putint R2
+                putchar ' '
+                putint R3
+                putchar ' '
+                putint R4
+                putchar ' '
+                putint R5
+                putchar ' '
+                putint R6
+                putchar ' '
+                putint R8
+                putchar ' '
+                inc R0
+                dec R1, 3
+                putint R0
+                putchar ' '
+                putint R1
+            "#,
+            "",
+            "arithmetic",
+        );
+    }
+
+    #[test]
+    fn test_c_divide_by_zero() {
+        // The BrainFuck never finishes, but the C stops at the division
+        let program = parse("R0 = 1\nputint R0\nR0 div R0, 0\nputint R0").unwrap();
+        assert_eq!(run_c(&program.assemble_to_c(1), "", "zero"), "1");
+    }
+
+    #[test]
+    fn test_c_signed() {
+        assert_same_output(
+            r#"
+            main:
+                getint R0
+                getint R1
+                R2 idiv R0, R1
+                R3 imul R0, R1
+                R4 ilt R0, R1
+                R5 ilt R1, R0
+                neg R0, R6
+                putsint R0
+                putchar ' '
+                putsint R1
+                putchar ' '
+                putsint R2
+                putchar ' '
+                putsint R3
+                putchar ' '
+                putint R4
+                putint R5
+                putchar ' '
+                putsint R6
+            "#,
+            " -23\n4 ",
+            "signed",
+        );
+    }
+
+    #[test]
+    fn test_c_wide() {
+        assert_same_output(
+            r#"
+            main:
+                getchar R0
+                W0 = R0
+                W1 = 0x12345678
+                W2 mul W0, W1
+                W3 add W2, 0xf0000000
+                putint W2
+                putchar ' '
+                putint W3
+                putchar ' '
+                W3 sub 5, W0
+                putint W3
+            "#,
+            "!",
+            "wide",
+        );
+    }
+
+    #[test]
+    fn test_c_blocks() {
+        assert_same_output(
+            r#"
+            main:
+                R0 = 5
+                while R0
+                    R1 mod R0, 2
+                    if R1
+                        putint R0
+                    else
+                        putchar '-'
+                    end
+                    dec R0
+                end
+            "#,
+            "",
+            "blocks",
+        );
+    }
+
+    #[test]
+    fn test_c_functions() {
+        assert_same_output(
+            r#"
+            main:
+                push 5
+                call fact
+                putint [SP]
+                R0 lea [SP]
+                push R0
+                call show
+                quit
+
+            func fact(n) locals r
+                r eq n, 0
+                jmp_if r, base
+                push n
+                dec [SP]
+                call fact
+                pop r
+                r mul r, n
+                ret r
+            base:
+                ret 1
+            endfunc
+
+            func show(p) locals q
+                q lea p
+                R1 = p
+                putchar ' '
+                putint [R1]
+                putchar ' '
+                R1 sub p, q
+                putint R1
+            endfunc
+            "#,
+            "",
+            "functions",
+        );
+    }
+
+    #[test]
+    fn test_c_data() {
+        assert_same_output(
+            r#"
+            main:
+                R0 lea msg
+                push R0
+                call puts
+                putchar [digits + 2]
+                [digits] = '!'
+                putchar [digits]
+                putint SP
+                quit
+            puts:
+                pop R1
+            puts_loop:
+                jmp_if [R1], puts_char
+                ret
+            puts_char:
+                putchar [R1]
+                inc R1
+                jmp puts_loop
+
+            .data
+            msg: .string "data"
+            digits: .bytes '1', '2', '3'
+            "#,
+            "",
+            "data",
+        );
+    }
+
+    #[test]
+    fn test_c_comparisons_and_bits() {
+        assert_same_output(
+            r#"
+            main:
+                R0 = 12
+                R1 = 10
+                R2 lt R0, R1
+                R3 le R1, R1
+                R4 gt R0, R1
+                R5 ge R1, R0
+                R6 eq R0, 12
+                R7 neq R0, 12
+                putint R2
+                putint R3
+                putint R4
+                putint R5
+                putint R6
+                putint R7
+                putchar ' '
+                R2 and R0, R1
+                R3 or R0, R1
+                R4 xor R0, R1
+                not R0, R5
+                R6 shl R0, 2
+                R7 shr R0, 3
+                R8 shl R0, 20
+                putint R2
+                putchar ' '
+                putint R3
+                putchar ' '
+                putint R4
+                putchar ' '
+                putint R5
+                putchar ' '
+                putint R6
+                putchar ' '
+                putint R7
+                putchar ' '
+                putint R8
+            "#,
+            "",
+            "bits",
+        );
+    }
+
+    #[test]
+    fn test_c_control_flow_and_memory() {
+        assert_same_output(
+            r#"
+            main:
+                getchar R0
+                push R0
+                push 2
+                call twice
+                pop R1
+                putint R1
+                putchar '\n'
+                R2 alloc 3
+                {R2} = 'h'
+                R3 lea {R2} + 1
+                {R3} = 'i'
+                putchar {R2}
+                putchar {R3}
+                free R2
+                R4 alloc 2
+                putint R4
+                R5 = 3
+            loop:
+                putint R5
+                dec R5
+                jmp_if R5, loop
+                getchar R6
+                putint R6
+                quit
+            twice:
+                pop R1
+                pop R0
+                R1 mul R0, R1
+                push R1
+                ret
+            "#,
+            "!",
+            "control",
+        );
+    }
+
+    /// The cells of a hexadecimal dump.
+    fn dump_cells(dump: &str) -> Vec<u64> {
+        dump.lines()
+            .flat_map(|line| line[9..].split_whitespace())
+            .map(|cell| u64::from_str_radix(cell, 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_c_dump() {
+        let program = parse("R0 = 7\npush 63\nhex_dump").unwrap();
+        let bf = dump_cells(&interpret_with_input(program.assemble(), "", 1).unwrap());
+        let c = dump_cells(&run_c(&program.assemble_to_c(1), "", "dump"));
+        assert_eq!((bf.len(), c.len()), (256, 256));
+
+        // The registers and the stack are where the BrainFuck keeps them
+        let top = Layout::default().stack().cell(1).address();
+        for cell in [R0.address(), SP.address(), top] {
+            assert_eq!(c[cell], bf[cell], "cell {cell}");
+        }
+        assert_eq!((c[R0.address()], c[SP.address()], c[top]), (7, 1, 63));
+
+        // The assembler's scratch cells hold whatever each backend last left
+        // in them, which is not the same
+        assert_eq!((bf[VAL_TEMP.address()], c[VAL_TEMP.address()]), (63, 0));
+        assert_eq!((bf[PUSH_TEMP.address()], c[PUSH_TEMP.address()]), (0, 63));
+    }
+}
@@ -0,0 +1,419 @@
+use super::*;
+use crate::bf::Op as BfOp;
+
+use std::collections::{BTreeSet, HashMap};
+use std::io::{Read, Write};
+use std::ops::Range;
+
+/// The registers a program can name, in the order they are listed by [`Debugger::registers`].
+const VISIBLE_REGISTERS: [&str; 19] = [
+    "R0", "R1", "R2", "R3", "R4", "R5", "R6", "R7", "R8", "R9", "R10", "R11", "R12", "R13", "R14",
+    "R15", "SP", "HP", "CALL_SP",
+];
+
+/// Why the debugger stopped running the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The step that was asked for is done.
+    Stepped,
+    /// The program reached a breakpoint on this line.
+    Breakpoint(usize),
+    /// The program finished.
+    Finished,
+}
+
+/// Runs an assembled program in the interpreter, one instruction or basic block at a time.
+///
+/// The program is assembled with a source map, so the debugger always knows which
+/// instruction is about to run. Breakpoints can be set on source lines or labels, and
+/// registers and tables are read by name and logical index instead of by tape cell.
+///
+/// ```rust
+/// use basm::{Debugger, Program, StopReason};
+///
+/// let program = Program::parse("R0 = 5\nR0 add 1\nputint R0").unwrap();
+/// let mut debugger = Debugger::new(&program, 1).unwrap();
+/// let mut output = Vec::new();
+/// assert_eq!(debugger.break_at_line(3), Ok(3));
+/// let stop = debugger.resume(&mut "".as_bytes(), &mut output).unwrap();
+/// assert_eq!(stop, StopReason::Breakpoint(3));
+/// assert_eq!(debugger.register("R0"), Some(6));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Debugger {
+    interpreter: Interpreter,
+    source_map: SourceMap,
+    /// Where the program's stack, heap, and call stack are on the tape.
+    layout: Layout,
+    /// For every BrainFuck operation, the index of the innermost instruction it belongs to.
+    instructions: Vec<Option<usize>>,
+    /// The line of the first instruction after each label.
+    labels: HashMap<Symbol, usize>,
+    /// The file and line of each breakpoint.
+    breakpoints: BTreeSet<(Option<String>, usize)>,
+    /// The instruction that is about to run.
+    current: Option<usize>,
+}
+
+impl Debugger {
+    /// Assemble a program and prepare to run it with cells of `cell_bytes` bytes.
+    pub fn new(program: &Program, cell_bytes: u8) -> Result<Self, String> {
+        let (bf, source_map) = Assembler::new()
+            .assemble_with_source_map(program)
+            .map_err(|e| e.to_string())?;
+        let layout = *program.layout();
+
+        // Parse the code between every boundary of an instruction separately,
+        // so no operation is shared between two instructions
+        let boundaries: BTreeSet<usize> = source_map
+            .mappings()
+            .iter()
+            .flat_map(|m| [m.bf.start, m.bf.end])
+            .chain([0, bf.len()])
+            .collect();
+        let mut ops: Vec<BfOp> = vec![];
+        let mut instructions = vec![];
+        for (&start, &end) in boundaries.iter().zip(boundaries.iter().skip(1)) {
+            let piece = crate::bf::parse_fragment(&bf[start..end]).map_err(|e| e.to_string())?;
+            instructions.extend(std::iter::repeat_n(
+                source_map.lookup_index(start),
+                piece.len(),
+            ));
+            ops.extend(piece);
+        }
+
+        // A label stops at the first instruction written after it
+        let mut labels = HashMap::new();
+        let mut pending = vec![];
+        for (i, op) in program.ops().iter().enumerate() {
+            let first = match op {
+                Op::Label(label, bb) => {
+                    pending.push(label.clone());
+                    bb.source(0)
+                }
+                Op::BasicBlock(bb) => bb.source(0),
+                _ => program.source(i),
+            };
+            if let Some(first) = first {
+                for label in pending.drain(..) {
+                    labels.insert(label, first.span.line);
+                }
+            }
+        }
+
+        Ok(Self {
+            interpreter: Interpreter::new(ops, cell_bytes, layout.tape_size)
+                .map_err(|e| e.to_string())?,
+            source_map,
+            layout,
+            instructions,
+            labels,
+            breakpoints: BTreeSet::new(),
+            current: None,
+        })
+    }
+
+    /// Attach the name of the file the program was read from, which its
+    /// breakpoints are set in.
+    pub fn with_file(mut self, file: impl Into<String>) -> Self {
+        self.source_map.file = Some(file.into());
+        self
+    }
+
+    /// The source map of the assembled program.
+    pub fn source_map(&self) -> &SourceMap {
+        &self.source_map
+    }
+
+    /// The instruction that is about to run, if the program has reached one.
+    pub fn location(&self) -> Option<&SourceLocation> {
+        self.current
+            .map(|i| &self.source_map.mappings()[i].location)
+    }
+
+    /// Has the program finished executing?
+    pub fn is_done(&self) -> bool {
+        self.interpreter.is_done()
+    }
+
+    /// Stop before running any instruction on `line`, and return the line.
+    /// Lines that no code was generated for can't have a breakpoint.
+    pub fn break_at_line(&mut self, line: usize) -> Result<usize, String> {
+        if !self
+            .source_map
+            .mappings()
+            .iter()
+            .any(|m| m.location.span.line == line)
+        {
+            return Err(format!("No instructions on line {line}"));
+        }
+        self.breakpoints
+            .insert((self.source_map.file.clone(), line));
+        Ok(line)
+    }
+
+    /// Stop before running the first instruction after `label`, and return its line.
+    pub fn break_at_label(&mut self, label: &str) -> Result<usize, String> {
+        let line = *self
+            .labels
+            .get(label)
+            .ok_or_else(|| format!("No instructions follow a label named `{label}`"))?;
+        self.break_at_line(line)
+    }
+
+    /// Remove the breakpoint on `line`, and return whether there was one.
+    pub fn clear_breakpoint(&mut self, line: usize) -> bool {
+        self.breakpoints
+            .remove(&(self.source_map.file.clone(), line))
+    }
+
+    /// The file and line of each breakpoint, in order.
+    pub fn breakpoints(&self) -> impl Iterator<Item = (Option<&str>, usize)> + '_ {
+        self.breakpoints
+            .iter()
+            .map(|(file, line)| (file.as_deref(), *line))
+    }
+
+    /// Run until the next instruction is about to start.
+    pub fn step_instruction(
+        &mut self,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<StopReason, Box<dyn std::error::Error>> {
+        self.run_until(input, output, |_| true)
+    }
+
+    /// Run until an instruction in another basic block is about to start.
+    pub fn step_block(
+        &mut self,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<StopReason, Box<dyn std::error::Error>> {
+        let block = self.basic_block();
+        self.run_until(input, output, |debugger| debugger.basic_block() != block)
+    }
+
+    /// Run until a breakpoint or the end of the program.
+    pub fn resume(
+        &mut self,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<StopReason, Box<dyn std::error::Error>> {
+        self.run_until(input, output, |_| false)
+    }
+
+    /// Run until an instruction is about to start and either has a breakpoint
+    /// or satisfies `stop`.
+    fn run_until(
+        &mut self,
+        input: &mut impl Read,
+        output: &mut impl Write,
+        stop: impl Fn(&Self) -> bool,
+    ) -> Result<StopReason, Box<dyn std::error::Error>> {
+        // Entering an instruction again after leaving it counts as a new step
+        let mut previous = self.current;
+        while !self.interpreter.is_done() {
+            self.interpreter.step(input, output)?;
+            let next = self
+                .instructions
+                .get(self.interpreter.pc())
+                .copied()
+                .flatten();
+            if next.is_some() && next != previous {
+                self.current = next;
+                let line = self.location().map_or(0, |l| l.span.line);
+                if self
+                    .breakpoints
+                    .contains(&(self.source_map.file.clone(), line))
+                {
+                    output.flush()?;
+                    return Ok(StopReason::Breakpoint(line));
+                }
+                if stop(self) {
+                    output.flush()?;
+                    return Ok(StopReason::Stepped);
+                }
+            }
+            previous = next;
+        }
+        self.current = None;
+        output.flush()?;
+        Ok(StopReason::Finished)
+    }
+
+    /// The number of the basic block being run.
+    pub fn basic_block(&self) -> u32 {
+        self.cell(CURRENT_BASIC_BLOCK)
+    }
+
+    /// The value of a register, by name.
+    pub fn register(&self, name: &str) -> Option<u32> {
+        register(name).map(|reg| self.cell(reg))
+    }
+
+    /// The general purpose registers and the stack, heap, and call stack pointers.
+    pub fn registers(&self) -> Vec<(&'static str, u32)> {
+        VISIBLE_REGISTERS
+            .iter()
+            .map(|&name| (name, self.register(name).unwrap_or(0)))
+            .collect()
+    }
+
+    /// The values at the logical `indices` of a table.
+    pub fn table(&self, table: &Table, indices: Range<usize>) -> Vec<u32> {
+        indices
+            .take_while(|&i| i < table.capacity())
+            .map(|i| self.cell(table.cell(i)))
+            .collect()
+    }
+
+    /// The values pushed on the stack, from the bottom up.
+    pub fn stack(&self) -> Vec<u32> {
+        self.table(&self.layout.stack(), 1..self.cell(SP) as usize + 1)
+    }
+
+    /// The return addresses on the call stack, from the bottom up.
+    pub fn call_stack(&self) -> Vec<u32> {
+        self.table(
+            &self.layout.call_stack(),
+            1..self.cell(CALL_SP) as usize + 1,
+        )
+    }
+
+    /// The values at the logical `indices` of the heap.
+    pub fn heap(&self, indices: Range<usize>) -> Vec<u32> {
+        self.table(&self.layout.heap(), indices)
+    }
+
+    fn cell(&self, location: StaticLocation) -> u32 {
+        self.interpreter.tape()[location.address()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = r#"main:
+    R0 = 3
+    push 7
+    push 8
+    R1 alloc 2
+    {R1} = 42
+loop:
+    putint R0
+    dec R0
+    jmp_if R0, loop
+    call done
+    quit
+done:
+    putchar '\n'
+    ret
+"#;
+
+    fn debugger() -> Debugger {
+        Debugger::new(&parse(SOURCE).unwrap(), 1).unwrap()
+    }
+
+    fn line(debugger: &Debugger) -> usize {
+        debugger.location().unwrap().span.line
+    }
+
+    #[test]
+    fn test_step_instruction() {
+        let mut debugger = debugger();
+        let mut output = Vec::new();
+        let mut lines = vec![];
+        while debugger
+            .step_instruction(&mut "".as_bytes(), &mut output)
+            .unwrap()
+            != StopReason::Finished
+        {
+            lines.push(line(&debugger));
+        }
+        assert_eq!(
+            lines,
+            [2, 3, 4, 5, 6, 8, 9, 10, 8, 9, 10, 8, 9, 10, 11, 14, 15, 12]
+        );
+        assert_eq!(output, b"321\n");
+        assert!(debugger.is_done());
+    }
+
+    #[test]
+    fn test_step_block() {
+        let mut debugger = debugger();
+        let mut output = Vec::new();
+        let mut lines = vec![];
+        while debugger
+            .step_block(&mut "".as_bytes(), &mut output)
+            .unwrap()
+            != StopReason::Finished
+        {
+            lines.push(line(&debugger));
+        }
+        // The loop body is one basic block, and the jump out of it is another
+        assert_eq!(lines, [2, 8, 10, 8, 10, 8, 10, 11, 14, 15, 12]);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut debugger = debugger();
+        let mut output = Vec::new();
+        assert_eq!(debugger.break_at_label("loop"), Ok(8));
+        assert_eq!(debugger.break_at_label("done"), Ok(14));
+        assert!(debugger.break_at_label("missing").is_err());
+        // Labels and lines past the end have no code to stop at
+        assert!(debugger.break_at_line(7).is_err());
+        assert!(debugger.break_at_line(100).is_err());
+        assert_eq!(
+            debugger.breakpoints().collect::<Vec<_>>(),
+            [(None, 8), (None, 14)]
+        );
+
+        let mut input = "".as_bytes();
+        assert_eq!(
+            debugger.resume(&mut input, &mut output).unwrap(),
+            StopReason::Breakpoint(8)
+        );
+        assert_eq!(debugger.register("R0"), Some(3));
+        assert_eq!(
+            debugger.resume(&mut input, &mut output).unwrap(),
+            StopReason::Breakpoint(8)
+        );
+        assert_eq!(debugger.register("R0"), Some(2));
+        assert_eq!(output, b"3");
+
+        assert!(debugger.clear_breakpoint(8));
+        assert_eq!(
+            debugger.resume(&mut input, &mut output).unwrap(),
+            StopReason::Breakpoint(14)
+        );
+        assert_eq!(debugger.call_stack().len(), 1);
+        assert_eq!(
+            debugger.resume(&mut input, &mut output).unwrap(),
+            StopReason::Finished
+        );
+        assert_eq!(output, b"321\n");
+    }
+
+    #[test]
+    fn test_inspect_tables() {
+        let mut debugger = debugger();
+        debugger.break_at_line(8).unwrap();
+        debugger
+            .resume(&mut "".as_bytes(), &mut Vec::new())
+            .unwrap();
+
+        assert_eq!(debugger.stack(), [7, 8]);
+        assert_eq!(debugger.register("SP"), Some(2));
+        let registers = debugger.registers();
+        assert_eq!(registers[0], ("R0", 3));
+        assert_eq!(registers.len(), 19);
+
+        // The block has a two cell header of its size and whether it is in use
+        let block = debugger.register("R1").unwrap() as usize;
+        assert_eq!(debugger.heap(block - 2..block + 1), [2, 1, 42]);
+        assert_eq!(debugger.register("HP"), Some(block as u32 + 2));
+        assert_eq!(debugger.register("NOT_A_REGISTER"), None);
+    }
+}
@@ -0,0 +1,165 @@
+use super::*;
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+/// A span of text in assembly source code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    /// The byte offset of the start of the span.
+    pub offset: usize,
+    /// The line the span starts on, starting at 1.
+    pub line: usize,
+    /// The column the span starts on, starting at 1.
+    pub column: usize,
+    /// The length of the span in bytes.
+    pub len: usize,
+}
+
+impl Span {
+    /// Create a span starting at `start` with the given length.
+    pub fn new(start: parse::Input, len: usize) -> Self {
+        Self {
+            offset: start.location_offset(),
+            line: start.location_line() as usize,
+            column: start.get_utf8_column(),
+            len,
+        }
+    }
+
+    /// Create a span covering the text consumed between `start` and `end`.
+    pub fn between(start: parse::Input, end: parse::Input) -> Self {
+        Self::new(start, end.location_offset() - start.location_offset())
+    }
+}
+
+/// Where an assembly error occurred, with enough context to display it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorLocation {
+    /// The file the error occurred in, if the source came from a file.
+    pub file: Option<String>,
+    /// The offending token.
+    pub span: Span,
+    /// The full line of source containing the offending token.
+    pub snippet: String,
+}
+
+impl ErrorLocation {
+    pub fn new(source: &str, span: Span) -> Self {
+        let snippet = source
+            .lines()
+            .nth(span.line.saturating_sub(1))
+            .unwrap_or("")
+            .to_string();
+        Self {
+            file: None,
+            span,
+            snippet,
+        }
+    }
+}
+
+/// An error encountered while assembling a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmError {
+    /// The source is not valid assembly.
+    Syntax {
+        message: String,
+        location: ErrorLocation,
+    },
+    /// An operand names a register that does not exist.
+    UnknownRegister {
+        name: String,
+        location: ErrorLocation,
+    },
+    /// A jump or call targets a label that is never defined.
+    UndefinedLabel {
+        label: Symbol,
+        location: ErrorLocation,
+    },
+    /// A label is defined more than once, possibly in different files.
+    DuplicateLabel {
+        label: Symbol,
+        location: ErrorLocation,
+        /// Where the label was first defined.
+        previous: Box<ErrorLocation>,
+    },
+}
+
+impl AsmError {
+    /// Where in the source the error occurred.
+    pub fn location(&self) -> &ErrorLocation {
+        match self {
+            Self::Syntax { location, .. }
+            | Self::UnknownRegister { location, .. }
+            | Self::UndefinedLabel { location, .. }
+            | Self::DuplicateLabel { location, .. } => location,
+        }
+    }
+
+    /// The span of the offending token.
+    pub fn span(&self) -> Span {
+        self.location().span
+    }
+
+    /// Every location the error refers to, starting with where it occurred.
+    pub(crate) fn locations_mut(&mut self) -> Vec<&mut ErrorLocation> {
+        match self {
+            Self::Syntax { location, .. }
+            | Self::UnknownRegister { location, .. }
+            | Self::UndefinedLabel { location, .. } => vec![location],
+            Self::DuplicateLabel {
+                location, previous, ..
+            } => vec![location, &mut **previous],
+        }
+    }
+
+    /// Attach the name of the file the source was read from to the locations
+    /// that aren't already known to be in another file.
+    pub fn with_file(mut self, file: impl Into<String>) -> Self {
+        let file = file.into();
+        for location in self.locations_mut() {
+            location.file.get_or_insert_with(|| file.clone());
+        }
+        self
+    }
+
+    /// A short description of the error, without its location.
+    pub fn message(&self) -> String {
+        match self {
+            Self::Syntax { message, .. } => message.clone(),
+            Self::UnknownRegister { name, .. } => format!("unknown register `{name}`"),
+            Self::UndefinedLabel { label, .. } => format!("undefined label `{label}`"),
+            Self::DuplicateLabel {
+                label, previous, ..
+            } => format!(
+                "label `{label}` is already defined at {}:{}:{}",
+                previous.file.as_deref().unwrap_or("<input>"),
+                previous.span.line,
+                previous.span.column
+            ),
+        }
+    }
+}
+
+impl Display for AsmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let location = self.location();
+        let span = location.span;
+        let file = location.file.as_deref().unwrap_or("<input>");
+        let line_number = span.line.to_string();
+        let gutter = " ".repeat(line_number.len());
+
+        writeln!(f, "error: {}", self.message())?;
+        writeln!(f, "{gutter}--> {file}:{}:{}", span.line, span.column)?;
+        writeln!(f, "{gutter} |")?;
+        writeln!(f, "{line_number} | {}", location.snippet)?;
+        write!(
+            f,
+            "{gutter} | {}{}",
+            " ".repeat(span.column.saturating_sub(1)),
+            "^".repeat(span.len.max(1))
+        )
+    }
+}
+
+impl std::error::Error for AsmError {}
@@ -0,0 +1,509 @@
+use super::*;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Finds the files named by `include` directives, and parses programs made of
+/// several files.
+///
+/// A line like `include "lib/io.basm"` is replaced by the contents of that file,
+/// so its labels and macros can be used by the including file. Relative names
+/// are looked up next to the including file first (or in the current directory
+/// for source that didn't come from a file), and then in each search path in
+/// the order they were added. Files added with [`Loader::with_file`] don't live
+/// on disk, and are found by the exact name they were added with. The
+/// [standard library](STDLIB) is available to every loader this way.
+///
+/// Each file is included at most once, so a library can be included by several
+/// files that use it. A file that includes itself, directly or through other
+/// files, is an error.
+///
+/// Errors point at the file and line where they were written. Everything else
+/// parsed from an included file is reported at the `include` line of the
+/// top-level file that brought it in, so source maps and the debugger only ever
+/// refer to the file that was parsed.
+///
+/// ```rust
+/// use basm::{interpret_with_input, Loader};
+///
+/// let loader = Loader::new().with_file("lib/greet.basm", "greet:\n    putchar 'h'\n    ret");
+/// let program = loader
+///     .parse("include \"lib/greet.basm\"\nmain:\n    call greet\n    putchar 'i'", None)
+///     .unwrap();
+/// assert_eq!(interpret_with_input(program.assemble(), "", 1).unwrap(), "hi");
+/// ```
+#[derive(Debug, Clone)]
+pub struct Loader {
+    search_paths: Vec<PathBuf>,
+    /// Files that don't live on disk, by the name they're included with.
+    files: HashMap<String, String>,
+}
+
+/// A file that has been included, identified so it's only included once.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum FileId {
+    Memory(String),
+    Disk(PathBuf),
+}
+
+/// A file that is part of the program.
+struct File {
+    /// The name to report errors with, if the source came from a file.
+    name: Option<String>,
+    /// The directory relative names are looked up in.
+    dir: Option<PathBuf>,
+    text: String,
+    /// The byte offset where each line of the text starts.
+    line_starts: Vec<usize>,
+    /// The span of the `include` line in the top-level file that brought this
+    /// file in, or `None` for the top-level file itself.
+    included_at: Option<Span>,
+}
+
+/// The source of every file in a program, with the includes replaced by the
+/// files they name.
+struct Inclusion {
+    files: Vec<File>,
+    source: String,
+    /// The source with its comments stripped.
+    stripped: String,
+    /// The file and line each line of the source came from.
+    origins: Vec<(usize, usize)>,
+    /// The byte offset where each line of the source starts.
+    line_starts: Vec<usize>,
+}
+
+impl Default for Loader {
+    fn default() -> Self {
+        Self {
+            search_paths: vec![],
+            files: STDLIB
+                .iter()
+                .map(|(name, source)| (name.to_string(), source.to_string()))
+                .collect(),
+        }
+    }
+}
+
+impl Loader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Look for included files in `dir` too, after the directories already added.
+    pub fn with_search_path(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.search_paths.push(dir.into());
+        self
+    }
+
+    /// Make a file that doesn't live on disk available to `include` as `name`.
+    pub fn with_file(mut self, name: impl Into<String>, source: impl Into<String>) -> Self {
+        self.files.insert(name.into(), source.into());
+        self
+    }
+
+    /// Parse a program along with every file it includes. `path` is where
+    /// the source was read from, if it came from a file.
+    pub fn parse(&self, input: &str, path: Option<&Path>) -> Result<Program, AsmError> {
+        let root = File::new(
+            path.map(|path| path.display().to_string()),
+            path.and_then(Path::parent).map(Path::to_path_buf),
+            input.to_string(),
+            None,
+        );
+        let mut inclusion = Inclusion {
+            files: vec![],
+            source: String::new(),
+            stripped: String::new(),
+            origins: vec![],
+            line_starts: vec![],
+        };
+        let mut including = vec![];
+        if let Some(path) = path {
+            including.push(FileId::Disk(
+                path.canonicalize().unwrap_or_else(|_| path.to_path_buf()),
+            ));
+        }
+        let mut included = including.clone();
+        self.include(&mut inclusion, root, &mut including, &mut included)?;
+
+        let mut program = parse::parse_source(&inclusion.source, &inclusion.stripped)
+            .map_err(|e| inclusion.relocate_error(e))?;
+        program.relocate(|span| inclusion.top_level_span(span));
+        Ok(program)
+    }
+
+    /// Add the lines of a file to the program, followed by the files it includes.
+    ///
+    /// Included files go after the file's own code, each behind a `quit`, so
+    /// including a library never changes where the program starts or lets the
+    /// code before it fall through into it.
+    fn include(
+        &self,
+        inclusion: &mut Inclusion,
+        file: File,
+        including: &mut Vec<FileId>,
+        included: &mut Vec<FileId>,
+    ) -> Result<(), AsmError> {
+        let index = inclusion.files.len();
+        let stripped = parse::strip_comments(&file.text);
+        inclusion.files.push(file);
+        let file = &inclusion.files[index];
+        let lines: Vec<String> = file.text.split('\n').map(str::to_string).collect();
+        let error = |inclusion: &Inclusion, line: usize, message: String| AsmError::Syntax {
+            message,
+            location: inclusion.files[index].location(line),
+        };
+
+        let mut includes = vec![];
+        for (i, stripped_line) in stripped.split('\n').enumerate() {
+            let Some(name) = include_directive(stripped_line) else {
+                inclusion.push(&lines[i], stripped_line, (index, i));
+                continue;
+            };
+            // Keep the line, so errors on it can still be found
+            inclusion.push("", "", (index, i));
+            let name = name.map_err(|message| error(inclusion, i, message))?;
+            let (id, mut file) = self
+                .find(name, &inclusion.files[index])
+                .map_err(|message| error(inclusion, i, message))?;
+            if let Some(start) = including.iter().position(|other| *other == id) {
+                let cycle: Vec<String> = including[start..]
+                    .iter()
+                    .chain([&id])
+                    .map(FileId::to_string)
+                    .collect();
+                return Err(error(
+                    inclusion,
+                    i,
+                    format!("include cycle: {}", cycle.join(" -> ")),
+                ));
+            }
+            if included.contains(&id) {
+                continue;
+            }
+            // Code from an included file is reported at the top-level `include`
+            let included_at = inclusion.files[index]
+                .included_at
+                .unwrap_or_else(|| inclusion.files[index].line_span(i));
+            file.included_at = Some(included_at);
+            included.push(id.clone());
+            includes.push((i, id, file));
+        }
+
+        for (i, id, file) in includes {
+            inclusion.push("quit", "quit", (index, i));
+            including.push(id);
+            self.include(inclusion, file, including, included)?;
+            including.pop();
+        }
+        Ok(())
+    }
+
+    /// Find and read the file an `include` in `from` names.
+    fn find(&self, name: &str, from: &File) -> Result<(FileId, File), String> {
+        if let Some(text) = self.files.get(name) {
+            let id = FileId::Memory(name.to_string());
+            return Ok((id, File::new(Some(name.into()), None, text.clone(), None)));
+        }
+
+        let dirs: Vec<PathBuf> = std::iter::once(from.dir.clone().unwrap_or_default())
+            .chain(self.search_paths.iter().cloned())
+            .collect();
+        let path = dirs
+            .iter()
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file())
+            .ok_or_else(|| {
+                let searched: Vec<String> = dirs
+                    .iter()
+                    .map(|dir| match dir.as_os_str().is_empty() {
+                        true => ".".to_string(),
+                        false => dir.display().to_string(),
+                    })
+                    .collect();
+                format!("can't find `{name}` in {}", searched.join(", "))
+            })?;
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| format!("can't read `{}`: {e}", path.display()))?;
+        let id = FileId::Disk(path.canonicalize().unwrap_or_else(|_| path.clone()));
+        let dir = path.parent().map(Path::to_path_buf);
+        Ok((
+            id,
+            File::new(Some(path.display().to_string()), dir, text, None),
+        ))
+    }
+}
+
+impl std::fmt::Display for FileId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Memory(name) => write!(f, "{name}"),
+            Self::Disk(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+impl File {
+    fn new(
+        name: Option<String>,
+        dir: Option<PathBuf>,
+        text: String,
+        included_at: Option<Span>,
+    ) -> Self {
+        Self {
+            name,
+            dir,
+            line_starts: std::iter::once(0)
+                .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+                .collect(),
+            text,
+            included_at,
+        }
+    }
+
+    /// The span of the text on a line, without its indentation.
+    fn line_span(&self, line: usize) -> Span {
+        let text = self.text.split('\n').nth(line).unwrap_or("");
+        let indent = text.len() - text.trim_start().len();
+        Span {
+            offset: self.line_starts[line] + indent,
+            line: line + 1,
+            column: text[..indent].chars().count() + 1,
+            len: text.trim().len(),
+        }
+    }
+
+    /// The location of the text on a line, for an error.
+    fn location(&self, line: usize) -> ErrorLocation {
+        self.error_location(self.line_span(line))
+    }
+
+    fn error_location(&self, span: Span) -> ErrorLocation {
+        ErrorLocation {
+            file: self.name.clone(),
+            ..ErrorLocation::new(&self.text, span)
+        }
+    }
+}
+
+impl Inclusion {
+    /// Add a line from a file to the source.
+    fn push(&mut self, line: &str, stripped: &str, origin: (usize, usize)) {
+        if !self.origins.is_empty() {
+            self.source.push('\n');
+            self.stripped.push('\n');
+        }
+        self.line_starts.push(self.source.len());
+        self.source.push_str(line);
+        self.stripped.push_str(stripped);
+        self.origins.push(origin);
+    }
+
+    /// The file a span of the source came from, and where it is in that file.
+    fn locate(&self, span: Span) -> Option<(&File, Span)> {
+        let line = span.line.checked_sub(1)?;
+        let &(file, original) = self.origins.get(line)?;
+        let file = &self.files[file];
+        Some((
+            file,
+            Span {
+                offset: file.line_starts[original] + span.offset - self.line_starts[line],
+                line: original + 1,
+                ..span
+            },
+        ))
+    }
+
+    /// Where a span of the source is reported in the top-level file.
+    fn top_level_span(&self, span: Span) -> Span {
+        match self.locate(span) {
+            Some((file, span)) => file.included_at.unwrap_or(span),
+            None => span,
+        }
+    }
+
+    /// Point an error in the source at the files it was written in.
+    fn relocate_error(&self, mut error: AsmError) -> AsmError {
+        for location in error.locations_mut() {
+            match self.locate(location.span) {
+                Some((file, span)) => *location = file.error_location(span),
+                // Past the last line, like a block still open at the end of the source
+                None => location.file = self.files[0].name.clone(),
+            }
+        }
+        error
+    }
+}
+
+/// The name of the file an `include` line names, if the line is an `include`.
+fn include_directive(line: &str) -> Option<Result<&str, String>> {
+    let rest = line.trim().strip_prefix("include")?;
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let name = rest
+        .trim()
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+        .filter(|name| !name.is_empty() && !name.contains('"'));
+    Some(name.ok_or_else(|| "expected a file name in quotes after `include`".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(program: &Program) -> String {
+        interpret_with_input(program.assemble(), "", 1).unwrap()
+    }
+
+    fn loader() -> Loader {
+        Loader::new()
+            .with_file(
+                "lib/io.basm",
+                "include \"lib/math.basm\"\n\
+                 print_square:\n    \
+                     R0 mul R1, R1\n    \
+                     putint R0\n    \
+                     ret\n",
+            )
+            .with_file(
+                "lib/math.basm",
+                "macro double reg\n    reg add reg\nendmacro\n",
+            )
+    }
+
+    #[test]
+    fn test_included_code_runs() {
+        let source = r#"
+        include "lib/io.basm"
+        include "lib/math.basm" ;; Already included by io, so this does nothing
+        main:
+            R1 = 3
+            double R1
+            call print_square
+            quit
+        "#;
+        let program = loader().parse(source, None).unwrap();
+        assert_eq!(run(&program), "36");
+
+        // Code from the library is reported at the include
+        let bb = program
+            .ops()
+            .iter()
+            .find_map(|op| match op {
+                Op::Label(label, bb) if label.as_str() == "print_square" => Some(bb),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(bb.label_span().unwrap().line, 2);
+        assert_eq!(bb.sources[0].span.line, 2);
+        assert_eq!(bb.sources[0].instruction, "R0 mul R1, R1");
+    }
+
+    #[test]
+    fn test_errors_point_into_included_files() {
+        let loader = Loader::new().with_file("bad.basm", "\nputint Q9\n");
+        let error = loader.parse("include \"bad.basm\"", None).unwrap_err();
+        let location = error.location();
+        assert_eq!(location.file.as_deref(), Some("bad.basm"));
+        assert_eq!(location.span.line, 2);
+        assert_eq!(location.snippet, "putint Q9");
+
+        let error = Loader::new()
+            .parse("\ninclude \"missing.basm\"", None)
+            .unwrap_err();
+        assert!(
+            error
+                .message()
+                .starts_with("can't find `missing.basm` in ."),
+            "{error}"
+        );
+        assert_eq!(error.span().line, 2);
+        assert!(Loader::new().parse("include missing.basm", None).is_err());
+    }
+
+    #[test]
+    fn test_errors_at_the_end_of_the_source() {
+        let error = Loader::new()
+            .with_file("lib.basm", "helper:\n    ret\n")
+            .parse(
+                "include \"lib.basm\"\nmain:\n    if R0\n",
+                Some(Path::new("main.basm")),
+            )
+            .unwrap_err();
+        assert_eq!(error.message(), "expected `end`");
+        assert_eq!(error.location().file.as_deref(), Some("main.basm"));
+    }
+
+    #[test]
+    fn test_include_cycles() {
+        let loader = Loader::new()
+            .with_file("a.basm", "include \"b.basm\"")
+            .with_file("b.basm", "\ninclude \"a.basm\"");
+        let error = loader.parse("include \"a.basm\"", None).unwrap_err();
+        assert_eq!(error.message(), "include cycle: a.basm -> b.basm -> a.basm");
+        assert_eq!(error.location().file.as_deref(), Some("b.basm"));
+        assert_eq!(error.span().line, 2);
+    }
+
+    #[test]
+    fn test_duplicate_labels_across_files() {
+        let loader = Loader::new().with_file("lib.basm", "helper:\n    ret\n");
+        let source = "include \"lib.basm\"\nmain:\n    quit\n  helper:\n    ret\n";
+        let error = loader
+            .parse(source, None)
+            .unwrap_err()
+            .with_file("main.basm");
+        let AsmError::DuplicateLabel {
+            label,
+            location,
+            previous,
+        } = &error
+        else {
+            panic!("expected a duplicate label, found {error:?}");
+        };
+        assert_eq!(label.as_str(), "helper");
+        // Included files come after the code that includes them
+        assert_eq!(location.file.as_deref(), Some("lib.basm"));
+        assert_eq!((location.span.line, location.span.column), (1, 1));
+        assert_eq!(previous.file.as_deref(), Some("main.basm"));
+        assert_eq!(
+            error.message(),
+            "label `helper` is already defined at main.basm:4:3"
+        );
+    }
+
+    #[test]
+    fn test_files_on_disk() {
+        let dir = std::env::temp_dir().join(format!("basm-include-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        std::fs::write(
+            dir.join("lib/io.basm"),
+            "include \"util.basm\"\nshow:\n    putint R1\n    ret\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("lib/util.basm"),
+            "macro seven reg\n    reg = 7\nendmacro\n",
+        )
+        .unwrap();
+        let main = dir.join("src/main.basm");
+        std::fs::write(
+            &main,
+            "include \"io.basm\"\nmain:\n    seven R1\n    call show\n    quit\n",
+        )
+        .unwrap();
+
+        let source = std::fs::read_to_string(&main).unwrap();
+        // `io.basm` is only found through the search path, but `util.basm` is next to it
+        assert!(Loader::new().parse(&source, Some(&main)).is_err());
+        let loader = Loader::new().with_search_path(dir.join("lib"));
+        let program = loader.parse(&source, Some(&main)).unwrap();
+        assert_eq!(run(&program), "7");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
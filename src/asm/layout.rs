@@ -0,0 +1,203 @@
+use super::*;
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+/// How a program's memory is laid out on the tape.
+///
+/// The stack starts a little after the registers, and the heap and call stack
+/// follow it in that order. Cells allocated by the assembler come after the
+/// call stack, and everything has to fit in the tape.
+///
+/// ```rust
+/// use basm::{Layout, Program};
+///
+/// let program = Program::parse(".stack 200\n.tape 5000\nputint 5").unwrap();
+/// let layout = program.layout();
+/// assert_eq!(layout.stack_size, 200);
+/// assert_eq!(layout.heap().base(), layout.stack().after());
+/// assert!(layout.globals().address() <= layout.tape_size);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    /// The number of values the stack can hold.
+    pub stack_size: usize,
+    /// The number of cells on the heap, including block headers.
+    pub heap_size: usize,
+    /// The number of return addresses the call stack can hold.
+    pub call_stack_size: usize,
+    /// The number of cells on the tape.
+    pub tape_size: usize,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self {
+            stack_size: STACK_SIZE,
+            heap_size: HEAP_SIZE,
+            call_stack_size: CALL_STACK_SIZE,
+            tape_size: crate::TAPE_SIZE,
+        }
+    }
+}
+
+impl Layout {
+    /// The stack, which starts after the registers.
+    pub const fn stack(&self) -> Table {
+        Table::new(
+            self.stack_size,
+            StaticLocation::Address(REGISTER_COUNT + 100),
+        )
+    }
+
+    /// The heap, which starts after the stack.
+    pub const fn heap(&self) -> Table {
+        Table::new(self.heap_size, self.stack().after())
+    }
+
+    /// The call stack, which starts after the heap.
+    pub const fn call_stack(&self) -> Table {
+        Table::new(self.call_stack_size, self.heap().after())
+    }
+
+    /// The first cell after the call stack, where the assembler allocates globals.
+    pub const fn globals(&self) -> StaticLocation {
+        self.call_stack().after()
+    }
+
+    /// Check that the first `cells` cells of the tape fit in it.
+    pub fn check(&self, cells: usize) -> Result<(), LayoutError> {
+        if cells > self.tape_size {
+            return Err(LayoutError {
+                needed: cells,
+                layout: *self,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// A program needs more cells than its tape has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LayoutError {
+    /// The number of cells the program uses.
+    pub needed: usize,
+    /// The layout the program was assembled with.
+    pub layout: Layout,
+}
+
+impl Display for LayoutError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let Layout {
+            stack_size,
+            heap_size,
+            call_stack_size,
+            tape_size,
+        } = self.layout;
+        write!(
+            f,
+            "the program needs {} cells, but the tape only has {tape_size} \
+             (stack {stack_size}, heap {heap_size}, call stack {call_stack_size})",
+            self.needed
+        )
+    }
+}
+
+impl std::error::Error for LayoutError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tables_follow_each_other() {
+        let layout = Layout {
+            stack_size: 10,
+            heap_size: 20,
+            call_stack_size: 5,
+            tape_size: 1000,
+        };
+        assert_eq!(layout.stack().capacity(), 10);
+        assert_eq!(layout.heap().base(), layout.stack().after());
+        assert_eq!(layout.call_stack().base(), layout.heap().after());
+        assert_eq!(layout.globals(), layout.call_stack().after());
+        // Each table has four cells of bookkeeping, and two cells per value
+        assert_eq!(
+            layout.globals().address(),
+            REGISTER_COUNT + 100 + 3 * 4 + 2 * (10 + 20 + 5)
+        );
+        assert!(layout.check(layout.globals().address()).is_ok());
+        assert!(layout.check(1001).is_err());
+    }
+
+    #[test]
+    fn test_layout_directives() {
+        let program = parse(".stack 4\n.heap 16\nputint 1\n.call_stack 2\n.tape 400\n").unwrap();
+        assert_eq!(
+            *program.layout(),
+            Layout {
+                stack_size: 4,
+                heap_size: 16,
+                call_stack_size: 2,
+                tape_size: 400,
+            }
+        );
+        assert_eq!(*parse("putint 1").unwrap().layout(), Layout::default());
+        assert!(parse(".stack\nputint 1").is_err());
+        assert!(parse(".stacks 4\nputint 1").is_err());
+    }
+
+    #[test]
+    fn test_small_layout_runs() {
+        let source = r#"
+        .stack 4
+        .heap 16
+        .call_stack 2
+        .tape 400
+        main:
+            push 6
+            push 7
+            R0 alloc 3
+            {R0} = 'x'
+            call show
+            quit
+        show:
+            pop R1
+            pop R2
+            R3 mul R1, R2
+            putint R3
+            putchar {R0}
+            ret
+        "#;
+        let program = parse(source).unwrap();
+        let bf = program.try_assemble().unwrap();
+        let mut output = Vec::new();
+        Interpreter::from_bf(&bf, 1, program.layout().tape_size)
+            .unwrap()
+            .run(&mut "".as_bytes(), &mut output)
+            .unwrap();
+        assert_eq!(output, b"42x");
+
+        let c = program.assemble_to_c(1);
+        assert!(c.contains("#define STACK_SIZE 4\n"), "{c}");
+        assert!(c.contains("#define HEAP_SIZE 16\n"), "{c}");
+        assert!(c.contains("#define CALL_STACK_SIZE 2\n"), "{c}");
+        assert!(compile_to_c_with_tape(bf, 1, 400)
+            .unwrap()
+            .contains("calloc(400, sizeof(char))"));
+    }
+
+    #[test]
+    fn test_program_must_fit_on_tape() {
+        let mut program = parse(".tape 100\nputint 1").unwrap();
+        let error = program.try_assemble().unwrap_err();
+        assert_eq!(error.needed, Layout::default().globals().address());
+        assert!(error.to_string().contains("tape only has 100"), "{error}");
+
+        // The default tables fit once the tape is long enough
+        program.set_layout(Layout {
+            tape_size: error.needed,
+            ..*program.layout()
+        });
+        assert!(program.try_assemble().is_ok());
+    }
+}
@@ -0,0 +1,501 @@
+use super::*;
+
+use std::collections::{HashMap, HashSet};
+
+/// How deeply macros may expand inside each other before we assume one invokes itself.
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+/// A macro defined with `macro name params… endmacro`.
+struct Macro {
+    params: Vec<String>,
+    /// The lines between the `macro` line and `endmacro`.
+    body: Vec<String>,
+    /// The labels defined in the body, which get fresh names in every expansion.
+    labels: HashSet<String>,
+}
+
+/// Where a line of the expanded source came from.
+#[derive(Debug, Clone)]
+enum Origin {
+    /// The line with this index in the original source.
+    Source(usize),
+    /// An expansion of the macro invoked at this span of the original source.
+    Expansion { name: String, span: Span },
+}
+
+/// Source code with its macros expanded, and where each of its lines came from.
+///
+/// Macros are expanded line by line before anything is parsed. A definition
+/// looks like this:
+///
+/// ```text
+/// macro countdown reg
+/// loop:
+///     putint reg
+///     dec reg
+///     jmp_if reg, loop
+/// endmacro
+/// ```
+///
+/// and `countdown R0` on a line of its own expands to the body, with every
+/// parameter replaced by its argument. Labels defined in the body are renamed
+/// in each expansion, so a macro can be used more than once without its labels
+/// clashing. Bodies can invoke other macros, which are expanded in turn.
+///
+/// Everything parsed from an expansion is reported at the invocation, so errors,
+/// source maps, and the debugger all point at the line the programmer wrote.
+pub(super) struct Expansion {
+    source: String,
+    origins: Vec<Origin>,
+    /// The byte offset where each line of the original source starts.
+    line_starts: Vec<usize>,
+    /// The byte offset where each line of the expanded source starts.
+    expanded_line_starts: Vec<usize>,
+}
+
+/// Expand the macros in `stripped`, which is `input` with its comments removed.
+pub(super) fn expand(input: &str, stripped: &str) -> Result<Expansion, AsmError> {
+    let lines: Vec<&str> = stripped.split('\n').collect();
+    let line_starts = line_offsets(stripped);
+    let span_of_line = |i: usize| {
+        let line = lines[i];
+        let indent = line.len() - line.trim_start().len();
+        Span {
+            offset: line_starts[i] + indent,
+            line: i + 1,
+            column: line[..indent].chars().count() + 1,
+            len: line.trim().len(),
+        }
+    };
+    let error = |i: usize, message: String| AsmError::Syntax {
+        message,
+        location: ErrorLocation::new(input, span_of_line(i)),
+    };
+
+    // Collect the definitions first, so a macro can be invoked before it is defined
+    let mut macros = HashMap::new();
+    let mut in_definition = vec![false; lines.len()];
+    let mut i = 0;
+    while i < lines.len() {
+        let Some(header) = macro_header(lines[i]) else {
+            i += 1;
+            continue;
+        };
+        let (name, params) = parse_header(header).map_err(|message| error(i, message))?;
+        let end = (i + 1..lines.len())
+            .find(|&j| lines[j].trim() == "endmacro")
+            .ok_or_else(|| error(i, format!("macro `{name}` is missing `endmacro`")))?;
+        if let Some(j) = (i + 1..end).find(|&j| macro_header(lines[j]).is_some()) {
+            return Err(error(
+                j,
+                "macros can't be defined inside other macros".into(),
+            ));
+        }
+        if macros.contains_key(name) {
+            return Err(error(i, format!("macro `{name}` is already defined")));
+        }
+
+        let body: Vec<String> = lines[i + 1..end].iter().map(|s| s.to_string()).collect();
+        let labels = body
+            .iter()
+            .filter_map(|line| line.trim().strip_suffix(':'))
+            .map(|label| label.trim_end().to_string())
+            .filter(|label| is_identifier(label))
+            .collect();
+        macros.insert(
+            name.to_string(),
+            Macro {
+                params,
+                body,
+                labels,
+            },
+        );
+        in_definition[i..=end].fill(true);
+        i = end + 1;
+    }
+
+    let mut expander = Expander {
+        macros: &macros,
+        expansions: 0,
+    };
+    let mut source = String::new();
+    let mut origins = vec![];
+    let mut push = |line: &str, origin: Origin| {
+        if !origins.is_empty() {
+            source.push('\n');
+        }
+        source.push_str(line);
+        origins.push(origin);
+    };
+    for (i, line) in lines.iter().enumerate() {
+        if in_definition[i] {
+            // Keep the line, so the lines after the definition don't move
+            push("", Origin::Source(i));
+        } else if line.trim() == "endmacro" {
+            return Err(error(i, "`endmacro` without a `macro`".into()));
+        } else if let Some((name, args)) = invocation(line, &macros) {
+            let origin = Origin::Expansion {
+                name: name.to_string(),
+                span: span_of_line(i),
+            };
+            for line in expander
+                .expand(name, args, 0)
+                .map_err(|message| error(i, message))?
+            {
+                push(&line, origin.clone());
+            }
+        } else {
+            push(line, Origin::Source(i));
+        }
+    }
+
+    Ok(Expansion {
+        expanded_line_starts: line_offsets(&source),
+        source,
+        origins,
+        line_starts,
+    })
+}
+
+impl Expansion {
+    /// The source with every macro expanded.
+    pub(super) fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Where a span of the expanded source was written in the original source.
+    fn span(&self, span: Span) -> Span {
+        let Some(line) = span.line.checked_sub(1) else {
+            return span;
+        };
+        match self.origins.get(line) {
+            Some(Origin::Source(original)) => Span {
+                offset: self.line_starts[*original] + span.offset - self.expanded_line_starts[line],
+                line: original + 1,
+                ..span
+            },
+            Some(Origin::Expansion { span, .. }) => *span,
+            None => span,
+        }
+    }
+
+    /// Point every location in a program parsed from the expanded source
+    /// back at the original source.
+    pub(super) fn relocate_program(&self, program: &mut Program) {
+        program.relocate(|span| self.span(span));
+    }
+
+    /// Point an error in the expanded source back at the original `input`.
+    pub(super) fn relocate_error(&self, input: &str, mut error: AsmError) -> AsmError {
+        let span = error.span();
+        let origin = span.line.checked_sub(1).and_then(|i| self.origins.get(i));
+        if let (AsmError::Syntax { message, .. }, Some(Origin::Expansion { name, .. })) =
+            (&mut error, origin)
+        {
+            message.push_str(&format!(" in an expansion of macro `{name}`"));
+        }
+        for location in error.locations_mut() {
+            *location = ErrorLocation::new(input, self.span(location.span));
+        }
+        error
+    }
+}
+
+/// Expands invocations, numbering each expansion to give its labels fresh names.
+struct Expander<'a> {
+    macros: &'a HashMap<String, Macro>,
+    expansions: usize,
+}
+
+impl Expander<'_> {
+    /// The lines an invocation of a macro expands to.
+    fn expand(
+        &mut self,
+        name: &str,
+        args: Vec<String>,
+        depth: usize,
+    ) -> Result<Vec<String>, String> {
+        let mac = &self.macros[name];
+        if args.len() != mac.params.len() {
+            return Err(format!(
+                "macro `{name}` takes {} arguments, but {} were given",
+                mac.params.len(),
+                args.len()
+            ));
+        }
+        if depth >= MAX_EXPANSION_DEPTH {
+            return Err(format!(
+                "macro `{name}` expands too deeply; does it invoke itself?"
+            ));
+        }
+
+        self.expansions += 1;
+        let number = self.expansions;
+        let mut result = vec![];
+        for line in &mac.body {
+            let line = substitute(line, |word| {
+                if let Some(i) = mac.params.iter().position(|param| param == word) {
+                    Some(args[i].clone())
+                } else if mac.labels.contains(word) {
+                    Some(format!("__{name}_{number}_{word}"))
+                } else {
+                    None
+                }
+            });
+            match invocation(&line, self.macros) {
+                Some((inner, args)) => result.extend(self.expand(inner, args, depth + 1)?),
+                None => result.push(line),
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// The byte offset where each line of `text` starts.
+fn line_offsets(text: &str) -> Vec<usize> {
+    std::iter::once(0)
+        .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+        .collect()
+}
+
+fn is_identifier(word: &str) -> bool {
+    word.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && word.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// The rest of the line after `macro`, if the line starts a definition.
+fn macro_header(line: &str) -> Option<&str> {
+    let rest = line.trim().strip_prefix("macro")?;
+    (rest.is_empty() || rest.starts_with(char::is_whitespace)).then_some(rest)
+}
+
+/// Parse the name and parameters of a macro from the rest of its `macro` line.
+fn parse_header(header: &str) -> Result<(&str, Vec<String>), String> {
+    let header = header.trim();
+    let (name, params) = header
+        .split_once(char::is_whitespace)
+        .unwrap_or((header, ""));
+    if !is_identifier(name) {
+        return Err("expected a macro name".into());
+    }
+    let params = split_args(params);
+    if let Some(param) = params.iter().find(|param| !is_identifier(param)) {
+        return Err(format!("expected a parameter name, found `{param}`"));
+    }
+    Ok((name, params))
+}
+
+/// The name and arguments of a macro invocation, if the line is one.
+fn invocation<'a>(
+    line: &str,
+    macros: &'a HashMap<String, Macro>,
+) -> Option<(&'a str, Vec<String>)> {
+    let line = line.trim();
+    let end = line
+        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .unwrap_or(line.len());
+    let (name, _) = macros.get_key_value(&line[..end])?;
+    let rest = &line[end..];
+    // A label with the same name as a macro is still a label
+    if rest.trim_start().starts_with(':') {
+        return None;
+    }
+    Some((name.as_str(), split_args(rest)))
+}
+
+/// Split a list of arguments on the commas between them.
+fn split_args(args: &str) -> Vec<String> {
+    if args.trim().is_empty() {
+        return vec![];
+    }
+    let mut result = vec![String::new()];
+    let mut chars = args.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ',' => result.push(String::new()),
+            '"' | '\'' => {
+                let current = result.last_mut().unwrap();
+                current.push(c);
+                skip_literal(c, &mut chars, current);
+            }
+            _ => result.last_mut().unwrap().push(c),
+        }
+    }
+    result
+        .into_iter()
+        .map(|arg| arg.trim().to_string())
+        .collect()
+}
+
+/// Copy the rest of a string or character literal that started with `quote`.
+fn skip_literal(quote: char, chars: &mut std::str::Chars, output: &mut String) {
+    while let Some(c) = chars.next() {
+        output.push(c);
+        if c == '\\' {
+            output.extend(chars.next());
+        } else if c == quote {
+            break;
+        }
+    }
+}
+
+/// Replace the words in a line for which `replace` returns something,
+/// leaving numbers and the insides of literals alone.
+fn substitute(line: &str, replace: impl Fn(&str) -> Option<String>) -> String {
+    let mut result = String::new();
+    let mut chars = line.chars();
+    let mut word = String::new();
+    let flush = |word: &mut String, result: &mut String| {
+        let replacement = if is_identifier(word) {
+            replace(word)
+        } else {
+            None
+        };
+        result.push_str(replacement.as_deref().unwrap_or(word));
+        word.clear();
+    };
+    while let Some(c) = chars.next() {
+        if c.is_ascii_alphanumeric() || c == '_' {
+            word.push(c);
+            continue;
+        }
+        flush(&mut word, &mut result);
+        result.push(c);
+        if c == '"' || c == '\'' {
+            skip_literal(c, &mut chars, &mut result);
+        }
+    }
+    flush(&mut word, &mut result);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(source: &str) -> String {
+        let program = parse(source).unwrap();
+        interpret_with_input(program.assemble(), "", 1).unwrap()
+    }
+
+    #[test]
+    fn test_parameters_are_substituted() {
+        let source = r#"
+        macro print_sum a, b
+            R5 add a, b
+            putint R5
+            putchar ' '
+        endmacro
+
+        main:
+            R0 = 3
+            print_sum R0, 4
+            print_sum 'a', R0
+            print_sum [SP], 1
+        "#;
+        assert_eq!(run(source), "7 100 1 ");
+    }
+
+    #[test]
+    fn test_literals_and_numbers_are_left_alone() {
+        assert_eq!(
+            substitute(r#"log "x\" x" 'x' 0x1x x_1 x"#, |word| {
+                (word == "x").then(|| "R0".to_string())
+            }),
+            r#"log "x\" x" 'x' 0x1x x_1 R0"#
+        );
+        assert_eq!(split_args(" R0, ',' , \"a, b\""), ["R0", "','", "\"a, b\""]);
+        assert!(split_args("  ").is_empty());
+    }
+
+    #[test]
+    fn test_labels_are_hygienic() {
+        let source = r#"
+        macro countdown reg
+        loop:
+            putint reg
+            dec reg
+            jmp_if reg, loop
+        endmacro
+
+        main:
+            R0 = 3
+            countdown R0
+            R1 = 2
+            countdown R1
+        loop:
+            putchar '!'
+        "#;
+        assert_eq!(run(source), "32121!");
+    }
+
+    #[test]
+    fn test_nested_expansion() {
+        let source = r#"
+        macro twice m, x
+            m x
+            m x
+        endmacro
+        macro show x
+            putint x
+        endmacro
+        macro four x
+            twice show, x
+            twice show, x
+        endmacro
+
+        four 7
+        "#;
+        assert_eq!(run(source), "7777");
+    }
+
+    #[test]
+    fn test_expansions_point_at_invocation() {
+        let source =
+            "macro bump r\n    inc r\n    putint r\nendmacro\nmain:\n    bump R0\n    putint R0\n";
+        let program = parse(source).unwrap();
+        let Op::Label(_, bb) = &program.ops()[0] else {
+            panic!("Expected a labeled block");
+        };
+        let lines: Vec<_> = (0..3).map(|i| bb.source(i).unwrap().span.line).collect();
+        assert_eq!(lines, [6, 6, 7]);
+        assert_eq!(bb.source(0).unwrap().instruction, "inc R0");
+        assert_eq!(
+            bb.source(2).unwrap().span.offset,
+            source.find("putint R0").unwrap()
+        );
+
+        let error = parse("macro bad\n    push 1 2\nendmacro\n\nbad\n").unwrap_err();
+        assert_eq!(error.span().line, 5);
+        assert_eq!(error.location().snippet, "bad");
+        assert!(
+            error.message().ends_with("in an expansion of macro `bad`"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn test_macro_errors() {
+        for (source, line, message) in [
+            ("macro m\nputint 1\n", 1, "missing `endmacro`"),
+            (
+                "macro m\nendmacro\nmacro m\nendmacro\n",
+                3,
+                "already defined",
+            ),
+            ("macro m\nmacro n\nendmacro\n", 2, "inside other macros"),
+            ("endmacro\n", 1, "without a `macro`"),
+            (
+                "macro m a\nendmacro\nm 1, 2\n",
+                3,
+                "takes 1 arguments, but 2",
+            ),
+            ("macro m\nm\nendmacro\nm\n", 4, "expands too deeply"),
+            ("macro m 1\nendmacro\n", 1, "parameter name"),
+        ] {
+            let error = parse(source).unwrap_err();
+            assert_eq!(error.span().line, line, "{error}");
+            assert!(error.message().contains(message), "{error}");
+        }
+    }
+}
@@ -1,15 +1,42 @@
 use super::*;
 
+mod error;
+pub use error::*;
+
 mod parse;
 pub use parse::parse;
 
-use std::collections::HashMap;
-use std::sync::RwLock;
+mod macros;
+
+mod include;
+pub use include::Loader;
+
+mod stdlib;
+pub use stdlib::STDLIB;
+
+mod assembler;
+pub use assembler::Assembler;
+
+mod source_map;
+pub use source_map::*;
+
+mod debugger;
+pub use debugger::*;
+
+mod c;
+
+mod layout;
+pub use layout::*;
+
+mod variables;
 
 pub const NULL: StaticLocation = StaticLocation::Address(0);
 
+/// The number of values the stack holds, unless a program asks for another size.
 pub const STACK_SIZE: usize = 1000;
+/// The number of cells on the heap, unless a program asks for another size.
 pub const HEAP_SIZE: usize = 1000;
+/// The number of return addresses the call stack holds, unless a program asks for another size.
 pub const CALL_STACK_SIZE: usize = 1000;
 
 pub const CELLS_PER_STACK_ELEMENT: usize = 2;
@@ -37,8 +64,8 @@ macro_rules! registers {
 registers!(
     NEXT_BASIC_BLOCK,
     CURRENT_BASIC_BLOCK,
-    CURRENT_BASIC_BLOCK_EQ0,
-    CURRENT_BASIC_BLOCK_EQ1,
+    DISPATCH_COUNT,
+    DISPATCH_FLAG,
     SP,
     HP,
     IDX_TEMP,
@@ -94,75 +121,158 @@ registers!(
     R15,
     ZERO,
     TRASH,
+    CMP_TEMP0,
+    CMP_TEMP1,
+    CMP_TEMP2,
+    BIT_TEMP0,
+    BIT_TEMP1,
+    BIT_TEMP2,
+    BIT_TEMP3,
+    BIT_TEMP4,
+    BIT_TEMP5,
+    ALLOC_TEMP0,
+    ALLOC_TEMP1,
+    ALLOC_TEMP2,
+    ALLOC_TEMP3,
+    ALLOC_TEMP4,
+    ALLOC_TEMP5,
+    SIGN_TEMP0,
+    SIGN_TEMP1,
+    SIGN_TEMP2,
+    SIGN_TEMP3,
+    SIGN_TEMP4,
+    SIGN_TEMP5,
+    W0_0,
+    W0_1,
+    W0_2,
+    W0_3,
+    W1_0,
+    W1_1,
+    W1_2,
+    W1_3,
+    W2_0,
+    W2_1,
+    W2_2,
+    W2_3,
+    W3_0,
+    W3_1,
+    W3_2,
+    W3_3,
+    WIDE_TEMP0_0,
+    WIDE_TEMP0_1,
+    WIDE_TEMP0_2,
+    WIDE_TEMP0_3,
+    WIDE_TEMP1_0,
+    WIDE_TEMP1_1,
+    WIDE_TEMP1_2,
+    WIDE_TEMP1_3,
+    WIDE_TEMP2_0,
+    WIDE_TEMP2_1,
+    WIDE_TEMP2_2,
+    WIDE_TEMP2_3,
+    WIDE_LHS_0,
+    WIDE_LHS_1,
+    WIDE_LHS_2,
+    WIDE_LHS_3,
+    WIDE_RHS_0,
+    WIDE_RHS_1,
+    WIDE_RHS_2,
+    WIDE_RHS_3,
+    WIDE_BASE,
+    WIDE_CARRY,
+    WIDE_COUNT,
+    WIDE_CONST,
+    WIDE_BYTE,
+    WIDE_BIT,
+    WIDE_BITS,
+    WIDE_MORE,
+    WIDE_QUOT,
+    WIDE_SUM,
+    WIDE_DIGIT0,
+    WIDE_DIGIT1,
+    WIDE_DIGIT2,
+    WIDE_DIGIT3,
+    WIDE_DIGIT4,
+    WIDE_DIGIT5,
+    WIDE_DIGIT6,
+    WIDE_DIGIT7,
+    WIDE_DIGIT8,
+    WIDE_DIGIT9,
+    BLOCK_TEMP0,
+    BLOCK_TEMP1,
+    BLOCK_TEMP2,
+    BLOCK_TEMP3,
+    BLOCK_TEMP4,
+    BLOCK_TEMP5,
+    BLOCK_TEMP6,
+    BLOCK_TEMP7,
+    DISPATCH_TEMP,
+    FP,
+    FRAME_TEMP0,
+    FRAME_TEMP1,
+    FRAME_RESULT,
 );
 
-fn log(msg: String, locations: &[DynamicLocation]) -> String {
+pub const W0: WideLocation = WideLocation::new("W0", W0_0);
+pub const W1: WideLocation = WideLocation::new("W1", W1_0);
+pub const W2: WideLocation = WideLocation::new("W2", W2_0);
+pub const W3: WideLocation = WideLocation::new("W3", W3_0);
+pub const WIDE_REGISTERS: [WideLocation; 4] = [W0, W1, W2, W3];
+
+pub const WIDE_TEMP0: WideLocation = WideLocation::new("WIDE_TEMP0", WIDE_TEMP0_0);
+pub const WIDE_TEMP1: WideLocation = WideLocation::new("WIDE_TEMP1", WIDE_TEMP1_0);
+pub const WIDE_TEMP2: WideLocation = WideLocation::new("WIDE_TEMP2", WIDE_TEMP2_0);
+pub const WIDE_LHS: WideLocation = WideLocation::new("WIDE_LHS", WIDE_LHS_0);
+pub const WIDE_RHS: WideLocation = WideLocation::new("WIDE_RHS", WIDE_RHS_0);
+/// The decimal digits of a wide number being printed.
+pub const WIDE_DIGITS: [StaticLocation; 10] = [
+    WIDE_DIGIT0,
+    WIDE_DIGIT1,
+    WIDE_DIGIT2,
+    WIDE_DIGIT3,
+    WIDE_DIGIT4,
+    WIDE_DIGIT5,
+    WIDE_DIGIT6,
+    WIDE_DIGIT7,
+    WIDE_DIGIT8,
+    WIDE_DIGIT9,
+];
+/// The registers that programs use however they like, and that variables are allocated to.
+pub const GENERAL_REGISTERS: [StaticLocation; 16] = [
+    R0, R1, R2, R3, R4, R5, R6, R7, R8, R9, R10, R11, R12, R13, R14, R15,
+];
+/// The flags of the `if` and `while` blocks, one for each level of nesting.
+pub const BLOCK_TEMPS: [StaticLocation; 8] = [
+    BLOCK_TEMP0,
+    BLOCK_TEMP1,
+    BLOCK_TEMP2,
+    BLOCK_TEMP3,
+    BLOCK_TEMP4,
+    BLOCK_TEMP5,
+    BLOCK_TEMP6,
+    BLOCK_TEMP7,
+];
+
+fn log(layout: &Layout, msg: String, locations: &[DynamicLocation]) -> String {
     let mut result = String::new();
     result.push_str(&TRASH.putmsg(&msg));
     for location in locations {
         result.push_str(&TRASH.putmsg(&format!("{location} = ")));
-        result.push_str(&location.putint());
+        result.push_str(&location.putint(layout));
         result.push_str(&TRASH.putmsg("\n"));
     }
     result
 }
 
-pub fn allocate_registers_and_stack() -> Table {
-    // Allocate the registers
-    info!("Allocating registers...");
-    let register_addresses = global_alloc(REGISTER_COUNT + 100);
-    info!("Allocated registers at {register_addresses}");
-    // Allocate the stack
-    info!("Allocating stack...");
-    Table::allocate(STACK_SIZE)
-}
-
-pub fn allocate_heap() -> Table {
-    info!("Allocating heap...");
-    Table::allocate(HEAP_SIZE)
-}
-
-pub fn allocate_call_stack() -> Table {
-    info!("Allocating call stack...");
-    Table::allocate(CALL_STACK_SIZE)
-}
-
-pub fn allocate_string(string: &str) -> (StaticLocation, String) {
-    // Allocate the size of the string
-    let result_addr = global_alloc(string.len() + 1);
-    // Write the string to the global
-    let mut result_code = String::new();
-    for (i, ch) in string.chars().enumerate() {
-        let ch = ch as u8;
-        let cell = result_addr.off(i as i64);
-        result_code.push_str(&cell.set_const(ch as u64));
-    }
-    (result_addr, result_code)
-}
-
-pub fn register(name: &str) -> StaticLocation {
+pub fn register(name: &str) -> Option<StaticLocation> {
     // REGISTERS[i]
-    let index = REGISTER_NAMES
-        .iter()
-        .position(|&r| r == name)
-        .expect(format!("Unknown register: {name}").as_str());
-    REGISTERS[index]
+    let index = REGISTER_NAMES.iter().position(|&r| r == name)?;
+    Some(REGISTERS[index])
 }
 
 pub fn register_name(i: usize) -> &'static str {
     REGISTER_NAMES[i]
 }
-lazy_static! {
-    pub static ref STACK_HEAP_CALL_STACK: (Table, Table, Table) = {
-        let stack = allocate_registers_and_stack();
-        let heap = allocate_heap();
-        let call_stack = allocate_call_stack();
-        (stack, heap, call_stack)
-    };
-    pub static ref STACK: Table = STACK_HEAP_CALL_STACK.0;
-    pub static ref HEAP: Table = STACK_HEAP_CALL_STACK.1;
-    pub static ref CALL_STACK: Table = STACK_HEAP_CALL_STACK.2;
-}
 
 #[derive(Debug, Clone, Copy)]
 pub enum Operand {
@@ -171,13 +281,24 @@ pub enum Operand {
     ///
     /// A dynamic location is one of:
     /// - A static location (=DynamicLocation::Static(StaticLocation))
-    /// - A stack dereference of a static location "SP[R0]" (=DynamicLocation::DerefStack(StaticLocation))
-    /// - A heap dereference of a static location "HP[R0]" (=DynamicLocation::DerefHeap(StaticLocation))
+    /// - A stack dereference of a static location "[R0]" (=DynamicLocation::DerefStack(StaticLocation))
+    /// - A heap dereference of a static location "{R0}" or "HP[R0]" (=DynamicLocation::DerefHeap(StaticLocation))
     Location(DynamicLocation),
     /// A constant value
     Immediate(u64),
 }
 
+/// An operand of an instruction on wide registers.
+#[derive(Debug, Clone, Copy)]
+pub enum WideOperand {
+    /// A wide register "W0"
+    Register(WideLocation),
+    /// A constant value, up to 32 bits
+    Immediate(u64),
+    /// An ordinary location like "R0" or "[R0]", zero-extended
+    Narrow(DynamicLocation),
+}
+
 #[derive(Debug, Clone)]
 pub enum BasicBlockOp {
     /// `push R0` gets the value of R0 and pushes it onto the stack
@@ -193,13 +314,23 @@ pub enum BasicBlockOp {
     /// if supplied. Otherwise, the character is discarded.
     ///
     /// `getchar R0` gets a character from the user and stores it in R0.
-    /// `getchar SP[R0]` gets a character from the user and stores it in the stack location pointed to by R0.
+    /// `getchar [R0]` gets a character from the user and stores it in the stack location pointed to by R0.
     GetChar(Option<DynamicLocation>),
 
     /// Get a character operand and print it.
     PutChar(Operand),
     /// Get an integer operand and print it.
     PutInt(Operand),
+    /// Get an integer operand and print it as a signed number.
+    PutSInt(Operand),
+    /// Print a wide register in decimal.
+    ///
+    /// `putint W0` prints all 32 bits of W0.
+    WidePutInt(WideLocation),
+    /// Read a signed decimal number from the input and store it in the location.
+    ///
+    /// `getint R0` skips any whitespace, then reads an optional `-` and the digits after it.
+    GetInt(DynamicLocation),
 
     /// Print a string literal
     Log(String, Vec<DynamicLocation>),
@@ -243,6 +374,46 @@ pub enum BasicBlockOp {
         dest: DynamicLocation,
     },
 
+    /// Set a wide register to a wide register, a 32-bit constant, or a zero-extended location.
+    ///
+    /// `W0 = 100000` sets W0 to 100000, and `W1 = R0` sets W1 to the value of R0.
+    WideSet {
+        src: WideOperand,
+        dest: WideLocation,
+    },
+    /// Add two wide operands and store the result in a wide register.
+    WideAdd {
+        lhs: WideOperand,
+        rhs: WideOperand,
+        dest: WideLocation,
+    },
+    /// Subtract two wide operands and store the result in a wide register.
+    WideSub {
+        lhs: WideOperand,
+        rhs: WideOperand,
+        dest: WideLocation,
+    },
+    /// Multiply two wide operands and store the result in a wide register.
+    WideMul {
+        lhs: WideOperand,
+        rhs: WideOperand,
+        dest: WideLocation,
+    },
+
+    /// Multiply two signed operands and store the result in the destination.
+    IMul {
+        lhs: Operand,
+        rhs: Operand,
+        dest: DynamicLocation,
+    },
+
+    /// Divide two signed operands, rounding towards zero, and store the result in the destination.
+    IDiv {
+        lhs: Operand,
+        rhs: Operand,
+        dest: DynamicLocation,
+    },
+
     /// Negate an operand and store the result in the destination.
     Neg { src: Operand, dest: DynamicLocation },
 
@@ -260,66 +431,330 @@ pub enum BasicBlockOp {
         dest: DynamicLocation,
     },
 
+    /// Get the remainder of dividing two operands and store it in the destination.
+    Mod {
+        lhs: Operand,
+        rhs: Operand,
+        dest: DynamicLocation,
+    },
+
+    /// Check if the lhs is less than the rhs and store the result in the destination.
+    Lt {
+        lhs: Operand,
+        rhs: Operand,
+        dest: DynamicLocation,
+    },
+
+    /// Check if the signed lhs is less than the signed rhs and store the result in the destination.
+    ILt {
+        lhs: Operand,
+        rhs: Operand,
+        dest: DynamicLocation,
+    },
+
+    /// Check if the lhs is less than or equal to the rhs and store the result in the destination.
+    Le {
+        lhs: Operand,
+        rhs: Operand,
+        dest: DynamicLocation,
+    },
+
+    /// Check if the lhs is greater than the rhs and store the result in the destination.
+    Gt {
+        lhs: Operand,
+        rhs: Operand,
+        dest: DynamicLocation,
+    },
+
+    /// Check if the lhs is greater than or equal to the rhs and store the result in the destination.
+    Ge {
+        lhs: Operand,
+        rhs: Operand,
+        dest: DynamicLocation,
+    },
+
+    /// Bitwise and two operands and store the result in the destination.
+    And {
+        lhs: Operand,
+        rhs: Operand,
+        dest: DynamicLocation,
+    },
+
+    /// Bitwise or two operands and store the result in the destination.
+    Or {
+        lhs: Operand,
+        rhs: Operand,
+        dest: DynamicLocation,
+    },
+
+    /// Bitwise xor two operands and store the result in the destination.
+    Xor {
+        lhs: Operand,
+        rhs: Operand,
+        dest: DynamicLocation,
+    },
+
+    /// Bitwise invert an operand and store the result in the destination.
+    Not { src: Operand, dest: DynamicLocation },
+
+    /// Shift the lhs left by rhs bits and store the result in the destination.
+    Shl {
+        lhs: Operand,
+        rhs: Operand,
+        dest: DynamicLocation,
+    },
+
+    /// Shift the lhs right by rhs bits and store the result in the destination.
+    Shr {
+        lhs: Operand,
+        rhs: Operand,
+        dest: DynamicLocation,
+    },
+
+    /// Allocate a block of cells on the heap, and store the address of the block in the destination.
+    ///
+    /// `R0 alloc 10` allocates 10 cells, which can be accessed with `{R0}`.
+    Alloc { size: Operand, dest: DynamicLocation },
+    /// Free a block of cells allocated with `alloc`, so later allocations can reuse it.
+    Free(Operand),
+
     /// Increment a dynamic location by an optional amount.
     Inc(DynamicLocation, Option<u64>),
     /// Decrement a dynamic location by an optional amount.
     Dec(DynamicLocation, Option<u64>),
 
+    /// Run the first ops if a location is not zero, and the others if it is.
+    ///
+    /// Unlike a `jmp_if`, this stays inside the basic block, so it doesn't go
+    /// through the dispatcher.
+    If {
+        cond: DynamicLocation,
+        then: Vec<BasicBlockOp>,
+        otherwise: Vec<BasicBlockOp>,
+    },
+    /// Run the ops for as long as a location is not zero.
+    While {
+        cond: DynamicLocation,
+        body: Vec<BasicBlockOp>,
+    },
+
+    /// Start the frame of a function: save the frame pointer on the stack,
+    /// point it at the saved value, and push a zero for each local.
+    Enter { locals: usize },
+    /// Tear down the frame of a function: pop its locals, the saved frame
+    /// pointer, and its arguments, and push the result if there is one.
+    Leave {
+        args: usize,
+        result: Option<Operand>,
+    },
+
     /// Print a hexadecimal dump
     HexDump,
     /// Print a decimal dump
     DecimalDump,
 }
 
-fn push_to_call_stack(loc: StaticLocation) -> String {
-    CALL_SP.inc() + &CALL_STACK.set(CALL_SP, loc)
+fn push_to_call_stack(layout: &Layout, loc: StaticLocation) -> String {
+    CALL_SP.inc() + &layout.call_stack().set(CALL_SP, loc)
 }
 
-fn pop_from_call_stack(loc: StaticLocation) -> String {
-    CALL_STACK.get(CALL_SP, loc) + &CALL_SP.dec()
+fn pop_from_call_stack(layout: &Layout, loc: StaticLocation) -> String {
+    layout.call_stack().get(CALL_SP, loc) + &CALL_SP.dec()
 }
 
-fn push(op: Operand) -> String {
+fn push(layout: &Layout, op: Operand) -> String {
     use Operand::*;
     match op {
-        Immediate(n) => SP.inc() + &SP.stack_deref().set_const(n as u64),
+        Immediate(n) => SP.inc() + &SP.stack_deref().set_const(layout, n as u64),
         Location(loc) => {
-            DynamicLocation::from(PUSH_TEMP).set_from(loc)
+            DynamicLocation::from(PUSH_TEMP).set_from(layout, loc)
                 + &SP.inc()
-                + &SP.stack_deref().set_from(PUSH_TEMP)
+                + &SP.stack_deref().set_from(layout, PUSH_TEMP)
         }
     }
 }
 
-fn pop(op: Option<DynamicLocation>) -> String {
+fn pop(layout: &Layout, op: Option<DynamicLocation>) -> String {
     match op {
         None => SP.dec(),
-        Some(loc) => loc.set_from(SP.stack_deref()) + &SP.dec(),
+        Some(loc) => loc.set_from(layout, SP.stack_deref()) + &SP.dec(),
+    }
+}
+
+/// Allocate `size` cells on the heap, and store the address of the first one in `dest`.
+///
+/// Each block on the heap is preceded by two header cells: the size of the block,
+/// and whether it is in use. HP is the index of the first cell that has never been
+/// allocated. Allocation reuses the first free block that is large enough, and
+/// otherwise takes a new block from HP.
+fn alloc(layout: &Layout, size: StaticLocation, dest: DynamicLocation) -> String {
+    let block = ALLOC_TEMP0;
+    let searching = ALLOC_TEMP1;
+    let found = ALLOC_TEMP2;
+    let block_size = ALLOC_TEMP3;
+    let free = ALLOC_TEMP4;
+    let fits = ALLOC_TEMP5;
+
+    // Walk the blocks from the start of the heap
+    block.zero()
+        + &found.zero()
+        + &StaticLocation::less_than(searching, block, HP)
+        + &while_on(
+            &searching,
+            layout.heap().get(block, block_size)
+                + &free.set_from(block)
+                + &free.inc()
+                + &layout.heap().get(free, fits)
+                + &StaticLocation::boolean_not(free, fits)
+                + &StaticLocation::greater_equal(fits, block_size, size)
+                + &if_stmt(&free, if_stmt(&fits, found.set_const(1)))
+                // Stop at the first free block that fits
+                + &StaticLocation::boolean_not(searching, found)
+                + &if_stmt(
+                    &searching,
+                    StaticLocation::plus(block, block, block_size)
+                        + &block.add_const(2)
+                        + &StaticLocation::less_than(searching, block, HP),
+                ),
+        )
+        // Otherwise, take a new block from the end of the heap
+        + &StaticLocation::boolean_not(searching, found)
+        + &if_stmt(
+            &searching,
+            block.set_from(HP)
+                + &layout.heap().set(block, size)
+                + &StaticLocation::plus(HP, HP, size)
+                + &HP.add_const(2),
+        )
+        // Mark the block as used, and return the address after its header
+        + &free.set_from(block)
+        + &free.inc()
+        + &layout.heap().set_const(free, 1)
+        + &block.add_const(2)
+        + &dest.set_from(layout, block)
+}
+
+/// Assemble the ops inside an `if` or `while` block, nested `depth` blocks deep.
+fn nested(layout: &Layout, ops: &[BasicBlockOp], depth: usize) -> String {
+    ops.iter()
+        .map(|op| match op {
+            BasicBlockOp::If {
+                cond,
+                then,
+                otherwise,
+            } => if_else(layout, cond, then, otherwise, depth),
+            BasicBlockOp::While { cond, body } => while_block(layout, cond, body, depth),
+            op => op.assemble(layout),
+        })
+        .collect()
+}
+
+/// Assemble an `if` block. The block's flag starts as the condition, and
+/// becomes whether the `else` ops should run, so the flag of every level of
+/// nesting needs its own cell.
+fn if_else(
+    layout: &Layout,
+    cond: &DynamicLocation,
+    then: &[BasicBlockOp],
+    otherwise: &[BasicBlockOp],
+    depth: usize,
+) -> String {
+    let flag = *BLOCK_TEMPS
+        .get(depth)
+        .unwrap_or_else(|| panic!("blocks can only be nested {} deep", BLOCK_TEMPS.len()));
+    let then = nested(layout, then, depth + 1);
+    let load = DynamicLocation::from(flag).set_from(layout, *cond);
+    if otherwise.is_empty() {
+        return load + &if_stmt(&flag, then);
     }
+    load + &IF_TEMP0.set_from(flag)
+        + &flag.set_const(1)
+        + &IF_TEMP0.to()
+        + "["
+        + &IF_TEMP0.from()
+        + &flag.zero()
+        + &then
+        + &IF_TEMP0.to()
+        + "[-]]"
+        + &IF_TEMP0.from()
+        + &if_stmt(&flag, nested(layout, otherwise, depth + 1))
+}
+
+/// Assemble a `while` block, which loads its condition into its flag before
+/// every iteration.
+fn while_block(
+    layout: &Layout,
+    cond: &DynamicLocation,
+    body: &[BasicBlockOp],
+    depth: usize,
+) -> String {
+    let flag = *BLOCK_TEMPS
+        .get(depth)
+        .unwrap_or_else(|| panic!("blocks can only be nested {} deep", BLOCK_TEMPS.len()));
+    let load = DynamicLocation::from(flag).set_from(layout, *cond);
+    load.clone() + &while_on(&flag, nested(layout, body, depth + 1) + &load)
+}
+
+/// Start the frame of a function, with `locals` cells after the saved frame pointer.
+fn enter(layout: &Layout, locals: usize) -> String {
+    let mut result = push(layout, Operand::Location(FP.into())) + &FP.set_from(SP);
+    for _ in 0..locals {
+        result += &push(layout, Operand::Immediate(0));
+    }
+    result
+}
+
+/// Tear down the frame of a function with `args` arguments. The result is read
+/// before the frame is gone, since it's usually one of the frame's locals.
+fn leave(layout: &Layout, args: usize, result: Option<Operand>) -> String {
+    let Some(result) = result else {
+        return SP.set_from(FP) + &pop(layout, Some(FP.into())) + &SP.sub_const(args as i64);
+    };
+    load_operand(layout, FRAME_RESULT, &result)
+        + &SP.set_from(FP)
+        + &pop(layout, Some(FP.into()))
+        + &SP.sub_const(args as i64)
+        + &push(layout, Operand::Location(FRAME_RESULT.into()))
+}
+
+/// Free a block allocated with [`alloc`] by clearing its in-use flag.
+fn free(layout: &Layout, ptr: StaticLocation) -> String {
+    let flag = ALLOC_TEMP0;
+    flag.set_from(ptr) + &flag.dec() + &layout.heap().set_const(flag, 0)
 }
 
 impl BasicBlockOp {
-    pub fn assemble(&self) -> String {
+    pub fn assemble(&self, layout: &Layout) -> String {
         use BasicBlockOp::*;
         use Operand::*;
         match self {
-            Push(op) => push(*op),
+            Push(op) => push(layout, *op),
 
-            Pop(op) => pop(*op),
+            Pop(op) => pop(layout, *op),
+
+            If {
+                cond,
+                then,
+                otherwise,
+            } => if_else(layout, cond, then, otherwise, 0),
+            While { cond, body } => while_block(layout, cond, body, 0),
+
+            Enter { locals } => enter(layout, *locals),
+            Leave { args, result } => leave(layout, *args, *result),
 
             HexDump => "#".to_string(),
             DecimalDump => "$".to_string(),
 
-            Log(msg, locations) => log(msg.clone(), locations),
+            Log(msg, locations) => log(layout, msg.clone(), locations),
 
             Inc(loc, amount) => match amount {
-                None => loc.inc(),
-                Some(amount) => loc.add_const(*amount as i64),
+                None => loc.inc(layout),
+                Some(amount) => loc.add_const(layout, *amount as i64),
             },
 
             Dec(loc, amount) => match amount {
-                None => loc.dec(),
-                Some(amount) => loc.sub_const(*amount as i64),
+                None => loc.dec(layout),
+                Some(amount) => loc.sub_const(layout, *amount as i64),
             },
 
             GetAddr {
@@ -329,87 +764,117 @@ impl BasicBlockOp {
                 negative,
             } => {
                 match (src, offset) {
-                    (DynamicLocation::Static(loc), None) => dest.set_const(loc.address() as u64),
+                    (DynamicLocation::Static(loc), None) => {
+                        dest.set_const(layout, loc.address() as u64)
+                    }
                     (DynamicLocation::DerefStack(loc), None) => {
-                        dest.set_from(*loc)
+                        dest.set_from(layout, *loc)
                         // + &dest.add_const(*offset)
                     }
                     (DynamicLocation::DerefHeap(loc), None) => {
-                        dest.set_from(*loc)
+                        dest.set_from(layout, *loc)
                         // + &dest.add_const(*offset)
                     }
 
                     (DynamicLocation::Static(loc), Some(Immediate(n))) => dest.set_const(
+                        layout,
                         (loc.address() as i64 + if *negative { -(*n as i64) } else { *n as i64 })
                             as u64,
                     ),
                     (DynamicLocation::Static(loc), Some(Location(offset))) => {
-                        dest.set_const(loc.address() as u64)
-                            + &DynamicLocation::plus(*dest, *dest, *offset)
+                        dest.set_const(layout, loc.address() as u64)
+                            + &DynamicLocation::plus(layout, *dest, *dest, *offset)
                     }
 
                     (DynamicLocation::DerefStack(loc), Some(Immediate(n))) => {
-                        dest.set_from(*loc)
+                        dest.set_from(layout, *loc)
                             + &if *negative {
-                                dest.sub_const(*n as i64)
+                                dest.sub_const(layout, *n as i64)
                             } else {
-                                dest.add_const(*n as i64)
+                                dest.add_const(layout, *n as i64)
                             }
                     }
                     (DynamicLocation::DerefStack(loc), Some(Location(offset))) => {
-                        dest.set_from(*loc)
+                        dest.set_from(layout, *loc)
                             + &if *negative {
-                                DynamicLocation::minus(*dest, *dest, *offset)
+                                DynamicLocation::minus(layout, *dest, *dest, *offset)
                             } else {
-                                DynamicLocation::plus(*dest, *dest, *offset)
+                                DynamicLocation::plus(layout, *dest, *dest, *offset)
                             }
                     }
 
                     (DynamicLocation::DerefHeap(loc), Some(Immediate(n))) => {
-                        dest.set_from(*loc)
+                        dest.set_from(layout, *loc)
                             + &if *negative {
-                                dest.sub_const(*n as i64)
+                                dest.sub_const(layout, *n as i64)
                             } else {
-                                dest.add_const(*n as i64)
+                                dest.add_const(layout, *n as i64)
                             }
                     }
                     (DynamicLocation::DerefHeap(loc), Some(Location(offset))) => {
-                        dest.set_from(*loc)
+                        dest.set_from(layout, *loc)
                         // + &DynamicLocation::plus(*dest, *dest, *offset)
                         + &if *negative {
-                            DynamicLocation::minus(*dest, *dest, *offset)
+                            DynamicLocation::minus(layout, *dest, *dest, *offset)
                         } else {
-                            DynamicLocation::plus(*dest, *dest, *offset)
+                            DynamicLocation::plus(layout, *dest, *dest, *offset)
+                        }
+                    }
+
+                    // The address of an argument or local is relative to the frame pointer
+                    (DynamicLocation::Frame(frame), offset) => {
+                        let frame = dest.set_from(layout, FP) + &dest.add_const(layout, *frame);
+                        match offset {
+                            None => frame,
+                            Some(Immediate(n)) if *negative => {
+                                frame + &dest.sub_const(layout, *n as i64)
+                            }
+                            Some(Immediate(n)) => frame + &dest.add_const(layout, *n as i64),
+                            Some(Location(offset)) if *negative => {
+                                frame + &DynamicLocation::minus(layout, *dest, *dest, *offset)
+                            }
+                            Some(Location(offset)) => {
+                                frame + &DynamicLocation::plus(layout, *dest, *dest, *offset)
+                            }
                         }
                     }
                 }
             }
 
             Set { src, dest } => match src {
-                Immediate(n) => dest.set_const(*n as u64),
-                Location(loc) => dest.set_from(*loc),
+                Immediate(n) => dest.set_const(layout, *n as u64),
+                Location(loc) => dest.set_from(layout, *loc),
             },
 
             GetChar(None) => TRASH.getchar(),
-            GetChar(Some(loc)) => loc.getchar(),
+            GetChar(Some(loc)) => loc.getchar(layout),
 
             PutChar(Immediate(n)) => TRASH.set_const(*n as u64) + &TRASH.putchar(),
-            PutChar(Location(loc)) => loc.putchar(),
+            PutChar(Location(loc)) => loc.putchar(layout),
 
             PutInt(Immediate(n)) => TRASH.set_const(*n as u64) + &TRASH.putint(),
-            PutInt(Location(loc)) => loc.putint(),
+            PutInt(Location(loc)) => loc.putint(layout),
+            PutSInt(Immediate(n)) => TRASH.set_const(*n) + &TRASH.putsint(),
+            PutSInt(Location(loc)) => loc.putsint(layout),
+            GetInt(loc) => loc.getint(layout),
+            WidePutInt(src) => src.putint(),
+            WideSet { src, dest } => load_wide_operand(layout, *dest, src),
+            WideAdd { lhs, rhs, dest } => wide_binop(layout, WideLocation::plus, lhs, rhs, dest),
+            WideSub { lhs, rhs, dest } => wide_binop(layout, WideLocation::minus, lhs, rhs, dest),
+            WideMul { lhs, rhs, dest } => wide_binop(layout, WideLocation::times, lhs, rhs, dest),
 
             Add { lhs, rhs, dest } => {
                 let lhs = match lhs {
-                    Immediate(n) => DynamicLocation::from(T0).set_const(*n as u64),
-                    Location(loc) => DynamicLocation::from(T0).set_from(*loc),
+                    Immediate(n) => DynamicLocation::from(T0).set_const(layout, *n as u64),
+                    Location(loc) => DynamicLocation::from(T0).set_from(layout, *loc),
                 };
                 let rhs = match rhs {
-                    Immediate(n) => DynamicLocation::from(T1).set_const(*n as u64),
-                    Location(loc) => DynamicLocation::from(T1).set_from(*loc),
+                    Immediate(n) => DynamicLocation::from(T1).set_const(layout, *n as u64),
+                    Location(loc) => DynamicLocation::from(T1).set_from(layout, *loc),
                 };
                 lhs + &rhs
                     + &DynamicLocation::static_binop(
+                        layout,
                         StaticLocation::plus,
                         *dest,
                         T0.into(),
@@ -419,15 +884,16 @@ impl BasicBlockOp {
 
             Sub { lhs, rhs, dest } => {
                 let lhs = match lhs {
-                    Immediate(n) => DynamicLocation::from(T0).set_const(*n as u64),
-                    Location(loc) => DynamicLocation::from(T0).set_from(*loc),
+                    Immediate(n) => DynamicLocation::from(T0).set_const(layout, *n as u64),
+                    Location(loc) => DynamicLocation::from(T0).set_from(layout, *loc),
                 };
                 let rhs = match rhs {
-                    Immediate(n) => DynamicLocation::from(T1).set_const(*n as u64),
-                    Location(loc) => DynamicLocation::from(T1).set_from(*loc),
+                    Immediate(n) => DynamicLocation::from(T1).set_const(layout, *n as u64),
+                    Location(loc) => DynamicLocation::from(T1).set_from(layout, *loc),
                 };
                 lhs + &rhs
                     + &DynamicLocation::static_binop(
+                        layout,
                         StaticLocation::minus,
                         *dest,
                         T0.into(),
@@ -437,15 +903,16 @@ impl BasicBlockOp {
 
             Mul { lhs, rhs, dest } => {
                 let lhs = match lhs {
-                    Immediate(n) => DynamicLocation::from(T0).set_const(*n as u64),
-                    Location(loc) => DynamicLocation::from(T0).set_from(*loc),
+                    Immediate(n) => DynamicLocation::from(T0).set_const(layout, *n as u64),
+                    Location(loc) => DynamicLocation::from(T0).set_from(layout, *loc),
                 };
                 let rhs = match rhs {
-                    Immediate(n) => DynamicLocation::from(T1).set_const(*n as u64),
-                    Location(loc) => DynamicLocation::from(T1).set_from(*loc),
+                    Immediate(n) => DynamicLocation::from(T1).set_const(layout, *n as u64),
+                    Location(loc) => DynamicLocation::from(T1).set_from(layout, *loc),
                 };
                 lhs + &rhs
                     + &DynamicLocation::static_binop(
+                        layout,
                         StaticLocation::times,
                         *dest,
                         T0.into(),
@@ -455,15 +922,16 @@ impl BasicBlockOp {
 
             Div { lhs, rhs, dest } => {
                 let lhs = match lhs {
-                    Immediate(n) => DynamicLocation::from(T0).set_const(*n as u64),
-                    Location(loc) => DynamicLocation::from(T0).set_from(*loc),
+                    Immediate(n) => DynamicLocation::from(T0).set_const(layout, *n as u64),
+                    Location(loc) => DynamicLocation::from(T0).set_from(layout, *loc),
                 };
                 let rhs = match rhs {
-                    Immediate(n) => DynamicLocation::from(T1).set_const(*n as u64),
-                    Location(loc) => DynamicLocation::from(T1).set_from(*loc),
+                    Immediate(n) => DynamicLocation::from(T1).set_const(layout, *n as u64),
+                    Location(loc) => DynamicLocation::from(T1).set_from(layout, *loc),
                 };
                 lhs + &rhs
                     + &DynamicLocation::static_binop(
+                        layout,
                         StaticLocation::divide,
                         *dest,
                         T0.into(),
@@ -473,110 +941,233 @@ impl BasicBlockOp {
 
             Neg { src, dest } => {
                 let src = match src {
-                    Immediate(n) => DynamicLocation::from(T0).set_const(*n as u64),
-                    Location(loc) => DynamicLocation::from(T0).set_from(*loc),
+                    Immediate(n) => DynamicLocation::from(T0).set_const(layout, *n as u64),
+                    Location(loc) => DynamicLocation::from(T0).set_from(layout, *loc),
                 };
-                src + &DynamicLocation::static_unop(StaticLocation::negate, *dest, T0.into())
+                src + &DynamicLocation::static_unop(
+                    layout,
+                    StaticLocation::negate,
+                    *dest,
+                    T0.into(),
+                )
             }
 
             Eq { lhs, rhs, dest } => {
                 let lhs = match lhs {
-                    Immediate(n) => DynamicLocation::from(T0).set_const(*n as u64),
-                    Location(loc) => DynamicLocation::from(T0).set_from(*loc),
+                    Immediate(n) => DynamicLocation::from(T0).set_const(layout, *n as u64),
+                    Location(loc) => DynamicLocation::from(T0).set_from(layout, *loc),
                 };
                 let rhs = match rhs {
-                    Immediate(n) => DynamicLocation::from(T1).set_const(*n as u64),
-                    Location(loc) => DynamicLocation::from(T1).set_from(*loc),
+                    Immediate(n) => DynamicLocation::from(T1).set_const(layout, *n as u64),
+                    Location(loc) => DynamicLocation::from(T1).set_from(layout, *loc),
                 };
                 lhs
                 + &rhs
                 // + &debug_helper(&[T0, T1, EQUALS_TEMP0, EQUALS_TEMP1, DYN_OP_TEMP2, DYN_OP_TEMP0, DYN_OP_TEMP1])
-                + &DynamicLocation::equals(*dest, T0.into(), T1.into())
+                + &DynamicLocation::equals(layout, *dest, T0.into(), T1.into())
                 // + &debug_helper(&[T0, T1, EQUALS_TEMP0, EQUALS_TEMP1, DYN_OP_TEMP2, DYN_OP_TEMP0, DYN_OP_TEMP1])
             }
 
             Ne { lhs, rhs, dest } => {
                 let lhs = match lhs {
-                    Immediate(n) => DynamicLocation::from(T0).set_const(*n as u64),
-                    Location(loc) => DynamicLocation::from(T0).set_from(*loc),
+                    Immediate(n) => DynamicLocation::from(T0).set_const(layout, *n as u64),
+                    Location(loc) => DynamicLocation::from(T0).set_from(layout, *loc),
                 };
                 let rhs = match rhs {
-                    Immediate(n) => DynamicLocation::from(T1).set_const(*n as u64),
-                    Location(loc) => DynamicLocation::from(T1).set_from(*loc),
+                    Immediate(n) => DynamicLocation::from(T1).set_const(layout, *n as u64),
+                    Location(loc) => DynamicLocation::from(T1).set_from(layout, *loc),
                 };
                 lhs + &rhs
                     + &DynamicLocation::static_binop(
+                        layout,
                         StaticLocation::not_equals,
                         *dest,
                         T0.into(),
                         T1.into(),
                     )
             }
+
+            Mod { lhs, rhs, dest } => binop(layout, StaticLocation::modulo, lhs, rhs, dest),
+            Lt { lhs, rhs, dest } => binop(layout, StaticLocation::less_than, lhs, rhs, dest),
+            ILt { lhs, rhs, dest } => {
+                binop(layout, StaticLocation::signed_less_than, lhs, rhs, dest)
+            }
+            IMul { lhs, rhs, dest } => binop(layout, StaticLocation::signed_times, lhs, rhs, dest),
+            IDiv { lhs, rhs, dest } => binop(layout, StaticLocation::signed_divide, lhs, rhs, dest),
+            Le { lhs, rhs, dest } => binop(layout, StaticLocation::less_equal, lhs, rhs, dest),
+            Gt { lhs, rhs, dest } => binop(layout, StaticLocation::greater_than, lhs, rhs, dest),
+            Ge { lhs, rhs, dest } => binop(layout, StaticLocation::greater_equal, lhs, rhs, dest),
+            And { lhs, rhs, dest } => binop(layout, StaticLocation::bitwise_and, lhs, rhs, dest),
+            Or { lhs, rhs, dest } => binop(layout, StaticLocation::bitwise_or, lhs, rhs, dest),
+            Xor { lhs, rhs, dest } => binop(layout, StaticLocation::bitwise_xor, lhs, rhs, dest),
+            Shl { lhs, rhs, dest } => binop(layout, StaticLocation::shift_left, lhs, rhs, dest),
+            Shr { lhs, rhs, dest } => binop(layout, StaticLocation::shift_right, lhs, rhs, dest),
+
+            Alloc { size, dest } => load_operand(layout, T0, size) + &alloc(layout, T0, *dest),
+            Free(ptr) => load_operand(layout, T0, ptr) + &free(layout, T0),
+
+            Not { src, dest } => {
+                load_operand(layout, T0, src)
+                    + &DynamicLocation::static_unop(
+                        layout,
+                        StaticLocation::bitwise_not,
+                        *dest,
+                        T0.into(),
+                    )
+            }
         }
     }
 }
 
-lazy_static! {
-    pub static ref BASIC_BLOCK: RwLock<usize> = RwLock::new(0);
-    pub static ref BASIC_BLOCK_NAMES: RwLock<HashMap<Symbol, BasicBlock>> =
-        RwLock::new(HashMap::new());
-    pub static ref BASIC_BLOCK_IDS: RwLock<HashMap<usize, BasicBlock>> =
-        RwLock::new(HashMap::new());
+/// Load an operand into a static location.
+fn load_operand(layout: &Layout, dest: StaticLocation, op: &Operand) -> String {
+    match op {
+        Operand::Immediate(n) => dest.set_const(*n),
+        Operand::Location(loc) => DynamicLocation::from(dest).set_from(layout, *loc),
+    }
 }
 
-fn next_basic_block_number() -> usize {
-    let mut bb = BASIC_BLOCK.write().unwrap();
-    *bb += 1;
-    *bb
+/// Load the operands of a binary operation into T0 and T1, and store the result in the destination.
+fn binop(
+    layout: &Layout,
+    f: impl Fn(StaticLocation, StaticLocation, StaticLocation) -> String,
+    lhs: &Operand,
+    rhs: &Operand,
+    dest: &DynamicLocation,
+) -> String {
+    load_operand(layout, T0, lhs)
+        + &load_operand(layout, T1, rhs)
+        + &DynamicLocation::static_binop(layout, f, *dest, T0.into(), T1.into())
 }
 
-fn add_basic_block(bb: BasicBlock) {
-    let mut bb_names = BASIC_BLOCK_NAMES.write().unwrap();
-    let mut bb_ids = BASIC_BLOCK_IDS.write().unwrap();
-    if let Some(ref label) = bb.label {
-        bb_names.insert(label.clone(), bb.clone());
+fn load_wide_operand(layout: &Layout, dest: WideLocation, op: &WideOperand) -> String {
+    match op {
+        WideOperand::Register(src) => dest.set_from(*src),
+        WideOperand::Immediate(n) => dest.set_const(*n),
+        WideOperand::Narrow(loc) => {
+            load_operand(layout, T0, &Operand::Location(*loc)) + &dest.set_from_narrow(T0)
+        }
     }
-    bb_ids.insert(bb.number, bb);
 }
 
-#[derive(Debug, Clone)]
-pub struct Program(pub Vec<Op>);
+/// Load the operands of a wide operation into WIDE_LHS and WIDE_RHS, and store the result in the destination.
+fn wide_binop(
+    layout: &Layout,
+    f: impl Fn(WideLocation, WideLocation, WideLocation) -> String,
+    lhs: &WideOperand,
+    rhs: &WideOperand,
+    dest: &WideLocation,
+) -> String {
+    load_wide_operand(layout, WIDE_LHS, lhs)
+        + &load_wide_operand(layout, WIDE_RHS, rhs)
+        + &f(*dest, WIDE_LHS, WIDE_RHS)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    ops: Vec<Op>,
+    /// Where each op was written in the source, if the program was parsed.
+    sources: Vec<Option<SourceLocation>>,
+    /// The sizes of the stack, heap, call stack, and tape the program runs with.
+    layout: Layout,
+    /// The values the stack starts with, from its first address up, declared in the `.data` section.
+    data: Vec<u64>,
+}
 
 impl Program {
+    pub fn new(ops: Vec<Op>) -> Self {
+        let sources = vec![None; ops.len()];
+        Self {
+            ops,
+            sources,
+            layout: Layout::default(),
+            data: vec![],
+        }
+    }
+
     pub fn len(&self) -> usize {
-        self.0.len()
+        self.ops.len()
+    }
+
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    /// Where the op at `index` was written in the source, if it is known.
+    ///
+    /// The ops inside a basic block keep track of their own sources.
+    pub fn source(&self, index: usize) -> Option<&SourceLocation> {
+        self.sources.get(index).and_then(Option::as_ref)
+    }
+
+    /// The memory layout the program runs with.
+    ///
+    /// Directives like `.stack 200` in the source change it from the default.
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// Run the program with another memory layout.
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.layout = layout;
+    }
+
+    /// The values the stack starts with. The stack pointer starts at the last of them.
+    pub fn data(&self) -> &[u64] {
+        &self.data
+    }
+
+    /// Start the stack with the given values at addresses 1 and up.
+    pub fn set_data(&mut self, data: Vec<u64>) {
+        self.data = data;
     }
 
-    pub fn parse(source: &str) -> Result<Self, String> {
+    pub fn parse(source: &str) -> Result<Self, AsmError> {
         parse(source)
     }
 
+    /// Move every span in the program to where `relocate` says it belongs.
+    fn relocate(&mut self, relocate: impl Fn(Span) -> Span) {
+        for source in self.sources.iter_mut().flatten() {
+            source.span = relocate(source.span);
+        }
+        for op in &mut self.ops {
+            match op {
+                Op::BasicBlock(bb) | Op::Label(_, bb) => {
+                    for source in &mut bb.sources {
+                        source.span = relocate(source.span);
+                    }
+                    if let Some(span) = &mut bb.label_span {
+                        *span = relocate(*span);
+                    }
+                }
+                Op::Jmp(_, span) | Op::Call(_, span) | Op::JmpIf(_, _, span) => {
+                    *span = relocate(*span);
+                }
+                Op::Quit | Op::Return => {}
+            }
+        }
+    }
+
     pub fn push(&mut self, op: Op) {
-        self.0.push(op);
+        self.push_from_source(op, None);
     }
 
-    fn assemble_ops(&self) -> String {
-        self.0.iter().map(|op| op.assemble()).collect()
+    /// Add an op that was written at the given location in the source.
+    pub fn push_from_source(&mut self, op: Op, source: Option<SourceLocation>) {
+        self.ops.push(op);
+        self.sources.push(source);
     }
 
+    /// Assemble the program into BrainFuck with a fresh assembler.
     pub fn assemble(&self) -> String {
-        // Add a while loop while the basic block is not 0
-        CURRENT_BASIC_BLOCK.set_const(1)
-            + &NEXT_BASIC_BLOCK.set_const(1)
-            + &while_on(
-                &NEXT_BASIC_BLOCK,
-                NEXT_BASIC_BLOCK.inc()
-            // // Debugging
-            // + &TRASH.putmsg("Current basic block is: ")
-            // + &CURRENT_BASIC_BLOCK.putint()
-            // + &TRASH.putmsg("\n")
-
-            + &self.assemble_ops()
-            + &CURRENT_BASIC_BLOCK.set_from(NEXT_BASIC_BLOCK), // // Debugging
-                                                                               // + &TRASH.putmsg("Next basic block is: ")
-                                                                               // + &NEXT_BASIC_BLOCK.putint()
-                                                                               // + &TRASH.putmsg("\n")
-            )
+        Assembler::new().assemble(self)
+    }
+
+    /// Assemble the program into BrainFuck with a fresh assembler, or fail
+    /// if it doesn't fit on its tape.
+    pub fn try_assemble(&self) -> Result<String, LayoutError> {
+        Assembler::new().try_assemble(self)
     }
 }
 
@@ -584,137 +1175,149 @@ impl Program {
 pub enum Op {
     BasicBlock(BasicBlock),
     Label(Symbol, BasicBlock),
-    Quit(usize),
-    Jmp(usize, Symbol),
-    Call(usize, Symbol),
-    Return(usize),
-    JmpIf(usize, DynamicLocation, Symbol),
+    Quit,
+    /// Jump to a label. The span is where the label was referenced.
+    Jmp(Symbol, Span),
+    /// Call a label. The span is where the label was referenced.
+    Call(Symbol, Span),
+    Return,
+    /// Jump to a label if the location is non-zero. The span is where the label was referenced.
+    JmpIf(DynamicLocation, Symbol, Span),
+}
+
+/// Set a cell to `number`, which can be as large as the number of basic blocks.
+///
+/// Block numbers grow with the size of the program, so large ones are built
+/// with a multiplication loop instead of a `+` for each.
+fn set_block_number(dest: StaticLocation, number: usize) -> String {
+    let factor = number.isqrt();
+    if factor < 4 {
+        return dest.set_const(number as u64);
+    }
+    DISPATCH_TEMP.set_const(factor as u64)
+        + &dest.zero()
+        + &while_on(
+            &DISPATCH_TEMP,
+            dest.add_const(factor as i64) + &DISPATCH_TEMP.dec(),
+        )
+        + &dest.add_const((number - factor * factor) as i64)
 }
 
 impl Op {
-    pub fn goto_next_basic_block(&self) -> String {
+    pub fn goto_next_basic_block(&self, asm: &Assembler) -> String {
+        let label_number = |label: &Symbol| {
+            asm.label_number(label)
+                .unwrap_or_else(|| panic!("Unknown basic block {label}"))
+        };
+
         match self {
             Op::BasicBlock(_bb) | Op::Label(_, _bb) => {
-                // Write it to the current basic block register
-                // let followed_immediately_by = bb.next_basic_block();
-                // NEXT_BASIC_BLOCK.set_const(followed_immediately_by as u64)
+                // Basic blocks fall through to the next one
                 "".to_string()
             }
-            Op::Quit(_) => NEXT_BASIC_BLOCK.set_const(0),
-            Op::Jmp(_current, label) => {
-                let bb_names = BASIC_BLOCK_NAMES.read().unwrap();
-                let next = bb_names
-                    .get(label)
-                    .map(|bb| bb.number)
-                    .expect(&format!("Unknown basic block {label}"));
-                NEXT_BASIC_BLOCK.set_const(next as u64)
-            }
+            Op::Quit => NEXT_BASIC_BLOCK.set_const(0),
+            Op::Jmp(label, _) => set_block_number(NEXT_BASIC_BLOCK, label_number(label)),
 
-            Op::Call(_current, label) => {
+            Op::Call(label, _) => {
                 // This will push the next basic block onto the stack,
                 // and set the "next" basic block to the label
-                // todo!()
-
-                let bb_names = BASIC_BLOCK_NAMES.read().unwrap();
-                let next = bb_names
-                    .get(label)
-                    .map(|bb| bb.number)
-                    .expect(&format!("Unknown basic block {label}"));
-
-                push_to_call_stack(NEXT_BASIC_BLOCK) + &NEXT_BASIC_BLOCK.set_const(next as u64)
+                push_to_call_stack(asm.layout(), NEXT_BASIC_BLOCK)
+                    + &set_block_number(NEXT_BASIC_BLOCK, label_number(label))
                 // // Debugging
                 // + &TRASH.putmsg(&format!("Calling basic block {label}\n"))
                 // + &debug_helper(&[CALL_SP])
             }
 
-            Op::Return(_current) => {
+            Op::Return => {
                 // This will pop the next basic block from the stack,
                 // and set the "next" basic block to the label
-                pop_from_call_stack(NEXT_BASIC_BLOCK)
+                pop_from_call_stack(asm.layout(), NEXT_BASIC_BLOCK)
                 // // Debugging
                 // + &TRASH.putmsg(&format!("Retuning from {current}\n"))
                 // + &debug_helper(&[CALL_SP])
             }
-            Op::JmpIf(_current, location, label) => {
-                let bb_names = BASIC_BLOCK_NAMES.read().unwrap();
-                let next = bb_names
-                    .get(label)
-                    .map(|bb| bb.number)
-                    .expect(&format!("Unknown basic block {label}"));
-
-                DynamicLocation::from(JMP_TEMP).set_from(*location)
-                    + &if_stmt(&JMP_TEMP, NEXT_BASIC_BLOCK.set_const(next as u64))
+            Op::JmpIf(location, label, _) => {
+                DynamicLocation::from(JMP_TEMP).set_from(asm.layout(), *location)
+                    + &if_stmt(
+                        &JMP_TEMP,
+                        set_block_number(NEXT_BASIC_BLOCK, label_number(label)),
+                    )
             }
         }
     }
 
-    pub fn assemble(&self) -> String {
-        let number = match self {
-            Op::BasicBlock(bb) | Op::Label(_, bb) => bb.number,
-            Op::Quit(number)
-            | Op::Jmp(number, _)
-            | Op::Call(number, _)
-            | Op::Return(number)
-            | Op::JmpIf(number, _, _) => *number,
-        };
-
-        CURRENT_BASIC_BLOCK_EQ1.set_const(number as u64)
-            + &StaticLocation::equals(
-                CURRENT_BASIC_BLOCK_EQ0,
-                CURRENT_BASIC_BLOCK,
-                CURRENT_BASIC_BLOCK_EQ1,
-            )
-            + &if_stmt(
-                &CURRENT_BASIC_BLOCK_EQ0,
-                // TRASH.putmsg(&format!("Executing basic block: {self:?}\n\n"))
-                // + &
-                match self {
-                    Op::BasicBlock(bb) => bb.assemble(),
-                    Op::Label(_, bb) => bb.assemble(),
-                    Op::Quit(..) | Op::Call(..) | Op::Return(..) | Op::Jmp(..) | Op::JmpIf(..) => {
-                        // TRASH.putmsg("Executing jump\n") + &
-                        self.goto_next_basic_block()
-                    }
-                }, // + &TRASH.putmsg(&format!("Done executing block {self:?}\n"))
-                   // + &debug_helper(&[CURRENT_BASIC_BLOCK, NEXT_BASIC_BLOCK, CURRENT_BASIC_BLOCK_EQ0])
-            )
-        // + &CURRENT_BASIC_BLOCK_EQ0.dec()
-        // + &if_stmt(&CURRENT_BASIC_BLOCK_EQ0,
-        //     TRASH.putmsg(&format!("Not executing basic block: {self:?}\n\n")))
-        // + &TRASH.putmsg(&format!("Done executing block {self:?}\n"))
-        // + &debug_helper(&[CURRENT_BASIC_BLOCK, NEXT_BASIC_BLOCK, CURRENT_BASIC_BLOCK_EQ0])
+    /// Assemble the code for the op, which the dispatcher runs when the op's
+    /// basic block is the current one.
+    pub fn assemble(&self, source: Option<&SourceLocation>, asm: &mut Assembler) -> String {
+        match self {
+            Op::BasicBlock(bb) => asm.assemble_basic_block(bb),
+            Op::Label(_, bb) => asm.assemble_basic_block(bb),
+            Op::Quit | Op::Call(..) | Op::Return | Op::Jmp(..) | Op::JmpIf(..) => {
+                // TRASH.putmsg("Executing jump\n") + &
+                let code = self.goto_next_basic_block(asm);
+                asm.mark(source, code)
+            }
+        }
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct BasicBlock {
     label: Option<Symbol>,
-    number: usize,
+    /// Where the label was defined, if the block was parsed.
+    label_span: Option<Span>,
     ops: Vec<BasicBlockOp>,
+    /// Where each op was written in the source, if the block was parsed.
+    sources: Vec<SourceLocation>,
 }
 
 impl BasicBlock {
     pub fn new(label: Option<impl Into<Symbol>>, ops: Vec<BasicBlockOp>) -> Self {
-        let result = Self {
+        Self {
             label: label.map(Into::into),
-            number: next_basic_block_number(),
+            label_span: None,
             ops,
-        };
-        add_basic_block(result.clone());
-        result
+            sources: vec![],
+        }
     }
 
-    pub fn next_basic_block(&self) -> usize {
-        self.number + 1
+    /// Record where the block's label was defined in the source.
+    pub fn with_label_span(mut self, span: Span) -> Self {
+        self.label_span = Some(span);
+        self
     }
 
-    pub fn assemble_ops(&self) -> String {
-        self.ops.iter().map(|op| op.assemble()).collect()
+    /// Record where each op in the block was written in the source.
+    pub fn with_sources(mut self, sources: Vec<SourceLocation>) -> Self {
+        self.sources = sources;
+        self
     }
 
-    pub fn assemble(&self) -> String {
+    pub fn label(&self) -> Option<&Symbol> {
+        self.label.as_ref()
+    }
+
+    /// Where the block's label was defined, if the block was parsed.
+    pub fn label_span(&self) -> Option<Span> {
+        self.label_span
+    }
+
+    pub fn ops(&self) -> &[BasicBlockOp] {
+        &self.ops
+    }
+
+    /// Where the op at `index` was written in the source, if it is known.
+    pub fn source(&self, index: usize) -> Option<&SourceLocation> {
+        self.sources.get(index)
+    }
+
+    pub fn assemble_ops(&self, layout: &Layout) -> String {
+        self.ops.iter().map(|op| op.assemble(layout)).collect()
+    }
+
+    pub fn assemble(&self, layout: &Layout) -> String {
         // Add a check to make sure we're executing the correct basic block
-        self.assemble_ops()
+        self.assemble_ops(layout)
     }
 }
 
@@ -748,7 +1351,7 @@ mod tests {
             Err(e) => panic!("Error: {}", e),
         };
         assert_eq!(
-            compile_and_run_with_input(program.assemble(), "Hello!\n", 1).unwrap(),
+            interpret_with_input(program.assemble(), "Hello!\n", 1).unwrap(),
             "Hello!\nBye!\n"
         );
     }
@@ -778,7 +1381,7 @@ mod tests {
             Err(e) => panic!("Error: {}", e),
         };
 
-        compile_and_run(program.assemble(), 1).unwrap();
+        interpret_with_input(program.assemble(), "", 1).unwrap();
     }
 
     #[test]
@@ -800,11 +1403,213 @@ mod tests {
         };
 
         assert_eq!(
-            compile_and_run_with_input(program.assemble(), "", 1).unwrap(),
+            interpret_with_input(program.assemble(), "", 1).unwrap(),
             "15\n"
         );
     }
 
+    #[test]
+    fn test_mod_program() {
+        init_logging();
+
+        let source = r#"
+        main:
+            R0 = 17
+            R1 mod R0, 5
+            putint R1
+            putchar ' '
+            R1 mod R0, 17
+            putint R1
+            putchar ' '
+            R0 mod 3
+            putint R0
+            putchar '\n'
+        "#;
+        let program = match parse(source) {
+            Ok(program) => program,
+            Err(e) => panic!("Error: {}", e),
+        };
+
+        assert_eq!(
+            interpret_with_input(program.assemble(), "", 1).unwrap(),
+            "2 0 2\n"
+        );
+    }
+
+    #[test]
+    fn test_comparison_program() {
+        init_logging();
+
+        let source = r#"
+        main:
+            R0 = 3
+            R1 = 7
+            R2 lt R0, R1
+            putint R2
+            R2 lt R1, R0
+            putint R2
+            R2 lt R0, R0
+            putint R2
+            putchar ' '
+            R2 le R0, R1
+            putint R2
+            R2 le R1, R0
+            putint R2
+            R2 le R0, R0
+            putint R2
+            putchar ' '
+            R2 gt R0, R1
+            putint R2
+            R2 gt R1, R0
+            putint R2
+            R2 gt R0, R0
+            putint R2
+            putchar ' '
+            R2 ge R0, R1
+            putint R2
+            R2 ge R1, R0
+            putint R2
+            R2 ge R0, R0
+            putint R2
+            putchar ' '
+            R2 lt 0, 255
+            putint R2
+            R2 gt 0, 255
+            putint R2
+            putchar '\n'
+        "#;
+        let program = match parse(source) {
+            Ok(program) => program,
+            Err(e) => panic!("Error: {}", e),
+        };
+
+        assert_eq!(
+            interpret_with_input(program.assemble(), "", 1).unwrap(),
+            "100 101 010 011 10\n"
+        );
+    }
+
+    #[test]
+    fn test_bitwise_program() {
+        init_logging();
+
+        let source = r#"
+        main:
+            R0 = 12
+            R1 = 10
+            R2 and R0, R1
+            putint R2
+            putchar ' '
+            R2 or R0, R1
+            putint R2
+            putchar ' '
+            R2 xor R0, R1
+            putint R2
+            putchar ' '
+            not R0, R2
+            putint R2
+            putchar ' '
+            R2 shl R0, 2
+            putint R2
+            putchar ' '
+            R2 shr R0, 2
+            putint R2
+            putchar ' '
+            R0 shl 5
+            putint R0
+            putchar ' '
+            R2 and 255, 129
+            putint R2
+            putchar '\n'
+        "#;
+        let program = match parse(source) {
+            Ok(program) => program,
+            Err(e) => panic!("Error: {}", e),
+        };
+
+        assert_eq!(
+            interpret_with_input(program.assemble(), "", 1).unwrap(),
+            "8 14 6 243 48 3 128 129\n"
+        );
+    }
+
+    #[test]
+    fn test_heap_program() {
+        init_logging();
+
+        let source = r#"
+        main:
+            R0 alloc 3
+            R1 alloc 2
+            putint R0
+            putchar ' '
+            putint R1
+            putchar ' '
+
+            {R0} = 'h'
+            R2 lea {R0} + 1
+            HP[R2] = 'i'
+            putchar {R0}
+            putchar HP[R2]
+            putchar ' '
+
+            free R0
+            R2 alloc 2
+            R3 alloc 4
+            putint R2
+            putchar ' '
+            putint R3
+            putchar ' '
+            putint HP
+            putchar '\n'
+        "#;
+        let program = match parse(source) {
+            Ok(program) => program,
+            Err(e) => panic!("Error: {}", e),
+        };
+
+        assert_eq!(
+            interpret_with_input(program.assemble(), "", 1).unwrap(),
+            "2 7 hi 2 11 15\n"
+        );
+    }
+
+    #[test]
+    fn test_linked_list_program() {
+        init_logging();
+
+        // Build the list 3 -> 2 -> 1 on the heap, then walk it
+        let source = r#"
+        main:
+            R0 = 0
+            R1 = 1
+        build:
+            R2 alloc 2
+            {R2} = R1
+            R3 lea {R2} + 1
+            {R3} = R0
+            R0 = R2
+            inc R1
+            R4 le R1, 3
+            jmp_if R4, build
+        walk:
+            putint {R0}
+            R3 lea {R0} + 1
+            R0 = {R3}
+            jmp_if R0, walk
+            putchar '\n'
+        "#;
+        let program = match parse(source) {
+            Ok(program) => program,
+            Err(e) => panic!("Error: {}", e),
+        };
+
+        assert_eq!(
+            interpret_with_input(program.assemble(), "", 1).unwrap(),
+            "321\n"
+        );
+    }
+
     #[test]
     fn test_inc_dec_program() {
         init_logging();
@@ -825,11 +1630,116 @@ mod tests {
         };
 
         assert_eq!(
-            compile_and_run_with_input(program.assemble(), "", 1).unwrap(),
+            interpret_with_input(program.assemble(), "", 1).unwrap(),
             "5\n4\n"
         );
     }
 
+    #[test]
+    fn test_structured_program() {
+        init_logging();
+
+        let source = r#"
+        main:
+            R0 = 3
+            while R0
+                putint R0
+                R1 mod R0, 2
+                if R1
+                    putchar 'o'
+                else
+                    putchar 'e'
+                    if [SP]
+                        putchar '!'
+                    end
+                end
+                dec R0
+            end
+            putchar '\n'
+
+            ;; Blocks can test pointers
+            push 1
+            push 2
+            push 0
+            R2 lea [SP] - 2
+            while [R2]
+                putint [R2]
+                inc R2
+            end
+            if [R2]
+            else
+                putchar '.'
+            end
+        "#;
+
+        let program = match parse(source) {
+            Ok(program) => program,
+            Err(e) => panic!("Error: {}", e),
+        };
+
+        // The blocks never go through the dispatcher
+        assert_eq!(program.len(), 2);
+        for cell_bytes in [1, 2] {
+            assert_eq!(
+                interpret_with_input(program.assemble(), "", cell_bytes).unwrap(),
+                "3o2e1o\n12."
+            );
+        }
+    }
+
+    #[test]
+    fn test_function_program() {
+        init_logging();
+
+        let source = r#"
+        main:
+            R0 = 7
+            push 4
+            call fact
+            pop R1
+            putint R1
+            putchar ' '
+            push 9
+            push 5
+            call minus
+            putint [SP]
+            putchar ' '
+            putint R0
+            quit
+
+        func fact(n) locals r
+            r eq n, 1
+            jmp_if r, base
+            push n
+            dec [SP]
+            call fact
+            pop r
+            r mul r, n
+            ret r
+        base:
+            ret 1
+        endfunc
+
+        func minus(a, b) locals d, p
+            d sub a, b
+            p lea b
+            R1 = p
+            [R1] = 0
+            ret d
+        endfunc
+        "#;
+
+        let program = match parse(source) {
+            Ok(program) => program,
+            Err(e) => panic!("Error: {}", e),
+        };
+
+        assert_eq!(
+            interpret_with_input(program.assemble(), "", 1).unwrap(),
+            "24 4 7"
+        );
+    }
+
     #[test]
     fn test_call_ret_program() {
         init_logging();
@@ -880,7 +1790,7 @@ mod tests {
         // use std::io::Write;
         // file.write_all(program.assemble().as_bytes()).unwrap();
 
-        let output = compile_and_run_with_input(program.assemble(), "", 1).unwrap();
+        let output = interpret_with_input(program.assemble(), "", 1).unwrap();
         info!("Output: {}", output);
         assert_eq!(output, "Fact of 5: 120\n");
     }
@@ -915,7 +1825,7 @@ mod tests {
             Err(e) => panic!("Error: {}", e),
         };
 
-        compile_and_run_with_input(program.assemble(), "?!\n", 1).unwrap();
+        interpret_with_input(program.assemble(), "?!\n", 1).unwrap();
     }
 
     // fn compile_and_run(filename: &str) {
@@ -935,7 +1845,7 @@ mod tests {
         assert_eq!(NULL, StaticLocation::Address(0));
         for i in 0..REGISTER_COUNT {
             let name = register_name(i);
-            let r = register(name);
+            let r = register(name).unwrap();
             println!("{name} = {r:?}");
             // assert_eq!(r, StaticLocation::Address(i + 1).strip_name());
         }
@@ -2,42 +2,120 @@
 use nom::{
     branch::alt,
     bytes::complete::{is_not, tag, take_while1, take_while_m_n},
-    character::complete::{anychar, char, digit1, hex_digit1, multispace0, multispace1, space0},
-    combinator::{cut, eof, map, map_opt, map_res, opt, recognize, value, verify},
-    error::{convert_error, ParseError, VerboseError},
-    multi::{fold_many0, many0, many1},
+    character::complete::{
+        anychar, char, digit1, hex_digit1, multispace0, multispace1, satisfy, space0, space1,
+    },
+    combinator::{cut, eof, map, map_opt, map_res, not, opt, recognize, value, verify},
+    error::{context, ParseError, VerboseError, VerboseErrorKind},
+    multi::{fold_many0, many0, many1, many_till, separated_list0, separated_list1},
     sequence::{delimited, pair, preceded, terminated, tuple},
-    IResult, Parser,
+    IResult, InputTake, Parser,
 };
+use nom_locate::LocatedSpan;
 
 use super::*;
 
+use std::collections::HashMap;
+use std::ops::Range;
+
 // ---------------------------------------------------------------------
 // Error and Location
 // ---------------------------------------------------------------------
 
+/// The parser input, which keeps track of its line and column in the source,
+/// and carries the declarations so instructions can refer to them by name.
+pub type Input<'a> = LocatedSpan<&'a str, &'a Declarations>;
+
+/// The names declared in a program: the labels of the `.data` section along
+/// with the values the stack starts with, the arguments and locals of each
+/// function, and the variables.
+///
+/// Declarations are collected before the rest of the program is parsed, so
+/// instructions can use labels that are declared after them.
+#[derive(Debug, Default)]
+pub struct Declarations {
+    /// The stack address of each label.
+    labels: HashMap<Symbol, u64>,
+    /// The values of the data, starting at stack address 1.
+    cells: Vec<u64>,
+    /// Where the last label or variable was declared.
+    last: Span,
+    /// The frame of each function, in program order.
+    frames: Vec<Frame>,
+    /// The number of each variable, in the order they were declared.
+    variables: HashMap<Symbol, usize>,
+}
+
+/// The arguments and locals of a function, which can be used by name between
+/// its `func` and its `endfunc`.
+#[derive(Debug)]
+struct Frame {
+    /// The offsets of the function in the source, from `func` to `endfunc`.
+    range: Range<usize>,
+    /// The number of arguments the caller pushes.
+    args: usize,
+    /// The number of locals the function pushes.
+    locals: usize,
+    /// The offset of each argument and local from the frame pointer.
+    slots: HashMap<Symbol, i64>,
+}
+
+impl Declarations {
+    /// The frame of the function that the source offset is in, if any.
+    fn frame_at(&self, offset: usize) -> Option<&Frame> {
+        self.frames
+            .iter()
+            .find(|frame| frame.range.contains(&offset))
+    }
+}
+
 /// The error type we will use.
-pub type Error<'a> = VerboseError<&'a str>;
+pub type Error<'a> = VerboseError<Input<'a>>;
 
 /// A convenient alias for our IResult with that error type.
-pub type Res<'a, T> = IResult<&'a str, T, Error<'a>>;
+pub type Res<'a, T> = IResult<Input<'a>, T, Error<'a>>;
 
 //
 // Parsers
 //
 
 // A helper to “eat” optional whitespace before and after a parser.
-fn ws<'a, F: 'a, O>(inner: F) -> impl FnMut(&'a str) -> Res<'a, O>
+fn ws<'a, F, O>(inner: F) -> impl FnMut(Input<'a>) -> Res<'a, O>
 where
-    F: FnMut(&'a str) -> Res<'a, O>,
+    F: FnMut(Input<'a>) -> Res<'a, O> + 'a,
 {
     // delimited(multispace0, inner, multispace0)
     delimited(space0, inner, space0)
     // preceded(multispace0, inner)
 }
 
+/// Parse a keyword, making sure it isn't just the prefix of a longer identifier.
+fn keyword<'a>(name: &'static str) -> impl FnMut(Input<'a>) -> Res<'a, Input<'a>> {
+    terminated(
+        tag(name),
+        not(satisfy(|c: char| c.is_ascii_alphanumeric() || c == '_')),
+    )
+}
+
+/// Run a parser, and record the instruction it consumed and where it was written.
+fn located<'a, O>(
+    mut parser: impl FnMut(Input<'a>) -> Res<'a, O>,
+) -> impl FnMut(Input<'a>) -> Res<'a, (O, SourceLocation)> {
+    move |input: Input<'a>| {
+        let (start, _) = space0(input)?;
+        let (input, output) = parser(start)?;
+        let consumed = &start.fragment()[..input.location_offset() - start.location_offset()];
+        let instruction = consumed.trim_end();
+        let location = SourceLocation {
+            span: Span::new(start, instruction.len()),
+            instruction: instruction.to_string(),
+        };
+        Ok((input, (output, location)))
+    }
+}
+
 /// Parse an identifier: a letter or underscore followed by alphanumerics or underscores.
-fn parse_identifier(input: &str) -> Res<Symbol> {
+fn parse_identifier(input: Input) -> Res<Symbol> {
     let (input, _) = space0(input)?;
     let first_char = |c: char| c.is_ascii_alphabetic() || c == '_';
     let other_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
@@ -45,53 +123,75 @@ fn parse_identifier(input: &str) -> Res<Symbol> {
         take_while1(first_char),
         opt(take_while1(other_char)),
     )))(input)?;
-    Ok((input, id.into()))
+    Ok((input, (*id.fragment()).into()))
 }
 
 /// A register is simply an identifier (e.g. "R0", "SP", "HP").
-fn parse_register(input: &str) -> Res<StaticLocation> {
+fn parse_register(input: Input) -> Res<StaticLocation> {
     // Parse a name in `REGISTER_NAMES`
     let (input, _) = space0(input)?;
-    let (input, id) = parse_identifier(input)?;
-    if REGISTER_NAMES.contains(&id.as_str()) {
-        Ok((input, StaticLocation::register(id.as_str())))
-    } else {
-        return Err(nom::Err::Error(nom::error::VerboseError::from_error_kind(
-            input,
-            nom::error::ErrorKind::Fail,
-        )));
-    }
+    context("register", |start| {
+        let (input, id) = parse_identifier(start)?;
+        match StaticLocation::register(id.as_str()) {
+            Some(reg) => Ok((input, reg)),
+            None => Err(nom::Err::Error(VerboseError::from_error_kind(
+                start,
+                nom::error::ErrorKind::Fail,
+            ))),
+        }
+    })(input)
+}
+
+/// Parse a wide register, such as `W0`.
+fn parse_wide_register(input: Input) -> Res<WideLocation> {
+    let (input, _) = space0(input)?;
+    context("wide register", |start| {
+        let (input, id) = parse_identifier(start)?;
+        match WideLocation::register(id.as_str()) {
+            Some(reg) => Ok((input, reg)),
+            None => Err(nom::Err::Error(VerboseError::from_error_kind(
+                start,
+                nom::error::ErrorKind::Fail,
+            ))),
+        }
+    })(input)
+}
 
-    // let (input, id) = parse_identifier(input)?;
-    // Ok((input, StaticLocation::register(id.as_str())))
+/// Parse a reference to a label, and remember where it was written.
+fn parse_label_reference(input: Input) -> Res<(Symbol, Span)> {
+    let (start, _) = space0(input)?;
+    let (input, label) = context("label", parse_identifier)(start)?;
+    Ok((input, (label, Span::between(start, input))))
 }
 
 /// Parse an immediate (number) and return it as an Operand.
-fn parse_immediate_literal(input: &str) -> Res<u64> {
+fn parse_immediate_literal(input: Input) -> Res<u64> {
     alt((
         // Parse a hexadecimal number
-        map_res(preceded(tag("0x"), ws(hex_digit1)), |hex_str: &str| {
-            u64::from_str_radix(hex_str, 16)
+        map_res(preceded(tag("0x"), ws(hex_digit1)), |hex_str: Input| {
+            u64::from_str_radix(hex_str.fragment(), 16)
         }),
         // Parse an octal number
         map_res(
             preceded(tag("0o"), ws(take_while1(|c: char| c.is_ascii_digit()))),
-            |octal_str: &str| u64::from_str_radix(octal_str, 8),
+            |octal_str: Input| u64::from_str_radix(octal_str.fragment(), 8),
         ),
         // Parse a decimal number
-        map_res(ws(digit1), |digit_str: &str| digit_str.parse::<u64>()),
+        map_res(ws(digit1), |digit_str: Input| {
+            digit_str.fragment().parse::<u64>()
+        }),
         // Parse a character literal
         map(ws(parse_char_literal), |c| c as u64),
     ))(input)
 }
 
 /// Parse an immediate (number) and return it as an Operand.
-fn parse_immediate(input: &str) -> Res<Operand> {
+fn parse_immediate(input: Input) -> Res<Operand> {
     map(ws(parse_immediate_literal), Operand::Immediate)(input)
 }
 
 /// Parses a single escaped character (e.g., `'\n'`, `'\t'`, `'\''`, `'\xNN'`, `'\u{NNNN}'`).
-fn parse_escape(input: &str) -> Res<char> {
+fn parse_escape(input: Input) -> Res<char> {
     preceded(
         char('\\'),
         alt((
@@ -106,77 +206,165 @@ fn parse_escape(input: &str) -> Res<char> {
                     tag("x"),
                     take_while_m_n(2, 2, |c: char| c.is_ascii_hexdigit()),
                 ),
-                |hex| u8::from_str_radix(hex, 16).map(|b| b as char),
+                |hex: Input| u8::from_str_radix(hex.fragment(), 16).map(|b| b as char),
             ),
-            map_res(preceded(tag("u{"), recognize(hex_digit1)), |hex| {
-                u32::from_str_radix(hex, 16)
+            map_res(preceded(tag("u{"), recognize(hex_digit1)), |hex: Input| {
+                u32::from_str_radix(hex.fragment(), 16)
                     .ok()
                     .and_then(std::char::from_u32)
-                    .ok_or_else(|| nom::Err::Error((hex, nom::error::ErrorKind::Char)))
+                    .ok_or(nom::Err::Error((hex, nom::error::ErrorKind::Char)))
             }),
         )),
     )(input)
 }
 
 /// Parses a single character, either normal or escaped.
-fn parse_char(input: &str) -> Res<char> {
+fn parse_char(input: Input) -> Res<char> {
     alt((parse_escape, anychar))(input)
 }
 
 /// Parses a full character literal, e.g., `'a'` or `'\n'`
-fn parse_char_literal(input: &str) -> Res<char> {
+fn parse_char_literal(input: Input) -> Res<char> {
     delimited(char('\''), parse_char, char('\''))(input)
 }
 /// Parse a dynamic location:
-///   - Either `SP[<register>]` (stack dereference)
-///   - Or `HP[<register>]` (heap dereference)
+///   - Either `[<register>]` (stack dereference)
+///   - Or `{<register>}` or `HP[<register>]` (heap dereference)
 ///   - Or a plain register.
-fn parse_dynamic_location(input: &str) -> Res<DynamicLocation> {
-    let (input, result) = alt((
-        // map(
-        //     delimited(tag("["), ws(tag("SP")), char(']')),
-        //     |_| DynamicLocation::DerefStack(StaticLocation::register("SP")),
-        // ),
-        // map(
-        // delimited(tag("["), ws(tag("HP")), char(']')),
-        //     |_| DynamicLocation::DerefStack(StaticLocation::register("HP")),
-        // ),
-        map(delimited(tag("["), ws(parse_register), char(']')), |reg| {
-            DynamicLocation::DerefStack(reg)
-        }),
-        // map(
-        //     delimited(delimited(tag("["), ws(tag("HBP")), ws(tag("+"))), ws(parse_register), char(']')),
-        //     |reg| DynamicLocation::DerefStack(reg),
-        // ),
-        map(parse_register, |reg| DynamicLocation::Static(reg)),
-    ))(input)?;
+fn parse_dynamic_location(input: Input) -> Res<DynamicLocation> {
+    let (input, result) = context(
+        "location",
+        alt((
+            // The stack starts at the same cell whatever its size
+            map(parse_data_reference, |address| {
+                DynamicLocation::Static(Layout::default().stack().cell(address as usize))
+            }),
+            map(
+                preceded(tag("["), cut(terminated(ws(parse_cell), char(']')))),
+                DynamicLocation::DerefStack,
+            ),
+            map(
+                preceded(tag("{"), cut(terminated(ws(parse_cell), char('}')))),
+                DynamicLocation::DerefHeap,
+            ),
+            map(
+                preceded(tag("HP["), cut(terminated(ws(parse_cell), char(']')))),
+                DynamicLocation::DerefHeap,
+            ),
+            map(parse_frame_slot, DynamicLocation::Frame),
+            map(parse_cell, DynamicLocation::Static),
+        )),
+    )(input)?;
     Ok((input, result))
 }
 
-/// Parse an operand: either an immediate number or a dynamic location.
-fn parse_operand(input: &str) -> Res<Operand> {
+/// Parse a register or a variable, which stands in for the register or stack
+/// cell it is allocated once the program has been parsed.
+fn parse_cell(input: Input) -> Res<StaticLocation> {
+    // A register is tried last, so a misspelled one is reported as such
+    alt((parse_variable, parse_register))(input)
+}
+
+/// Parse the name of a variable.
+fn parse_variable(input: Input) -> Res<StaticLocation> {
+    let (start, _) = space0(input)?;
+    let (input, name) = parse_identifier(start)?;
+    match start.extra.variables.get(&name) {
+        Some(index) => Ok((input, variables::variable(*index))),
+        None => Err(nom::Err::Error(VerboseError::from_error_kind(
+            start,
+            nom::error::ErrorKind::Fail,
+        ))),
+    }
+}
+
+/// Parse the name of an argument or local of the function being parsed, and
+/// return its offset from the frame pointer.
+fn parse_frame_slot(input: Input) -> Res<i64> {
+    let (start, _) = space0(input)?;
+    let (input, name) = parse_identifier(start)?;
+    let frame = start.extra.frame_at(start.location_offset());
+    match frame.and_then(|frame| frame.slots.get(&name)) {
+        Some(offset) => Ok((input, *offset)),
+        None => Err(nom::Err::Error(VerboseError::from_error_kind(
+            start,
+            nom::error::ErrorKind::Fail,
+        ))),
+    }
+}
+
+/// Parse the text of an instruction's destination, without checking it.
+///
+/// Instructions like `R0 add R1` start with their destination, so we can't
+/// tell whether the line is an instruction until we've seen the operator.
+/// Once the operator is recognized, the destination is checked with
+/// [`check_destination`], so a misspelled register is reported as such.
+fn parse_destination(input: Input) -> Res<Input> {
     alt((
-        parse_immediate,
-        map(ws(parse_dynamic_location), Operand::Location),
+        recognize(parse_data_reference),
+        recognize(alt((
+            delimited(char('['), ws(parse_identifier), char(']')),
+            delimited(char('{'), ws(parse_identifier), char('}')),
+            delimited(tag("HP["), ws(parse_identifier), char(']')),
+            parse_identifier,
+        ))),
     ))(input)
 }
 
+/// Check a destination parsed with [`parse_destination`].
+fn check_destination(dest: Input) -> Result<DynamicLocation, nom::Err<Error>> {
+    let (_, dest) = cut(parse_dynamic_location)(dest)?;
+    Ok(dest)
+}
+
+/// Parse an optional location following an instruction that is otherwise complete.
+fn parse_optional_location(input: Input) -> Res<Option<DynamicLocation>> {
+    let (input, _) = space0(input)?;
+    if input.fragment().is_empty() || input.fragment().starts_with('\n') {
+        return Ok((input, None));
+    }
+    map(cut(parse_dynamic_location), Some)(input)
+}
+
+/// Parse an operand: either an immediate number or a dynamic location.
+fn parse_operand(input: Input) -> Res<Operand> {
+    context(
+        "operand",
+        alt((
+            parse_immediate,
+            map(ws(parse_dynamic_location), Operand::Location),
+        )),
+    )(input)
+}
+
+/// Parse an operand of a wide instruction: a wide register, an immediate, or a dynamic location.
+fn parse_wide_operand(input: Input) -> Res<WideOperand> {
+    context(
+        "operand",
+        alt((
+            map(ws(parse_wide_register), WideOperand::Register),
+            map(ws(parse_immediate_literal), WideOperand::Immediate),
+            map(ws(parse_dynamic_location), WideOperand::Narrow),
+        )),
+    )(input)
+}
+
 /// Parse the `hex_dump` instruction:
-fn parse_hex_dump(input: &str) -> Res<BasicBlockOp> {
-    let (input, _) = ws(tag("hex_dump"))(input)?;
+fn parse_hex_dump(input: Input) -> Res<BasicBlockOp> {
+    let (input, _) = ws(keyword("hex_dump"))(input)?;
     Ok((input, BasicBlockOp::HexDump))
 }
 
 /// Parse the `dec_dump` instruction:
-fn parse_dec_dump(input: &str) -> Res<BasicBlockOp> {
-    let (input, _) = ws(tag("dec_dump"))(input)?;
+fn parse_dec_dump(input: Input) -> Res<BasicBlockOp> {
+    let (input, _) = ws(keyword("dec_dump"))(input)?;
     Ok((input, BasicBlockOp::DecimalDump))
 }
 
 /// Parse a unicode sequence, of the form u{XXXX}, where XXXX is 1 to 6
 /// hexadecimal numerals. We will combine this later with parse_escaped_char
 /// to parse sequences like \u{00AC}.
-fn parse_unicode(input: &str) -> Res<char> {
+fn parse_unicode(input: Input) -> Res<char> {
     // `take_while_m_n` parses between `m` and `n` bytes (inclusive) that match
     // a predicate. `parse_hex` here parses between 1 and 6 hexadecimal numerals.
     let parse_hex = take_while_m_n(1, 6, |c: char| c.is_ascii_hexdigit());
@@ -194,7 +382,9 @@ fn parse_unicode(input: &str) -> Res<char> {
     // `map_res` takes the result of a parser and applies a function that returns
     // a Result. In this case we take the hex bytes from parse_hex and attempt to
     // convert them to a u32.
-    let parse_u32 = map_res(parse_delimited_hex, move |hex| u32::from_str_radix(hex, 16));
+    let parse_u32 = map_res(parse_delimited_hex, move |hex: Input| {
+        u32::from_str_radix(hex.fragment(), 16)
+    });
 
     // map_opt is like map_res, but it takes an Option instead of a Result. If
     // the function returns None, map_opt returns an error. In this case, because
@@ -204,7 +394,7 @@ fn parse_unicode(input: &str) -> Res<char> {
 }
 
 /// Parse an escaped character: \n, \t, \r, \u{00AC}, etc.
-fn parse_escaped_char(input: &str) -> Res<char> {
+fn parse_escaped_char(input: Input) -> Res<char> {
     preceded(
         char('\\'),
         // `alt` tries each parser in sequence, returning the result of
@@ -230,12 +420,12 @@ fn parse_escaped_char(input: &str) -> Res<char> {
 
 /// Parse a backslash, followed by any amount of whitespace. This is used later
 /// to discard any escaped whitespace.
-fn parse_escaped_whitespace(input: &str) -> Res<&str> {
+fn parse_escaped_whitespace(input: Input) -> Res<Input> {
     preceded(char('\\'), multispace1).parse(input)
 }
 
 /// Parse a non-empty block of text that doesn't include \ or "
-fn parse_literal(input: &str) -> Res<&str> {
+fn parse_literal(input: Input<'_>) -> Res<'_, &'_ str> {
     // `is_not` parses a string of 0 or more characters that aren't one of the
     // given characters.
     let not_quote_slash = is_not("\"\\");
@@ -244,7 +434,11 @@ fn parse_literal(input: &str) -> Res<&str> {
     // the parser. The verification function accepts out output only if it
     // returns true. In this case, we want to ensure that the output of is_not
     // is non-empty.
-    verify(not_quote_slash, |s: &str| !s.is_empty()).parse(input)
+    map(
+        verify(not_quote_slash, |s: &Input| !s.fragment().is_empty()),
+        |s: Input| *s.fragment(),
+    )
+    .parse(input)
 }
 
 /// A string fragment contains a fragment of a string being parsed: either
@@ -259,7 +453,7 @@ enum StringFragment<'a> {
 
 /// Combine parse_literal, parse_escaped_whitespace, and parse_escaped_char
 /// into a StringFragment.
-fn parse_fragment(input: &str) -> Res<StringFragment> {
+fn parse_fragment(input: Input) -> Res<StringFragment> {
     alt((
         // The `map` combinator runs a parser, then applies a function to the output
         // of that parser.
@@ -272,7 +466,7 @@ fn parse_fragment(input: &str) -> Res<StringFragment> {
 
 /// Parse a string. Use a loop of parse_fragment and push all of the fragments
 /// into an output string.
-fn parse_string(input: &str) -> Res<String> {
+fn parse_string(input: Input) -> Res<String> {
     // fold is the equivalent of iterator::fold. It runs a parser in a loop,
     // and for each output value, calls a folding function on each output value.
     let build_string = fold_many0(
@@ -296,38 +490,43 @@ fn parse_string(input: &str) -> Res<String> {
     // " character, the closing delimiter " would never match. When using
     // `delimited` with a looping parser (like fold), be sure that the
     // loop won't accidentally match your closing delimiter!
-    delimited(char('"'), build_string, char('"')).parse(input)
+    context("string", delimited(char('"'), build_string, char('"'))).parse(input)
 }
 
 /// Parse a `log` instruction:
 /// Takes a string literal, plus an optional number of registers to print
-fn parse_log(input: &str) -> Res<BasicBlockOp> {
-    let (input, _) = ws(tag("log"))(input)?;
-    let (input, string) = parse_string(input)?;
-    let (input, locs) = many0(preceded(ws(char(',')), parse_dynamic_location))(input)?;
+fn parse_log(input: Input) -> Res<BasicBlockOp> {
+    let (input, _) = ws(keyword("log"))(input)?;
+    let (input, string) = cut(parse_string)(input)?;
+    let (input, locs) = many0(preceded(ws(char(',')), cut(parse_dynamic_location)))(input)?;
     Ok((input, BasicBlockOp::Log(string, locs)))
 }
 
 /// Parse the `set` instruction:
 ///   [<dynamic_location>] = <operand>
-fn parse_set(input: &str) -> Res<BasicBlockOp> {
-    let (input, dest) = parse_dynamic_location(input)?;
+fn parse_set(input: Input) -> Res<BasicBlockOp> {
+    let (input, dest) = parse_destination(input)?;
     let (input, _) = ws(tag("="))(input)?;
-    let (input, src) = parse_operand(input)?;
+    let dest = check_destination(dest)?;
+    let (input, src) = cut(parse_operand)(input)?;
     Ok((input, BasicBlockOp::Set { dest, src }))
 }
 
 /// Parse the `ne` instruction.
-fn parse_lea(input: &str) -> Res<BasicBlockOp> {
-    let (input, dest) = parse_dynamic_location(input)?;
-    let (input, _) = ws(tag("lea"))(input)?;
-    let (input, src) = parse_dynamic_location(input)?;
+fn parse_lea(input: Input) -> Res<BasicBlockOp> {
+    let (input, dest) = parse_destination(input)?;
+    let (input, _) = ws(keyword("lea"))(input)?;
+    let dest = check_destination(dest)?;
+    if let Ok((input, address)) = alt((parse_data_reference, ws(parse_data_label)))(input) {
+        return parse_data_address(input, address, dest);
+    }
+    let (input, src) = cut(parse_dynamic_location)(input)?;
     let mut negative = false;
     let (input, offset) = opt(alt((
-        preceded(pair(space0, ws(char('+'))), parse_operand),
+        preceded(pair(space0, ws(char('+'))), cut(parse_operand)),
         preceded(
             pair(space0, ws(char('-'))),
-            map(parse_operand, |op| {
+            map(cut(parse_operand), |op| {
                 negative = true;
                 op
             }),
@@ -345,84 +544,258 @@ fn parse_lea(input: &str) -> Res<BasicBlockOp> {
     ))
 }
 
+/// Parse the rest of a `lea` of a data label, which is a constant address on the stack:
+///   <dest> lea <data_label> [(+|-) <operand>]
+fn parse_data_address(input: Input, address: u64, dest: DynamicLocation) -> Res<BasicBlockOp> {
+    let (input, offset) = opt(pair(ws(alt((char('+'), char('-')))), cut(parse_operand)))(input)?;
+    let op = match offset {
+        None => BasicBlockOp::Set {
+            src: Operand::Immediate(address),
+            dest,
+        },
+        Some(('+', Operand::Immediate(n))) => BasicBlockOp::Set {
+            src: Operand::Immediate(address.wrapping_add(n)),
+            dest,
+        },
+        Some((_, Operand::Immediate(n))) => BasicBlockOp::Set {
+            src: Operand::Immediate(address.wrapping_sub(n)),
+            dest,
+        },
+        Some(('+', rhs)) => BasicBlockOp::Add {
+            lhs: Operand::Immediate(address),
+            rhs,
+            dest,
+        },
+        Some((_, rhs)) => BasicBlockOp::Sub {
+            lhs: Operand::Immediate(address),
+            rhs,
+            dest,
+        },
+    };
+    Ok((input, op))
+}
+
+/// Parse the name of a data label, giving its address on the stack.
+fn parse_data_label(input: Input) -> Res<u64> {
+    let (rest, name) = parse_identifier(input)?;
+    // Arguments and locals hide data labels with the same name
+    let frame = input.extra.frame_at(input.location_offset());
+    match input.extra.labels.get(&name) {
+        Some(_) if frame.is_some_and(|frame| frame.slots.contains_key(&name)) => {
+            Err(nom::Err::Error(VerboseError::from_error_kind(
+                input,
+                nom::error::ErrorKind::Fail,
+            )))
+        }
+        Some(address) => Ok((rest, *address)),
+        None => Err(nom::Err::Error(VerboseError::from_error_kind(
+            input,
+            nom::error::ErrorKind::Fail,
+        ))),
+    }
+}
+
+/// Parse a reference to data on the stack, giving its address:
+///   [<data_label>]
+///   [<data_label> + <imm>]
+fn parse_data_reference(input: Input) -> Res<u64> {
+    let (input, _) = char('[')(input)?;
+    let (input, address) = ws(parse_data_label)(input)?;
+    let (input, offset) = opt(preceded(ws(char('+')), cut(ws(parse_immediate_literal))))(input)?;
+    let (input, _) = cut(char(']'))(input)?;
+    Ok((input, address + offset.unwrap_or(0)))
+}
+
 /// Parse the `inc` instruction:
 ///   inc <dynamic_location> [<imm>]
-fn parse_inc(input: &str) -> Res<BasicBlockOp> {
-    let (input, _) = ws(tag("inc"))(input)?;
-    let (input, op) = parse_dynamic_location(input)?;
-    let (input, imm) = opt(preceded(ws(tag(",")), parse_immediate_literal))(input)?;
+fn parse_inc(input: Input) -> Res<BasicBlockOp> {
+    let (input, _) = ws(keyword("inc"))(input)?;
+    let (input, op) = cut(parse_dynamic_location)(input)?;
+    let (input, imm) = opt(preceded(ws(tag(",")), cut(parse_immediate_literal)))(input)?;
 
     Ok((input, BasicBlockOp::Inc(op, imm)))
 }
 
 /// Parse the `dec` instruction:
 ///   dec <dynamic_location> [<imm>]
-fn parse_dec(input: &str) -> Res<BasicBlockOp> {
-    let (input, _) = ws(tag("dec"))(input)?;
-    let (input, op) = parse_dynamic_location(input)?;
-    let (input, imm) = opt(preceded(ws(tag(",")), parse_immediate_literal))(input)?;
+fn parse_dec(input: Input) -> Res<BasicBlockOp> {
+    let (input, _) = ws(keyword("dec"))(input)?;
+    let (input, op) = cut(parse_dynamic_location)(input)?;
+    let (input, imm) = opt(preceded(ws(tag(",")), cut(parse_immediate_literal)))(input)?;
 
     Ok((input, BasicBlockOp::Dec(op, imm)))
 }
 
 /// Parse the `push` instruction:
 ///   push <operand>
-fn parse_push(input: &str) -> Res<BasicBlockOp> {
-    let (input, _) = ws(tag("push"))(input)?;
-    let (input, op) = parse_operand(input)?;
+fn parse_push(input: Input) -> Res<BasicBlockOp> {
+    let (input, _) = ws(keyword("push"))(input)?;
+    let (input, op) = cut(parse_operand)(input)?;
     Ok((input, BasicBlockOp::Push(op)))
 }
 
+/// Parse the `alloc` instruction:
+///   <dest> alloc <size>
+fn parse_alloc(input: Input) -> Res<BasicBlockOp> {
+    let (input, dest) = parse_destination(input)?;
+    let (input, _) = ws(keyword("alloc"))(input)?;
+    let dest = check_destination(dest)?;
+    let (input, size) = cut(parse_operand)(input)?;
+    Ok((input, BasicBlockOp::Alloc { size, dest }))
+}
+
+/// Parse the `free` instruction:
+///   free <operand>
+fn parse_free(input: Input) -> Res<BasicBlockOp> {
+    let (input, _) = ws(keyword("free"))(input)?;
+    let (input, ptr) = cut(parse_operand)(input)?;
+    Ok((input, BasicBlockOp::Free(ptr)))
+}
+
 /// Parse the `pop` instruction:
 ///   pop [<dynamic_location>]
-fn parse_pop(input: &str) -> Res<BasicBlockOp> {
-    let (input, _) = ws(tag("pop"))(input)?;
-    // Optionally follow with a dynamic location (e.g. "pop R0" or "pop SP[R0]")
-    let (input, loc) = opt(preceded(space0, parse_dynamic_location))(input)?;
+fn parse_pop(input: Input) -> Res<BasicBlockOp> {
+    let (input, _) = ws(keyword("pop"))(input)?;
+    // Optionally follow with a dynamic location (e.g. "pop R0" or "pop [R0]")
+    let (input, loc) = parse_optional_location(input)?;
     Ok((input, BasicBlockOp::Pop(loc)))
 }
 
 /// Parse the `getchar` instruction:
 ///   getchar [<dynamic_location>]
-fn parse_getchar(input: &str) -> Res<BasicBlockOp> {
-    let (input, _) = ws(tag("getchar"))(input)?;
-    let (input, loc) = opt(preceded(space0, parse_dynamic_location))(input)?;
+fn parse_getchar(input: Input) -> Res<BasicBlockOp> {
+    let (input, _) = ws(keyword("getchar"))(input)?;
+    let (input, loc) = parse_optional_location(input)?;
     Ok((input, BasicBlockOp::GetChar(loc)))
 }
 
 /// Parse the `putchar` instruction:
 ///   putchar <operand>
-fn parse_putchar(input: &str) -> Res<BasicBlockOp> {
-    let (input, _) = ws(tag("putchar"))(input)?;
-    let (input, op) = parse_operand(input)?;
+fn parse_putchar(input: Input) -> Res<BasicBlockOp> {
+    let (input, _) = ws(keyword("putchar"))(input)?;
+    let (input, op) = cut(parse_operand)(input)?;
     Ok((input, BasicBlockOp::PutChar(op)))
 }
 
 /// Parse the `putchar` instruction:
 ///   putchar <operand>
-fn parse_putint(input: &str) -> Res<BasicBlockOp> {
-    let (input, _) = ws(tag("putint"))(input)?;
-    let (input, op) = parse_operand(input)?;
+fn parse_putint(input: Input) -> Res<BasicBlockOp> {
+    let (input, _) = ws(keyword("putint"))(input)?;
+    let (input, op) = cut(parse_operand)(input)?;
     Ok((input, BasicBlockOp::PutInt(op)))
 }
 
+/// Parse the `putsint` instruction:
+///   putsint <operand>
+fn parse_putsint(input: Input) -> Res<BasicBlockOp> {
+    let (input, _) = ws(keyword("putsint"))(input)?;
+    let (input, op) = cut(parse_operand)(input)?;
+    Ok((input, BasicBlockOp::PutSInt(op)))
+}
+
+/// Parse the `getint` instruction:
+///   getint <dynamic_location>
+fn parse_getint(input: Input) -> Res<BasicBlockOp> {
+    let (input, _) = ws(keyword("getint"))(input)?;
+    let (input, loc) = cut(parse_dynamic_location)(input)?;
+    Ok((input, BasicBlockOp::GetInt(loc)))
+}
+
+/// Parse setting a wide register:
+///   <wide_register> = <wide_operand>
+fn parse_wide_set(input: Input) -> Res<BasicBlockOp> {
+    let (input, dest) = parse_wide_register(input)?;
+    let (input, _) = ws(tag("="))(input)?;
+    let (input, src) = cut(parse_wide_operand)(input)?;
+    Ok((input, BasicBlockOp::WideSet { src, dest }))
+}
+
+/// Parse `putint` with a wide register:
+///   putint <wide_register>
+fn parse_wide_putint(input: Input) -> Res<BasicBlockOp> {
+    let (input, _) = ws(keyword("putint"))(input)?;
+    let (input, reg) = ws(parse_wide_register)(input)?;
+    Ok((input, BasicBlockOp::WidePutInt(reg)))
+}
+
+/// Like [`parse_binary_op`], but for a wide register destination and wide operands:
+///   <wide_register> <op> <lhs>[, <rhs>]
+fn parse_wide_binary_op<'a, F>(
+    op_name: &'static str,
+    constructor: F,
+) -> impl FnMut(Input<'a>) -> Res<'a, BasicBlockOp>
+where
+    F: Fn(WideOperand, WideOperand, WideLocation) -> BasicBlockOp,
+{
+    move |input: Input<'a>| {
+        let (input, dest) = parse_wide_register(input)?;
+        let (input, _) = space0(input)?;
+        let (input, _) = ws(keyword(op_name))(input)?;
+        let (input, lhs) = cut(parse_wide_operand)(input)?;
+        let (input, rhs) = opt(preceded(ws(char(',')), cut(parse_wide_operand)))(input)?;
+        match rhs {
+            None => Ok((input, constructor(WideOperand::Register(dest), lhs, dest))),
+            Some(rhs) => Ok((input, constructor(lhs, rhs, dest))),
+        }
+    }
+}
+
+/// Parse an instruction on wide registers.
+fn parse_wide_op(input: Input) -> Res<BasicBlockOp> {
+    alt((
+        parse_wide_set,
+        parse_wide_putint,
+        parse_wide_add,
+        parse_wide_sub,
+        parse_wide_mul,
+    ))(input)
+}
+
+/// Parse the `add` instruction on wide registers.
+fn parse_wide_add(input: Input) -> Res<BasicBlockOp> {
+    parse_wide_binary_op("add", |lhs, rhs, dest| BasicBlockOp::WideAdd {
+        lhs,
+        rhs,
+        dest,
+    })(input)
+}
+
+/// Parse the `sub` instruction on wide registers.
+fn parse_wide_sub(input: Input) -> Res<BasicBlockOp> {
+    parse_wide_binary_op("sub", |lhs, rhs, dest| BasicBlockOp::WideSub {
+        lhs,
+        rhs,
+        dest,
+    })(input)
+}
+
+/// Parse the `mul` instruction on wide registers.
+fn parse_wide_mul(input: Input) -> Res<BasicBlockOp> {
+    parse_wide_binary_op("mul", |lhs, rhs, dest| BasicBlockOp::WideMul {
+        lhs,
+        rhs,
+        dest,
+    })(input)
+}
+
 /// A helper for binary operations (add, sub, etc.) that take the form:
 ///   <op> <lhs>, <rhs>, <dest>
 /// where lhs and rhs are operands and dest is a dynamic location.
 fn parse_binary_op<'a, F>(
     op_name: &'static str,
     constructor: F,
-) -> impl FnMut(&'a str) -> Res<'a, BasicBlockOp>
+) -> impl FnMut(Input<'a>) -> Res<'a, BasicBlockOp>
 where
     F: Fn(Operand, Operand, DynamicLocation) -> BasicBlockOp,
 {
-    move |input: &str| {
-        let (input, dest) = parse_dynamic_location(input)?;
+    move |input: Input<'a>| {
+        let (input, dest) = parse_destination(input)?;
         let (input, _) = space0(input)?;
-        let (input, _) = ws(tag(op_name))(input)?;
+        let (input, _) = ws(keyword(op_name))(input)?;
+        let dest = check_destination(dest)?;
         let (input, _) = space0(input)?;
-        let (input, lhs) = parse_operand(input)?;
-        let (input, rhs) = opt(preceded(ws(char(',')), parse_operand))(input)?;
+        let (input, lhs) = cut(parse_operand)(input)?;
+        let (input, rhs) = opt(preceded(ws(char(',')), cut(parse_operand)))(input)?;
         match rhs {
             // Pass the dst as the lhs
             None => Ok((input, constructor(Operand::Location(dest), lhs, dest))),
@@ -432,130 +805,274 @@ where
 }
 
 /// Parse the `add` instruction.
-fn parse_add(input: &str) -> Res<BasicBlockOp> {
+fn parse_add(input: Input) -> Res<BasicBlockOp> {
     parse_binary_op("add", |lhs, rhs, dest| BasicBlockOp::Add { lhs, rhs, dest })(input)
 }
 
 /// Parse the `sub` instruction.
-fn parse_sub(input: &str) -> Res<BasicBlockOp> {
+fn parse_sub(input: Input) -> Res<BasicBlockOp> {
     parse_binary_op("sub", |lhs, rhs, dest| BasicBlockOp::Sub { lhs, rhs, dest })(input)
 }
 
 /// Parse the `mul` instruction.
-fn parse_mul(input: &str) -> Res<BasicBlockOp> {
+fn parse_mul(input: Input) -> Res<BasicBlockOp> {
     parse_binary_op("mul", |lhs, rhs, dest| BasicBlockOp::Mul { lhs, rhs, dest })(input)
 }
 
 /// Parse the `div` instruction.
-fn parse_div(input: &str) -> Res<BasicBlockOp> {
+fn parse_div(input: Input) -> Res<BasicBlockOp> {
     parse_binary_op("div", |lhs, rhs, dest| BasicBlockOp::Div { lhs, rhs, dest })(input)
 }
 
-/*
 /// Parse the `mod` instruction.
-fn parse_mod(input: &str) -> Res<BasicBlockOp> {
+fn parse_mod(input: Input) -> Res<BasicBlockOp> {
     parse_binary_op("mod", |lhs, rhs, dest| BasicBlockOp::Mod { lhs, rhs, dest })(input)
 }
- */
+
+/// Parse the `imul` instruction.
+fn parse_imul(input: Input) -> Res<BasicBlockOp> {
+    parse_binary_op("imul", |lhs, rhs, dest| BasicBlockOp::IMul {
+        lhs,
+        rhs,
+        dest,
+    })(input)
+}
+
+/// Parse the `idiv` instruction.
+fn parse_idiv(input: Input) -> Res<BasicBlockOp> {
+    parse_binary_op("idiv", |lhs, rhs, dest| BasicBlockOp::IDiv {
+        lhs,
+        rhs,
+        dest,
+    })(input)
+}
+
+/// Parse the `ilt` instruction.
+fn parse_ilt(input: Input) -> Res<BasicBlockOp> {
+    parse_binary_op("ilt", |lhs, rhs, dest| BasicBlockOp::ILt { lhs, rhs, dest })(input)
+}
+
+/// Parse the `lt` instruction.
+fn parse_lt(input: Input) -> Res<BasicBlockOp> {
+    parse_binary_op("lt", |lhs, rhs, dest| BasicBlockOp::Lt { lhs, rhs, dest })(input)
+}
+
+/// Parse the `le` instruction.
+fn parse_le(input: Input) -> Res<BasicBlockOp> {
+    parse_binary_op("le", |lhs, rhs, dest| BasicBlockOp::Le { lhs, rhs, dest })(input)
+}
+
+/// Parse the `gt` instruction.
+fn parse_gt(input: Input) -> Res<BasicBlockOp> {
+    parse_binary_op("gt", |lhs, rhs, dest| BasicBlockOp::Gt { lhs, rhs, dest })(input)
+}
+
+/// Parse the `ge` instruction.
+fn parse_ge(input: Input) -> Res<BasicBlockOp> {
+    parse_binary_op("ge", |lhs, rhs, dest| BasicBlockOp::Ge { lhs, rhs, dest })(input)
+}
+
+/// Parse the `and` instruction.
+fn parse_and(input: Input) -> Res<BasicBlockOp> {
+    parse_binary_op("and", |lhs, rhs, dest| BasicBlockOp::And { lhs, rhs, dest })(input)
+}
+
+/// Parse the `or` instruction.
+fn parse_or(input: Input) -> Res<BasicBlockOp> {
+    parse_binary_op("or", |lhs, rhs, dest| BasicBlockOp::Or { lhs, rhs, dest })(input)
+}
+
+/// Parse the `xor` instruction.
+fn parse_xor(input: Input) -> Res<BasicBlockOp> {
+    parse_binary_op("xor", |lhs, rhs, dest| BasicBlockOp::Xor { lhs, rhs, dest })(input)
+}
+
+/// Parse the `shl` instruction.
+fn parse_shl(input: Input) -> Res<BasicBlockOp> {
+    parse_binary_op("shl", |lhs, rhs, dest| BasicBlockOp::Shl { lhs, rhs, dest })(input)
+}
+
+/// Parse the `shr` instruction.
+fn parse_shr(input: Input) -> Res<BasicBlockOp> {
+    parse_binary_op("shr", |lhs, rhs, dest| BasicBlockOp::Shr { lhs, rhs, dest })(input)
+}
 
 /// Parse the `eq` instruction.
-fn parse_eq(input: &str) -> Res<BasicBlockOp> {
+fn parse_eq(input: Input) -> Res<BasicBlockOp> {
     parse_binary_op("eq", |lhs, rhs, dest| BasicBlockOp::Eq { lhs, rhs, dest })(input)
 }
 
 /// Parse the `ne` instruction.
-fn parse_ne(input: &str) -> Res<BasicBlockOp> {
+fn parse_ne(input: Input) -> Res<BasicBlockOp> {
     parse_binary_op("neq", |lhs, rhs, dest| BasicBlockOp::Ne { lhs, rhs, dest })(input)
 }
 
 /// Parse the unary `neg` instruction:
 ///   neg <src>, <dest>
-fn parse_neg(input: &str) -> Res<BasicBlockOp> {
-    let (input, _) = ws(tag("neg"))(input)?;
+fn parse_neg(input: Input) -> Res<BasicBlockOp> {
+    let (input, _) = ws(keyword("neg"))(input)?;
     let (input, _) = space0(input)?;
-    let (input, src) = parse_operand(input)?;
-    let (input, _) = ws(char(','))(input)?;
-    let (input, dest) = parse_dynamic_location(input)?;
+    let (input, src) = cut(parse_operand)(input)?;
+    let (input, _) = cut(ws(char(',')))(input)?;
+    let (input, dest) = cut(parse_dynamic_location)(input)?;
     Ok((input, BasicBlockOp::Neg { src, dest }))
 }
 
-/// Parse a label line: an identifier immediately followed by a colon.
-fn parse_label(input: &str) -> Res<Symbol> {
+/// Parse the unary `not` instruction:
+///   not <src>, <dest>
+fn parse_not(input: Input) -> Res<BasicBlockOp> {
+    let (input, _) = ws(keyword("not"))(input)?;
     let (input, _) = space0(input)?;
-    let (input, result) = map(terminated(parse_identifier, ws(char(':'))), Symbol::from)(input)?;
+    let (input, src) = cut(parse_operand)(input)?;
+    let (input, _) = cut(ws(char(',')))(input)?;
+    let (input, dest) = cut(parse_dynamic_location)(input)?;
+    Ok((input, BasicBlockOp::Not { src, dest }))
+}
+
+/// Parse a label line: an identifier immediately followed by a colon.
+fn parse_label(input: Input) -> Res<(Symbol, Span)> {
+    let (start, _) = space0(input)?;
+    let (input, result) = map(terminated(parse_identifier, ws(char(':'))), Symbol::from)(start)?;
+    let span = Span::new(start, result.as_str().len());
     let (input, _) = cut(parse_end_of_lines)(input)?;
-    Ok((input, result))
+    Ok((input, (result, span)))
 }
 
 /// Parse the `quit` instruction:
 ///   quit
-fn parse_quit(input: &str) -> Res<Op> {
+fn parse_quit(input: Input) -> Res<Op> {
     let (input, _) = space0(input)?;
-    let (input, _) = tag("quit")(input)?;
+    let (input, _) = keyword("quit")(input)?;
     let (input, _) = cut(parse_end_of_lines)(input)?;
-    Ok((input, Op::Quit(next_basic_block_number())))
+    Ok((input, Op::Quit))
 }
 
 /// Parse the `jmp` instruction:
 ///   jmp <label>
-fn parse_jmp(input: &str) -> Res<Op> {
-    let (input, _) = ws(tag("jmp"))(input)?;
-    let (input, _) = space0(input)?;
-    let (input, label) = parse_identifier(input)?;
+fn parse_jmp(input: Input) -> Res<Op> {
+    let (input, _) = ws(keyword("jmp"))(input)?;
+    let (input, (label, span)) = cut(parse_label_reference)(input)?;
     let (input, _) = cut(parse_end_of_lines)(input)?;
-    Ok((input, Op::Jmp(next_basic_block_number(), label)))
+    Ok((input, Op::Jmp(label, span)))
 }
 
 /// Parse the `call` instruction:
 ///   call <label>
-fn parse_call(input: &str) -> Res<Op> {
-    let (input, _) = ws(tag("call"))(input)?;
-    let (input, _) = space0(input)?;
-    let (input, label) = parse_identifier(input)?;
+fn parse_call(input: Input) -> Res<Op> {
+    let (input, _) = ws(keyword("call"))(input)?;
+    let (input, (label, span)) = cut(parse_label_reference)(input)?;
     let (input, _) = cut(parse_end_of_lines)(input)?;
-    Ok((input, Op::Call(next_basic_block_number(), label)))
+    Ok((input, Op::Call(label, span)))
 }
 
 /// Parse the `ret` instruction:
 ///  ret
-fn parse_ret(input: &str) -> Res<Op> {
-    let (input, _) = ws(tag("ret"))(input)?;
+fn parse_ret(input: Input) -> Res<Op> {
+    let (input, _) = ws(keyword("ret"))(input)?;
     let (input, _) = cut(parse_end_of_lines)(input)?;
-    Ok((input, Op::Return(next_basic_block_number())))
+    Ok((input, Op::Return))
 }
 
 /// Parse the `jmp_if` instruction:
 ///   jmp_if <dynamic_location>, <label>
-fn parse_jmp_if(input: &str) -> Res<Op> {
-    let (input, _) = ws(tag("jmp_if"))(input)?;
+fn parse_jmp_if(input: Input) -> Res<Op> {
+    let (input, _) = ws(keyword("jmp_if"))(input)?;
     let (input, _) = space0(input)?;
-    let (input, loc) = parse_dynamic_location(input)?;
-    let (input, _) = ws(char(','))(input)?;
-    let (input, label) = parse_identifier(input)?;
+    let (input, loc) = cut(parse_dynamic_location)(input)?;
+    let (input, _) = cut(ws(char(',')))(input)?;
+    let (input, (label, span)) = cut(parse_label_reference)(input)?;
     let (input, _) = cut(parse_end_of_lines)(input)?;
-    Ok((input, Op::JmpIf(next_basic_block_number(), loc, label)))
+    Ok((input, Op::JmpIf(loc, label, span)))
 }
 
-fn parse_end_of_line(input: &str) -> Res<()> {
+fn parse_end_of_line(input: Input) -> Res<()> {
     let (input, _) = space0(input)?;
     let (input, _) = char('\n')(input)?;
     Ok((input, ()))
 }
 
-fn parse_end_of_lines(input: &str) -> Res<()> {
-    alt((map(many1(parse_end_of_line), |_| ()), map(eof, |_| ())))(input)
+fn parse_end_of_lines(input: Input) -> Res<()> {
+    context(
+        "end of line",
+        alt((map(many1(parse_end_of_line), |_| ()), map(eof, |_| ()))),
+    )(input)
 }
 
 /// Parse one “line” of assembly. This line can be a basic block op,
 /// a control op, or a label.
-fn parse_basic_block_op(input: &str) -> Res<BasicBlockOp> {
-    let (input, op) = ws(alt((
+/// Parse an op in a basic block, which is either a single instruction or a
+/// structured `if` or `while` block.
+fn parse_basic_block_op(input: Input) -> Res<(BasicBlockOp, SourceLocation)> {
+    alt((parse_if, parse_while, parse_instruction))(input)
+}
+
+/// Parse an `if` block, which runs its ops when the condition isn't zero:
+///   if <location>
+///       <ops>
+///   [else
+///       <ops>]
+///   end
+fn parse_if(input: Input) -> Res<(BasicBlockOp, SourceLocation)> {
+    let (input, (cond, location)) = located(preceded(
+        // A label with the same name isn't a block
+        pair(keyword("if"), space1),
+        cut(ws(parse_dynamic_location)),
+    ))(input)?;
+    let (input, _) = cut(parse_end_of_lines)(input)?;
+    let (input, then) = parse_block_body(input)?;
+    let (input, otherwise) = opt(preceded(
+        tuple((space0, keyword("else"), cut(parse_end_of_lines))),
+        parse_block_body,
+    ))(input)?;
+    let (input, _) = parse_block_end(input)?;
+    let op = BasicBlockOp::If {
+        cond,
+        then,
+        otherwise: otherwise.unwrap_or_default(),
+    };
+    Ok((input, (op, location)))
+}
+
+/// Parse a `while` block, which runs its ops until the condition is zero:
+///   while <location>
+///       <ops>
+///   end
+fn parse_while(input: Input) -> Res<(BasicBlockOp, SourceLocation)> {
+    let (input, (cond, location)) = located(preceded(
+        // A label with the same name isn't a block
+        pair(keyword("while"), space1),
+        cut(ws(parse_dynamic_location)),
+    ))(input)?;
+    let (input, _) = cut(parse_end_of_lines)(input)?;
+    let (input, body) = parse_block_body(input)?;
+    let (input, _) = parse_block_end(input)?;
+    Ok((input, (BasicBlockOp::While { cond, body }, location)))
+}
+
+/// Parse the ops inside a block. Labels and jumps can't be used in them, since
+/// a block has to stay inside its basic block.
+fn parse_block_body(input: Input) -> Res<Vec<BasicBlockOp>> {
+    many0(map(parse_basic_block_op, |(op, _)| op))(input)
+}
+
+/// Parse the `end` of a block.
+fn parse_block_end(input: Input) -> Res<()> {
+    let (input, _) = space0(input)?;
+    let (input, _) = cut(context("`end`", keyword("end")))(input)?;
+    let (input, _) = cut(parse_end_of_lines)(input)?;
+    Ok((input, ()))
+}
+
+/// Parse a single instruction in a basic block.
+fn parse_instruction(input: Input) -> Res<(BasicBlockOp, SourceLocation)> {
+    let (input, op) = located(alt((
         parse_hex_dump,
         parse_log,
         parse_dec_dump,
         parse_inc,
         parse_dec,
-        parse_set,
+        // Wide registers look like destinations to the ordinary instructions
+        alt((parse_wide_op, parse_set)),
         parse_lea,
         parse_getchar,
         parse_putchar,
@@ -569,16 +1086,30 @@ fn parse_basic_block_op(input: &str) -> Res<BasicBlockOp> {
         parse_neg,
         parse_eq,
         parse_ne,
+        alt((
+            parse_mod, parse_lt, parse_le, parse_gt, parse_ge, parse_and, parse_or, parse_xor,
+            parse_not, parse_shl, parse_shr,
+        )),
+        alt((
+            parse_alloc,
+            parse_free,
+            parse_imul,
+            parse_idiv,
+            parse_ilt,
+            parse_putsint,
+            parse_getint,
+        )),
     )))(input)?;
     let (input, _) = cut(parse_end_of_lines)(input)?;
     Ok((input, op))
 }
 
-fn parse_basic_block(label: Option<Symbol>, input: &str) -> Res<BasicBlock> {
+fn parse_basic_block(label: Option<Symbol>, input: Input) -> Res<BasicBlock> {
     let (input, _) = space0(input)?;
     // let (input, ops) = separated_list0(multispace0, parse_basic_block_op)(input)?;
     let (input, ops) = many0(parse_basic_block_op)(input)?;
-    Ok((input, BasicBlock::new(label, ops)))
+    let (ops, sources) = ops.into_iter().unzip();
+    Ok((input, BasicBlock::new(label, ops).with_sources(sources)))
     //     map(parse_push, |op| Instruction::BasicBlockOp(op)),
     //     map(parse_pop, |op| Instruction::BasicBlockOp(op)),
     //     map(parse_getchar, |op| Instruction::BasicBlockOp(op)),
@@ -597,24 +1128,24 @@ fn parse_basic_block(label: Option<Symbol>, input: &str) -> Res<BasicBlock> {
     // ))(input)
 }
 
-fn parse_labeled_basic_block(input: &str) -> Res<Op> {
+fn parse_labeled_basic_block(input: Input) -> Res<Op> {
     // First try to parse a label
     let (input, _) = space0(input)?;
     let (input, label) = opt(parse_label)(input)?;
     let (input, _) = space0(input)?;
     // Then parse the basic block op
-    if let Some(label) = label {
+    if let Some((label, span)) = label {
         let (input, bb) = parse_basic_block(Some(label.clone()), input)?;
-        Ok((input, Op::Label(label, bb)))
+        Ok((input, Op::Label(label, bb.with_label_span(span))))
     } else {
         let (input, bb) = parse_basic_block(None, input)?;
         Ok((input, Op::BasicBlock(bb)))
     }
 }
 
-fn parse_op(input: &str) -> Res<Op> {
-    if input == "" {
-        return Err(nom::Err::Error(nom::error::VerboseError::from_error_kind(
+fn parse_op(input: Input) -> Res<(Op, Option<SourceLocation>)> {
+    if input.fragment().is_empty() {
+        return Err(nom::Err::Error(VerboseError::from_error_kind(
             input,
             nom::error::ErrorKind::Fail,
         )));
@@ -625,47 +1156,481 @@ fn parse_op(input: &str) -> Res<Op> {
     // JmpIf(DynamicLocation, Symbol),
     let (input, _) = space0(input)?;
     let (input, op) = alt((
-        parse_quit,
-        parse_call,
-        parse_ret,
-        parse_jmp_if,
-        parse_jmp,
-        parse_labeled_basic_block,
+        map(
+            located(alt((parse_quit, parse_call, parse_ret, parse_jmp_if, parse_jmp))),
+            |(op, location)| (op, Some(location)),
+        ),
+        // The ops in a basic block keep track of their own locations
+        map(parse_labeled_basic_block, |op| (op, None)),
     ))(input)?;
 
     Ok((input, op))
 }
 
+/// Parse the `.data` line that starts the data section.
+fn parse_data_marker(input: Input) -> Res<()> {
+    let (input, _) = space0(input)?;
+    let (input, _) = char('.')(input)?;
+    let (input, _) = keyword("data")(input)?;
+    let (input, _) = cut(parse_end_of_lines)(input)?;
+    Ok((input, ()))
+}
+
+/// Parse a declaration in the data section, giving its label, where the
+/// label was written, and the values it holds:
+///   <name>: .string "<text>"
+///   <name>: .bytes <imm>, <imm>, ...
+///   <name>: .zero <count>
+///
+/// Strings are followed by a zero, like the ones the standard library works with.
+fn parse_data_declaration(input: Input) -> Res<(Symbol, Span, Vec<u64>)> {
+    let (start, _) = space0(input)?;
+    let (input, name) = parse_identifier(start)?;
+    let span = Span::new(start, name.as_str().len());
+    let (input, _) = ws(char(':'))(input)?;
+    let (input, _) = char('.')(input)?;
+    let (input, values) = cut(context(
+        "data directive",
+        alt((
+            preceded(
+                ws(keyword("string")),
+                map(cut(parse_string), |text| {
+                    text.chars().map(|c| c as u64).chain([0]).collect()
+                }),
+            ),
+            preceded(
+                ws(keyword("bytes")),
+                cut(separated_list1(ws(char(',')), ws(parse_immediate_literal))),
+            ),
+            preceded(
+                ws(keyword("zero")),
+                map(cut(ws(parse_immediate_literal)), |count| {
+                    vec![0; count as usize]
+                }),
+            ),
+        )),
+    ))(input)?;
+    let (input, _) = cut(parse_end_of_lines)(input)?;
+    Ok((input, (name, span, values)))
+}
+
+/// The first line of a function, which names its arguments and locals:
+///   func <name>([<arg>, ...]) [locals <local>, ...]
+struct FunctionHeader {
+    name: Symbol,
+    /// Where the name was written.
+    span: Span,
+    args: Vec<(Symbol, Span)>,
+    locals: Vec<(Symbol, Span)>,
+}
+
+/// Parse a name in the header of a function, and remember where it was written.
+fn parse_declared_name(input: Input) -> Res<(Symbol, Span)> {
+    let (start, _) = space0(input)?;
+    let (input, name) = context("name", parse_identifier)(start)?;
+    let span = Span::new(start, name.as_str().len());
+    let (input, _) = space0(input)?;
+    Ok((input, (name, span)))
+}
+
+/// Parse the first line of a function.
+fn parse_function_header(input: Input) -> Res<FunctionHeader> {
+    let (input, _) = space0(input)?;
+    // A label with the same name isn't a function
+    let (input, _) = pair(keyword("func"), space1)(input)?;
+    let (input, (name, span)) = cut(parse_declared_name)(input)?;
+    let (input, args) = cut(delimited(
+        char('('),
+        separated_list0(char(','), parse_declared_name),
+        ws(char(')')),
+    ))(input)?;
+    let (input, locals) = opt(preceded(
+        ws(keyword("locals")),
+        cut(separated_list1(char(','), parse_declared_name)),
+    ))(input)?;
+    let (input, _) = cut(parse_end_of_lines)(input)?;
+    let header = FunctionHeader {
+        name,
+        span,
+        args,
+        locals: locals.unwrap_or_default(),
+    };
+    Ok((input, header))
+}
+
+/// Parse the declaration of some variables:
+///   var <name>, ...
+fn parse_variable_declaration(input: Input) -> Res<Vec<(Symbol, Span)>> {
+    let (input, _) = space0(input)?;
+    // A label with the same name isn't a declaration
+    let (input, _) = pair(keyword("var"), space1)(input)?;
+    let (input, names) = cut(separated_list1(char(','), parse_declared_name))(input)?;
+    let (input, _) = cut(parse_end_of_lines)(input)?;
+    Ok((input, names))
+}
+
+/// Parse the `endfunc` line that ends a function.
+fn parse_function_end(input: Input) -> Res<()> {
+    let (input, _) = space0(input)?;
+    let (input, _) = keyword("endfunc")(input)?;
+    let (input, _) = cut(parse_end_of_lines)(input)?;
+    Ok((input, ()))
+}
+
+/// Parse a `ret` inside a function, which can return a value:
+///   ret [<operand>]
+fn parse_function_ret(input: Input) -> Res<Option<Operand>> {
+    let (input, _) = ws(keyword("ret"))(input)?;
+    let (input, result) = opt(parse_operand)(input)?;
+    let (input, _) = cut(parse_end_of_lines)(input)?;
+    Ok((input, result))
+}
+
+/// Parse a function, from its `func` to its `endfunc`.
+///
+/// The function starts with a label of its name, which sets up its frame.
+/// Every `ret` in it, and its `endfunc`, tear the frame down before returning.
+fn parse_function(input: Input) -> Res<Vec<(Op, Option<SourceLocation>)>> {
+    let (input, (header, location)) = located(parse_function_header)(input)?;
+    let frame = input
+        .extra
+        .frame_at(location.span.offset)
+        .expect("functions are collected before they're parsed");
+    let args = frame.args;
+
+    // The prologue starts the first basic block
+    let (input, body) = many0(parse_basic_block_op)(input)?;
+    let (mut ops, mut sources): (Vec<_>, Vec<_>) = body.into_iter().unzip();
+    ops.insert(
+        0,
+        BasicBlockOp::Enter {
+            locals: frame.locals,
+        },
+    );
+    sources.insert(0, location);
+    let entry = BasicBlock::new(Some(header.name.clone()), ops)
+        .with_sources(sources)
+        .with_label_span(header.span);
+    let mut result = vec![(Op::Label(header.name, entry), None)];
+
+    let leave = |result: Option<Operand>, location: SourceLocation| {
+        let leave = BasicBlock::new(None::<Symbol>, vec![BasicBlockOp::Leave { args, result }]);
+        [
+            (
+                Op::BasicBlock(leave.with_sources(vec![location.clone()])),
+                None,
+            ),
+            (Op::Return, Some(location)),
+        ]
+    };
+    let (input, (body, (_, end))) = cut(many_till(
+        alt((
+            map(located(parse_function_ret), |(result, location)| {
+                leave(result, location).to_vec()
+            }),
+            map(parse_op, |op| vec![op]),
+        )),
+        located(parse_function_end),
+    ))(input)?;
+    result.extend(body.into_iter().flatten());
+    // Falling off the end of the function returns from it
+    result.extend(leave(None, end));
+    Ok((input, result))
+}
+
+/// Check the names in the header of a function, and work out where each
+/// of them is in the function's frame.
+///
+/// The arguments are pushed by the caller before the saved frame pointer, and
+/// the locals come after it.
+fn function_slots(
+    header: &FunctionHeader,
+    error: impl Fn(String, Span) -> AsmError,
+) -> Result<HashMap<Symbol, i64>, AsmError> {
+    let args = header.args.len() as i64;
+    let names = header.args.iter().chain(&header.locals);
+    let offsets = (-args..0).chain(1..);
+    let mut slots = HashMap::new();
+    for ((name, span), offset) in names.zip(offsets) {
+        if register(name.as_str()).is_some() {
+            return Err(error(
+                format!("`{name}` is a register, so it can't name an argument or local"),
+                *span,
+            ));
+        }
+        if slots.insert(name.clone(), offset).is_some() {
+            return Err(error(
+                format!(
+                    "`{name}` is already an argument or local of `{}`",
+                    header.name
+                ),
+                *span,
+            ));
+        }
+    }
+    Ok(slots)
+}
+
+/// Collect the data section and the frames of the functions in a program
+/// before parsing the rest of it.
+///
+/// The data section starts at a `.data` line and holds the declarations after
+/// it, up to the first line that is neither a declaration nor blank.
+fn collect_declarations(source: &str) -> Result<Declarations, AsmError> {
+    let no_data = Declarations::default();
+    let mut data = Declarations::default();
+    let mut input = Input::new_extra(source, &no_data);
+    let mut in_section = false;
+    let error = |message: String, span: Span| AsmError::Syntax {
+        message,
+        location: ErrorLocation::new(source, span),
+    };
+
+    // The function being collected, and where it starts
+    let mut function: Option<(FunctionHeader, usize)> = None;
+    // The variables, and where they were declared
+    let mut declared = vec![];
+
+    while !input.fragment().is_empty() {
+        match parse_function_header(input) {
+            Ok((rest, header)) => {
+                if let Some((outer, _)) = &function {
+                    return Err(error(
+                        format!(
+                            "`{}` can't be declared inside of `{}`",
+                            header.name, outer.name
+                        ),
+                        header.span,
+                    ));
+                }
+                function = Some((header, input.location_offset()));
+                in_section = false;
+                input = rest;
+                continue;
+            }
+            Err(nom::Err::Failure(e)) => return Err(convert_error(source, e)),
+            Err(_) => {}
+        }
+        match located(parse_function_end)(input) {
+            Ok((rest, ((), location))) => {
+                let Some((header, start)) = function.take() else {
+                    return Err(error(
+                        "`endfunc` doesn't end a function".to_string(),
+                        location.span,
+                    ));
+                };
+                data.frames.push(Frame {
+                    range: start..rest.location_offset(),
+                    args: header.args.len(),
+                    locals: header.locals.len(),
+                    slots: function_slots(&header, error)?,
+                });
+                in_section = false;
+                input = rest;
+                continue;
+            }
+            Err(nom::Err::Failure(e)) => return Err(convert_error(source, e)),
+            Err(_) => {}
+        }
+        match parse_variable_declaration(input) {
+            Ok((rest, names)) => {
+                if let Some((header, _)) = &function {
+                    return Err(error(
+                        format!(
+                            "variables can't be declared inside of `{}`, use `locals` instead",
+                            header.name
+                        ),
+                        names[0].1,
+                    ));
+                }
+                for (name, span) in names {
+                    if register(name.as_str()).is_some() {
+                        return Err(error(
+                            format!("`{name}` is a register, so it can't name a variable"),
+                            span,
+                        ));
+                    }
+                    let index = data.variables.len();
+                    if data.variables.insert(name.clone(), index).is_some() {
+                        return Err(error(
+                            format!("variable `{name}` is already declared"),
+                            span,
+                        ));
+                    }
+                    declared.push((name, span));
+                    data.last = span;
+                }
+                in_section = false;
+                input = rest;
+                continue;
+            }
+            Err(nom::Err::Failure(e)) => return Err(convert_error(source, e)),
+            Err(_) => {}
+        }
+        match parse_data_marker(input) {
+            Ok((rest, ())) => {
+                in_section = true;
+                input = rest;
+                continue;
+            }
+            Err(nom::Err::Failure(e)) => return Err(convert_error(source, e)),
+            Err(_) => {}
+        }
+        match parse_data_declaration(input) {
+            Ok((rest, (name, span, values))) => {
+                if !in_section {
+                    return Err(error(
+                        format!("`{name}` must be declared in a `.data` section"),
+                        span,
+                    ));
+                }
+                let address = data.cells.len() as u64 + 1;
+                if data.labels.insert(name.clone(), address).is_some() {
+                    return Err(error(
+                        format!("data label `{name}` is already defined"),
+                        span,
+                    ));
+                }
+                data.cells.extend(values);
+                data.last = span;
+                input = rest;
+                continue;
+            }
+            Err(nom::Err::Failure(e)) => return Err(convert_error(source, e)),
+            Err(_) => {}
+        }
+        // Any other line ends the section, unless it's blank
+        let line_end = input.fragment().find('\n').map_or(input.len(), |i| i + 1);
+        let (rest, line) = input.take_split(line_end);
+        if !line.trim().is_empty() {
+            in_section = false;
+        }
+        input = rest;
+    }
+    if let Some((header, _)) = function {
+        return Err(error(
+            format!("`{}` is missing its `endfunc`", header.name),
+            header.span,
+        ));
+    }
+    if let Some((name, span)) = declared
+        .into_iter()
+        .find(|(name, _)| data.labels.contains_key(name))
+    {
+        return Err(error(
+            format!("`{name}` is already a data label, so it can't name a variable"),
+            span,
+        ));
+    }
+    Ok(data)
+}
+
+/// Parse a directive that sets the size of part of the program's memory:
+///   .stack <size>
+///   .heap <size>
+///   .call_stack <size>
+///   .tape <size>
+fn parse_layout_directive(input: Input<'_>) -> Res<'_, (&'_ str, usize)> {
+    let (input, _) = space0(input)?;
+    let (input, _) = char('.')(input)?;
+    let (input, name) = cut(context(
+        "directive",
+        alt((
+            keyword("stack"),
+            keyword("heap"),
+            keyword("call_stack"),
+            keyword("tape"),
+        )),
+    ))(input)?;
+    let (input, size) = match *name.fragment() {
+        // The stack pointer starts on a cell of the stack, so it can't be empty
+        "stack" => cut(context(
+            "a stack size of at least 1",
+            verify(parse_immediate_literal, |size| *size > 0),
+        ))(input)?,
+        _ => cut(context("size", parse_immediate_literal))(input)?,
+    };
+    let (input, _) = cut(parse_end_of_lines)(input)?;
+    Ok((input, (name.fragment(), size as usize)))
+}
+
+/// A line at the top level of a program.
+enum Line<'a> {
+    Op(Op, Option<SourceLocation>),
+    /// The ops of a function.
+    Function(Vec<(Op, Option<SourceLocation>)>),
+    Directive(&'a str, usize),
+    /// Part of the data section or a variable declaration, which was already collected.
+    Data,
+}
+
 /// Parse a full program – a list of instructions separated by optional whitespace.
-fn parse_program(input: &str) -> Res<Program> {
+fn parse_program(input: Input) -> Res<Program> {
     let (input, _) = multispace0(input)?;
     // let (input, result) = map(separated_list0(multispace0, parse_op), Program)(input)?;
-    let (input, mut result) = map(many0(parse_op), Program)(input)?;
-    result.push(Op::Quit(next_basic_block_number()));
+    let (input, lines) = many0(alt((
+        map(parse_data_marker, |_| Line::Data),
+        map(parse_data_declaration, |_| Line::Data),
+        map(parse_variable_declaration, |_| Line::Data),
+        map(parse_layout_directive, |(name, size)| {
+            Line::Directive(name, size)
+        }),
+        map(parse_function, Line::Function),
+        map(parse_op, |(op, source)| Line::Op(op, source)),
+    )))(input)?;
+    let mut result = Program::default();
+    let mut layout = Layout::default();
+    for line in lines {
+        match line {
+            Line::Op(op, source) => result.push_from_source(op, source),
+            Line::Function(ops) => {
+                for (op, source) in ops {
+                    result.push_from_source(op, source);
+                }
+            }
+            Line::Directive("stack", size) => layout.stack_size = size,
+            Line::Directive("heap", size) => layout.heap_size = size,
+            Line::Directive("call_stack", size) => layout.call_stack_size = size,
+            Line::Directive(_, size) => layout.tape_size = size,
+            Line::Data => {}
+        }
+    }
+    result.set_layout(layout);
+    result.push(Op::Quit);
     let (input, _) = multispace0(input)?;
 
     Ok((input, result))
 }
 
-fn strip_comments(input: &str) -> String {
+/// Replace comments with whitespace.
+///
+/// Newlines are kept and every other character of a comment becomes a space,
+/// so positions in the stripped source match positions in the original.
+pub(super) fn strip_comments(input: &str) -> String {
+    let blank = |c: char| if c == '\n' { '\n' } else { ' ' };
+
     let mut output = String::new();
     let mut input_chars = input.chars().peekable();
     while let Some(c) = input_chars.next() {
         if c == '/' {
             if let Some('/') = input_chars.peek() {
                 // Skip the rest of the line
-                while let Some(c) = input_chars.next() {
+                output.push(' ');
+                for c in input_chars.by_ref() {
+                    output.push(blank(c));
                     if c == '\n' {
-                        output.push(c);
                         break;
                     }
                 }
             } else if let Some('*') = input_chars.peek() {
                 // Skip the block comment
+                output.push(' ');
                 while let Some(c) = input_chars.next() {
+                    output.push(blank(c));
                     if c == '*' {
                         if let Some('/') = input_chars.peek() {
                             input_chars.next();
+                            output.push(' ');
                             break;
                         }
                     }
@@ -674,12 +1639,13 @@ fn strip_comments(input: &str) -> String {
                 output.push(c);
             }
         } else if c == ';' {
+            output.push(' ');
             // Skip the rest of the line
             if let Some(';') = input_chars.peek() {
                 // Skip the rest of the line
-                while let Some(c) = input_chars.next() {
+                for c in input_chars.by_ref() {
+                    output.push(blank(c));
                     if c == '\n' {
-                        output.push(c);
                         break;
                     }
                 }
@@ -691,27 +1657,200 @@ fn strip_comments(input: &str) -> String {
     output
 }
 
-pub fn parse(input: &str) -> Result<Program, String> {
-    match parse_program(&(strip_comments(input) + "\n")) {
+/// The text of the token starting at `input`, used to underline errors.
+fn token_at(input: Input<'_>) -> &'_ str {
+    let text = *input.fragment();
+    let is_ident = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let len = if text.starts_with(is_ident) {
+        text.find(|c: char| !is_ident(c)).unwrap_or(text.len())
+    } else {
+        text.find(char::is_whitespace).unwrap_or(text.len())
+    };
+    &text[..len]
+}
+
+/// Skip spaces and tabs, so that errors point at the offending token.
+fn skip_spaces(input: Input) -> Input {
+    space0::<_, Error>(input).map(|(input, _)| input).unwrap_or(input)
+}
+
+/// Turn a nom error into an `AsmError` pointing at the offending token.
+fn convert_error(source: &str, error: Error) -> AsmError {
+    let Some(&(innermost, ref kind)) = error.errors.first() else {
+        let location = ErrorLocation::new(source, Span::default());
+        return AsmError::Syntax {
+            message: "invalid syntax".to_string(),
+            location,
+        };
+    };
+    let input = skip_spaces(innermost);
+    let token = token_at(input);
+    let location = ErrorLocation::new(source, Span::new(input, token.len()));
+
+    let is_at_error = |(other, _): &(Input, VerboseErrorKind)| {
+        skip_spaces(*other).location_offset() == input.location_offset()
+    };
+    let failed_register = error
+        .errors
+        .iter()
+        .filter(|e| is_at_error(e))
+        .any(|(_, kind)| matches!(kind, VerboseErrorKind::Context("register")));
+    if failed_register && WideLocation::register(token).is_some() {
+        return AsmError::Syntax {
+            message: format!(
+                "`{token}` is a wide register, but this instruction only takes narrow registers"
+            ),
+            location,
+        };
+    }
+    if failed_register && token.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        return AsmError::UnknownRegister {
+            name: token.to_string(),
+            location,
+        };
+    }
+
+    let expected = error.errors.iter().find_map(|(_, kind)| match kind {
+        VerboseErrorKind::Context(context) => Some(context.to_string()),
+        _ => None,
+    });
+    let message = match (kind, expected) {
+        (VerboseErrorKind::Char(c), _) => format!("expected {c:?}"),
+        (_, Some(expected)) => format!("expected {expected}"),
+        _ if token.is_empty() => "unexpected end of line".to_string(),
+        _ => format!("unexpected `{token}`"),
+    };
+    AsmError::Syntax { message, location }
+}
+
+/// Check that `if` and `while` blocks aren't nested deeper than there are
+/// flags for them.
+fn check_blocks(source: &str, program: &Program) -> Result<(), AsmError> {
+    fn depth(ops: &[BasicBlockOp]) -> usize {
+        ops.iter()
+            .map(|op| match op {
+                BasicBlockOp::If {
+                    then, otherwise, ..
+                } => 1 + depth(then).max(depth(otherwise)),
+                BasicBlockOp::While { body, .. } => 1 + depth(body),
+                _ => 0,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    for op in program.ops() {
+        let (Op::BasicBlock(bb) | Op::Label(_, bb)) = op else {
+            continue;
+        };
+        for (i, op) in bb.ops().iter().enumerate() {
+            if depth(std::slice::from_ref(op)) > BLOCK_TEMPS.len() {
+                let span = bb.source(i).map(|source| source.span).unwrap_or_default();
+                return Err(AsmError::Syntax {
+                    message: format!("blocks can only be nested {} deep", BLOCK_TEMPS.len()),
+                    location: ErrorLocation::new(source, span),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that every label is defined once, and that every jump and call
+/// targets one of them.
+fn check_labels(source: &str, program: &Program) -> Result<(), AsmError> {
+    let mut defined: HashMap<&Symbol, Span> = HashMap::new();
+    for op in program.ops() {
+        let Op::Label(label, bb) = op else {
+            continue;
+        };
+        let span = bb.label_span().unwrap_or_default();
+        if let Some(previous) = defined.insert(label, span) {
+            return Err(AsmError::DuplicateLabel {
+                label: label.clone(),
+                location: ErrorLocation::new(source, span),
+                previous: Box::new(ErrorLocation::new(source, previous)),
+            });
+        }
+    }
+
+    for op in program.ops() {
+        match op {
+            Op::Jmp(label, span) | Op::Call(label, span) | Op::JmpIf(_, label, span)
+                if !defined.contains_key(label) =>
+            {
+                return Err(AsmError::UndefinedLabel {
+                    label: label.clone(),
+                    location: ErrorLocation::new(source, *span),
+                });
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Parse a program, reading any files it includes with the default [`Loader`].
+pub fn parse(input: &str) -> Result<Program, AsmError> {
+    Loader::new().parse(input, None)
+}
+
+/// Parse a program whose includes have been resolved, given the source
+/// and the same source with its comments stripped.
+pub(super) fn parse_source(input: &str, stripped: &str) -> Result<Program, AsmError> {
+    let expansion = macros::expand(input, stripped)?;
+    let source = expansion.source().to_string() + "\n";
+    let result =
+        collect_declarations(&source).and_then(|data| parse_declared_program(&source, &data));
+    // Point everything back at the source as it was written
+    let mut program = result.map_err(|e| expansion.relocate_error(input, e))?;
+    expansion.relocate_program(&mut program);
+    check_labels(input, &program)?;
+    check_blocks(input, &program)?;
+    Ok(program)
+}
+
+/// Parse a program whose data section has been collected.
+fn parse_declared_program(source: &str, data: &Declarations) -> Result<Program, AsmError> {
+    let mut program = match parse_program(Input::new_extra(source, data)) {
         Ok((rest, program)) => {
-            if rest.is_empty() {
+            if rest.fragment().is_empty() {
                 Ok(program)
             } else {
-                Err(format!(
-                    "Failed to parse the entire input. Remaining: {}",
-                    rest
+                Err(convert_error(
+                    source,
+                    VerboseError::from_error_kind(rest, nom::error::ErrorKind::Eof),
                 ))
             }
         }
-        Err(e) => {
-            println!("Error: {:#?}", e);
-            match e {
-                nom::Err::Error(e) => Err(format!("Error: {}", convert_error(input, e))),
-                nom::Err::Failure(e) => Err(format!("Failure: {}", convert_error(input, e))),
-                nom::Err::Incomplete(_) => Err("Incomplete input".into()),
-            }
-        }
+        Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => Err(convert_error(source, e)),
+        Err(nom::Err::Incomplete(_)) => Err(convert_error(
+            source,
+            VerboseError::from_error_kind(Input::new_extra("", data), nom::error::ErrorKind::Eof),
+        )),
+    }?;
+    // Variables that don't fit in registers are spilled to the stack, above the data
+    let spilled = program.allocate_variables(data.variables.len(), data.cells.len());
+    let cells = data.cells.len() + spilled;
+    program.set_data([data.cells.as_slice(), &vec![0; spilled]].concat());
+
+    // The stack pointer starts at the last cell of data, and has to fit in the stack
+    let stack_size = program.layout().stack_size;
+    if cells >= stack_size {
+        let needs = match (data.cells.len(), spilled) {
+            (_, 0) => "the data section needs".to_string(),
+            (0, _) => format!("{spilled} spilled variables need"),
+            _ => format!("the data section and {spilled} spilled variables need"),
+        };
+        return Err(AsmError::Syntax {
+            message: format!(
+                "{needs} {} cells, but the stack only has {stack_size}",
+                cells + 1,
+            ),
+            location: ErrorLocation::new(source, data.last),
+        });
     }
+    Ok(program)
 }
 
 #[cfg(test)]
@@ -719,14 +1858,332 @@ mod tests {
     use super::*;
     #[test]
     fn test_parse_string() {
-        let (rest, s) = parse_string("\"Hello, world!\"").unwrap();
+        let data = Declarations::default();
+        let (rest, s) = parse_string(Input::new_extra("\"Hello, world!\"", &data)).unwrap();
         println!("rest: {}", rest);
         assert_eq!(s, "Hello, world!");
     }
     #[test]
     fn test_log_string() {
-        let (rest, s) = parse_log("log \"Hello, world!\"").unwrap();
+        let data = Declarations::default();
+        let (rest, s) = parse_log(Input::new_extra("log \"Hello, world!\"", &data)).unwrap();
         println!("rest: {}", rest);
         println!("s: {:?}", s);
     }
+
+    #[test]
+    fn test_syntax_error_location() {
+        let source = "main:\n    R0 = 5\n    putchar R0 R1\n";
+        let error = parse(source).unwrap_err();
+        println!("{error}");
+        assert!(matches!(error, AsmError::Syntax { .. }));
+        let span = error.span();
+        assert_eq!((span.line, span.column, span.len), (3, 16, 2));
+        assert_eq!(error.location().snippet, "    putchar R0 R1");
+    }
+
+    #[test]
+    fn test_unknown_register() {
+        for (source, column) in [
+            ("putint R99", 8),
+            ("R0 add R1, R42", 12),
+            ("[R77] = 1", 2),
+            ("X = 1", 1),
+            ("  pop   Q", 9),
+        ] {
+            let error = parse(source).unwrap_err();
+            println!("{error}");
+            let AsmError::UnknownRegister { name, location } = &error else {
+                panic!("Expected an unknown register error, got {error:?}");
+            };
+            assert_eq!(location.span.line, 1);
+            assert_eq!(location.span.column, column);
+            assert_eq!(location.span.len, name.len());
+        }
+    }
+
+    #[test]
+    fn test_wide_register_in_narrow_operand() {
+        for (source, column) in [
+            ("R0 = W0", 6),
+            ("getchar W1", 9),
+            ("R0 add R1, W2", 12),
+            ("push W3", 6),
+        ] {
+            let error = parse(source).unwrap_err();
+            assert_eq!(
+                error.message(),
+                format!(
+                    "`{}` is a wide register, but this instruction only takes narrow registers",
+                    &source[column - 1..column + 1]
+                ),
+                "{source}"
+            );
+            assert_eq!((error.span().column, error.span().len), (column, 2));
+        }
+    }
+
+    #[test]
+    fn test_undefined_label() {
+        let source = "main:\n    // Jump somewhere\n    jmp_if R0, nowhere\n    jmp main\n";
+        let error = parse(source).unwrap_err();
+        println!("{error}");
+        assert_eq!(
+            error,
+            AsmError::UndefinedLabel {
+                label: "nowhere".into(),
+                location: ErrorLocation {
+                    file: None,
+                    span: Span {
+                        offset: 43,
+                        line: 3,
+                        column: 16,
+                        len: 7
+                    },
+                    snippet: "    jmp_if R0, nowhere".to_string(),
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn test_duplicate_label() {
+        let source = "main:\n    inc R0\nloop:\n    dec R0\n  loop:\n    quit\n";
+        let error = parse(source).unwrap_err();
+        println!("{error}");
+        let AsmError::DuplicateLabel {
+            label,
+            location,
+            previous,
+        } = &error
+        else {
+            panic!("expected a duplicate label, found {error:?}");
+        };
+        assert_eq!(label.as_str(), "loop");
+        assert_eq!((location.span.line, location.span.column), (5, 3));
+        assert_eq!(location.snippet, "  loop:");
+        assert_eq!((previous.span.line, previous.span.column), (3, 1));
+        assert!(error.to_string().contains("already defined at <input>:3:1"));
+    }
+
+    #[test]
+    fn test_error_with_file() {
+        let error = parse("main:\n    jmp end\n").unwrap_err().with_file("main.basm");
+        assert_eq!(error.location().file.as_deref(), Some("main.basm"));
+        assert!(error.to_string().contains("--> main.basm:2:9"));
+    }
+
+    #[test]
+    fn test_keywords_are_not_label_prefixes() {
+        let source = "main:\n    jmp increment\nincrement:\n    inc R0\n    quit\n";
+        assert!(parse(source).is_ok());
+    }
+
+    #[test]
+    fn test_data_section() {
+        let source = r#"
+        main:
+            R0 lea msg
+        loop:
+            jmp_if [R0], print
+            jmp done
+        print:
+            putchar [R0]
+            inc R0
+            jmp loop
+        done:
+            putchar [table + 3]
+            [buf + 1] = 7
+            R1 lea buf + 1
+            putint [R1]
+            R1 lea [table]
+            putint R1
+            push 9
+            putint SP
+
+        .data
+        msg: .string "hi"
+
+        table: .bytes 1, 2, 3, 'x'
+        buf: .zero 4
+        "#;
+        let program = parse(source).unwrap();
+        assert_eq!(program.data(), [104, 105, 0, 1, 2, 3, 120, 0, 0, 0, 0]);
+        let output = interpret_with_input(program.assemble(), "", 1).unwrap();
+        assert_eq!(output, "hix7412");
+    }
+
+    #[test]
+    fn test_data_errors() {
+        let error = parse("msg: .string \"hi\"\n").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("must be declared in a `.data` section"));
+
+        // The section ends at the first instruction
+        let error = parse(".data\na: .zero 1\nputint 1\nb: .zero 1\n").unwrap_err();
+        assert_eq!(error.span().line, 4);
+
+        let error = parse(".data\na: .zero 1\na: .bytes 2\n").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("data label `a` is already defined"));
+
+        let error = parse(".stack 4\n.data\na: .zero 4\n").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("the data section needs 5 cells, but the stack only has 4"));
+
+        // An empty stack is wrong before there's any data to blame it on
+        let error = parse("main:\n.stack 0\n    putint 1\n").unwrap_err();
+        assert_eq!(error.message(), "expected a stack size of at least 1");
+        assert_eq!((error.span().line, error.span().column), (2, 8));
+        let error = parse(".stack 0\n.data\na: .zero 1\n").unwrap_err();
+        assert_eq!(error.message(), "expected a stack size of at least 1");
+        assert!(parse(".stack 1\nputint 1\n").is_ok());
+        let mut source = ".stack 2\nvar a, b\n".to_string();
+        for i in 0..16 {
+            source += &format!("R{i} = {i}\n");
+        }
+        let error = parse(&(source + "a = 1\nb = 2\nputint a\nputint b\n")).unwrap_err();
+        assert_eq!(
+            error.message(),
+            "2 spilled variables need 3 cells, but the stack only has 2"
+        );
+        assert_eq!(error.span().line, 2);
+
+        let error = parse(".data\na: .words 1\n").unwrap_err();
+        assert!(matches!(error, AsmError::Syntax { .. }));
+    }
+
+    #[test]
+    fn test_function_errors() {
+        let error = parse("func f(a)\n    putint a\n").unwrap_err();
+        assert!(error.to_string().contains("`f` is missing its `endfunc`"));
+        assert_eq!(error.span().column, 6);
+
+        let error = parse("func f()\nfunc g()\nendfunc\nendfunc\n").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("`g` can't be declared inside of `f`"));
+
+        let error = parse("putint R0\nendfunc\n").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("`endfunc` doesn't end a function"));
+
+        let error = parse("func f(a, R0)\nendfunc\n").unwrap_err();
+        assert!(error.to_string().contains("`R0` is a register"));
+        assert_eq!(error.span().column, 11);
+
+        let error = parse("func f(a) locals b, a\nendfunc\n").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("`a` is already an argument or local of `f`"));
+
+        // Arguments can only be used inside of their function
+        let error = parse("func f(a)\n    putint a\nendfunc\nputint a\n").unwrap_err();
+        assert!(matches!(error, AsmError::UnknownRegister { .. }));
+        assert_eq!(error.span().line, 4);
+
+        let error = parse("func f(a)\n    putint a\n    oops\nendfunc\n").unwrap_err();
+        assert_eq!(error.span().line, 3);
+    }
+
+    #[test]
+    fn test_variable_errors() {
+        let error = parse("var a, R1\n").unwrap_err();
+        assert!(error.to_string().contains("`R1` is a register"));
+        assert_eq!(error.span().column, 8);
+
+        let error = parse("var a\nvar b, a\n").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("variable `a` is already declared"));
+        assert_eq!(error.span().line, 2);
+
+        let error = parse(".data\nmsg: .string \"hi\"\nvar msg\n").unwrap_err();
+        assert!(error.to_string().contains("`msg` is already a data label"));
+
+        let error = parse("func f()\n    var x\nendfunc\n").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("variables can't be declared inside of `f`"));
+
+        let error = parse("var\n").unwrap_err();
+        assert!(matches!(error, AsmError::Syntax { .. }));
+
+        // Variables can be used before they're declared, and labels can share their names
+        let program = parse("var:\n    count = 1\n    putint [count]\nvar count\n").unwrap();
+        assert_eq!(program.ops().len(), 2);
+
+        // Arguments and locals hide variables with the same name
+        let program = parse("var a\nfunc f(a)\n    a = 1\nendfunc\n").unwrap();
+        let Op::Label(_, entry) = &program.ops()[0] else {
+            panic!("Expected the function to start with a label");
+        };
+        assert!(matches!(
+            entry.ops()[1],
+            BasicBlockOp::Set {
+                dest: DynamicLocation::Frame(-1),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_function_frames() {
+        let program = parse("func f(a, b) locals x\n    x = b\n    ret a\nendfunc\n").unwrap();
+        let Op::Label(name, entry) = &program.ops()[0] else {
+            panic!("Expected the function to start with a label");
+        };
+        assert_eq!(name.as_str(), "f");
+        assert!(matches!(entry.ops()[0], BasicBlockOp::Enter { locals: 1 }));
+        assert!(matches!(
+            entry.ops()[1],
+            BasicBlockOp::Set {
+                src: Operand::Location(DynamicLocation::Frame(-1)),
+                dest: DynamicLocation::Frame(1),
+            }
+        ));
+        assert!(matches!(
+            &program.ops()[1],
+            Op::BasicBlock(leave) if matches!(
+                leave.ops()[0],
+                BasicBlockOp::Leave {
+                    args: 2,
+                    result: Some(Operand::Location(DynamicLocation::Frame(-2))),
+                }
+            )
+        ));
+        assert!(matches!(program.ops()[2], Op::Return));
+    }
+
+    #[test]
+    fn test_block_errors() {
+        let error = parse("if R0\n    putint R0\n").unwrap_err();
+        assert!(error.to_string().contains("expected `end`"));
+
+        // Jumps can't leave a block
+        let error = parse("while R0\n    jmp main\nend\nmain:\n").unwrap_err();
+        assert!(error.to_string().contains("expected `end`"));
+        assert_eq!(error.span().line, 2);
+
+        let error = parse("putint R0\nend\n").unwrap_err();
+        assert_eq!(error.span().line, 2);
+
+        let error = parse("if 1\nend\n").unwrap_err();
+        assert!(matches!(error, AsmError::Syntax { .. }));
+
+        // Labels can still have the names of the keywords
+        assert!(parse("putint R0\nwhile:\n    putint R0\n    jmp while\n").is_ok());
+
+        let nested = |depth: usize| "while R0\n".repeat(depth) + &"end\n".repeat(depth);
+        assert!(parse(&nested(8)).is_ok());
+        let error = parse(&format!("putint R0\n{}", nested(9))).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("blocks can only be nested 8 deep"));
+        assert_eq!(error.span().line, 2);
+    }
 }
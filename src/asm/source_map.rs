@@ -0,0 +1,244 @@
+use super::*;
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::ops::Range;
+
+/// Marks the start of the code for an instruction while assembling, followed by its id.
+const MARK_START: char = '\u{1}';
+/// Separates the id of an instruction from its code.
+const MARK_ID_END: char = '\u{2}';
+/// Marks the end of the code for an instruction.
+const MARK_END: char = '\u{3}';
+
+/// An instruction in the assembly source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    /// Where the instruction was written.
+    pub span: Span,
+    /// The text of the instruction.
+    pub instruction: String,
+}
+
+/// The BrainFuck code generated for a single instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceMapping {
+    /// The byte offsets of the generated code in the BrainFuck text.
+    pub bf: Range<usize>,
+    /// The instruction the code was generated for.
+    pub location: SourceLocation,
+}
+
+/// Maps ranges of generated BrainFuck back to the assembly instructions that produced them.
+///
+/// Code that isn't part of any instruction, like the dispatcher between basic blocks,
+/// has no mapping.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    /// The file the source was read from, if any.
+    pub file: Option<String>,
+    /// The mappings, sorted by the start of their BrainFuck range.
+    mappings: Vec<SourceMapping>,
+}
+
+impl SourceMap {
+    /// Attach the name of the file the source was read from.
+    pub fn with_file(mut self, file: impl Into<String>) -> Self {
+        self.file = Some(file.into());
+        self
+    }
+
+    /// All of the mappings, sorted by the start of their BrainFuck range.
+    pub fn mappings(&self) -> &[SourceMapping] {
+        &self.mappings
+    }
+
+    /// Find the innermost instruction whose code contains the given BrainFuck offset.
+    pub fn lookup(&self, offset: usize) -> Option<&SourceMapping> {
+        self.lookup_index(offset).map(|i| &self.mappings[i])
+    }
+
+    /// Like [`SourceMap::lookup`], but return the index of the mapping.
+    pub(super) fn lookup_index(&self, offset: usize) -> Option<usize> {
+        let end = self.mappings.partition_point(|m| m.bf.start <= offset);
+        (0..end).rev().find(|&i| self.mappings[i].bf.contains(&offset))
+    }
+
+    /// Wrap the code generated for an instruction in markers, so its position
+    /// can be found after the rest of the program is assembled around it.
+    pub(super) fn mark(id: usize, code: String) -> String {
+        format!("{MARK_START}{id}{MARK_ID_END}{code}{MARK_END}")
+    }
+
+    /// Remove the markers from assembled code, and build a source map from their positions.
+    /// The ids in the markers are indices into `locations`.
+    pub(super) fn extract(marked: &str, locations: Vec<SourceLocation>) -> (String, Self) {
+        let mut bf = String::with_capacity(marked.len());
+        let mut open = vec![];
+        let mut ranges = vec![];
+        let mut chars = marked.chars();
+        while let Some(ch) = chars.next() {
+            match ch {
+                MARK_START => {
+                    let id: String = chars.by_ref().take_while(|&ch| ch != MARK_ID_END).collect();
+                    open.push((id.parse::<usize>().expect("Invalid source marker"), bf.len()));
+                }
+                MARK_END => {
+                    let (id, start) = open.pop().expect("Unbalanced source marker");
+                    ranges.push((id, start..bf.len()));
+                }
+                _ => bf.push(ch),
+            }
+        }
+
+        let mut mappings: Vec<SourceMapping> = ranges
+            .into_iter()
+            .map(|(id, bf)| SourceMapping {
+                bf,
+                location: locations[id].clone(),
+            })
+            .collect();
+        // Outer instructions come before the instructions nested inside them
+        mappings.sort_by_key(|m| (m.bf.start, std::cmp::Reverse(m.bf.end)));
+        (
+            bf,
+            Self {
+                file: None,
+                mappings,
+            },
+        )
+    }
+}
+
+impl Display for SourceMap {
+    /// Write one mapping per line:
+    ///   <start>..<end> <file>:<line>:<column> <instruction>
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let file = self.file.as_deref().unwrap_or("<input>");
+        for mapping in &self.mappings {
+            let span = mapping.location.span;
+            writeln!(
+                f,
+                "{}..{} {file}:{}:{} {}",
+                mapping.bf.start, mapping.bf.end, span.line, span.column, mapping.location.instruction
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Simplify BrainFuck code like [`simplify_bf`], and update its source map to match.
+///
+/// Runs of operations are not merged across the boundaries of instructions, so the
+/// result may be slightly longer than what [`simplify_bf`] would produce.
+pub fn simplify_bf_with_source_map(
+    bf: String,
+    source_map: &SourceMap,
+) -> Result<(String, SourceMap), BfError> {
+    // Split the code at every boundary of an instruction, and simplify each piece on its own
+    let mut boundaries: BTreeMap<usize, usize> = source_map
+        .mappings
+        .iter()
+        .flat_map(|m| [m.bf.start, m.bf.end])
+        .chain([0, bf.len()])
+        .map(|offset| (offset, 0))
+        .collect();
+
+    let mut result = String::new();
+    let mut last = 0;
+    for (offset, new_offset) in boundaries.iter_mut() {
+        if *offset > last {
+            result.push_str(&crate::bf::simplify(&bf[last..*offset])?);
+        }
+        *new_offset = result.len();
+        last = *offset;
+    }
+
+    let mappings = source_map
+        .mappings
+        .iter()
+        .map(|m| SourceMapping {
+            bf: boundaries[&m.bf.start]..boundaries[&m.bf.end],
+            location: m.location.clone(),
+        })
+        .collect();
+    Ok((
+        result,
+        SourceMap {
+            file: source_map.file.clone(),
+            mappings,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "main:\n    R0 = 2\nloop:\n    putint R0\n    dec R0\n    jmp_if R0, loop\n";
+
+    fn assemble(source: &str) -> (String, SourceMap) {
+        let program = parse(source).unwrap();
+        Assembler::new().assemble_with_source_map(&program).unwrap()
+    }
+
+    #[test]
+    fn test_source_map_instructions() {
+        let (bf, source_map) = assemble(SOURCE);
+        assert_eq!(bf, parse(SOURCE).unwrap().assemble());
+
+        let instructions: Vec<_> = source_map
+            .mappings()
+            .iter()
+            .map(|m| (m.location.span.line, m.location.instruction.as_str()))
+            .collect();
+        assert_eq!(
+            instructions,
+            [
+                (2, "R0 = 2"),
+                (4, "putint R0"),
+                (5, "dec R0"),
+                (6, "jmp_if R0, loop")
+            ]
+        );
+
+        // The code for `putint` prints, and the code for `dec` decrements
+        let putint = &source_map.mappings()[1];
+        assert!(bf[putint.bf.clone()].contains('.'));
+        let dec = &source_map.mappings()[2];
+        assert!(!bf[dec.bf.clone()].contains('.'));
+        assert_eq!(source_map.lookup(dec.bf.start), Some(dec));
+        assert_eq!(source_map.lookup(0), None);
+    }
+
+    #[test]
+    fn test_source_map_through_simplify() {
+        let (bf, source_map) = assemble(SOURCE);
+        let (simplified, simplified_map) =
+            simplify_bf_with_source_map(bf.clone(), &source_map).unwrap();
+        assert!(simplified.len() < bf.len());
+        assert_eq!(simplified_map.mappings().len(), source_map.mappings().len());
+
+        for (before, after) in source_map.mappings().iter().zip(simplified_map.mappings()) {
+            assert_eq!(before.location, after.location);
+            // Each instruction still has the same code, apart from comments and merged runs
+            assert_eq!(
+                crate::bf::simplify(&bf[before.bf.clone()]).unwrap(),
+                &simplified[after.bf.clone()]
+            );
+        }
+        assert_eq!(
+            interpret_with_input(simplified, "", 1).unwrap(),
+            interpret_with_input(bf, "", 1).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_source_map_display() {
+        let (_, source_map) = assemble(SOURCE);
+        let text = source_map.with_file("loop.basm").to_string();
+        let first = text.lines().next().unwrap();
+        assert!(first.ends_with(" loop.basm:2:5 R0 = 2"), "{first}");
+        assert_eq!(text.lines().count(), 4);
+    }
+}
@@ -0,0 +1,158 @@
+/// The files of the standard library, by the name they're included with.
+///
+/// Every [`Loader`](super::Loader) can include them, so a program can use the
+/// whole library with `include "std.basm"`, or just a part of it with
+/// `include "std/string.basm"`.
+pub const STDLIB: &[(&str, &str)] = &[
+    ("std.basm", include_str!("./stdlib/std.basm")),
+    ("std/string.basm", include_str!("./stdlib/string.basm")),
+    ("std/memory.basm", include_str!("./stdlib/memory.basm")),
+    ("std/math.basm", include_str!("./stdlib/math.basm")),
+    ("std/io.basm", include_str!("./stdlib/io.basm")),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+
+    /// Run a program that uses the standard library, with the given input.
+    fn run(source: &str, input: &str) -> String {
+        let program = parse(&format!("include \"std.basm\"\n{source}")).unwrap();
+        interpret_with_input(program.assemble(), input, 1).unwrap()
+    }
+
+    #[test]
+    fn test_every_file_parses_on_its_own() {
+        for (name, _) in STDLIB {
+            let program = parse(&format!("include \"{name}\"")).unwrap();
+            assert!(program.try_assemble().is_ok(), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_strings() {
+        let source = r#"
+        main:
+            R0 = 7
+            push 'a'
+            push 'b'
+            push 'c'
+            push 0
+            push 'a'
+            push 'b'
+            push 0
+            R1 lea [SP] - 6
+            R2 lea [SP] - 2
+
+            push R1
+            call puts
+            push R1
+            call strlen
+            pop R3
+            putint R3
+
+            push R1
+            push R2
+            call strcmp
+            pop R3
+            putint R3
+            push R2
+            push R1
+            call strcmp
+            pop R3
+            R3 eq R3, 255
+            putint R3
+            push R1
+            push R1
+            call strcmp
+            pop R3
+            putint R3
+
+            ;; Registers are left alone
+            putint R0
+        "#;
+        assert_eq!(run(source, ""), "abc31107");
+    }
+
+    #[test]
+    fn test_memory() {
+        let source = r#"
+        main:
+            push 1
+            push 2
+            push 3
+            push 0
+            push 0
+            push 0
+            R0 lea [SP] - 5
+            R1 lea [SP] - 2
+
+            push R1
+            push R0
+            push 3
+            call memcpy
+            push R0
+            push 9
+            push 2
+            call memset
+
+            putint [SP]
+            pop
+            putint [SP]
+            pop
+            putint [SP]
+            pop
+            putint [SP]
+            pop
+            putint [SP]
+        "#;
+        assert_eq!(run(source, ""), "32139");
+    }
+
+    #[test]
+    fn test_math() {
+        let source = r#"
+        main:
+            push 3
+            push 7
+            call min
+            pop R0
+            putint R0
+            push 7
+            push 3
+            call max
+            pop R0
+            putint R0
+            push 5
+            call abs
+            pop R0
+            putint R0
+            R0 = 0
+            R0 sub 5
+            push R0
+            call abs
+            pop R0
+            putint R0
+        "#;
+        assert_eq!(run(source, ""), "3755");
+    }
+
+    #[test]
+    fn test_io() {
+        let source = r#"
+        main:
+            call read_int
+            call print_signed
+            putchar ' '
+            call read_int
+            call print_signed
+            putchar ' '
+            call read_int
+            R0 = [SP]
+            R0 add 1
+            push R0
+            call print_signed
+        "#;
+        assert_eq!(run(source, "  42\n-17 0"), "42 -17 1");
+    }
+}
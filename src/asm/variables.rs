@@ -0,0 +1,426 @@
+use super::*;
+
+use std::collections::{BTreeSet, HashMap};
+
+/// Variables are parsed as cells far past the end of any tape, and are given
+/// real cells once the whole program has been parsed.
+const FIRST_VARIABLE: usize = usize::MAX / 2;
+
+/// The cell that the `index`th variable declared is parsed as.
+pub(super) const fn variable(index: usize) -> StaticLocation {
+    StaticLocation::Address(FIRST_VARIABLE + index)
+}
+
+/// The variable that a cell was parsed from, if it isn't a real cell.
+fn variable_index(cell: StaticLocation) -> Option<usize> {
+    cell.address().checked_sub(FIRST_VARIABLE)
+}
+
+/// Whether an instruction reads a cell or overwrites it.
+///
+/// Instructions that change a cell based on its old value, like `inc`, read it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Read,
+    Write,
+}
+
+fn visit_operand(op: &mut Operand, f: &mut dyn FnMut(&mut DynamicLocation, Access)) {
+    if let Operand::Location(loc) = op {
+        f(loc, Access::Read)
+    }
+}
+
+fn visit_wide_operand(op: &mut WideOperand, f: &mut dyn FnMut(&mut DynamicLocation, Access)) {
+    if let WideOperand::Narrow(loc) = op {
+        f(loc, Access::Read)
+    }
+}
+
+impl BasicBlockOp {
+    /// Call `f` with each location the op uses, visiting the ones it reads
+    /// before the one it writes.
+    fn visit_locations(&mut self, f: &mut dyn FnMut(&mut DynamicLocation, Access)) {
+        use Access::*;
+        use BasicBlockOp::*;
+        match self {
+            Push(src) | PutChar(src) | PutInt(src) | PutSInt(src) | Free(src) => {
+                visit_operand(src, f)
+            }
+            Pop(dest) | GetChar(dest) => {
+                if let Some(dest) = dest {
+                    f(dest, Write)
+                }
+            }
+            GetInt(dest) => f(dest, Write),
+            Log(_, locations) => {
+                for loc in locations {
+                    f(loc, Read)
+                }
+            }
+            Set { src, dest }
+            | Neg { src, dest }
+            | Not { src, dest }
+            | Alloc { size: src, dest } => {
+                visit_operand(src, f);
+                f(dest, Write)
+            }
+            GetAddr {
+                src, dest, offset, ..
+            } => {
+                f(src, Read);
+                if let Some(offset) = offset {
+                    visit_operand(offset, f)
+                }
+                f(dest, Write)
+            }
+            Add { lhs, rhs, dest }
+            | Sub { lhs, rhs, dest }
+            | Mul { lhs, rhs, dest }
+            | Div { lhs, rhs, dest }
+            | IMul { lhs, rhs, dest }
+            | IDiv { lhs, rhs, dest }
+            | Eq { lhs, rhs, dest }
+            | Ne { lhs, rhs, dest }
+            | Mod { lhs, rhs, dest }
+            | Lt { lhs, rhs, dest }
+            | ILt { lhs, rhs, dest }
+            | Le { lhs, rhs, dest }
+            | Gt { lhs, rhs, dest }
+            | Ge { lhs, rhs, dest }
+            | And { lhs, rhs, dest }
+            | Or { lhs, rhs, dest }
+            | Xor { lhs, rhs, dest }
+            | Shl { lhs, rhs, dest }
+            | Shr { lhs, rhs, dest } => {
+                visit_operand(lhs, f);
+                visit_operand(rhs, f);
+                f(dest, Write)
+            }
+            WideSet { src, .. } => visit_wide_operand(src, f),
+            WideAdd { lhs, rhs, .. } | WideSub { lhs, rhs, .. } | WideMul { lhs, rhs, .. } => {
+                visit_wide_operand(lhs, f);
+                visit_wide_operand(rhs, f)
+            }
+            Inc(loc, _) | Dec(loc, _) => f(loc, Read),
+            // The ops in a block might not run, so they can't be relied on to overwrite anything
+            If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                f(cond, Read);
+                for op in then.iter_mut().chain(otherwise) {
+                    op.visit_locations(&mut |loc, _| f(loc, Read))
+                }
+            }
+            While { cond, body } => {
+                f(cond, Read);
+                for op in body {
+                    op.visit_locations(&mut |loc, _| f(loc, Read))
+                }
+            }
+            Leave {
+                result: Some(result),
+                ..
+            } => visit_operand(result, f),
+            Leave { result: None, .. } | Enter { .. } | WidePutInt(_) | HexDump | DecimalDump => {}
+        }
+    }
+}
+
+impl Op {
+    /// Call `f` with each cell the op uses. The pointers of dereferenced
+    /// locations are only ever read.
+    fn visit_cells(&mut self, f: &mut dyn FnMut(&mut StaticLocation, Access)) {
+        let mut visit = |loc: &mut DynamicLocation, access| match loc {
+            DynamicLocation::Static(cell) => f(cell, access),
+            DynamicLocation::DerefStack(cell) | DynamicLocation::DerefHeap(cell) => {
+                f(cell, Access::Read)
+            }
+            DynamicLocation::Frame(_) => {}
+        };
+        match self {
+            Op::BasicBlock(bb) | Op::Label(_, bb) => {
+                for op in &mut bb.ops {
+                    op.visit_locations(&mut visit)
+                }
+            }
+            Op::JmpIf(cond, _, _) => visit(cond, Access::Read),
+            Op::Quit | Op::Jmp(_, _) | Op::Call(_, _) | Op::Return => {}
+        }
+    }
+}
+
+/// How one op of a program uses the variables.
+#[derive(Debug, Default)]
+struct Usage {
+    /// The variables read before the op overwrites them.
+    reads: BTreeSet<usize>,
+    /// The variables the op overwrites.
+    writes: BTreeSet<usize>,
+}
+
+impl Usage {
+    fn access(&mut self, var: usize, access: Access) {
+        match access {
+            Access::Read if !self.writes.contains(&var) => {
+                self.reads.insert(var);
+            }
+            Access::Read => {}
+            Access::Write => {
+                self.writes.insert(var);
+            }
+        }
+    }
+}
+
+impl Program {
+    /// The ops that can run after each op.
+    fn successors(&self) -> Vec<Vec<usize>> {
+        let labels: HashMap<&Symbol, usize> = self
+            .ops
+            .iter()
+            .enumerate()
+            .filter_map(|(i, op)| match op {
+                Op::Label(label, _) => Some((label, i)),
+                _ => None,
+            })
+            .collect();
+        // A `ret` can go back to the op after any `call`
+        let returns: Vec<usize> = self
+            .ops
+            .iter()
+            .enumerate()
+            .filter(|(i, op)| matches!(op, Op::Call(_, _)) && i + 1 < self.ops.len())
+            .map(|(i, _)| i + 1)
+            .collect();
+
+        self.ops
+            .iter()
+            .enumerate()
+            .map(|(i, op)| {
+                let next = (i + 1 < self.ops.len()).then_some(i + 1);
+                match op {
+                    Op::BasicBlock(_) | Op::Label(_, _) => next.into_iter().collect(),
+                    Op::Quit => vec![],
+                    Op::Jmp(label, _) | Op::Call(label, _) => {
+                        labels.get(label).copied().into_iter().collect()
+                    }
+                    Op::JmpIf(_, label, _) => {
+                        labels.get(label).copied().into_iter().chain(next).collect()
+                    }
+                    Op::Return => returns.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Give each of the program's `count` variables a cell, and return how
+    /// many cells were spilled onto the stack above the `data` cells of the
+    /// data section.
+    ///
+    /// Variables go in the general purpose registers that the program never
+    /// names itself. Two variables can share a register as long as no basic
+    /// block needs both of them: a variable is needed by every block that
+    /// uses it, and by every block it's live on the way into, because a later
+    /// block reads it before overwriting it. Variables that don't get a
+    /// register are spilled to cells at the bottom of the stack, which are
+    /// shared the same way.
+    pub(super) fn allocate_variables(&mut self, count: usize, data: usize) -> usize {
+        if count == 0 {
+            return 0;
+        }
+
+        let mut named = BTreeSet::new();
+        let mut usages = vec![];
+        for op in &mut self.ops {
+            let mut usage = Usage::default();
+            op.visit_cells(&mut |cell, access| match variable_index(*cell) {
+                Some(var) => usage.access(var, access),
+                None => {
+                    named.insert(cell.address());
+                }
+            });
+            usages.push(usage);
+        }
+
+        // Find the variables live on the way into each op, until nothing changes
+        let successors = self.successors();
+        let mut live_in = vec![BTreeSet::new(); self.ops.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (i, usage) in usages.iter().enumerate().rev() {
+                let live_out: BTreeSet<usize> = successors[i]
+                    .iter()
+                    .flat_map(|&next| live_in[next].iter().copied())
+                    .collect();
+                let live: BTreeSet<usize> = live_out
+                    .difference(&usage.writes)
+                    .chain(&usage.reads)
+                    .copied()
+                    .collect();
+                if live != live_in[i] {
+                    live_in[i] = live;
+                    changed = true;
+                }
+            }
+        }
+
+        // Anything live inside an op is live on the way into it, or used by it
+        let mut conflicts: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); count];
+        for (usage, live) in usages.iter().zip(&live_in) {
+            let needed: BTreeSet<usize> = live
+                .iter()
+                .chain(&usage.reads)
+                .chain(&usage.writes)
+                .copied()
+                .collect();
+            for &var in &needed {
+                conflicts[var].extend(needed.iter().filter(|&&other| other != var));
+            }
+        }
+
+        // Give each variable the first cell none of its conflicts have, in the order they were declared
+        let free: Vec<StaticLocation> = GENERAL_REGISTERS
+            .into_iter()
+            .filter(|reg| !named.contains(&reg.address()))
+            .collect();
+        let mut cells: Vec<StaticLocation> = vec![];
+        let mut spilled = 0;
+        for conflicts in &conflicts {
+            let taken: Vec<StaticLocation> = conflicts
+                .iter()
+                .filter_map(|&other| cells.get(other).copied())
+                .collect();
+            let cell = free
+                .iter()
+                .copied()
+                .chain((data + 1..).map(|address| Layout::default().stack().cell(address)))
+                .find(|cell| !taken.contains(cell))
+                .unwrap();
+            if let Some(index) = Layout::default().stack().index_of(cell) {
+                spilled = spilled.max(index - data);
+            }
+            cells.push(cell);
+        }
+
+        for op in &mut self.ops {
+            op.visit_cells(&mut |cell, _| {
+                if let Some(var) = variable_index(*cell) {
+                    *cell = cells[var];
+                }
+            });
+        }
+        spilled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The destinations of the `=` instructions in a program, in order.
+    fn set_destinations(program: &Program) -> Vec<DynamicLocation> {
+        program
+            .ops()
+            .iter()
+            .flat_map(|op| match op {
+                Op::BasicBlock(bb) | Op::Label(_, bb) => bb.ops(),
+                _ => &[],
+            })
+            .filter_map(|op| match op {
+                BasicBlockOp::Set { dest, .. } => Some(*dest),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn run(program: &Program) -> String {
+        interpret_with_input(program.assemble(), "", 1).unwrap()
+    }
+
+    #[test]
+    fn test_variables_share_registers() {
+        // `first` is dead once `second` is set, but `total` is needed throughout
+        let source = r#"
+        var first, second, total
+            total = 0
+            first = 3
+            total add total, first
+            jmp next
+        next:
+            second = 4
+            total add total, second
+            putint total
+        "#;
+        let program = parse(source).unwrap();
+        assert_eq!(
+            set_destinations(&program),
+            [R1, R0, R0].map(DynamicLocation::Static)
+        );
+        assert_eq!(run(&program), "7");
+    }
+
+    #[test]
+    fn test_variables_avoid_named_registers() {
+        let source = "var x\nR0 = 1\nR2 = 2\nx = 3\nputint R0\nputint R2\nputint x";
+        let program = parse(source).unwrap();
+        assert_eq!(
+            set_destinations(&program),
+            [R0, R2, R1].map(DynamicLocation::Static)
+        );
+        assert_eq!(run(&program), "123");
+    }
+
+    #[test]
+    fn test_variables_live_across_loops_and_calls() {
+        // `i` and `sum` are live across the call to `step`, which reads `n`
+        let source = r#"
+        var i, n, sum
+            i = 4
+            sum = 0
+        loop:
+            n = i
+            call step
+            dec i
+            jmp_if i, loop
+            putint sum
+            quit
+        step:
+            sum add sum, n
+            ret
+        "#;
+        let program = parse(source).unwrap();
+        assert_eq!(
+            set_destinations(&program),
+            [R0, R2, R1].map(DynamicLocation::Static)
+        );
+        assert_eq!(run(&program), "10");
+    }
+
+    #[test]
+    fn test_variables_spill_to_stack() {
+        let mut source = ".data\nmessage: .string \"hi\"\nvar a, b\n".to_string();
+        for i in 0..15 {
+            source += &format!("R{i} = {i}\n");
+        }
+        source += "a = 7\nb = 8\npush 9\nputint a\nputint b\npop R0\nputint R0\nputint R14";
+        let program = parse(&source).unwrap();
+        // `a` gets the last register, and `b` goes above the data on the stack
+        let destinations = set_destinations(&program);
+        assert_eq!(
+            destinations[15..],
+            [
+                DynamicLocation::Static(R15),
+                DynamicLocation::Static(Layout::default().stack().cell(4))
+            ]
+        );
+        assert_eq!(program.data(), [b'h' as u64, b'i' as u64, 0, 0]);
+        assert_eq!(run(&program), "78914");
+
+        let c = program.assemble_to_c(1);
+        assert!(c.contains("stack[4] = 8;"), "{c}");
+    }
+}
@@ -0,0 +1,365 @@
+//! Static x86-64 Linux executables, made without a C compiler.
+//!
+//! The executable is the machine code from [`x86_64::lower`] behind an ELF
+//! header, with the tape mapped as zeroed memory. It talks to the world with
+//! raw `read`, `write`, and `exit` system calls, so it needs no libc.
+use super::{x86_64::*, *};
+
+/// Where the headers and code are loaded.
+const CODE_ADDRESS: u64 = 0x40_0000;
+/// Where the tape is mapped, far enough past the code that they can't overlap.
+const TAPE_ADDRESS: u64 = 0x1_0000_0000;
+
+const ELF_HEADER_SIZE: u64 = 64;
+const PROGRAM_HEADER_SIZE: u64 = 56;
+
+const SYS_READ: u64 = 0;
+const SYS_WRITE: u64 = 1;
+const SYS_EXIT: u64 = 60;
+
+/// Runs the compiled code as its own process, using system calls directly.
+struct Syscalls {
+    bytes: u8,
+    /// The routines that dump the tape in hexadecimal and decimal, once
+    /// the program needs them
+    dumps: [Option<Label>; 2],
+}
+
+impl Runtime for Syscalls {
+    fn enter(&mut self, code: &mut Code) {
+        code.mov_imm(RBX, TAPE_ADDRESS);
+    }
+
+    fn put(&mut self, code: &mut Code) {
+        code.mov_imm(RAX, SYS_WRITE);
+        code.mov_imm(RDI, 1);
+        code.mov_imm(RDX, 1);
+        code.syscall();
+    }
+
+    fn get(&mut self, code: &mut Code) {
+        // A failed or empty read leaves the cell zero
+        code.mov_imm(RAX, SYS_READ);
+        code.zero(RDI);
+        code.mov(RSI, RBX);
+        code.mov_imm(RDX, 1);
+        code.syscall();
+    }
+
+    fn dump(&mut self, code: &mut Code, hex: bool) {
+        let routine = *self.dumps[!hex as usize].get_or_insert_with(|| code.label());
+        code.call(routine);
+    }
+
+    fn exit(&mut self, code: &mut Code) {
+        code.mov_imm(RAX, SYS_EXIT);
+        code.zero(RDI);
+        code.syscall();
+    }
+
+    fn finish(&mut self, code: &mut Code) {
+        if self.dumps.iter().all(Option::is_none) {
+            return;
+        }
+        let number = code.label();
+        for (routine, hex) in self.dumps.into_iter().zip([true, false]) {
+            if let Some(routine) = routine {
+                code.bind(routine);
+                write_dump(code, self.bytes, hex, number);
+            }
+        }
+        code.bind(number);
+        write_number(code);
+    }
+}
+
+/// Set up the arguments for the number routine and call it.
+fn format_number(code: &mut Code, number: Label, base: u64, width: u64, pad: u8) {
+    code.mov_imm(RSI, base);
+    code.mov_imm(RCX, width);
+    code.mov_imm(R8, pad as u64);
+    code.call(number);
+}
+
+/// A routine that prints the first 256 cells, formatted like the C backend's
+/// dumps. Each line is put together on the stack and written all at once.
+fn write_dump(code: &mut Code, bytes: u8, hex: bool, number: Label) {
+    // No line is longer than 9 + 16 * 11 + 1 bytes
+    code.add_imm(RSP, -256);
+    code.mov_imm(R12, TAPE_ADDRESS);
+    code.zero(R13);
+
+    let line = code.label();
+    code.bind(line);
+    code.mov(RDI, RSP);
+    code.mov32(RAX, R13);
+    format_number(code, number, 10, 3, b'0');
+    code.store_byte_imm(RDI, b'-');
+    code.inc(RDI);
+    code.mov32(RAX, R13);
+    code.add32_imm(RAX, 15);
+    format_number(code, number, 10, 3, b'0');
+    for c in *b": " {
+        code.store_byte_imm(RDI, c);
+        code.inc(RDI);
+    }
+
+    let cell = code.label();
+    code.bind(cell);
+    code.load_cell(bytes, RAX, R12, Some(R13), 0);
+    if hex {
+        format_number(code, number, 16, 2, b'0');
+    } else {
+        format_number(code, number, 10, 3, b' ');
+    }
+    code.store_byte_imm(RDI, b' ');
+    code.inc(RDI);
+    code.inc(R13);
+    code.mov32(RAX, R13);
+    code.and32_imm(RAX, 15);
+    code.jcc(Cond::NotEqual, cell);
+    code.store_byte_imm(RDI, b'\n');
+    code.inc(RDI);
+
+    code.mov(RDX, RDI);
+    code.sub(RDX, RSP);
+    code.mov(RSI, RSP);
+    code.mov_imm(RDI, 1);
+    code.mov_imm(RAX, SYS_WRITE);
+    code.syscall();
+    code.cmp32_imm(R13, 0x100);
+    code.jcc(Cond::Below, line);
+
+    code.add_imm(RSP, 256);
+    code.ret();
+}
+
+/// A routine that writes `eax` in base `esi` to `rdi`, padded on the left
+/// to `ecx` characters with the byte in `r8`, and moves `rdi` past it.
+fn write_number(code: &mut Code) {
+    // The digits come out last first, so keep them on the stack until the padding is written
+    code.zero(R9);
+    let digit = code.label();
+    code.bind(digit);
+    code.zero(RDX);
+    code.div32(RSI);
+    let decimal = code.label();
+    code.cmp32_imm(RDX, 10);
+    code.jcc(Cond::Below, decimal);
+    code.add32_imm(RDX, (b'a' - b'0' - 10) as i32);
+    code.bind(decimal);
+    code.add32_imm(RDX, b'0' as i32);
+    code.push(RDX);
+    code.inc(R9);
+    code.test32(RAX);
+    code.jcc(Cond::NotEqual, digit);
+
+    let (pad, copy) = (code.label(), code.label());
+    code.bind(pad);
+    code.cmp32(RCX, R9);
+    code.jcc(Cond::BelowOrEqual, copy);
+    code.store_byte(RDI, R8);
+    code.inc(RDI);
+    code.dec32(RCX);
+    code.jmp(pad);
+
+    code.bind(copy);
+    code.pop(RAX);
+    code.store_byte(RDI, RAX);
+    code.inc(RDI);
+    code.dec32(R9);
+    code.jcc(Cond::NotEqual, copy);
+    code.ret();
+}
+
+/// Append a program header for a segment loaded from the start of the file.
+fn write_program_header(elf: &mut Vec<u8>, flags: u32, address: u64, file_size: u64, size: u64) {
+    elf.extend(1u32.to_le_bytes()); // A loadable segment
+    elf.extend(flags.to_le_bytes());
+    elf.extend(0u64.to_le_bytes()); // Its offset in the file
+    elf.extend(address.to_le_bytes());
+    elf.extend(address.to_le_bytes());
+    elf.extend(file_size.to_le_bytes());
+    elf.extend(size.to_le_bytes());
+    elf.extend(0x1000u64.to_le_bytes()); // Page aligned
+}
+
+/// Compile optimized operations to an executable with a tape of `tape_size` cells.
+pub(super) fn compile(ops: &[Op], bytes: u8, tape_size: usize) -> Result<Vec<u8>, BfError> {
+    let code = lower(
+        ops,
+        bytes,
+        &mut Syscalls {
+            bytes,
+            dumps: [None, None],
+        },
+    )?;
+
+    let headers = ELF_HEADER_SIZE + 2 * PROGRAM_HEADER_SIZE;
+    let file_size = headers + code.len() as u64;
+    let mut elf = Vec::with_capacity(file_size as usize);
+    // 64-bit, little-endian, version 1, for System V
+    elf.extend(b"\x7fELF\x02\x01\x01\x00");
+    elf.extend([0; 8]);
+    elf.extend(2u16.to_le_bytes()); // An executable
+    elf.extend(0x3eu16.to_le_bytes()); // For x86-64
+    elf.extend(1u32.to_le_bytes());
+    elf.extend((CODE_ADDRESS + headers).to_le_bytes()); // The entry point
+    elf.extend(ELF_HEADER_SIZE.to_le_bytes()); // Where the program headers are
+    elf.extend(0u64.to_le_bytes()); // There are no section headers
+    elf.extend(0u32.to_le_bytes());
+    elf.extend((ELF_HEADER_SIZE as u16).to_le_bytes());
+    elf.extend((PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    elf.extend(2u16.to_le_bytes());
+    elf.extend([0; 6]);
+
+    // Dumps always read 256 cells, so the tape is never shorter than that
+    let tape_bytes = tape_size.max(0x100) as u64 * bytes as u64;
+    write_program_header(&mut elf, 0b101, CODE_ADDRESS, file_size, file_size);
+    write_program_header(&mut elf, 0b110, TAPE_ADDRESS, 0, tape_bytes);
+    elf.extend(code);
+    Ok(elf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_elf_header() {
+        let elf = compile_to_elf("+.".to_string(), 2).unwrap();
+        assert_eq!(&elf[..4], b"\x7fELF");
+        assert_eq!(elf[18], 0x3e);
+        let entry = u64::from_le_bytes(elf[24..32].try_into().unwrap());
+        assert_eq!(entry, CODE_ADDRESS + 176);
+        // The tape's segment is only in memory, two bytes for each cell
+        let tape = &elf[120..176];
+        assert_eq!(
+            u64::from_le_bytes(tape[16..24].try_into().unwrap()),
+            TAPE_ADDRESS
+        );
+        assert_eq!(u64::from_le_bytes(tape[32..40].try_into().unwrap()), 0);
+        assert_eq!(
+            u64::from_le_bytes(tape[40..48].try_into().unwrap()),
+            crate::TAPE_SIZE as u64 * 2
+        );
+
+        assert!(matches!(
+            compile_to_elf("+".to_string(), 3),
+            Err(BfError::UnsupportedCellSize(3))
+        ));
+        assert!(matches!(
+            compile_to_elf("+]".to_string(), 1),
+            Err(BfError::UnmatchedBracket { bracket: ']', .. })
+        ));
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    mod run {
+        use super::*;
+
+        use std::os::unix::fs::PermissionsExt;
+        use std::process::{Command, Stdio};
+
+        /// Compile BrainFuck to an executable, and run it with the given input.
+        fn run_elf(bf: &str, input: &str, bytes: u8, name: &str) -> String {
+            let elf = compile_to_elf(bf.to_string(), bytes).unwrap();
+            let path = std::env::temp_dir().join(format!(
+                "basm_elf_test_{name}{bytes}_{}",
+                std::process::id()
+            ));
+            std::fs::write(&path, elf).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+            // Another test can fork while the file is still open for writing,
+            // which makes it busy until that child execs
+            let mut child = loop {
+                match Command::new(&path)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .spawn()
+                {
+                    Err(e) if e.raw_os_error() == Some(26) => {
+                        std::thread::sleep(std::time::Duration::from_millis(10))
+                    }
+                    child => break child.unwrap(),
+                }
+            };
+            child
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(input.as_bytes())
+                .unwrap();
+            let output = child.wait_with_output().unwrap();
+            std::fs::remove_file(path).unwrap();
+            assert!(output.status.success(), "{bf}");
+            String::from_utf8(output.stdout).unwrap()
+        }
+
+        /// Check that the executable prints the same thing as the interpreter.
+        fn assert_same_output(bf: &str, input: &str, name: &str) {
+            for bytes in [1, 2, 4] {
+                let expected = interpret_with_input(bf.to_string(), input, bytes).unwrap();
+                assert_eq!(run_elf(bf, input, bytes, name), expected, "{bf}");
+            }
+        }
+
+        #[test]
+        fn test_hello_world() {
+            let bf = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+            assert_eq!(run_elf(bf, "", 1, "hello"), "Hello World!\n");
+            assert_same_output(bf, "", "hello");
+        }
+
+        #[test]
+        fn test_input() {
+            // The end of the input reads as zero
+            assert_same_output(",[.,]", "Hello!\n", "echo");
+            assert_same_output(",+.,+.", "a", "eof");
+        }
+
+        #[test]
+        fn test_cell_sizes() {
+            // 256 only fits in cells wider than a byte
+            let bf = "++++++++++++++++[>++++++++++++++++<-]>[[-]<+>]<.";
+            assert_eq!(run_elf(bf, "", 1, "wrap"), "\0");
+            assert_eq!(run_elf(bf, "", 2, "wrap"), "\x01");
+            // Multiplying and scanning loops, and negative factors
+            let bf = "++++++++[>++++++++<-]>[>+>+<<-]>+.>++.[<]++++[>>------<<-]>>.";
+            assert_eq!(run_elf(bf, "", 1, "loops"), "AB*");
+            assert_same_output(bf, "", "loops");
+        }
+
+        #[test]
+        fn test_dumps() {
+            assert_same_output("+++++[>+++++++<-]>[>+>++<<-]>>+#<$", "", "dumps");
+            // The largest 32-bit value, in both bases
+            assert_eq!(
+                run_elf("-#$", "", 4, "wide_dumps"),
+                interpret_with_input("-#$".to_string(), "", 4).unwrap()
+            );
+        }
+
+        #[test]
+        fn test_assembled_program() {
+            let program = crate::Program::parse(
+                "
+                R0 = 7
+                R1 = 6
+                R2 mul R0, R1
+                putint R2
+                putchar '\\n'
+                getchar R3
+                putchar R3
+                ",
+            )
+            .unwrap();
+            for bytes in [1, 2] {
+                let bf = program.assemble();
+                let expected = interpret_with_input(bf.clone(), "x", bytes).unwrap();
+                assert_eq!(expected, "42\nx");
+                assert_eq!(run_elf(&bf, "x", bytes, "assembled"), expected);
+            }
+        }
+    }
+}
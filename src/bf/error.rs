@@ -0,0 +1,181 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use super::{Op, Position};
+
+/// An error from parsing, compiling, or running BrainFuck.
+#[derive(Debug)]
+pub enum BfError {
+    /// The source couldn't be parsed.
+    Parse {
+        /// The byte offset in the source where parsing stopped.
+        offset: usize,
+        message: String,
+    },
+    /// A `[` without a matching `]`, or a `]` without a matching `[`.
+    UnmatchedBracket {
+        bracket: char,
+        /// Where the bracket is in the source. For an
+        /// [`Interpreter`](super::Interpreter) made from operations, the
+        /// operations are treated as a single line, one column each.
+        position: Position,
+    },
+    /// Cells can only be 1, 2, or 4 bytes wide.
+    UnsupportedCellSize(u8),
+    /// An operation made by [`optimize`](super::optimize), which can't be
+    /// written back out as BrainFuck or Ook.
+    OptimizedOp(Op),
+    /// The tape pointer moved off either end of the tape.
+    OutOfBounds {
+        /// The cell the pointer moved to.
+        cell: i64,
+        tape_size: usize,
+    },
+    /// The C compiler rejected the generated code.
+    Compile(String),
+    /// The program's output isn't valid UTF-8.
+    Utf8(std::string::FromUtf8Error),
+    /// Reading, writing, or running a program failed.
+    Io(std::io::Error),
+}
+
+impl Display for BfError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Parse { offset, message } => {
+                write!(f, "Failed to parse brainfuck at byte {offset}: {message}")
+            }
+            Self::UnmatchedBracket { bracket, position } => write!(
+                f,
+                "Unmatched `{bracket}` at line {}, column {}",
+                position.line, position.column
+            ),
+            Self::UnsupportedCellSize(bytes) => write!(f, "Unsupported cell size: {bytes}"),
+            Self::OptimizedOp(op) => {
+                write!(f, "Optimized operation {op:?} has no BrainFuck equivalent")
+            }
+            Self::OutOfBounds { cell, tape_size } => write!(
+                f,
+                "Tape pointer moved out of bounds to {cell} (tape size is {tape_size})"
+            ),
+            Self::Compile(message) => write!(f, "Compilation failed: {message}"),
+            Self::Utf8(e) => write!(f, "Output is not valid UTF-8: {e}"),
+            Self::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for BfError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Utf8(e) => Some(e),
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for BfError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<std::string::FromUtf8Error> for BfError {
+    fn from(e: std::string::FromUtf8Error) -> Self {
+        Self::Utf8(e)
+    }
+}
+
+/// I/O errors are passed through as they are, and anything else is invalid data.
+impl From<BfError> for std::io::Error {
+    fn from(e: BfError) -> Self {
+        match e {
+            BfError::Io(e) => e,
+            e => std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bf::*;
+
+    #[test]
+    fn test_unbalanced_brackets() {
+        let error = simplify_bf("+[>[-]<".to_string()).unwrap_err();
+        assert!(matches!(
+            error,
+            BfError::UnmatchedBracket {
+                bracket: '[',
+                position: Position { offset: 1, .. }
+            }
+        ));
+        assert_eq!(error.to_string(), "Unmatched `[` at line 1, column 2");
+
+        // Comments count towards the position
+        assert!(matches!(
+            compile_to_ook("ok\n ]".to_string()),
+            Err(BfError::UnmatchedBracket {
+                bracket: ']',
+                position: Position {
+                    offset: 4,
+                    line: 2,
+                    column: 2
+                }
+            })
+        ));
+        assert!(matches!(
+            compile_to_c("]".to_string(), 1),
+            Err(BfError::UnmatchedBracket { .. })
+        ));
+        assert!(matches!(
+            interpret_with_input("[".to_string(), "", 1),
+            Err(BfError::UnmatchedBracket { .. })
+        ));
+    }
+
+    #[test]
+    fn test_unsupported_cell_size() {
+        assert!(matches!(
+            compile_to_c("+".to_string(), 3),
+            Err(BfError::UnsupportedCellSize(3))
+        ));
+        assert!(matches!(
+            Op::Add(1).write_bf(&mut String::new(), 8),
+            Err(BfError::UnsupportedCellSize(8))
+        ));
+        assert!(matches!(
+            Op::Add(1).write_ook(&mut String::new(), 2),
+            Err(BfError::UnsupportedCellSize(2))
+        ));
+        let error: std::io::Error = BfError::UnsupportedCellSize(3).into();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_optimized_ops() {
+        let mut bf = String::new();
+        assert!(matches!(
+            Op::Scan(1).write_bf(&mut bf, 1),
+            Err(BfError::OptimizedOp(Op::Scan(1)))
+        ));
+        assert!(bf.is_empty());
+        let error = Op::ZeroAt(2).write_ook(&mut String::new(), 1).unwrap_err();
+        assert!(matches!(error, BfError::OptimizedOp(Op::ZeroAt(2))));
+        assert_eq!(
+            error.to_string(),
+            "Optimized operation ZeroAt(2) has no BrainFuck equivalent"
+        );
+    }
+
+    #[test]
+    fn test_valid_programs() {
+        assert_eq!(simplify_bf("a+b+-[c-]>".to_string()).unwrap(), "+[-]>");
+        assert_eq!(
+            compile_to_ook("+<.".to_string()).unwrap(),
+            "Ook. Ook. Ook? Ook. Ook! Ook. "
+        );
+        assert!(compile_to_c("+[-]".to_string(), 4).is_ok());
+    }
+}
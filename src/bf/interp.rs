@@ -0,0 +1,312 @@
+use super::*;
+
+use std::io::{Read, Write};
+
+/// An in-process interpreter for parsed BrainFuck operations.
+///
+/// The interpreter supports 8, 16, and 32-bit cells, a configurable tape size,
+/// and any reader/writer pair for input and output. This lets programs run
+/// without writing C to disk or invoking `gcc`.
+///
+/// ```rust
+/// use basm::Interpreter;
+///
+/// let mut interpreter = Interpreter::from_bf("++++++++[>++++++++<-]>+.", 1, 100).unwrap();
+/// let mut output = Vec::new();
+/// interpreter.run(&mut "".as_bytes(), &mut output).unwrap();
+/// assert_eq!(output, b"A");
+/// ```
+#[derive(Debug, Clone)]
+pub struct Interpreter {
+    ops: Vec<Op>,
+    /// For every `While` and `End`, the index of its matching bracket.
+    jumps: Vec<usize>,
+    tape: Vec<u32>,
+    mask: u32,
+    ptr: usize,
+    pc: usize,
+}
+
+/// The position of an operation in a program without source, as a column on one line.
+pub(super) fn op_position(index: usize) -> Position {
+    Position {
+        offset: index,
+        line: 1,
+        column: index + 1,
+    }
+}
+
+impl Interpreter {
+    /// Create an interpreter for the given operations.
+    ///
+    /// An unmatched bracket is reported at the index of its operation, as
+    /// though each operation were a column on a single line.
+    pub fn new(ops: Vec<Op>, cell_bytes: u8, tape_size: usize) -> Result<Self, BfError> {
+        let mask = match cell_bytes {
+            1 => 0xff,
+            2 => 0xffff,
+            4 => 0xffff_ffff,
+            _ => return Err(BfError::UnsupportedCellSize(cell_bytes)),
+        };
+
+        let mut jumps = vec![0; ops.len()];
+        let mut open = Vec::new();
+        for (i, op) in ops.iter().enumerate() {
+            match op {
+                Op::While => open.push(i),
+                Op::End => {
+                    let start = open.pop().ok_or(BfError::UnmatchedBracket {
+                        bracket: ']',
+                        position: op_position(i),
+                    })?;
+                    jumps[start] = i;
+                    jumps[i] = start;
+                }
+                _ => {}
+            }
+        }
+        if let Some(start) = open.pop() {
+            return Err(BfError::UnmatchedBracket {
+                bracket: '[',
+                position: op_position(start),
+            });
+        }
+
+        Ok(Self {
+            ops,
+            jumps,
+            tape: vec![0; tape_size],
+            mask,
+            ptr: 0,
+            pc: 0,
+        })
+    }
+
+    /// Parse BrainFuck source, optimize it, and create an interpreter for it.
+    ///
+    /// An unmatched bracket is reported at its byte offset in the source.
+    pub fn from_bf(bf: &str, cell_bytes: u8, tape_size: usize) -> Result<Self, BfError> {
+        Self::new(optimize(parse::parse(bf)?), cell_bytes, tape_size)
+    }
+
+    /// The contents of the tape.
+    pub fn tape(&self) -> &[u32] {
+        &self.tape
+    }
+
+    /// The index of the cell the tape pointer is on.
+    pub fn pointer(&self) -> usize {
+        self.ptr
+    }
+
+    /// The index of the next operation to execute.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Has the program finished executing?
+    pub fn is_done(&self) -> bool {
+        self.pc >= self.ops.len()
+    }
+
+    /// Run the program until it finishes, reading from `input` and writing to `output`.
+    ///
+    /// Reading past the end of the input stores zero in the current cell.
+    pub fn run(&mut self, input: &mut impl Read, output: &mut impl Write) -> Result<(), BfError> {
+        while !self.is_done() {
+            self.step(input, output)?;
+        }
+        output.flush()?;
+        Ok(())
+    }
+
+    /// Execute a single operation.
+    pub fn step(&mut self, input: &mut impl Read, output: &mut impl Write) -> Result<(), BfError> {
+        match self.ops[self.pc] {
+            Op::Move(n) => self.ptr = self.offset(n)?,
+            Op::Add(n) => self.add(self.ptr, n as u32),
+            Op::Zero => self.tape[self.ptr] = 0,
+            Op::Put => output.write_all(&[self.tape[self.ptr] as u8])?,
+            Op::Get => {
+                // Make sure any prompt is visible before blocking on input
+                output.flush()?;
+                let mut byte = [0];
+                self.tape[self.ptr] = match input.read(&mut byte)? {
+                    0 => 0,
+                    _ => byte[0] as u32,
+                };
+            }
+            Op::While => {
+                if self.tape[self.ptr] == 0 {
+                    self.pc = self.jumps[self.pc];
+                }
+            }
+            Op::End => {
+                if self.tape[self.ptr] != 0 {
+                    self.pc = self.jumps[self.pc];
+                }
+            }
+            Op::MulAdd { offset, factor } => {
+                if self.tape[self.ptr] != 0 {
+                    let cell = self.offset(offset)?;
+                    self.add(cell, self.tape[self.ptr].wrapping_mul(factor as u32));
+                }
+            }
+            Op::Scan(n) => {
+                while self.tape[self.ptr] != 0 {
+                    self.ptr = self.offset(n)?;
+                }
+            }
+            Op::AddAt { offset, value } => self.add(self.offset(offset)?, value as u32),
+            Op::ZeroAt(offset) => {
+                let cell = self.offset(offset)?;
+                self.tape[cell] = 0;
+            }
+            Op::PutAt(offset) => output.write_all(&[self.tape[self.offset(offset)?] as u8])?,
+            Op::HexDump => dump(output, &self.tape, true)?,
+            Op::DecDump => dump(output, &self.tape, false)?,
+        }
+        self.pc += 1;
+        Ok(())
+    }
+
+    /// The index of the cell `offset` cells away from the pointer.
+    fn offset(&self, offset: i32) -> Result<usize, BfError> {
+        let cell = self.ptr as i64 + offset as i64;
+        if cell < 0 || cell >= self.tape.len() as i64 {
+            return Err(BfError::OutOfBounds {
+                cell,
+                tape_size: self.tape.len(),
+            });
+        }
+        Ok(cell as usize)
+    }
+
+    /// Add to a cell, wrapping around at the cell size.
+    fn add(&mut self, cell: usize, value: u32) {
+        self.tape[cell] = self.tape[cell].wrapping_add(value) & self.mask;
+    }
+}
+
+/// Print the first 256 cells of a tape in hexadecimal or decimal, formatted
+/// like the C backend's dumps.
+pub(super) fn dump(output: &mut impl Write, tape: &[u32], hex: bool) -> std::io::Result<()> {
+    for i in 0..0x100 {
+        if i % 16 == 0 {
+            write!(output, "{:03}-{:03}: ", i, i + 15)?;
+        }
+        let cell = tape.get(i).copied().unwrap_or(0);
+        if hex {
+            write!(output, "{cell:02x} ")?;
+        } else {
+            write!(output, "{cell:3} ")?;
+        }
+        if (i + 1) % 16 == 0 {
+            writeln!(output)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(bf: &str, input: &str, cell_bytes: u8) -> String {
+        let mut interpreter = Interpreter::from_bf(bf, cell_bytes, crate::TAPE_SIZE).unwrap();
+        let mut output = Vec::new();
+        interpreter.run(&mut input.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_hello_world() {
+        let bf = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+        assert_eq!(run(bf, "", 1), "Hello World!\n");
+    }
+
+    #[test]
+    fn test_echo_input() {
+        assert_eq!(run(",[.,]", "Hello!\n", 1), "Hello!\n");
+    }
+
+    #[test]
+    fn test_cell_widths() {
+        // Decrementing zero wraps around to the maximum cell value
+        let mut interpreter = Interpreter::from_bf("-", 1, 1).unwrap();
+        interpreter
+            .run(&mut "".as_bytes(), &mut Vec::new())
+            .unwrap();
+        assert_eq!(interpreter.tape()[0], 0xff);
+
+        let mut interpreter = Interpreter::from_bf("-", 2, 1).unwrap();
+        interpreter
+            .run(&mut "".as_bytes(), &mut Vec::new())
+            .unwrap();
+        assert_eq!(interpreter.tape()[0], 0xffff);
+
+        let mut interpreter = Interpreter::from_bf("-", 4, 1).unwrap();
+        interpreter
+            .run(&mut "".as_bytes(), &mut Vec::new())
+            .unwrap();
+        assert_eq!(interpreter.tape()[0], 0xffff_ffff);
+
+        // 256 only fits in cells wider than a byte
+        let bf = "++++++++++++++++[>++++++++++++++++<-]>[[-]<+>]<.";
+        assert_eq!(run(bf, "", 1), "\0");
+        assert_eq!(run(bf, "", 2), "\x01");
+    }
+
+    #[test]
+    fn test_skipped_multiply() {
+        // A multiply loop that is never entered doesn't touch the cells it would move to
+        assert_eq!(run("[<+>-]+++++++[>++++++++++<-]>-.", "", 1), "E");
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(matches!(
+            Interpreter::from_bf("+[", 1, 10),
+            Err(BfError::UnmatchedBracket {
+                bracket: '[',
+                position: Position {
+                    offset: 1,
+                    line: 1,
+                    column: 2
+                }
+            })
+        ));
+        assert!(matches!(
+            Interpreter::from_bf("]", 1, 10),
+            Err(BfError::UnmatchedBracket {
+                bracket: ']',
+                position: Position { offset: 0, .. }
+            })
+        ));
+        assert!(matches!(
+            Interpreter::from_bf("+", 3, 10),
+            Err(BfError::UnsupportedCellSize(3))
+        ));
+        // Without the source, brackets are found by their operation
+        assert!(matches!(
+            Interpreter::new(vec![Op::Add(1), Op::Move(2), Op::End], 1, 10),
+            Err(BfError::UnmatchedBracket {
+                bracket: ']',
+                position: Position {
+                    offset: 2,
+                    line: 1,
+                    column: 3
+                }
+            })
+        ));
+
+        let mut interpreter = Interpreter::from_bf("<", 1, 10).unwrap();
+        assert!(matches!(
+            interpreter.run(&mut "".as_bytes(), &mut Vec::new()),
+            Err(BfError::OutOfBounds {
+                cell: -1,
+                tape_size: 10
+            })
+        ));
+    }
+}
@@ -0,0 +1,478 @@
+use super::*;
+
+use std::io::{Read, Write};
+
+/// BrainFuck compiled to machine code, which runs in this process.
+///
+/// On x86-64 Linux, the optimized operations are lowered to machine code in
+/// executable memory, which calls back into Rust to read, write, and dump the
+/// tape. It behaves like the [`Interpreter`], down to stopping with an error
+/// when the pointer moves off the tape, but doesn't need to write files or
+/// start a process like compiling with `gcc` does. On other platforms, it
+/// runs the operations in the interpreter instead.
+///
+/// ```rust
+/// use basm::Jit;
+///
+/// let mut jit = Jit::from_bf("++++++++[>++++++++<-]>+.,[.,]", 1, 100).unwrap();
+/// let mut output = Vec::new();
+/// jit.run(&mut "BC".as_bytes(), &mut output).unwrap();
+/// assert_eq!(output, b"ABC");
+/// ```
+#[derive(Debug)]
+pub struct Jit {
+    program: Program,
+    cell_bytes: u8,
+    tape_size: usize,
+}
+
+impl Jit {
+    /// Compile the given operations, for cells of `cell_bytes` bytes on a
+    /// tape of `tape_size` cells.
+    ///
+    /// An unmatched bracket is reported at the index of its operation.
+    pub fn new(ops: Vec<Op>, cell_bytes: u8, tape_size: usize) -> Result<Self, BfError> {
+        Ok(Self {
+            program: Program::new(ops, cell_bytes, tape_size)?,
+            cell_bytes,
+            tape_size,
+        })
+    }
+
+    /// Parse BrainFuck source, optimize it, and compile it.
+    ///
+    /// An unmatched bracket is reported at its position in the source.
+    pub fn from_bf(bf: &str, cell_bytes: u8, tape_size: usize) -> Result<Self, BfError> {
+        Self::new(optimize(parse::parse(bf)?), cell_bytes, tape_size)
+    }
+
+    /// Does this platform run programs as machine code, rather than in the interpreter?
+    pub const fn is_native() -> bool {
+        cfg!(all(target_arch = "x86_64", target_os = "linux"))
+    }
+
+    /// Run the program from the start on an empty tape, reading from `input`
+    /// and writing to `output`.
+    ///
+    /// Reading past the end of the input stores zero in the current cell.
+    pub fn run(&mut self, input: &mut impl Read, output: &mut impl Write) -> Result<(), BfError> {
+        self.program
+            .run(input, output, self.cell_bytes, self.tape_size)?;
+        output.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+#[derive(Debug)]
+struct Program(Vec<Op>);
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+impl Program {
+    fn new(ops: Vec<Op>, cell_bytes: u8, tape_size: usize) -> Result<Self, BfError> {
+        // Check the operations now, like compiling them would
+        Interpreter::new(ops.clone(), cell_bytes, tape_size)?;
+        Ok(Self(ops))
+    }
+
+    fn run(
+        &self,
+        input: &mut impl Read,
+        output: &mut impl Write,
+        cell_bytes: u8,
+        tape_size: usize,
+    ) -> Result<(), BfError> {
+        Interpreter::new(self.0.clone(), cell_bytes, tape_size)?.run(input, output)
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+use native::Program;
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod native {
+    use super::{super::x86_64::*, *};
+
+    // Where each callback is in the `Context`
+    const PUT: i32 = 0;
+    const GET: i32 = 8;
+    const DUMP: i32 = 16;
+    const OUT_OF_BOUNDS: i32 = 24;
+
+    /// Everything the compiled code's callbacks need while it runs.
+    ///
+    /// The compiled code only knows about the callbacks at the start, which
+    /// each take the context first. They return a negative number to stop
+    /// the program, once they've stored an error.
+    #[repr(C)]
+    struct Context<'a> {
+        put: extern "C" fn(&mut Context<'a>, *const u8) -> i64,
+        get: extern "C" fn(&mut Context<'a>) -> i64,
+        dump: extern "C" fn(&mut Context<'a>, u64) -> i64,
+        out_of_bounds: extern "C" fn(&mut Context<'a>, *const u8) -> i64,
+        input: &'a mut dyn Read,
+        output: &'a mut dyn Write,
+        tape: *const u8,
+        tape_size: usize,
+        cell_bytes: u8,
+        error: Option<BfError>,
+    }
+
+    impl Context<'_> {
+        fn fail(&mut self, error: impl Into<BfError>) -> i64 {
+            self.error = Some(error.into());
+            -1
+        }
+    }
+
+    /// Write the byte at a cell.
+    extern "C" fn put(context: &mut Context, cell: *const u8) -> i64 {
+        // SAFETY: the compiled code only passes cells it has checked are on the tape
+        let byte = unsafe { *cell };
+        match context.output.write_all(&[byte]) {
+            Ok(()) => 0,
+            Err(e) => context.fail(e),
+        }
+    }
+
+    /// Read a byte, or zero at the end of the input.
+    extern "C" fn get(context: &mut Context) -> i64 {
+        // Make sure any prompt is visible before blocking on input
+        if let Err(e) = context.output.flush() {
+            return context.fail(e);
+        }
+        let mut byte = [0];
+        match context.input.read(&mut byte) {
+            Ok(0) => 0,
+            Ok(_) => byte[0] as i64,
+            Err(e) => context.fail(e),
+        }
+    }
+
+    /// Print the first 256 cells, in hexadecimal if `hex` isn't zero.
+    extern "C" fn dump(context: &mut Context, hex: u64) -> i64 {
+        let bytes = context.cell_bytes as usize;
+        // SAFETY: the tape outlives the compiled code's run, and nothing
+        // writes to it while the compiled code is waiting on this callback
+        let tape = unsafe { std::slice::from_raw_parts(context.tape, context.tape_size * bytes) };
+        let cells: Vec<u32> = tape
+            .chunks(bytes)
+            .take(0x100)
+            .map(|cell| cell.iter().rev().fold(0, |n, &byte| n << 8 | byte as u32))
+            .collect();
+        match interp::dump(&mut context.output, &cells, hex != 0) {
+            Ok(()) => 0,
+            Err(e) => context.fail(e),
+        }
+    }
+
+    /// Stop the program, because the pointer moved off the tape.
+    extern "C" fn out_of_bounds(context: &mut Context, cell: *const u8) -> i64 {
+        let offset = cell as i64 - context.tape as i64;
+        context.fail(BfError::OutOfBounds {
+            cell: offset.div_euclid(context.cell_bytes as i64),
+            tape_size: context.tape_size,
+        })
+    }
+
+    /// Runs the compiled code as a function, called with the first cell of
+    /// the tape, the context, and the end of the tape.
+    #[derive(Default)]
+    struct Callbacks {
+        /// Where the code returns
+        stop: Option<Label>,
+        /// Where the code goes when the pointer is off the tape, if it can be
+        out_of_bounds: Option<Label>,
+    }
+
+    impl Callbacks {
+        /// Call back into Rust through the context in `r12`, and stop if it fails.
+        fn call(&self, code: &mut Code, callback: i32) {
+            code.call_mem(R12, callback);
+            code.test(RAX);
+            code.jcc(Cond::Sign, self.stop.expect("Called back before entering"));
+        }
+    }
+
+    impl Runtime for Callbacks {
+        fn enter(&mut self, code: &mut Code) {
+            // An odd number of pushes keeps the stack aligned for calls
+            for reg in [RBX, R12, R13, R14, R15] {
+                code.push(reg);
+            }
+            code.mov(RBX, RDI);
+            code.mov(R12, RSI);
+            code.mov(R13, RDI);
+            code.mov(R14, RDX);
+            self.stop = Some(code.label());
+        }
+
+        fn put(&mut self, code: &mut Code) {
+            code.mov(RDI, R12);
+            self.call(code, PUT);
+        }
+
+        fn get(&mut self, code: &mut Code) {
+            code.mov(RDI, R12);
+            self.call(code, GET);
+            code.store_byte(RBX, RAX);
+        }
+
+        fn dump(&mut self, code: &mut Code, hex: bool) {
+            code.mov(RDI, R12);
+            code.mov_imm(RSI, hex as u64);
+            self.call(code, DUMP);
+        }
+
+        fn check(&mut self, code: &mut Code, disp: i32) {
+            let out_of_bounds = *self.out_of_bounds.get_or_insert_with(|| code.label());
+            code.lea(RAX, RBX, disp);
+            code.cmp(RAX, R13);
+            code.jcc(Cond::Below, out_of_bounds);
+            code.cmp(RAX, R14);
+            code.jcc(Cond::AboveOrEqual, out_of_bounds);
+        }
+
+        fn exit(&mut self, code: &mut Code) {
+            code.bind(self.stop.expect("Exited before entering"));
+            for reg in [R15, R14, R13, R12, RBX] {
+                code.pop(reg);
+            }
+            code.ret();
+        }
+
+        fn finish(&mut self, code: &mut Code) {
+            if let Some(out_of_bounds) = self.out_of_bounds {
+                code.bind(out_of_bounds);
+                code.mov(RDI, R12);
+                code.mov(RSI, RAX);
+                self.call(code, OUT_OF_BOUNDS);
+            }
+        }
+    }
+
+    /// Memory holding machine code, which is executable and never written again.
+    #[derive(Debug)]
+    struct ExecutableMemory {
+        ptr: *mut libc::c_void,
+        len: usize,
+    }
+
+    // SAFETY: the memory is only ever read and executed once it's made
+    unsafe impl Send for ExecutableMemory {}
+    unsafe impl Sync for ExecutableMemory {}
+
+    impl ExecutableMemory {
+        fn new(code: &[u8]) -> Result<Self, BfError> {
+            let len = code.len();
+            // SAFETY: this maps fresh memory, which nothing else refers to
+            let ptr = unsafe {
+                libc::mmap(
+                    std::ptr::null_mut(),
+                    len,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                    -1,
+                    0,
+                )
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            // Unmap the memory if anything goes wrong from here
+            let memory = Self { ptr, len };
+            // SAFETY: the memory was just mapped with room for the code
+            unsafe { std::ptr::copy_nonoverlapping(code.as_ptr(), ptr as *mut u8, len) };
+            // SAFETY: the memory belongs to us, and only holds the code
+            if unsafe { libc::mprotect(ptr, len, libc::PROT_READ | libc::PROT_EXEC) } != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            Ok(memory)
+        }
+    }
+
+    impl Drop for ExecutableMemory {
+        fn drop(&mut self) {
+            // SAFETY: the memory was mapped by `new`, and nothing can run it after this
+            unsafe { libc::munmap(self.ptr, self.len) };
+        }
+    }
+
+    #[derive(Debug)]
+    pub(super) struct Program(ExecutableMemory);
+
+    impl Program {
+        pub fn new(ops: Vec<Op>, cell_bytes: u8, _tape_size: usize) -> Result<Self, BfError> {
+            let code = lower(&ops, cell_bytes, &mut Callbacks::default())?;
+            Ok(Self(ExecutableMemory::new(&code)?))
+        }
+
+        pub fn run(
+            &self,
+            input: &mut impl Read,
+            output: &mut impl Write,
+            cell_bytes: u8,
+            tape_size: usize,
+        ) -> Result<(), BfError> {
+            let bytes = cell_bytes as usize;
+            // The pointer starts on the first cell before it's checked, so
+            // even an empty tape has room for one
+            let mut tape = vec![0u8; tape_size.max(1) * bytes];
+            let start = tape.as_mut_ptr();
+            let end = start.wrapping_add(tape_size * bytes);
+            let mut context = Context {
+                put,
+                get,
+                dump,
+                out_of_bounds,
+                input,
+                output,
+                tape: start,
+                tape_size,
+                cell_bytes,
+                error: None,
+            };
+            // SAFETY: the code was lowered as a function taking these
+            // arguments, for cells of this size, and it checks that every
+            // cell it touches is between `start` and `end`
+            let entry = unsafe {
+                std::mem::transmute::<
+                    *mut libc::c_void,
+                    extern "C" fn(*mut u8, &mut Context, *mut u8),
+                >(self.0.ptr)
+            };
+            entry(start, &mut context, end);
+            match context.error {
+                Some(e) => Err(e),
+                None => Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(bf: &str, input: &str, cell_bytes: u8) -> String {
+        let mut jit = Jit::from_bf(bf, cell_bytes, crate::TAPE_SIZE).unwrap();
+        let mut output = Vec::new();
+        jit.run(&mut input.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    /// Check that the program prints the same thing as it does in the interpreter.
+    fn assert_same_output(bf: &str, input: &str) {
+        for cell_bytes in [1, 2, 4] {
+            let expected = interpret_with_input(bf.to_string(), input, cell_bytes).unwrap();
+            assert_eq!(run(bf, input, cell_bytes), expected, "{bf}");
+        }
+    }
+
+    #[test]
+    fn test_programs() {
+        let bf = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+        assert_eq!(run(bf, "", 1), "Hello World!\n");
+        assert_same_output(bf, "");
+        assert_same_output(",[.,]", "Hello!\n");
+        assert_same_output(",+.,+.", "a");
+        assert_same_output(
+            "++++++++[>++++++++<-]>[>+>+<<-]>+.>++.[<]++++[>>------<<-]>>.",
+            "",
+        );
+        assert_same_output("+++++[>+++++++<-]>[>+>++<<-]>>+#<$-#$", "");
+
+        // A multiply loop that is never entered doesn't touch the cells it would move to
+        assert_eq!(run("[<+>-]+++++++[>++++++++++<-]>-.", "", 1), "E");
+
+        // 256 only fits in cells wider than a byte
+        let bf = "++++++++++++++++[>++++++++++++++++<-]>[[-]<+>]<.";
+        assert_eq!(run(bf, "", 1), "\0");
+        assert_eq!(run(bf, "", 2), "\x01");
+    }
+
+    #[test]
+    fn test_runs_again() {
+        let mut jit = Jit::from_bf(",+.", 2, 10).unwrap();
+        for input in ["a", "b"] {
+            let mut output = Vec::new();
+            jit.run(&mut input.as_bytes(), &mut output).unwrap();
+            assert_eq!(output, [input.as_bytes()[0] + 1]);
+        }
+    }
+
+    #[test]
+    fn test_assembled_program() {
+        let program = crate::Program::parse(
+            "
+            R0 = 7
+            R1 = 6
+            R2 mul R0, R1
+            putint R2
+            putchar '\\n'
+            getchar R3
+            putchar R3
+            ",
+        )
+        .unwrap();
+        for cell_bytes in [1, 2] {
+            assert_eq!(run(&program.assemble(), "x", cell_bytes), "42\nx");
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let run_err = |bf: &str, tape_size| {
+            let mut jit = Jit::from_bf(bf, 1, tape_size).unwrap();
+            jit.run(&mut "".as_bytes(), &mut Vec::new()).unwrap_err()
+        };
+        assert!(matches!(
+            run_err("<", 10),
+            BfError::OutOfBounds {
+                cell: -1,
+                tape_size: 10
+            }
+        ));
+        assert!(matches!(
+            run_err("+[>+]", 10),
+            BfError::OutOfBounds { cell: 10, .. }
+        ));
+        assert!(matches!(
+            run_err("+>+>+>+>+<<<<[>>]", 5),
+            BfError::OutOfBounds { cell: 6, .. }
+        ));
+        assert!(matches!(
+            run_err(">>>>+[-<<<<<<+>>>>>>]", 10),
+            BfError::OutOfBounds { cell: -2, .. }
+        ));
+
+        assert!(matches!(
+            Jit::from_bf("+", 3, 10),
+            Err(BfError::UnsupportedCellSize(3))
+        ));
+        assert!(matches!(
+            Jit::from_bf("+[", 1, 10),
+            Err(BfError::UnmatchedBracket { bracket: '[', .. })
+        ));
+        assert!(matches!(
+            Jit::new(vec![Op::Add(1), Op::End], 1, 10),
+            Err(BfError::UnmatchedBracket { bracket: ']', .. })
+        ));
+
+        // Errors from the output stop the program
+        struct Broken;
+        impl Write for Broken {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut jit = Jit::from_bf("+.>+[]", 1, 10).unwrap();
+        assert!(matches!(
+            jit.run(&mut "".as_bytes(), &mut Broken),
+            Err(BfError::Io(e)) if e.kind() == std::io::ErrorKind::BrokenPipe
+        ));
+    }
+}
@@ -8,73 +8,93 @@ use std::{
 use lazy_static::lazy_static;
 use tracing::*;
 
+mod elf;
+mod error;
+pub use error::BfError;
+mod interp;
+pub use interp::Interpreter;
+mod jit;
+pub use jit::Jit;
 mod parse;
+pub(crate) use parse::parse_fragment;
+mod optimize;
+pub use optimize::optimize;
+mod tree;
+pub use tree::{Loop, LoopNode, LoopTree, Position};
+mod wat;
+mod x86_64;
 // Create a compile lock
 lazy_static! {
     static ref COMPILE_LOCK: Mutex<()> = Mutex::new(());
 }
 
-pub fn simplify_bf(mut bf: String) -> String {
+/// Simplify a BrainFuck program, removing comments and canceling out operations.
+pub fn simplify_bf(bf: String) -> Result<String, BfError> {
     info!("Emitting brainfuck...");
+    emit_bf(&parse::parse(&bf)?)
+}
+
+/// Simplify a piece of BrainFuck code, which may have unbalanced brackets.
+pub(crate) fn simplify(bf: &str) -> Result<String, BfError> {
     // Parse with nom
-    let ops = match parse::parse(&bf) {
-        Ok(ops) => ops,
-        Err(e) => {
-            error!("Failed to parse brainfuck: {e}");
-            return String::new();
-        }
-    };
-    bf.clear();
-    for op in &ops {
+    emit_bf(&parse::parse_fragment(bf)?)
+}
+
+fn emit_bf(ops: &[Op]) -> Result<String, BfError> {
+    let mut result = String::new();
+    for op in ops {
         // bf.push_str(&op.to_bf());
-        op.write_bf(&mut bf, 1);
+        op.write_bf(&mut result, 1)?;
     }
-    bf
+    Ok(result)
 }
 
-pub fn compile_to_ook(mut bf: String) -> String {
+pub fn compile_to_ook(mut bf: String) -> Result<String, BfError> {
     info!("Compiling brainfuck to Ook...");
     // Parse with nom
-    let ops = match parse::parse(&bf) {
-        Ok(ops) => ops,
-        Err(e) => {
-            error!("Failed to parse brainfuck: {e}");
-            return String::new();
-        }
-    };
+    let ops = parse::parse(&bf)?;
     bf.clear();
     for op in &ops {
-        op.write_ook(&mut bf, 1);
+        op.write_ook(&mut bf, 1)?;
     }
-    bf
+    Ok(bf)
 }
 
-pub fn compile_to_c(mut bf: String, bytes: u8) -> String {
+pub fn compile_to_c(bf: String, bytes: u8) -> Result<String, BfError> {
+    compile_to_c_with_tape(bf, bytes, crate::TAPE_SIZE)
+}
+
+/// Compile brainfuck to C, with a tape of `tape_size` cells.
+pub fn compile_to_c_with_tape(
+    mut bf: String,
+    bytes: u8,
+    tape_size: usize,
+) -> Result<String, BfError> {
     info!("Compiling brainfuck to C...");
     // Parse with nom
-    let ops = match parse::parse(&bf) {
-        Ok(ops) => ops,
-        Err(e) => {
-            error!("Failed to parse brainfuck: {e}");
-            return String::new();
-        }
-    };
+    let ops = optimize(parse::parse(&bf)?);
     bf.clear();
 
     bf.push_str("#include <stdio.h>\n");
     bf.push_str("#include <stdlib.h>\n");
     bf.push_str("int main() {\n");
     if bytes == 1 {
-        bf.push_str("    unsigned char *tape = calloc(30000, sizeof(char));\n");
+        bf.push_str(&format!(
+            "    unsigned char *tape = calloc({tape_size}, sizeof(char));\n"
+        ));
         bf.push_str("    unsigned char *ptr = tape;\n");
     } else if bytes == 2 {
-        bf.push_str("    unsigned short *tape = calloc(30000, sizeof(short));\n");
+        bf.push_str(&format!(
+            "    unsigned short *tape = calloc({tape_size}, sizeof(short));\n"
+        ));
         bf.push_str("    unsigned short *ptr = tape;\n");
     } else if bytes == 4 {
-        bf.push_str("    unsigned int *tape = calloc(30000, sizeof(int));\n");
+        bf.push_str(&format!(
+            "    unsigned int *tape = calloc({tape_size}, sizeof(int));\n"
+        ));
         bf.push_str("    unsigned int *ptr = tape;\n");
     } else {
-        panic!("Unsupported cell size: {bytes}");
+        return Err(BfError::UnsupportedCellSize(bytes));
     }
     bf.push_str("    char ch;\n");
 
@@ -88,18 +108,18 @@ pub fn compile_to_c(mut bf: String, bytes: u8) -> String {
     bf.push_str("    return 0;\n");
     bf.push_str("}\n");
 
-    bf
+    Ok(bf)
 }
 
-pub fn compile_to_exe(mut bf: String, bytes: u8) -> Result<(), Box<dyn std::error::Error>> {
+pub fn compile_to_exe(mut bf: String, bytes: u8) -> Result<(), BfError> {
     info!("Writing brainfuck to file...");
     let mut file = File::create("main.bf")?;
     // file.write_all(bf.as_bytes())?;
-    bf = simplify_bf(bf.clone());
+    bf = simplify_bf(bf.clone())?;
     file.write_all(bf.as_bytes())?;
 
     info!("Compiling brainfuck...");
-    let c = compile_to_c(bf, bytes);
+    let c = compile_to_c(bf, bytes)?;
     info!("Creating output file...");
     let mut file = File::create("main.c")?;
     file.write_all(c.as_bytes())?;
@@ -113,13 +133,48 @@ pub fn compile_to_exe(mut bf: String, bytes: u8) -> Result<(), Box<dyn std::erro
     // Check if the compilation was successful
     if !child.status.success() {
         let error_message = String::from_utf8_lossy(&child.stderr);
-        return Err(format!("Compilation failed: {}", error_message).into());
+        return Err(BfError::Compile(error_message.into_owned()));
     }
 
     Ok(())
 }
 
-pub fn compile_and_run(bf: String, bytes: u8) -> Result<(), Box<dyn std::error::Error>> {
+/// Compile brainfuck to a static x86-64 Linux executable, without a C compiler.
+pub fn compile_to_elf(bf: String, bytes: u8) -> Result<Vec<u8>, BfError> {
+    compile_to_elf_with_tape(bf, bytes, crate::TAPE_SIZE)
+}
+
+/// Compile brainfuck to a static x86-64 Linux executable, with a tape of
+/// `tape_size` cells.
+pub fn compile_to_elf_with_tape(
+    bf: String,
+    bytes: u8,
+    tape_size: usize,
+) -> Result<Vec<u8>, BfError> {
+    info!("Compiling brainfuck to x86-64...");
+    elf::compile(&optimize(parse::parse(&bf)?), bytes, tape_size)
+}
+
+/// Compile brainfuck to a WebAssembly text module.
+///
+/// The module imports `getchar` and `putchar` functions from `env`, exports
+/// its tape as `memory`, and runs the program when its `main` is called.
+pub fn compile_to_wat(bf: String, bytes: u8) -> Result<String, BfError> {
+    compile_to_wat_with_tape(bf, bytes, crate::TAPE_SIZE)
+}
+
+/// Compile brainfuck to a WebAssembly text module, with a tape of
+/// `tape_size` cells.
+pub fn compile_to_wat_with_tape(
+    bf: String,
+    bytes: u8,
+    tape_size: usize,
+) -> Result<String, BfError> {
+    info!("Compiling brainfuck to WebAssembly...");
+    wat::compile(&optimize(parse::parse(&bf)?), bytes, tape_size)
+}
+
+pub fn compile_and_run(bf: String, bytes: u8) -> Result<(), BfError> {
     let lock = COMPILE_LOCK.lock().unwrap();
     compile_to_exe(bf, bytes)?;
     info!("Running executable...");
@@ -129,11 +184,7 @@ pub fn compile_and_run(bf: String, bytes: u8) -> Result<(), Box<dyn std::error::
     Ok(())
 }
 
-pub fn compile_and_run_with_input(
-    bf: String,
-    input: &str,
-    bytes: u8,
-) -> Result<String, Box<dyn std::error::Error>> {
+pub fn compile_and_run_with_input(bf: String, input: &str, bytes: u8) -> Result<String, BfError> {
     let lock = COMPILE_LOCK.lock().unwrap();
     compile_to_exe(bf, bytes)?;
     // std::process::Command::new(&format!("./{filename}")).status()?;
@@ -153,6 +204,56 @@ pub fn compile_and_run_with_input(
     }
 }
 
+/// Run brainfuck in the built-in interpreter, using stdin and stdout.
+pub fn interpret(bf: String, bytes: u8) -> Result<(), BfError> {
+    interpret_with_tape(bf, bytes, crate::TAPE_SIZE)
+}
+
+/// Run brainfuck in the built-in interpreter with a tape of `tape_size` cells,
+/// using stdin and stdout.
+pub fn interpret_with_tape(bf: String, bytes: u8, tape_size: usize) -> Result<(), BfError> {
+    info!("Interpreting brainfuck...");
+    let mut interpreter = Interpreter::from_bf(&bf, bytes, tape_size)?;
+    interpreter.run(&mut std::io::stdin().lock(), &mut std::io::stdout().lock())
+}
+
+/// Run brainfuck in the built-in interpreter with the given input, and return its output.
+///
+/// Unlike `compile_and_run_with_input`, this does not touch the filesystem
+/// or require a C compiler, so it is safe to call from many threads at once.
+pub fn interpret_with_input(bf: String, input: &str, bytes: u8) -> Result<String, BfError> {
+    let mut interpreter = Interpreter::from_bf(&bf, bytes, crate::TAPE_SIZE)?;
+    let mut output = Vec::new();
+    interpreter.run(&mut input.as_bytes(), &mut output)?;
+    Ok(String::from_utf8(output)?)
+}
+
+/// Run brainfuck as machine code in this process, using stdin and stdout.
+///
+/// This uses the interpreter on platforms other than x86-64 Linux.
+pub fn jit(bf: String, bytes: u8) -> Result<(), BfError> {
+    jit_with_tape(bf, bytes, crate::TAPE_SIZE)
+}
+
+/// Run brainfuck as machine code in this process with a tape of `tape_size`
+/// cells, using stdin and stdout.
+pub fn jit_with_tape(bf: String, bytes: u8, tape_size: usize) -> Result<(), BfError> {
+    info!("Compiling brainfuck to machine code...");
+    let mut jit = Jit::from_bf(&bf, bytes, tape_size)?;
+    jit.run(&mut std::io::stdin().lock(), &mut std::io::stdout().lock())
+}
+
+/// Run brainfuck as machine code in this process with the given input, and
+/// return its output.
+///
+/// Like `interpret_with_input`, this is safe to call from many threads at once.
+pub fn jit_with_input(bf: String, input: &str, bytes: u8) -> Result<String, BfError> {
+    let mut jit = Jit::from_bf(&bf, bytes, crate::TAPE_SIZE)?;
+    let mut output = Vec::new();
+    jit.run(&mut input.as_bytes(), &mut output)?;
+    Ok(String::from_utf8(output)?)
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Op {
     /// Move pointer (>^N) or (<^N)
@@ -173,6 +274,16 @@ pub enum Op {
     HexDump,
     /// Decimal dump ($)
     DecDump,
+    /// Add the current cell times `factor` to the cell at `offset` ([->+<])
+    MulAdd { offset: i32, factor: i32 },
+    /// Move by `stride` until the current cell is zero ([>>])
+    Scan(i32),
+    /// Add to the cell at `offset` (>+<)
+    AddAt { offset: i32, value: i32 },
+    /// Set the cell at `offset` to zero (>[-]<)
+    ZeroAt(i32),
+    /// Print the cell at `offset` to stdout (>.<)
+    PutAt(i32),
 }
 
 impl Op {
@@ -191,9 +302,25 @@ impl Op {
         }
     }
 
-    pub fn write_ook(&self, ook: &mut String, target_cell_bytes: u8) {
+    /// Is this one of the operations made by [`optimize`], which have no
+    /// BrainFuck equivalent on their own?
+    pub fn is_optimized(&self) -> bool {
+        matches!(
+            self,
+            Self::MulAdd { .. }
+                | Self::Scan(_)
+                | Self::AddAt { .. }
+                | Self::ZeroAt(_)
+                | Self::PutAt(_)
+        )
+    }
+
+    pub fn write_ook(&self, ook: &mut String, target_cell_bytes: u8) -> Result<(), BfError> {
+        if self.is_optimized() {
+            return Err(BfError::OptimizedOp(*self));
+        }
         if target_cell_bytes != 1 {
-            panic!("Unsupported cell size: {target_cell_bytes}");
+            return Err(BfError::UnsupportedCellSize(target_cell_bytes));
         }
 
         match self {
@@ -201,7 +328,7 @@ impl Op {
                 if *n > 0 {
                     ook.push_str(&"Ook. Ook? ".repeat(*n as usize));
                 } else {
-                    ook.push_str(&"Ook? Ook. ".repeat(*n as usize));
+                    ook.push_str(&"Ook? Ook. ".repeat(-*n as usize));
                 }
             }
             Self::Add(n) => {
@@ -212,9 +339,9 @@ impl Op {
                 }
             }
             Self::Zero => {
-                Self::While.write_ook(ook, target_cell_bytes);
-                Self::Add(-1).write_ook(ook, target_cell_bytes);
-                Self::End.write_ook(ook, target_cell_bytes);
+                Self::While.write_ook(ook, target_cell_bytes)?;
+                Self::Add(-1).write_ook(ook, target_cell_bytes)?;
+                Self::End.write_ook(ook, target_cell_bytes)?;
             }
             Self::Put => ook.push_str("Ook! Ook. "),
             Self::Get => ook.push_str("Ook. Ook! "),
@@ -223,9 +350,13 @@ impl Op {
             Self::End => ook.push_str("Ook? Ook! "),
             _ => {}
         }
+        Ok(())
     }
 
-    pub fn write_bf(&self, bf: &mut String, target_cell_bytes: u8) {
+    pub fn write_bf(&self, bf: &mut String, target_cell_bytes: u8) -> Result<(), BfError> {
+        if self.is_optimized() {
+            return Err(BfError::OptimizedOp(*self));
+        }
         if target_cell_bytes == 1 {
             match self {
                 Self::Move(n) => {
@@ -249,6 +380,7 @@ impl Op {
                 Self::End => bf.push(']'),
                 Self::HexDump => bf.push('#'),
                 Self::DecDump => bf.push('$'),
+                _ => unreachable!(),
             }
         } else if target_cell_bytes == 2 {
             match self {
@@ -267,9 +399,9 @@ impl Op {
                     }
                 }
                 Self::Zero => {
-                    Self::While.write_bf(bf, target_cell_bytes);
-                    Self::Add(-1).write_bf(bf, target_cell_bytes);
-                    Self::End.write_bf(bf, target_cell_bytes);
+                    Self::While.write_bf(bf, target_cell_bytes)?;
+                    Self::Add(-1).write_bf(bf, target_cell_bytes)?;
+                    Self::End.write_bf(bf, target_cell_bytes)?;
                 },
                 Self::Put => bf.push('.'),
                 Self::Get => bf.push(','),
@@ -277,6 +409,7 @@ impl Op {
                 Self::End => bf.push_str("[>>+>>>+<<<<<-]>>>>>[<<<<<+>>>>>-]<<<[[-]<<<+>>>]<[>+>>>+<<<<-]>>>>[<<<<+>>>>-]<<<[[-]<<<+>>>]<<<]>"),
                 Self::HexDump => bf.push('#'),
                 Self::DecDump => bf.push('$'),
+                _ => unreachable!(),
             }
         } else if target_cell_bytes == 4 {
             match self {
@@ -295,9 +428,9 @@ impl Op {
                     }
                 }
                 Self::Zero => {
-                    Self::While.write_bf(bf, target_cell_bytes);
-                    Self::Add(-1).write_bf(bf, target_cell_bytes);
-                    Self::End.write_bf(bf, target_cell_bytes);
+                    Self::While.write_bf(bf, target_cell_bytes)?;
+                    Self::Add(-1).write_bf(bf, target_cell_bytes)?;
+                    Self::End.write_bf(bf, target_cell_bytes)?;
                 },
                 Self::Put => bf.push('.'),
                 Self::Get => bf.push(','),
@@ -305,10 +438,12 @@ impl Op {
                 Self::End => bf.push_str("[>>>>+>>>>>+<<<<<<<<<-]>>>>>>>>>[<<<<<<<<<+>>>>>>>>>-]<<<<<[[-]<<<<<+>>>>>]<<<[>>>+>>>>>+<<<<<<<<-]>>>>>>>>[<<<<<<<<+>>>>>>>>-]<<<<<[[-]<<<<<+>>>>>]<<[>>+>>>>>+<<<<<<<-]>>>>>>>[<<<<<<<+>>>>>>>-]<<<<<[[-]<<<<<+>>>>>]<[>+>>>>>+<<<<<<-]>>>>>>[<<<<<<+>>>>>>-]<<<<<[[-]<<<<<+>>>>>]<<<<<]>"),
                 Self::HexDump => bf.push('#'),
                 Self::DecDump => bf.push('$'),
+                _ => unreachable!(),
             }
         } else {
-            panic!("Unsupported cell size: {target_cell_bytes}");
+            return Err(BfError::UnsupportedCellSize(target_cell_bytes));
         }
+        Ok(())
     }
 
     //     pub fn to_c(&self) -> String {
@@ -350,6 +485,16 @@ impl Op {
             Self::Get => bf.push_str("*ptr = (ch = getchar()) == EOF? 0 : ch;"),
             Self::While => bf.push_str("while (*ptr) {"),
             Self::End => bf.push('}'),
+            Self::MulAdd { offset, factor: 1 } => bf.push_str(&format!("ptr[{offset}] += *ptr;")),
+            Self::MulAdd { offset, factor: -1 } => bf.push_str(&format!("ptr[{offset}] -= *ptr;")),
+            Self::MulAdd { offset, factor } => {
+                bf.push_str(&format!("ptr[{offset}] += *ptr * {factor};"))
+            }
+            Self::Scan(n) if *n < 0 => bf.push_str(&format!("while (*ptr) ptr -= {};", -n)),
+            Self::Scan(n) => bf.push_str(&format!("while (*ptr) ptr += {n};")),
+            Self::AddAt { offset, value } => bf.push_str(&format!("ptr[{offset}] += {value};")),
+            Self::ZeroAt(offset) => bf.push_str(&format!("ptr[{offset}] = 0;")),
+            Self::PutAt(offset) => bf.push_str(&format!("putchar(ptr[{offset}]);")),
             Self::HexDump => bf.push_str(
                 r#"for (int i = 0; i < 0x100; i++) {
     if (i % 16 == 0) {
@@ -0,0 +1,239 @@
+use super::*;
+
+use std::collections::BTreeMap;
+
+/// Replace common loop idioms with single operations, and address cells by
+/// their offset from the pointer instead of moving the pointer back and forth.
+///
+/// Most of the code the assembler generates is copy and multiply loops like
+/// `[->+>+<<]`, which become `MulAdd`s followed by a `Zero`, and scans over
+/// tables like `[>>]`, which become a `Scan`. The result runs the same as the
+/// input, but it can't be written back as BrainFuck.
+pub fn optimize(ops: Vec<Op>) -> Vec<Op> {
+    address_by_offset(replace_loops(ops))
+}
+
+/// Replace every innermost loop that matches an idiom.
+fn replace_loops(ops: Vec<Op>) -> Vec<Op> {
+    let mut result = Vec::with_capacity(ops.len());
+    // The index in `result` of each `While` that hasn't been closed yet
+    let mut open = vec![];
+    for op in ops {
+        match op {
+            Op::While => open.push(result.len()),
+            Op::End => {
+                if let Some(start) = open.pop() {
+                    if let Some(replacement) = replace_loop(&result[start + 1..]) {
+                        result.truncate(start);
+                        result.extend(replacement);
+                        continue;
+                    }
+                }
+            }
+            _ => {}
+        }
+        result.push(op);
+    }
+    result
+}
+
+/// Find the idiom a loop body implements, if any.
+fn replace_loop(body: &[Op]) -> Option<Vec<Op>> {
+    // `[>>]` moves until it finds a zero cell
+    if let [Op::Move(stride)] = body {
+        return Some(vec![Op::Scan(*stride)]);
+    }
+
+    // A loop that only adds and moves, ends where it started, and decrements
+    // its counter once per iteration, adds a multiple of the counter to each
+    // cell it touches
+    let mut offset = 0;
+    let mut factors = BTreeMap::new();
+    for op in body {
+        match op {
+            Op::Move(n) => offset += n,
+            Op::Add(n) => *factors.entry(offset).or_insert(0) += n,
+            _ => return None,
+        }
+    }
+    if offset != 0 || factors.remove(&0) != Some(-1) {
+        return None;
+    }
+    Some(
+        factors
+            .into_iter()
+            .filter(|&(_, factor)| factor != 0)
+            .map(|(offset, factor)| Op::MulAdd { offset, factor })
+            .chain([Op::Zero])
+            .collect(),
+    )
+}
+
+/// Within each run of code without loops or input, address cells by their
+/// offset from where the run started, and move the pointer once at its end.
+fn address_by_offset(ops: Vec<Op>) -> Vec<Op> {
+    let mut result = Vec::with_capacity(ops.len());
+    let mut offset = 0;
+    for op in ops {
+        match (op, offset) {
+            (Op::Move(n), _) => offset += n,
+            (Op::Add(_) | Op::Zero | Op::Put, 0) => result.push(op),
+            (Op::Add(value), _) => match result.last_mut() {
+                Some(Op::AddAt {
+                    offset: last,
+                    value: total,
+                }) if *last == offset => *total += value,
+                _ => result.push(Op::AddAt { offset, value }),
+            },
+            (Op::Zero, _) => result.push(Op::ZeroAt(offset)),
+            (Op::Put, _) => result.push(Op::PutAt(offset)),
+            _ => {
+                if offset != 0 {
+                    result.push(Op::Move(offset));
+                    offset = 0;
+                }
+                result.push(op);
+            }
+        }
+    }
+    if offset != 0 {
+        result.push(Op::Move(offset));
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn optimized(bf: &str) -> Vec<Op> {
+        optimize(parse::parse(bf).unwrap())
+    }
+
+    #[test]
+    fn test_copy_and_multiply_loops() {
+        assert_eq!(
+            optimized("[->+>+++<<]"),
+            [
+                Op::MulAdd {
+                    offset: 1,
+                    factor: 1
+                },
+                Op::MulAdd {
+                    offset: 2,
+                    factor: 3
+                },
+                Op::Zero
+            ]
+        );
+        assert_eq!(
+            optimized("[<<->>-]"),
+            [
+                Op::MulAdd {
+                    offset: -2,
+                    factor: -1
+                },
+                Op::Zero
+            ]
+        );
+        // Loops that move, or don't count down by one, are left alone
+        assert_eq!(optimized("[->+]").len(), 5);
+        assert_eq!(optimized("[-->+<]").len(), 4);
+        assert_eq!(optimized("[->.<]").len(), 4);
+    }
+
+    #[test]
+    fn test_scan_loops() {
+        assert_eq!(optimized("[>>]"), [Op::Scan(2)]);
+        assert_eq!(optimized("[<]"), [Op::Scan(-1)]);
+        // Only innermost loops are replaced
+        assert_eq!(
+            optimized("[[>>]<-]"),
+            [
+                Op::While,
+                Op::Scan(2),
+                Op::AddAt {
+                    offset: -1,
+                    value: -1
+                },
+                Op::Move(-1),
+                Op::End
+            ]
+        );
+    }
+
+    #[test]
+    fn test_offset_addressing() {
+        assert_eq!(
+            optimized(">+>>--<.<[-]+>"),
+            [
+                Op::AddAt {
+                    offset: 1,
+                    value: 1
+                },
+                Op::AddAt {
+                    offset: 3,
+                    value: -2
+                },
+                Op::PutAt(2),
+                Op::ZeroAt(1),
+                Op::AddAt {
+                    offset: 1,
+                    value: 1
+                },
+                Op::Move(2)
+            ]
+        );
+        // The pointer is moved into place before loops and input
+        assert_eq!(
+            optimized(">+>,<[>]"),
+            [
+                Op::AddAt {
+                    offset: 1,
+                    value: 1
+                },
+                Op::Move(2),
+                Op::Get,
+                Op::Move(-1),
+                Op::Scan(1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_optimized_programs_run_the_same() {
+        let program = r#"
+            main:
+                R0 = 6
+                R1 = 7
+                R2 mul R0, R1
+                putint R2
+                push R2
+                R3 = [SP]
+                R4 alloc 3
+                {R4} = 'x'
+                putchar {R4}
+                putint R3
+        "#;
+        let bf = crate::Program::parse(program).unwrap().assemble();
+        let ops = parse::parse(&bf).unwrap();
+        for cell_bytes in [1, 2, 4] {
+            let mut output = Vec::new();
+            Interpreter::new(optimize(ops.clone()), cell_bytes, crate::TAPE_SIZE)
+                .unwrap()
+                .run(&mut "".as_bytes(), &mut output)
+                .unwrap();
+            assert_eq!(output, b"42x42");
+        }
+        assert!(optimize(ops.clone()).len() < ops.len());
+    }
+
+    #[test]
+    fn test_c_uses_idioms() {
+        let c = compile_to_c("+++[->++>+<<]>>[<]>.".into(), 1).unwrap();
+        assert!(c.contains("ptr[1] += *ptr * 2;"), "{c}");
+        assert!(c.contains("ptr[2] += *ptr;"), "{c}");
+        assert!(c.contains("while (*ptr) ptr -= 1;"), "{c}");
+        assert!(!c.contains("while (*ptr) {"), "{c}");
+    }
+}
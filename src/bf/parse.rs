@@ -19,14 +19,15 @@ pub type Error<'a> = VerboseError<&'a str>;
 /// A convenient alias for our IResult with that error type.
 pub type Res<'a, T> = IResult<&'a str, T, Error<'a>>;
 
-fn parse_comments(input: &str) -> Res<()> {
+fn parse_comments(input: &str) -> Res<'_, ()> {
     let (input, _) = many0(none_of("[]<>+-.,#$"))(input)?;
     Ok((input, ()))
 }
 
-/// Parses a single operator into an `Op`
-fn parse_op(input: &str) -> Res<Op> {
+/// Parses a single operator into an `Op`, along with the input from where it starts
+fn parse_op(input: &str) -> Res<'_, (&str, Op)> {
     let (input, _) = parse_comments(input)?;
+    let start = input;
     let (input, result) = alt((
         map(tag("[-]"), |_| Op::Zero), // Detect `[-]` as Zero
         map(char('>'), |_| Op::Move(1)),
@@ -42,17 +43,19 @@ fn parse_op(input: &str) -> Res<Op> {
     ))(input)?;
     let (input, _) = parse_comments(input)?;
 
-    Ok((input, result))
+    Ok((input, (start, result)))
 }
 
 /// Parses multiple operations while **coalescing** `Move(n)` and `Add(n)`.
-fn parse_ops(input: &str) -> Res<Vec<Op>> {
+///
+/// A coalesced operation starts where the first of its operators does.
+fn parse_ops(input: &str) -> Res<'_, Vec<(&str, Op)>> {
     let (input, ops) = all_consuming(many0(parse_op))(input)?;
-    let mut optimized_ops: Vec<Op> = Vec::new();
+    let mut optimized_ops: Vec<(&str, Op)> = Vec::new();
 
     for op in ops {
-        if let Some(last) = optimized_ops.last_mut() {
-            if last.coalesce(op) {
+        if let Some((_, last)) = optimized_ops.last_mut() {
+            if last.coalesce(op.1) {
                 continue;
             }
         }
@@ -62,22 +65,43 @@ fn parse_ops(input: &str) -> Res<Vec<Op>> {
     Ok((input, optimized_ops))
 }
 
-pub fn parse(input: &str) -> Result<Vec<Op>, String> {
+/// Parse a whole BrainFuck program, whose brackets must be balanced.
+pub fn parse(input: &str) -> Result<Vec<Op>, BfError> {
+    Ok(LoopTree::parse(input)?.to_ops())
+}
+
+/// Parse a piece of BrainFuck code, which may have unbalanced brackets.
+pub fn parse_fragment(input: &str) -> Result<Vec<Op>, BfError> {
+    Ok(parse_located(input)?
+        .into_iter()
+        .map(|(_, op)| op)
+        .collect())
+}
+
+/// Parse a piece of BrainFuck code, along with the byte offset where each
+/// operation starts.
+pub fn parse_located(input: &str) -> Result<Vec<(usize, Op)>, BfError> {
+    let error = |rest: &str, message: String| BfError::Parse {
+        offset: input.len() - rest.len(),
+        message,
+    };
     match parse_ops(input) {
         Ok((rest, program)) => {
             if rest.is_empty() {
-                Ok(program)
+                Ok(program
+                    .into_iter()
+                    .map(|(start, op)| (input.len() - start.len(), op))
+                    .collect())
             } else {
-                Err(format!(
-                    "Failed to parse the entire input. Remaining: {}",
-                    rest
-                ))
+                Err(error(rest, "Failed to parse the entire input".into()))
             }
         }
         Err(e) => match e {
-            nom::Err::Error(e) => Err(format!("Error: {}", convert_error(input, e))),
-            nom::Err::Failure(e) => Err(format!("Failure: {}", convert_error(input, e))),
-            nom::Err::Incomplete(_) => Err("Incomplete input".into()),
+            nom::Err::Error(e) | nom::Err::Failure(e) => {
+                let rest = e.errors.first().map_or(input, |(rest, _)| rest);
+                Err(error(rest, convert_error(input, e.clone())))
+            }
+            nom::Err::Incomplete(_) => Err(error("", "Incomplete input".into())),
         },
     }
 }
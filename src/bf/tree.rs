@@ -0,0 +1,261 @@
+use super::*;
+
+/// A position in BrainFuck source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    /// The byte offset from the start of the source.
+    pub offset: usize,
+    /// The line, starting at 1.
+    pub line: usize,
+    /// The column, starting at 1.
+    pub column: usize,
+}
+
+impl Position {
+    /// The position of the byte at `offset` in `source`.
+    pub fn new(source: &str, offset: usize) -> Self {
+        Positions::new(source).at(offset)
+    }
+}
+
+/// Finds the positions of increasing offsets in a source, without going
+/// back over the text before the last one.
+struct Positions<'a> {
+    source: &'a str,
+    last: Position,
+}
+
+impl<'a> Positions<'a> {
+    fn new(source: &'a str) -> Self {
+        Self {
+            source,
+            last: Position {
+                offset: 0,
+                line: 1,
+                column: 1,
+            },
+        }
+    }
+
+    fn at(&mut self, offset: usize) -> Position {
+        for c in self.source[self.last.offset..offset].chars() {
+            if c == '\n' {
+                self.last.line += 1;
+                self.last.column = 1;
+            } else {
+                self.last.column += 1;
+            }
+        }
+        self.last.offset = offset;
+        self.last
+    }
+}
+
+/// A piece of a BrainFuck program: an operation, or a loop and everything in it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoopNode {
+    Op(Op),
+    Loop(Loop),
+}
+
+/// A loop, from a `[` to its matching `]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Loop {
+    /// Where the `[` is.
+    pub start: Position,
+    /// Where the `]` is.
+    pub end: Position,
+    /// The operations and loops inside the loop.
+    pub body: Vec<LoopNode>,
+}
+
+impl Loop {
+    /// How deeply loops are nested inside this one, counting itself.
+    pub fn depth(&self) -> usize {
+        1 + depth(&self.body)
+    }
+}
+
+fn depth(nodes: &[LoopNode]) -> usize {
+    nodes
+        .iter()
+        .map(|node| match node {
+            LoopNode::Op(_) => 0,
+            LoopNode::Loop(l) => l.depth(),
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+fn collect_loops<'a>(nodes: &'a [LoopNode], loops: &mut Vec<&'a Loop>) {
+    for node in nodes {
+        if let LoopNode::Loop(l) = node {
+            loops.push(l);
+            collect_loops(&l.body, loops);
+        }
+    }
+}
+
+fn flatten(nodes: &[LoopNode], ops: &mut Vec<Op>) {
+    for node in nodes {
+        match node {
+            LoopNode::Op(op) => ops.push(*op),
+            LoopNode::Loop(l) => {
+                ops.push(Op::While);
+                flatten(&l.body, ops);
+                ops.push(Op::End);
+            }
+        }
+    }
+}
+
+/// A BrainFuck program with every `[` matched to its `]`.
+///
+/// Parsing fails with the line and column of the first bracket found to be
+/// unmatched. A `[-]` is a single [`Op::Zero`](crate::BfOp::Zero), not a loop.
+///
+/// ```rust
+/// use basm::{BfError, LoopTree};
+///
+/// let tree = LoopTree::parse("+[>[->+<]\n<-]").unwrap();
+/// assert_eq!(tree.depth(), 2);
+/// let loops = tree.loops();
+/// assert_eq!((loops[0].start.line, loops[0].end.line), (1, 2));
+/// assert_eq!(loops[1].body.len(), 4);
+///
+/// let Err(BfError::UnmatchedBracket { bracket, position }) = LoopTree::parse("+\n+]") else {
+///     panic!("Expected an unmatched bracket");
+/// };
+/// assert_eq!((bracket, position.line, position.column), (']', 2, 2));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoopTree {
+    nodes: Vec<LoopNode>,
+}
+
+impl LoopTree {
+    /// Parse BrainFuck source and match up its loops.
+    pub fn parse(bf: &str) -> Result<Self, BfError> {
+        let mut positions = Positions::new(bf);
+        // The start of each loop we're inside, and the nodes outside of it so far
+        let mut open: Vec<(Position, Vec<LoopNode>)> = vec![];
+        let mut nodes = vec![];
+        for (offset, op) in parse::parse_located(bf)? {
+            match op {
+                Op::While => open.push((positions.at(offset), std::mem::take(&mut nodes))),
+                Op::End => {
+                    let end = positions.at(offset);
+                    let Some((start, outer)) = open.pop() else {
+                        return Err(BfError::UnmatchedBracket {
+                            bracket: ']',
+                            position: end,
+                        });
+                    };
+                    let body = std::mem::replace(&mut nodes, outer);
+                    nodes.push(LoopNode::Loop(Loop { start, end, body }));
+                }
+                op => nodes.push(LoopNode::Op(op)),
+            }
+        }
+        match open.pop() {
+            Some((start, _)) => Err(BfError::UnmatchedBracket {
+                bracket: '[',
+                position: start,
+            }),
+            None => Ok(Self { nodes }),
+        }
+    }
+
+    /// The operations and loops at the top level of the program.
+    pub fn nodes(&self) -> &[LoopNode] {
+        &self.nodes
+    }
+
+    /// Every loop in the program, each one before the loops inside it.
+    pub fn loops(&self) -> Vec<&Loop> {
+        let mut loops = vec![];
+        collect_loops(&self.nodes, &mut loops);
+        loops
+    }
+
+    /// How deeply the program's loops are nested, or 0 if it has none.
+    pub fn depth(&self) -> usize {
+        depth(&self.nodes)
+    }
+
+    /// The program's operations, with each loop between a `While` and an `End`.
+    pub fn to_ops(&self) -> Vec<Op> {
+        let mut ops = vec![];
+        flatten(&self.nodes, &mut ops);
+        ops
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loop_tree() {
+        let bf = "a+[>,[-]\n[.>]<]\n[]";
+        let tree = LoopTree::parse(bf).unwrap();
+        assert_eq!(tree.depth(), 2);
+        assert_eq!(tree.nodes().len(), 3);
+        let loops = tree.loops();
+        assert_eq!(loops.len(), 3);
+        assert_eq!(
+            (loops[0].start, loops[0].end),
+            (
+                Position {
+                    offset: 2,
+                    line: 1,
+                    column: 3
+                },
+                Position {
+                    offset: 14,
+                    line: 2,
+                    column: 6
+                }
+            )
+        );
+        assert_eq!(loops[0].depth(), 2);
+        assert_eq!(
+            loops[1].body,
+            [LoopNode::Op(Op::Put), LoopNode::Op(Op::Move(1))]
+        );
+        assert!(loops[2].body.is_empty());
+        assert_eq!(tree.to_ops(), parse::parse_fragment(bf).unwrap());
+    }
+
+    #[test]
+    fn test_unmatched_brackets() {
+        let error = LoopTree::parse("[[]\n]]").unwrap_err();
+        assert!(matches!(
+            error,
+            BfError::UnmatchedBracket {
+                bracket: ']',
+                position: Position {
+                    offset: 5,
+                    line: 2,
+                    column: 2
+                }
+            }
+        ));
+        assert_eq!(error.to_string(), "Unmatched `]` at line 2, column 2");
+
+        // Columns count characters, not bytes
+        let error = LoopTree::parse("+\n\u{e9}[[]").unwrap_err();
+        assert!(matches!(
+            error,
+            BfError::UnmatchedBracket {
+                bracket: '[',
+                position: Position {
+                    line: 2,
+                    column: 2,
+                    ..
+                }
+            }
+        ));
+        assert_eq!(Position::new("ab\ncd", 4).column, 2);
+    }
+}
@@ -0,0 +1,382 @@
+//! WebAssembly text modules.
+//!
+//! The tape is at the start of the module's exported linear memory, and the
+//! program talks to the host through `getchar` and `putchar` functions it
+//! imports from `env`. The host's `getchar` returns a negative number at the
+//! end of the input, which stores zero in the cell like the other backends.
+//! The program itself is the exported `main` function.
+use super::*;
+
+/// The size of a page of WebAssembly memory.
+const PAGE_SIZE: usize = 0x1_0000;
+
+/// Prints `$n` in `$base`, padded with `$pad` to at least `$width` digits.
+const NUMBER: &str = r#"  (func $number (param $n i32) (param $base i32) (param $width i32) (param $pad i32)
+    (local $digit i32)
+    (if (i32.ge_u (local.get $n) (local.get $base))
+      (then
+        (call $number
+          (i32.div_u (local.get $n) (local.get $base))
+          (local.get $base)
+          (i32.sub (local.get $width) (i32.const 1))
+          (local.get $pad)))
+      (else
+        (block $padded
+          (loop $padding
+            (br_if $padded (i32.le_s (local.get $width) (i32.const 1)))
+            (call $putchar (local.get $pad))
+            (local.set $width (i32.sub (local.get $width) (i32.const 1)))
+            (br $padding)))))
+    (local.set $digit (i32.rem_u (local.get $n) (local.get $base)))
+    (call $putchar
+      (i32.add
+        (local.get $digit)
+        (select (i32.const 48) (i32.const 87) (i32.lt_u (local.get $digit) (i32.const 10)))))
+  )
+"#;
+
+/// Writes the module for a program, one operation at a time.
+struct Module {
+    wat: String,
+    bytes: u8,
+    load: &'static str,
+    store: &'static str,
+    /// How deeply the next line is nested
+    depth: usize,
+}
+
+impl Module {
+    fn line(&mut self, line: &str) {
+        for _ in 0..self.depth {
+            self.wat.push_str("  ");
+        }
+        self.wat.push_str(line);
+        self.wat.push('\n');
+    }
+
+    /// Start a block whose body is indented, until the matching `close`.
+    fn open(&mut self, line: &str) {
+        self.line(line);
+        self.depth += 1;
+    }
+
+    fn close(&mut self) {
+        self.depth -= 1;
+        self.line(")");
+    }
+
+    /// The address of the cell `offset` cells away from the pointer.
+    fn address(&self, offset: i32) -> String {
+        match offset {
+            0 => "(local.get $ptr)".to_string(),
+            _ => format!(
+                "(i32.add (local.get $ptr) (i32.const {}))",
+                offset * self.bytes as i32
+            ),
+        }
+    }
+
+    fn load(&self, offset: i32) -> String {
+        format!("({} {})", self.load, self.address(offset))
+    }
+
+    /// The low byte of a cell, which is all that gets printed.
+    fn load_byte(&self, offset: i32) -> String {
+        format!("(i32.load8_u {})", self.address(offset))
+    }
+
+    fn store(&mut self, offset: i32, value: &str) {
+        let line = format!("({} {} {value})", self.store, self.address(offset));
+        self.line(&line);
+    }
+
+    /// Store the current cell plus `value` in the cell at `offset`. The store
+    /// drops the high bits, so this wraps around at the cell size.
+    fn add(&mut self, offset: i32, value: &str) {
+        let sum = format!("(i32.add {} {value})", self.load(offset));
+        self.store(offset, &sum);
+    }
+
+    fn move_pointer(&mut self, n: i32) {
+        let line = format!(
+            "(local.set $ptr (i32.add (local.get $ptr) (i32.const {})))",
+            n * self.bytes as i32
+        );
+        self.line(&line);
+    }
+
+    /// Leave the enclosing `block` if the current cell is zero.
+    fn break_if_zero(&mut self, label: &str) {
+        let line = format!("(br_if {label} (i32.eqz {}))", self.load(0));
+        self.line(&line);
+    }
+}
+
+/// Compile optimized operations to a WebAssembly text module with a tape
+/// of `tape_size` cells of `bytes` bytes each.
+pub(super) fn compile(ops: &[Op], bytes: u8, tape_size: usize) -> Result<String, BfError> {
+    let (load, store) = match bytes {
+        1 => ("i32.load8_u", "i32.store8"),
+        2 => ("i32.load16_u", "i32.store16"),
+        4 => ("i32.load", "i32.store"),
+        _ => return Err(BfError::UnsupportedCellSize(bytes)),
+    };
+    // The dumps always read the first 256 cells
+    let pages = (tape_size.max(0x100) * bytes as usize).div_ceil(PAGE_SIZE);
+    let mut module = Module {
+        wat: String::new(),
+        bytes,
+        load,
+        store,
+        depth: 0,
+    };
+    module.open("(module");
+    module.line(r#"(import "env" "getchar" (func $getchar (result i32)))"#);
+    module.line(r#"(import "env" "putchar" (func $putchar (param i32)))"#);
+    module.line(&format!(r#"(memory (export "memory") {pages})"#));
+    module.open(r#"(func $main (export "main")"#);
+    module.line("(local $ptr i32)");
+    module.line("(local $char i32)");
+
+    // The index of each loop we're inside, which also names its labels
+    let mut loops = vec![];
+    let mut dumps = false;
+    for (i, op) in ops.iter().enumerate() {
+        match *op {
+            Op::Move(n) => module.move_pointer(n),
+            Op::Add(n) => module.add(0, &format!("(i32.const {n})")),
+            Op::Zero => module.store(0, "(i32.const 0)"),
+            Op::Put => {
+                let line = format!("(call $putchar {})", module.load_byte(0));
+                module.line(&line);
+            }
+            Op::Get => {
+                module.line("(local.set $char (call $getchar))");
+                module.store(
+                    0,
+                    "(select (i32.const 0) (local.get $char) (i32.lt_s (local.get $char) (i32.const 0)))",
+                );
+            }
+            Op::While => {
+                loops.push(i);
+                module.open(&format!("(block $end{i}"));
+                module.open(&format!("(loop $loop{i}"));
+                module.break_if_zero(&format!("$end{i}"));
+            }
+            Op::End => {
+                let start = loops.pop().ok_or(BfError::UnmatchedBracket {
+                    bracket: ']',
+                    position: interp::op_position(i),
+                })?;
+                module.line(&format!("(br $loop{start})"));
+                module.close();
+                module.close();
+            }
+            Op::MulAdd { offset, factor } => {
+                let product = format!("(i32.mul {} (i32.const {factor}))", module.load(0));
+                module.add(offset, &product);
+            }
+            Op::Scan(n) => {
+                module.open("(block");
+                module.open("(loop");
+                module.break_if_zero("1");
+                module.move_pointer(n);
+                module.line("(br 0)");
+                module.close();
+                module.close();
+            }
+            Op::AddAt { offset, value } => module.add(offset, &format!("(i32.const {value})")),
+            Op::ZeroAt(offset) => module.store(offset, "(i32.const 0)"),
+            Op::PutAt(offset) => {
+                let line = format!("(call $putchar {})", module.load_byte(offset));
+                module.line(&line);
+            }
+            Op::HexDump | Op::DecDump => {
+                dumps = true;
+                let hex = (*op == Op::HexDump) as u8;
+                module.line(&format!("(call $dump (i32.const {hex}))"));
+            }
+        }
+    }
+    if let Some(start) = loops.pop() {
+        return Err(BfError::UnmatchedBracket {
+            bracket: '[',
+            position: interp::op_position(start),
+        });
+    }
+    module.close();
+
+    if dumps {
+        write_dump(&mut module);
+        module.wat.push_str(NUMBER);
+    }
+    module.close();
+    Ok(module.wat)
+}
+
+/// Write the function that prints the first 256 cells, formatted like the
+/// C backend's dumps.
+fn write_dump(module: &mut Module) {
+    let cell = format!(
+        "({} (i32.mul (local.get $i) (i32.const {})))",
+        module.load, module.bytes
+    );
+    module.open("(func $dump (param $hex i32)");
+    module.line("(local $i i32)");
+    module.open("(loop $cells");
+    module.open("(if (i32.eqz (i32.rem_u (local.get $i) (i32.const 16)))");
+    module.open("(then");
+    module.line("(call $number (local.get $i) (i32.const 10) (i32.const 3) (i32.const 48))");
+    module.line("(call $putchar (i32.const 45))");
+    module.line(
+        "(call $number (i32.add (local.get $i) (i32.const 15)) (i32.const 10) (i32.const 3) (i32.const 48))",
+    );
+    module.line("(call $putchar (i32.const 58))");
+    module.line("(call $putchar (i32.const 32))");
+    module.close();
+    module.close();
+    module.open("(if (local.get $hex)");
+    module.line(&format!(
+        "(then (call $number {cell} (i32.const 16) (i32.const 2) (i32.const 48)))"
+    ));
+    module.line(&format!(
+        "(else (call $number {cell} (i32.const 10) (i32.const 3) (i32.const 32)))"
+    ));
+    module.close();
+    module.line("(call $putchar (i32.const 32))");
+    module.open("(if (i32.eq (i32.rem_u (local.get $i) (i32.const 16)) (i32.const 15))");
+    module.line("(then (call $putchar (i32.const 10)))");
+    module.close();
+    module.line("(local.set $i (i32.add (local.get $i) (i32.const 1)))");
+    module.line("(br_if $cells (i32.lt_u (local.get $i) (i32.const 256)))");
+    module.close();
+    module.close();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wat(bf: &str, bytes: u8, tape_size: usize) -> String {
+        compile(&optimize(parse::parse(bf).unwrap()), bytes, tape_size).unwrap()
+    }
+
+    /// Does every parenthesis close, outside of strings?
+    fn is_balanced(wat: &str) -> bool {
+        let mut depth = 0;
+        for part in wat.split('"').step_by(2) {
+            for c in part.chars() {
+                match c {
+                    '(' => depth += 1,
+                    ')' if depth == 0 => return false,
+                    ')' => depth -= 1,
+                    _ => {}
+                }
+            }
+        }
+        depth == 0
+    }
+
+    #[test]
+    fn test_module() {
+        let wat = wat(",[.,]", 1, crate::TAPE_SIZE);
+        assert!(is_balanced(&wat));
+        assert!(wat.starts_with("(module\n"));
+        assert!(wat.contains(r#"(import "env" "getchar" (func $getchar (result i32)))"#));
+        assert!(wat.contains(r#"(import "env" "putchar" (func $putchar (param i32)))"#));
+        assert!(wat.contains(r#"(memory (export "memory") 1)"#));
+        assert!(wat.contains(r#"(func $main (export "main")"#));
+        // The end of input stores zero
+        assert!(wat.contains("(local.set $char (call $getchar))"));
+        assert!(wat.contains("(i32.lt_s (local.get $char) (i32.const 0))"));
+        // A loop is a block to leave it, around a loop to repeat it
+        assert!(wat.contains(
+            "    (block $end1
+      (loop $loop1
+        (br_if $end1 (i32.eqz (i32.load8_u (local.get $ptr))))
+        (call $putchar (i32.load8_u (local.get $ptr)))"
+        ));
+        assert!(wat.contains("        (br $loop1)\n      )\n    )\n"));
+        // The dump routines are only written for programs that dump
+        assert!(!wat.contains("$dump"));
+        assert!(!wat.contains("$number"));
+    }
+
+    #[test]
+    fn test_nested_loops() {
+        let wat = wat(",[>,[.,]<.]", 1, 100);
+        assert!(is_balanced(&wat));
+        let outer = wat.find("(block $end1").unwrap();
+        let inner = wat.find("(block $end4").unwrap();
+        let inner_end = wat.find("(br $loop4)").unwrap();
+        let outer_end = wat.find("(br $loop1)").unwrap();
+        assert!(outer < inner && inner < inner_end && inner_end < outer_end);
+        assert!(wat.contains("\n            (br $loop4)\n"));
+    }
+
+    #[test]
+    fn test_cell_sizes() {
+        for (bytes, load, store) in [
+            (1, "i32.load8_u", "i32.store8"),
+            (2, "i32.load16_u", "i32.store16"),
+            (4, "i32.load", "i32.store"),
+        ] {
+            let wat = wat("+>>-[>>]<<,.", bytes, crate::TAPE_SIZE);
+            assert!(is_balanced(&wat));
+            assert!(wat.contains(&format!(
+                "({store} (local.get $ptr) (i32.add ({load} (local.get $ptr)) (i32.const 1)))"
+            )));
+            // Moving the pointer counts bytes, not cells
+            assert!(wat.contains(&format!(
+                "(local.set $ptr (i32.add (local.get $ptr) (i32.const {})))",
+                2 * bytes
+            )));
+            assert!(wat.contains(&format!("(br_if 1 (i32.eqz ({load} (local.get $ptr))))")));
+            // Only the low byte of a cell is printed
+            assert!(wat.contains("(call $putchar (i32.load8_u (local.get $ptr)))"));
+        }
+
+        // The memory holds the whole tape
+        assert!(wat("+", 4, crate::TAPE_SIZE).contains(r#"(memory (export "memory") 2)"#));
+        assert!(wat("+", 1, 0x1_0001).contains(r#"(memory (export "memory") 2)"#));
+        assert!(wat("+", 1, 1).contains(r#"(memory (export "memory") 1)"#));
+    }
+
+    #[test]
+    fn test_dumps() {
+        let wat = wat("+#>$", 2, crate::TAPE_SIZE);
+        assert!(is_balanced(&wat));
+        assert!(wat.contains("(call $dump (i32.const 1))"));
+        assert!(wat.contains("(call $dump (i32.const 0))"));
+        assert!(wat.contains("  (func $dump (param $hex i32)\n"));
+        assert!(wat.contains("  (func $number (param $n i32)"));
+        assert!(wat.contains("(i32.load16_u (i32.mul (local.get $i) (i32.const 2)))"));
+        assert!(wat.ends_with("  )\n)\n"));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(matches!(
+            compile_to_wat("+".to_string(), 3),
+            Err(BfError::UnsupportedCellSize(3))
+        ));
+        assert!(matches!(
+            compile_to_wat("+\n+[".to_string(), 1),
+            Err(BfError::UnmatchedBracket {
+                bracket: '[',
+                position: Position {
+                    line: 2,
+                    column: 2,
+                    ..
+                }
+            })
+        ));
+        assert!(matches!(
+            compile(&[Op::Add(1), Op::End], 1, 10),
+            Err(BfError::UnmatchedBracket {
+                bracket: ']',
+                position: Position { offset: 1, .. }
+            })
+        ));
+    }
+}
@@ -0,0 +1,453 @@
+//! Lowering optimized BrainFuck operations to x86-64 machine code.
+//!
+//! The generated code keeps the tape pointer in `rbx`, and leaves reading,
+//! writing, dumping the tape, and stopping to a [`Runtime`], so the same
+//! lowering can be wrapped up as an executable or run in place.
+// Some instructions are only used to call back into Rust from the JIT
+#![cfg_attr(
+    not(all(target_arch = "x86_64", target_os = "linux")),
+    allow(dead_code)
+)]
+use super::{interp::op_position, *};
+
+pub(super) const RAX: u8 = 0;
+pub(super) const RCX: u8 = 1;
+pub(super) const RDX: u8 = 2;
+pub(super) const RBX: u8 = 3;
+pub(super) const RSP: u8 = 4;
+pub(super) const RSI: u8 = 6;
+pub(super) const RDI: u8 = 7;
+pub(super) const R8: u8 = 8;
+pub(super) const R9: u8 = 9;
+pub(super) const R12: u8 = 12;
+pub(super) const R13: u8 = 13;
+pub(super) const R14: u8 = 14;
+pub(super) const R15: u8 = 15;
+
+/// The condition of a conditional jump, as the low nibble of its opcode.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Cond {
+    Below = 0x2,
+    AboveOrEqual = 0x3,
+    Equal = 0x4,
+    NotEqual = 0x5,
+    BelowOrEqual = 0x6,
+    Sign = 0x8,
+}
+
+/// A place in the code that jumps and calls can refer to before it is bound.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Label(usize);
+
+/// A buffer of machine code, with methods for the instructions we use.
+///
+/// Memory operands are all `[base + index * scale + disp32]`, always encoded
+/// with a SIB byte so that any register can be the base.
+#[derive(Debug, Default)]
+pub(super) struct Code {
+    bytes: Vec<u8>,
+    /// Where each label is bound, once it is
+    labels: Vec<Option<usize>>,
+    /// The position of each 32-bit displacement to a label
+    fixups: Vec<(usize, Label)>,
+}
+
+impl Code {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resolve every jump and call, and return the machine code.
+    pub fn finish(mut self) -> Vec<u8> {
+        for (at, Label(label)) in std::mem::take(&mut self.fixups) {
+            let target = self.labels[label].expect("Jump to a label that was never bound");
+            let rel = target as i64 - (at as i64 + 4);
+            self.bytes[at..at + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+        }
+        self.bytes
+    }
+
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    pub fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.bytes.len());
+    }
+
+    fn rel32(&mut self, label: Label) {
+        self.fixups.push((self.bytes.len(), label));
+        self.bytes.extend(0i32.to_le_bytes());
+    }
+
+    fn rex(&mut self, wide: bool, reg: u8, index: u8, base: u8) {
+        let rex = 0x40 | (wide as u8) << 3 | (reg >> 3) << 2 | (index >> 3) << 1 | base >> 3;
+        if rex != 0x40 {
+            self.bytes.push(rex);
+        }
+    }
+
+    /// An instruction whose operands are both registers.
+    fn reg_reg(&mut self, wide: bool, opcode: &[u8], reg: u8, rm: u8) {
+        self.rex(wide, reg, 0, rm);
+        self.bytes.extend(opcode);
+        self.bytes.push(0xc0 | (reg & 7) << 3 | rm & 7);
+    }
+
+    /// An instruction with a memory operand, and a register or opcode extension in `reg`.
+    #[allow(clippy::too_many_arguments)]
+    fn reg_mem(
+        &mut self,
+        prefix: Option<u8>,
+        wide: bool,
+        opcode: &[u8],
+        reg: u8,
+        base: u8,
+        index: Option<(u8, u8)>,
+        disp: i32,
+    ) {
+        self.bytes.extend(prefix);
+        // An index of `rsp` means there is no index
+        let (index, scale) = index.unwrap_or((RSP, 1));
+        self.rex(wide, reg, index, base);
+        self.bytes.extend(opcode);
+        self.bytes.push(0x84 | (reg & 7) << 3);
+        self.bytes
+            .push((scale.trailing_zeros() as u8) << 6 | (index & 7) << 3 | base & 7);
+        self.bytes.extend(disp.to_le_bytes());
+    }
+
+    /// An immediate operand as wide as a cell.
+    fn imm(&mut self, value: i32, bytes: u8) {
+        self.bytes.extend(&value.to_le_bytes()[..bytes as usize]);
+    }
+
+    /// `mov reg, imm`
+    pub fn mov_imm(&mut self, reg: u8, imm: u64) {
+        match u32::try_from(imm) {
+            // Writing a 32-bit register clears the upper half
+            Ok(imm) => {
+                self.rex(false, 0, 0, reg);
+                self.bytes.push(0xb8 | reg & 7);
+                self.bytes.extend(imm.to_le_bytes());
+            }
+            Err(_) => {
+                self.rex(true, 0, 0, reg);
+                self.bytes.push(0xb8 | reg & 7);
+                self.bytes.extend(imm.to_le_bytes());
+            }
+        }
+    }
+
+    /// `mov dst, src` for 64-bit registers
+    pub fn mov(&mut self, dst: u8, src: u8) {
+        self.reg_reg(true, &[0x89], src, dst);
+    }
+
+    /// `mov dst, src` for 32-bit registers
+    pub fn mov32(&mut self, dst: u8, src: u8) {
+        self.reg_reg(false, &[0x89], src, dst);
+    }
+
+    /// `add reg, imm` for a 64-bit register
+    pub fn add_imm(&mut self, reg: u8, imm: i32) {
+        self.reg_reg(true, &[0x81], 0, reg);
+        self.bytes.extend(imm.to_le_bytes());
+    }
+
+    /// `add reg, imm` for a 32-bit register
+    pub fn add32_imm(&mut self, reg: u8, imm: i32) {
+        self.reg_reg(false, &[0x81], 0, reg);
+        self.bytes.extend(imm.to_le_bytes());
+    }
+
+    /// `and reg, imm` for a 32-bit register
+    pub fn and32_imm(&mut self, reg: u8, imm: i32) {
+        self.reg_reg(false, &[0x81], 4, reg);
+        self.bytes.extend(imm.to_le_bytes());
+    }
+
+    /// `sub dst, src` for 64-bit registers
+    pub fn sub(&mut self, dst: u8, src: u8) {
+        self.reg_reg(true, &[0x29], src, dst);
+    }
+
+    /// `cmp reg, imm` for a 32-bit register
+    pub fn cmp32_imm(&mut self, reg: u8, imm: i32) {
+        self.reg_reg(false, &[0x81], 7, reg);
+        self.bytes.extend(imm.to_le_bytes());
+    }
+
+    /// `cmp lhs, rhs` for 32-bit registers
+    pub fn cmp32(&mut self, lhs: u8, rhs: u8) {
+        self.reg_reg(false, &[0x39], rhs, lhs);
+    }
+
+    /// `cmp lhs, rhs` for 64-bit registers
+    pub fn cmp(&mut self, lhs: u8, rhs: u8) {
+        self.reg_reg(true, &[0x39], rhs, lhs);
+    }
+
+    /// `test reg, reg` for a 64-bit register
+    pub fn test(&mut self, reg: u8) {
+        self.reg_reg(true, &[0x85], reg, reg);
+    }
+
+    /// `test reg, reg` for a 32-bit register
+    pub fn test32(&mut self, reg: u8) {
+        self.reg_reg(false, &[0x85], reg, reg);
+    }
+
+    /// `xor reg, reg` for a 32-bit register, which clears all of it
+    pub fn zero(&mut self, reg: u8) {
+        self.reg_reg(false, &[0x31], reg, reg);
+    }
+
+    /// `inc reg` for a 64-bit register
+    pub fn inc(&mut self, reg: u8) {
+        self.reg_reg(true, &[0xff], 0, reg);
+    }
+
+    /// `dec reg` for a 32-bit register
+    pub fn dec32(&mut self, reg: u8) {
+        self.reg_reg(false, &[0xff], 1, reg);
+    }
+
+    /// `div reg`, dividing `edx:eax` by a 32-bit register
+    pub fn div32(&mut self, reg: u8) {
+        self.reg_reg(false, &[0xf7], 6, reg);
+    }
+
+    /// `imul reg, reg, imm` for a 32-bit register
+    pub fn imul32_imm(&mut self, reg: u8, imm: i32) {
+        self.reg_reg(false, &[0x69], reg, reg);
+        self.bytes.extend(imm.to_le_bytes());
+    }
+
+    /// `lea dst, [base + disp]`
+    pub fn lea(&mut self, dst: u8, base: u8, disp: i32) {
+        self.reg_mem(None, true, &[0x8d], dst, base, None, disp);
+    }
+
+    /// `mov byte [base], imm`
+    pub fn store_byte_imm(&mut self, base: u8, imm: u8) {
+        self.reg_mem(None, false, &[0xc6], 0, base, None, 0);
+        self.bytes.push(imm);
+    }
+
+    /// `mov byte [base], reg`, for a register whose low byte needs no REX prefix
+    /// (`al`, `cl`, `dl`, `bl`) or is `r8b` through `r15b`
+    pub fn store_byte(&mut self, base: u8, reg: u8) {
+        self.reg_mem(None, false, &[0x88], reg, base, None, 0);
+    }
+
+    pub fn push(&mut self, reg: u8) {
+        self.rex(false, 0, 0, reg);
+        self.bytes.push(0x50 | reg & 7);
+    }
+
+    pub fn pop(&mut self, reg: u8) {
+        self.rex(false, 0, 0, reg);
+        self.bytes.push(0x58 | reg & 7);
+    }
+
+    pub fn jmp(&mut self, label: Label) {
+        self.bytes.push(0xe9);
+        self.rel32(label);
+    }
+
+    pub fn jcc(&mut self, cond: Cond, label: Label) {
+        self.bytes.extend([0x0f, 0x80 | cond as u8]);
+        self.rel32(label);
+    }
+
+    pub fn call(&mut self, label: Label) {
+        self.bytes.push(0xe8);
+        self.rel32(label);
+    }
+
+    pub fn ret(&mut self) {
+        self.bytes.push(0xc3);
+    }
+
+    /// `call [base + disp]`
+    pub fn call_mem(&mut self, base: u8, disp: i32) {
+        self.reg_mem(None, false, &[0xff], 2, base, None, disp);
+    }
+
+    pub fn syscall(&mut self) {
+        self.bytes.extend([0x0f, 0x05]);
+    }
+
+    /// The prefix and opcode for an instruction on a cell, which has one
+    /// opcode for bytes and another for 16-bit and 32-bit values.
+    fn cell_opcode(bytes: u8, byte_opcode: u8, opcode: u8) -> (Option<u8>, u8) {
+        match bytes {
+            1 => (None, byte_opcode),
+            2 => (Some(0x66), opcode),
+            _ => (None, opcode),
+        }
+    }
+
+    /// Add to the cell at `[base + index * bytes + disp]`, wrapping around.
+    fn add_cell(&mut self, bytes: u8, base: u8, disp: i32, value: i32) {
+        let (prefix, opcode) = Self::cell_opcode(bytes, 0x80, 0x81);
+        self.reg_mem(prefix, false, &[opcode], 0, base, None, disp);
+        self.imm(value, bytes);
+    }
+
+    /// Set the cell at `[base + disp]` to a value.
+    pub fn set_cell(&mut self, bytes: u8, base: u8, disp: i32, value: i32) {
+        let (prefix, opcode) = Self::cell_opcode(bytes, 0xc6, 0xc7);
+        self.reg_mem(prefix, false, &[opcode], 0, base, None, disp);
+        self.imm(value, bytes);
+    }
+
+    /// Compare the cell at `[base + disp]` to zero.
+    fn test_cell(&mut self, bytes: u8, base: u8, disp: i32) {
+        let (prefix, opcode) = Self::cell_opcode(bytes, 0x80, 0x83);
+        self.reg_mem(prefix, false, &[opcode], 7, base, None, disp);
+        self.bytes.push(0);
+    }
+
+    /// Load the cell at `[base + index * bytes + disp]` into a 32-bit
+    /// register, clearing the bits above it.
+    pub fn load_cell(&mut self, bytes: u8, reg: u8, base: u8, index: Option<u8>, disp: i32) {
+        let opcode: &[u8] = match bytes {
+            1 => &[0x0f, 0xb6],
+            2 => &[0x0f, 0xb7],
+            _ => &[0x8b],
+        };
+        let index = index.map(|index| (index, bytes));
+        self.reg_mem(None, false, opcode, reg, base, index, disp);
+    }
+
+    /// Add the low bits of `eax` to the cell at `[base + disp]`.
+    fn add_cell_eax(&mut self, bytes: u8, base: u8, disp: i32) {
+        let (prefix, opcode) = Self::cell_opcode(bytes, 0x00, 0x01);
+        self.reg_mem(prefix, false, &[opcode], RAX, base, None, disp);
+    }
+}
+
+/// How compiled code talks to the world outside of its tape.
+///
+/// Each method emits code where the operation happens. Any of them may
+/// clobber every caller-saved register, but `rbx` must survive them.
+pub(super) trait Runtime {
+    /// Set up the code, leaving the address of the first cell in `rbx`.
+    fn enter(&mut self, code: &mut Code);
+    /// Write the byte at `rsi`.
+    fn put(&mut self, code: &mut Code);
+    /// Read a byte into the cell at `rbx`, which is already zero, and
+    /// leave it zero at the end of the input.
+    fn get(&mut self, code: &mut Code);
+    /// Print the first 256 cells, in hexadecimal or decimal.
+    fn dump(&mut self, code: &mut Code, hex: bool);
+    /// Stop the program after its last operation.
+    fn exit(&mut self, code: &mut Code);
+    /// Check that the cell `disp` bytes past `rbx` is on the tape, before
+    /// it is used or after the pointer moves to it. This may clobber `rax`.
+    fn check(&mut self, _code: &mut Code, _disp: i32) {}
+    /// Emit anything the rest of the code refers to, after the program.
+    fn finish(&mut self, _code: &mut Code) {}
+}
+
+/// Lower optimized operations to machine code, with cells of `bytes` bytes.
+///
+/// An unmatched bracket is reported at the index of its operation, like
+/// [`Interpreter::new`] does. The tape pointer is only kept on the tape if
+/// the runtime checks it, otherwise programs that move off of it are
+/// undefined, like they are in C.
+pub(super) fn lower(ops: &[Op], bytes: u8, runtime: &mut impl Runtime) -> Result<Vec<u8>, BfError> {
+    if !matches!(bytes, 1 | 2 | 4) {
+        return Err(BfError::UnsupportedCellSize(bytes));
+    }
+    let cell = |offset: i32| offset.wrapping_mul(bytes as i32);
+    let mut code = Code::new();
+    runtime.enter(&mut code);
+    // The start of the body and the end of each loop we're inside
+    let mut loops = vec![];
+    for (i, op) in ops.iter().enumerate() {
+        match *op {
+            Op::Move(n) => {
+                code.add_imm(RBX, cell(n));
+                runtime.check(&mut code, 0);
+            }
+            Op::Add(value) => code.add_cell(bytes, RBX, 0, value),
+            Op::AddAt { offset, value } => {
+                runtime.check(&mut code, cell(offset));
+                code.add_cell(bytes, RBX, cell(offset), value);
+            }
+            Op::Zero => code.set_cell(bytes, RBX, 0, 0),
+            Op::ZeroAt(offset) => {
+                runtime.check(&mut code, cell(offset));
+                code.set_cell(bytes, RBX, cell(offset), 0);
+            }
+            // Cells are little-endian, so their low byte comes first
+            Op::Put => {
+                code.lea(RSI, RBX, 0);
+                runtime.put(&mut code);
+            }
+            Op::PutAt(offset) => {
+                runtime.check(&mut code, cell(offset));
+                code.lea(RSI, RBX, cell(offset));
+                runtime.put(&mut code);
+            }
+            Op::Get => {
+                code.set_cell(bytes, RBX, 0, 0);
+                runtime.get(&mut code);
+            }
+            Op::While => {
+                let (body, end) = (code.label(), code.label());
+                code.test_cell(bytes, RBX, 0);
+                code.jcc(Cond::Equal, end);
+                code.bind(body);
+                loops.push((i, body, end));
+            }
+            Op::End => {
+                let (_, body, end) = loops.pop().ok_or(BfError::UnmatchedBracket {
+                    bracket: ']',
+                    position: op_position(i),
+                })?;
+                code.test_cell(bytes, RBX, 0);
+                code.jcc(Cond::NotEqual, body);
+                code.bind(end);
+            }
+            Op::MulAdd { offset, factor } => {
+                // The loop this replaces is skipped on a zero cell, so its offset is never reached.
+                let skip = code.label();
+                code.test_cell(bytes, RBX, 0);
+                code.jcc(Cond::Equal, skip);
+                runtime.check(&mut code, cell(offset));
+                code.load_cell(bytes, RAX, RBX, None, 0);
+                if factor != 1 {
+                    code.imul32_imm(RAX, factor);
+                }
+                code.add_cell_eax(bytes, RBX, cell(offset));
+                code.bind(skip);
+            }
+            Op::Scan(stride) => {
+                let (top, done) = (code.label(), code.label());
+                code.bind(top);
+                code.test_cell(bytes, RBX, 0);
+                code.jcc(Cond::Equal, done);
+                code.add_imm(RBX, cell(stride));
+                runtime.check(&mut code, 0);
+                code.jmp(top);
+                code.bind(done);
+            }
+            Op::HexDump => runtime.dump(&mut code, true),
+            Op::DecDump => runtime.dump(&mut code, false),
+        }
+    }
+    if let Some((start, _, _)) = loops.pop() {
+        return Err(BfError::UnmatchedBracket {
+            bracket: '[',
+            position: op_position(start),
+        });
+    }
+    runtime.exit(&mut code);
+    runtime.finish(&mut code);
+    Ok(code.finish())
+}
@@ -1,7 +1,7 @@
 use clap::{Parser, ValueEnum};
 use std::{
     fmt::{Display, Formatter, Result as FmtResult},
-    io::{Read, Result, Write},
+    io::{BufRead, Read, Result, Write},
 };
 
 use basm::{util::ascii::check_valid_template, *};
@@ -41,6 +41,41 @@ pub struct Args {
     /// If not specified, no comment will be used.
     #[arg(short, long)]
     pub comment: Option<String>,
+
+    /// The file to write a source map to, which maps ranges of the output
+    /// BrainFuck back to the assembly instructions that produced them.
+    /// Only used when assembling to BrainFuck.
+    #[arg(long)]
+    pub source_map: Option<String>,
+
+    /// Compile assembly straight to C, without going through BrainFuck.
+    /// Only used when targeting C, an executable, or running with `gcc`.
+    /// Dumps show different values in the assembler's scratch cells, and
+    /// dividing by zero stops the program instead of never finishing.
+    #[arg(long, default_value_t = false)]
+    pub direct: bool,
+
+    /// The number of values the stack holds, overriding any `.stack` directive.
+    #[arg(long)]
+    pub stack_size: Option<usize>,
+
+    /// The number of cells on the heap, overriding any `.heap` directive.
+    #[arg(long)]
+    pub heap_size: Option<usize>,
+
+    /// The number of return addresses the call stack holds, overriding any
+    /// `.call_stack` directive.
+    #[arg(long)]
+    pub call_stack_size: Option<usize>,
+
+    /// The number of cells on the tape, overriding any `.tape` directive.
+    #[arg(long)]
+    pub tape_size: Option<usize>,
+
+    /// A directory to search for files named by `include`, after the directory
+    /// of the file doing the including. Can be given more than once.
+    #[arg(short = 'I', long = "include-path")]
+    pub include_paths: Vec<String>,
 }
 
 fn main() {
@@ -72,7 +107,7 @@ fn source_from_input_file(args: &Args) -> Result<Source> {
             // Return the source based on the file extension
             let new_source = Source::from_path(input).ok_or(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
-                "Invalid file extension, expected .b, .bf, .basm, or .exe",
+                "Invalid file extension, expected .b, .bf, .basm, or .asm",
             ))?;
 
             if !args.source.is_compatible_with(&new_source) {
@@ -93,7 +128,7 @@ fn backend_from_output_file(args: &Args) -> Result<Backend> {
             // Get the file extension
             let new_backend = Backend::from_path(output).ok_or(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
-                "Invalid file extension, expected .b, .bf, .basm, or .exe",
+                "Invalid file extension, expected .b, .bf, .c, .wat, .exe, or no extension",
             ))?;
 
             if !args.target.is_compatible_with(&new_backend) {
@@ -173,12 +208,42 @@ pub enum Backend {
     Exe16Bit,
     #[value(alias("exe32"))]
     Exe32Bit,
+    #[value(alias("native8"), alias("elf"), alias("elf8"))]
+    Native,
+    #[value(alias("native16"), alias("elf16"))]
+    Native16Bit,
+    #[value(alias("native32"), alias("elf32"))]
+    Native32Bit,
+    #[value(alias("wat"), alias("wat8"))]
+    Wat,
+    #[value(alias("wat16"))]
+    Wat16Bit,
+    #[value(alias("wat32"))]
+    Wat32Bit,
     #[value(alias("run"), alias("run8"))]
     Run,
     #[value(alias("run16"))]
     Run16Bit,
     #[value(alias("run32"))]
     Run32Bit,
+    #[value(alias("interp"), alias("interp8"))]
+    Interp,
+    #[value(alias("interp16"))]
+    Interp16Bit,
+    #[value(alias("interp32"))]
+    Interp32Bit,
+    #[value(alias("jit8"))]
+    Jit,
+    #[value(alias("jit16"))]
+    Jit16Bit,
+    #[value(alias("jit32"))]
+    Jit32Bit,
+    #[value(alias("debug"), alias("debug8"))]
+    Debug,
+    #[value(alias("debug16"))]
+    Debug16Bit,
+    #[value(alias("debug32"))]
+    Debug32Bit,
 }
 
 impl Display for Backend {
@@ -191,9 +256,24 @@ impl Display for Backend {
             Self::Exe => write!(f, "Executable"),
             Self::Exe16Bit => write!(f, "16-bit Executable"),
             Self::Exe32Bit => write!(f, "32-bit Executable"),
+            Self::Native => write!(f, "Native Executable"),
+            Self::Native16Bit => write!(f, "16-bit Native Executable"),
+            Self::Native32Bit => write!(f, "32-bit Native Executable"),
+            Self::Wat => write!(f, "WebAssembly Text"),
+            Self::Wat16Bit => write!(f, "WebAssembly Text 16-bit"),
+            Self::Wat32Bit => write!(f, "WebAssembly Text 32-bit"),
             Self::Run => write!(f, "Run"),
             Self::Run16Bit => write!(f, "Run 16-bit"),
             Self::Run32Bit => write!(f, "Run 32-bit"),
+            Self::Interp => write!(f, "Interpreter"),
+            Self::Interp16Bit => write!(f, "Interpreter 16-bit"),
+            Self::Interp32Bit => write!(f, "Interpreter 32-bit"),
+            Self::Jit => write!(f, "JIT"),
+            Self::Jit16Bit => write!(f, "JIT 16-bit"),
+            Self::Jit32Bit => write!(f, "JIT 32-bit"),
+            Self::Debug => write!(f, "Debugger"),
+            Self::Debug16Bit => write!(f, "Debugger 16-bit"),
+            Self::Debug32Bit => write!(f, "Debugger 32-bit"),
         }
     }
 }
@@ -201,8 +281,22 @@ impl Display for Backend {
 impl Backend {
     pub fn bytes(&self) -> u8 {
         match self {
-            Backend::C16Bit | Backend::Exe16Bit | Backend::Run16Bit => 2,
-            Backend::C32Bit | Backend::Exe32Bit | Backend::Run32Bit => 4,
+            Backend::C16Bit
+            | Backend::Exe16Bit
+            | Backend::Native16Bit
+            | Backend::Wat16Bit
+            | Backend::Run16Bit
+            | Backend::Interp16Bit
+            | Backend::Jit16Bit
+            | Backend::Debug16Bit => 2,
+            Backend::C32Bit
+            | Backend::Exe32Bit
+            | Backend::Native32Bit
+            | Backend::Wat32Bit
+            | Backend::Run32Bit
+            | Backend::Interp32Bit
+            | Backend::Jit32Bit
+            | Backend::Debug32Bit => 4,
             _ => 1,
         }
     }
@@ -220,19 +314,63 @@ impl Backend {
                 Backend::Exe | Backend::Exe16Bit | Backend::Exe32Bit,
                 Backend::Exe | Backend::Exe16Bit | Backend::Exe32Bit,
             ) => true,
+            // Native executables are written to the same kinds of files as
+            // ones compiled with `gcc`
+            (
+                Backend::Native | Backend::Native16Bit | Backend::Native32Bit,
+                Backend::Exe
+                | Backend::Exe16Bit
+                | Backend::Exe32Bit
+                | Backend::Native
+                | Backend::Native16Bit
+                | Backend::Native32Bit,
+            ) => true,
+            (
+                Backend::Wat | Backend::Wat16Bit | Backend::Wat32Bit,
+                Backend::Wat | Backend::Wat16Bit | Backend::Wat32Bit,
+            ) => true,
             (
                 Backend::Run | Backend::Run16Bit | Backend::Run32Bit,
                 Backend::Run | Backend::Run16Bit | Backend::Run32Bit,
             ) => true,
+            (
+                Backend::Interp | Backend::Interp16Bit | Backend::Interp32Bit,
+                Backend::Interp | Backend::Interp16Bit | Backend::Interp32Bit,
+            ) => true,
+            (
+                Backend::Jit | Backend::Jit16Bit | Backend::Jit32Bit,
+                Backend::Jit | Backend::Jit16Bit | Backend::Jit32Bit,
+            ) => true,
+            (
+                Backend::Debug | Backend::Debug16Bit | Backend::Debug32Bit,
+                Backend::Debug | Backend::Debug16Bit | Backend::Debug32Bit,
+            ) => true,
             (Backend::BrainFuck, Backend::BrainFuck) => true,
             _ => false,
         }
     }
 
+    /// Does this backend compile the BrainFuck to C?
+    pub fn uses_c(&self) -> bool {
+        matches!(
+            self,
+            Backend::C
+                | Backend::C16Bit
+                | Backend::C32Bit
+                | Backend::Exe
+                | Backend::Exe16Bit
+                | Backend::Exe32Bit
+                | Backend::Run
+                | Backend::Run16Bit
+                | Backend::Run32Bit
+        )
+    }
+
     pub fn from_file_extension(extension: &str) -> Option<Self> {
         match extension {
             "b" | "bf" => Some(Backend::BrainFuck),
             "c" => Some(Backend::C),
+            "wat" => Some(Backend::Wat),
             "" | "exe" => Some(Backend::Exe),
             _ => None,
         }
@@ -251,6 +389,7 @@ impl Backend {
         match self {
             Backend::BrainFuck => "bf",
             Backend::C => "c",
+            Backend::Wat => "wat",
             Backend::Exe => "exe",
             _ => "",
         }
@@ -274,21 +413,58 @@ pub fn read_input_file(args: &Args) -> Result<String> {
     }
 }
 
-pub fn read_source_to_bf(args: &Args) -> Result<String> {
+/// Read the source as BrainFuck, along with its source map if one was asked
+/// for, and the number of cells on the tape it runs with.
+pub fn read_source_to_bf(args: &Args) -> Result<(String, Option<SourceMap>, usize)> {
     Ok(match source_from_input_file(args)? {
         Source::BrainFuck => {
             info!("Reading BrainFuck source");
-            simplify_bf(read_input_file(args)?)
+            if args.source_map.is_some() {
+                warn!("Source maps can only be made for assembly sources, ignoring --source-map");
+            }
+            let tape_size = args.tape_size.unwrap_or(TAPE_SIZE);
+            (simplify_bf(read_input_file(args)?)?, None, tape_size)
         }
         Source::Assembly => {
             info!("Reading Assembly source");
-            let program = Program::parse(&read_input_file(args)?)
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-            program.assemble()
+            let program = read_program(args)?;
+            let tape_size = program.layout().tape_size;
+            let invalid = |e: LayoutError| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+            if args.source_map.is_some() {
+                let (bf, source_map) = Assembler::new()
+                    .assemble_with_source_map(&program)
+                    .map_err(invalid)?;
+                let file = args.input.as_deref().unwrap_or("<stdin>");
+                (bf, Some(source_map.with_file(file)), tape_size)
+            } else {
+                (program.try_assemble().map_err(invalid)?, None, tape_size)
+            }
         }
     })
 }
 
+/// Parse the input as assembly, with the memory layout flags applied on top
+/// of its directives.
+pub fn read_program(args: &Args) -> Result<Program> {
+    let loader = args
+        .include_paths
+        .iter()
+        .fold(Loader::new(), |loader, dir| loader.with_search_path(dir));
+    let path = args.input.as_deref().map(std::path::Path::new);
+    let file = args.input.as_deref().unwrap_or("<stdin>");
+    let mut program = loader
+        .parse(&read_input_file(args)?, path)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.with_file(file)))?;
+    let layout = *program.layout();
+    program.set_layout(Layout {
+        stack_size: args.stack_size.unwrap_or(layout.stack_size),
+        heap_size: args.heap_size.unwrap_or(layout.heap_size),
+        call_stack_size: args.call_stack_size.unwrap_or(layout.call_stack_size),
+        tape_size: args.tape_size.unwrap_or(layout.tape_size),
+    });
+    Ok(program)
+}
+
 pub fn write_output_file(args: &Args, output: &[u8]) -> Result<()> {
     match &args.output {
         Some(path) => {
@@ -318,19 +494,189 @@ pub fn make_ascii_art(args: &Args, bf: String) -> Result<String> {
     }
 }
 
+const DEBUGGER_HELP: &str = "\
+Commands:
+  break <line|label>  (b)  Stop before the instruction on a line, or after a label
+  delete <line>       (d)  Remove a breakpoint
+  step                (s)  Run the next instruction
+  next                (n)  Run until the next basic block
+  continue            (c)  Run until a breakpoint or the end of the program
+  print <register>    (p)  Print a register, like R0, SP, or HP
+  registers           (r)  Print the general purpose registers and pointers
+  stack                    Print the values on the stack, from the bottom up
+  callstack                Print the return addresses on the call stack
+  heap <start> [count]     Print heap cells by index
+  where               (w)  Print the instruction about to run
+  quit                (q)  Stop debugging";
+
+/// Step through an assembly program in the interpreter, reading debugger
+/// commands from stdin. The program reads its own input from stdin too.
+pub fn debug(args: &Args) -> Result<()> {
+    let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
+    let file = match &args.input {
+        Some(input) if source_from_input_file(args)? == Source::Assembly => input,
+        _ => {
+            return Err(invalid(
+                "The debugger needs an assembly file as input".into(),
+            ))
+        }
+    };
+    let mut debugger = Debugger::new(&read_program(args)?, args.target.bytes())
+        .map_err(invalid)?
+        .with_file(file.as_str());
+
+    let where_am_i = |debugger: &Debugger| match debugger.location() {
+        Some(location) => {
+            let span = location.span;
+            println!(
+                "{file}:{}:{}: {}",
+                span.line, span.column, location.instruction
+            )
+        }
+        None => println!("The program has not started"),
+    };
+
+    println!("Debugging {file}, type `help` for a list of commands");
+    let mut stdin = std::io::stdin().lock();
+    let mut stdout = std::io::stdout();
+    loop {
+        print!("(basm) ");
+        stdout.flush()?;
+        let mut line = String::new();
+        if stdin.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let stop = match words.as_slice() {
+            [] => continue,
+            ["s" | "step"] => debugger.step_instruction(&mut stdin, &mut stdout),
+            ["n" | "next"] => debugger.step_block(&mut stdin, &mut stdout),
+            ["c" | "continue"] => debugger.resume(&mut stdin, &mut stdout),
+            ["b" | "break", at] => {
+                let line = match at.parse() {
+                    Ok(line) => debugger.break_at_line(line),
+                    Err(_) => debugger.break_at_label(at),
+                };
+                match line {
+                    Ok(line) => println!("Breakpoint at {file}:{line}"),
+                    Err(e) => println!("{e}"),
+                }
+                continue;
+            }
+            ["d" | "delete", line] => {
+                if !line
+                    .parse()
+                    .is_ok_and(|line| debugger.clear_breakpoint(line))
+                {
+                    println!("No breakpoint on line {line}");
+                }
+                continue;
+            }
+            ["p" | "print", name] => {
+                match debugger.register(name) {
+                    Some(value) => println!("{name} = {value}"),
+                    None => println!("No register named `{name}`"),
+                }
+                continue;
+            }
+            ["r" | "registers"] => {
+                for (name, value) in debugger.registers() {
+                    println!("{name:>7} = {value}");
+                }
+                continue;
+            }
+            ["stack"] => {
+                println!("{:?}", debugger.stack());
+                continue;
+            }
+            ["callstack"] => {
+                println!("{:?}", debugger.call_stack());
+                continue;
+            }
+            ["heap", start, rest @ ..] if rest.len() <= 1 => {
+                let count = rest.first().map_or(Ok(16), |count| count.parse());
+                match (start.parse::<usize>(), count) {
+                    (Ok(start), Ok(count)) => {
+                        for (i, value) in debugger.heap(start..start + count).iter().enumerate() {
+                            println!("{:>5}: {value}", start + i);
+                        }
+                    }
+                    _ => println!("Usage: heap <start> [count]"),
+                }
+                continue;
+            }
+            ["w" | "where"] => {
+                where_am_i(&debugger);
+                continue;
+            }
+            ["h" | "help"] => {
+                println!("{DEBUGGER_HELP}");
+                continue;
+            }
+            ["q" | "quit"] => return Ok(()),
+            _ => {
+                println!("Unknown command, type `help` for a list of commands");
+                continue;
+            }
+        };
+
+        match stop.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))? {
+            StopReason::Stepped => where_am_i(&debugger),
+            StopReason::Breakpoint(_) => {
+                print!("Breakpoint at ");
+                where_am_i(&debugger);
+            }
+            StopReason::Finished => {
+                println!("The program finished");
+                return Ok(());
+            }
+        }
+    }
+}
+
 pub fn build_for_backend(args: &Args) -> Result<()> {
-    let bf = read_source_to_bf(args)?;
+    if matches!(
+        args.target,
+        Backend::Debug | Backend::Debug16Bit | Backend::Debug32Bit
+    ) {
+        return debug(args);
+    }
     let bytes = args.target.bytes();
     let backend = backend_from_output_file(args)?;
+    let direct = args.direct && backend.uses_c();
+    if args.direct && !direct {
+        warn!("Only C, executable, and run targets can skip BrainFuck, ignoring --direct");
+    }
+    let (bf, source_map, tape_size) = if direct {
+        (String::new(), None, TAPE_SIZE)
+    } else {
+        read_source_to_bf(args)?
+    };
+    let to_c = |bf: String| -> Result<String> {
+        if !direct {
+            return Ok(compile_to_c_with_tape(bf, bytes, tape_size)?);
+        }
+        if source_from_input_file(args)? != Source::Assembly {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Only assembly can be compiled straight to C",
+            ));
+        }
+        Ok(read_program(args)?.assemble_to_c(bytes))
+    };
+    if source_map.is_some() && backend != Backend::BrainFuck {
+        warn!("Source maps are only written when targeting BrainFuck, ignoring --source-map");
+    }
     match backend {
         Backend::C | Backend::C16Bit | Backend::C32Bit => {
-            write_output_file(args, compile_to_c(bf, bytes).as_bytes())?;
+            write_output_file(args, to_c(bf)?.as_bytes())?;
         }
 
         Backend::Exe | Backend::Exe16Bit | Backend::Exe32Bit if args.output.is_some() => {
             info!("Creating executable...");
             // First, compile to C
-            let c = compile_to_c(bf, bytes);
+            let c = to_c(bf)?;
 
             // Now, write to a temp file
             let mut temp_file = std::env::temp_dir();
@@ -369,9 +715,31 @@ pub fn build_for_backend(args: &Args) -> Result<()> {
             ));
         }
 
+        Backend::Native | Backend::Native16Bit | Backend::Native32Bit => {
+            let Some(path) = &args.output else {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "Output file must be specified when targeting an executable",
+                ));
+            };
+            info!("Creating native executable...");
+            std::fs::write(path, compile_to_elf_with_tape(bf, bytes, tape_size)?)?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+            }
+            info!("Successfully compiled to executable {path}");
+        }
+
+        Backend::Wat | Backend::Wat16Bit | Backend::Wat32Bit => {
+            let wat = compile_to_wat_with_tape(bf, bytes, tape_size)?;
+            write_output_file(args, wat.as_bytes())?;
+        }
+
         Backend::Run | Backend::Run16Bit | Backend::Run32Bit => {
             // First, compile to C
-            let c = compile_to_c(bf, bytes);
+            let c = to_c(bf)?;
 
             // Now, write to a temp file
             let temp_dir = std::env::temp_dir();
@@ -410,10 +778,58 @@ pub fn build_for_backend(args: &Args) -> Result<()> {
             // Now, we're done
             info!("Successfully ran code");
         }
-        Backend::BrainFuck => {
-            write_output_file(args, make_ascii_art(args, simplify_bf(bf))?.as_bytes())?;
+
+        Backend::Interp | Backend::Interp16Bit | Backend::Interp32Bit => {
+            info!("Running in the interpreter...");
+            interpret_with_tape(bf, bytes, tape_size)?;
+            info!("Successfully ran code");
         }
+        Backend::Jit | Backend::Jit16Bit | Backend::Jit32Bit => {
+            if !Jit::is_native() {
+                warn!("The JIT only compiles on x86-64 Linux, running in the interpreter instead");
+            }
+            jit_with_tape(bf, bytes, tape_size)?;
+            info!("Successfully ran code");
+        }
+        Backend::Debug | Backend::Debug16Bit | Backend::Debug32Bit => unreachable!(),
+        Backend::BrainFuck => match (source_map, &args.source_map) {
+            (Some(source_map), Some(path)) if args.art.is_none() => {
+                let (bf, source_map) = simplify_bf_with_source_map(bf, &source_map)?;
+                write_output_file(args, bf.as_bytes())?;
+                std::fs::write(path, source_map.to_string())?;
+                info!("Successfully wrote source map to {path}");
+            }
+            (source_map, _) => {
+                if source_map.is_some() {
+                    warn!("Source maps can't follow an ASCII art template, ignoring --source-map");
+                }
+                write_output_file(args, make_ascii_art(args, simplify_bf(bf)?)?.as_bytes())?;
+            }
+        },
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errors_name_the_input_file() {
+        let dir = std::env::temp_dir().join(format!("basm-cli-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("unclosed.basm");
+        std::fs::write(&input, "main:\n    if R0\n        putchar 'a'\n").unwrap();
+
+        let args = Args::parse_from(["basm", input.to_str().unwrap()]);
+        let error = read_program(&args).unwrap_err().to_string();
+        assert!(error.starts_with("error: expected `end`"), "{error}");
+        assert!(
+            error.contains(&format!("--> {}:", input.display())),
+            "{error}"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -19,15 +19,12 @@
 //!         putint R2
 //!     ").expect("Failed to parse assembly");
 //!     let bf = program.assemble();
-//!     let optimized_bf = simplify_bf(bf);
+//!     let optimized_bf = simplify_bf(bf).expect("Failed to simplify brainfuck");
 //!     println!("{}", optimized_bf);
 //! }
 //! ```
 
-#![recursion_limit = "1024"]
-use lazy_static::lazy_static;
-use std::sync::RwLock;
-use tracing::info;
+#![recursion_limit = "4096"]
 
 mod asm;
 pub use asm::*;
@@ -37,7 +34,11 @@ pub use symbol::*;
 
 mod bf;
 pub use bf::{
-    compile_and_run, compile_and_run_with_input, compile_to_c, compile_to_exe, simplify_bf, compile_to_ook
+    compile_and_run, compile_and_run_with_input, compile_to_c, compile_to_c_with_tape,
+    compile_to_elf, compile_to_elf_with_tape, compile_to_exe, compile_to_ook, compile_to_wat,
+    compile_to_wat_with_tape, interpret, interpret_with_input, interpret_with_tape, jit,
+    jit_with_input, jit_with_tape, simplify_bf, BfError, Interpreter, Jit, Loop, LoopNode,
+    LoopTree, Op as BfOp, Position,
 };
 
 pub mod util;
@@ -57,18 +58,6 @@ pub fn init_logging() {
 
 pub const TAPE_SIZE: usize = 30_000;
 
-lazy_static! {
-    // pub static ref NEXT_GLOBAL: RwLock<StaticLocation> = RwLock::new(STACK_END.off(1));
-    static ref NEXT_GLOBAL: RwLock<StaticLocation> = RwLock::new(StaticLocation::Address(0));
-}
-
-pub fn global_alloc(size: usize) -> StaticLocation {
-    let mut next = NEXT_GLOBAL.write().unwrap();
-    let result = *next;
-    *next = next.off(size as i64);
-    result
-}
-
 #[derive(Debug, Clone, Copy)]
 pub struct Table {
     data_cells: usize,
@@ -79,7 +68,7 @@ pub struct Table {
 }
 
 impl Table {
-    pub fn new(data_cells: usize, start_location: StaticLocation) -> Self {
+    pub const fn new(data_cells: usize, start_location: StaticLocation) -> Self {
         let base = start_location;
         let start_data = base.off(1);
         let temp0 = base.off(2);
@@ -95,14 +84,35 @@ impl Table {
         }
     }
 
-    pub fn allocate(data_cells: usize) -> Self {
-        let base = global_alloc(4 + 2 * data_cells);
-        info!("Allocated table {}", base);
-        Self::new(data_cells, base)
+    pub const fn total_size(&self) -> usize {
+        self.data_cells * 2 + 4
+    }
+
+    /// The first cell of the table, including its temporary cells.
+    pub const fn base(&self) -> StaticLocation {
+        self.temp2
     }
 
-    pub fn total_size(&self) -> usize {
-        self.data_cells * 2 + 4
+    /// The first cell after the table.
+    pub const fn after(&self) -> StaticLocation {
+        self.base().off(self.total_size() as i64)
+    }
+
+    /// The number of values the table can hold.
+    pub const fn capacity(&self) -> usize {
+        self.data_cells
+    }
+
+    /// The cell that holds the value at `index` in the table.
+    pub const fn cell(&self, index: usize) -> StaticLocation {
+        self.start_data.off(3 + 2 * index as i64)
+    }
+
+    /// The index of the value held in `cell`, if it is one of the table's values.
+    pub fn index_of(&self, cell: StaticLocation) -> Option<usize> {
+        let offset = cell.address().checked_sub(self.cell(0).address())?;
+        let index = offset / 2;
+        (offset % 2 == 0 && index < self.data_cells).then_some(index)
     }
 
     pub fn start(&self) -> StaticLocation {
@@ -196,7 +206,7 @@ pub enum StaticLocation {
 }
 
 impl StaticLocation {
-    pub fn register(name: &str) -> Self {
+    pub fn register(name: &str) -> Option<Self> {
         register(name)
     }
 
@@ -277,7 +287,7 @@ impl StaticLocation {
         x.set_from(src)
             + &temp0.zero()
             + &while_on(&x, temp0.inc() + &x.dec())
-            + &while_on(&temp0, x.dec() + &temp0.inc())
+            + &while_on(&temp0, x.dec() + &temp0.dec())
     }
 
     pub fn boolean_not(dest: StaticLocation, src: StaticLocation) -> String {
@@ -457,6 +467,256 @@ impl StaticLocation {
             )
     }
 
+    pub fn modulo(dest: StaticLocation, lhs: StaticLocation, rhs: StaticLocation) -> String {
+        // Count up to lhs, wrapping the remainder back to zero every time it reaches rhs.
+        // n = lhs
+        // r = 0
+        // n[n- r+ e=(r==rhs) e[r[-]] ]
+        let n = BIT_TEMP0;
+        let r = BIT_TEMP1;
+        let e = BIT_TEMP2;
+
+        n.set_from(lhs)
+            + &r.zero()
+            + &while_on(
+                &n,
+                n.dec()
+                    + &r.inc()
+                    + &Self::equals(e, r, rhs)
+                    + &if_stmt(&e, r.zero()),
+            )
+            + &dest.set_from(r)
+    }
+
+    /// Set `dest` to 1 if both `a` and `b` are non-zero, and 0 otherwise.
+    fn both_nonzero(dest: StaticLocation, a: StaticLocation, b: StaticLocation) -> String {
+        dest.zero() + &if_stmt(&a, if_stmt(&b, dest.set_const(1)))
+    }
+
+    /// Set `dest` to 1 if either `a` or `b` is non-zero, and 0 otherwise.
+    fn either_nonzero(dest: StaticLocation, a: StaticLocation, b: StaticLocation) -> String {
+        dest.zero() + &if_stmt(&a, dest.set_const(1)) + &if_stmt(&b, dest.set_const(1))
+    }
+
+    /// Copy `lhs` and `rhs` into CMP_TEMP0 and CMP_TEMP1, and decrement both
+    /// until one of them is zero. Whichever is left non-zero was the larger.
+    fn count_down(lhs: StaticLocation, rhs: StaticLocation) -> String {
+        let x = CMP_TEMP0;
+        let y = CMP_TEMP1;
+        let flag = CMP_TEMP2;
+
+        x.set_from(lhs)
+            + &y.set_from(rhs)
+            + &Self::both_nonzero(flag, x, y)
+            + &while_on(
+                &flag,
+                x.dec() + &y.dec() + &Self::both_nonzero(flag, x, y),
+            )
+    }
+
+    pub fn less_than(dest: StaticLocation, lhs: StaticLocation, rhs: StaticLocation) -> String {
+        Self::count_down(lhs, rhs) + &dest.zero() + &if_stmt(&CMP_TEMP1, dest.set_const(1))
+    }
+
+    pub fn less_equal(dest: StaticLocation, lhs: StaticLocation, rhs: StaticLocation) -> String {
+        Self::count_down(lhs, rhs) + &Self::boolean_not(dest, CMP_TEMP0)
+    }
+
+    pub fn greater_than(dest: StaticLocation, lhs: StaticLocation, rhs: StaticLocation) -> String {
+        Self::count_down(lhs, rhs) + &dest.zero() + &if_stmt(&CMP_TEMP0, dest.set_const(1))
+    }
+
+    pub fn greater_equal(dest: StaticLocation, lhs: StaticLocation, rhs: StaticLocation) -> String {
+        Self::count_down(lhs, rhs) + &Self::boolean_not(dest, CMP_TEMP1)
+    }
+
+    /// Split `x` into the sign and magnitude it has as a two's complement number.
+    /// `sign` is set to 1 if `x` is negative, and `magnitude` to its absolute value.
+    ///
+    /// One copy of `x` counts down and another counts up until either reaches zero,
+    /// so this takes as many steps as the magnitude, whatever the cell width.
+    fn sign_and_magnitude(
+        sign: StaticLocation,
+        magnitude: StaticLocation,
+        x: StaticLocation,
+    ) -> String {
+        let down = CMP_TEMP0;
+        let up = CMP_TEMP1;
+        let flag = CMP_TEMP2;
+
+        down.set_from(x)
+            + &up.set_from(x)
+            + &magnitude.zero()
+            + &Self::both_nonzero(flag, down, up)
+            + &while_on(
+                &flag,
+                down.dec() + &up.inc() + &magnitude.inc() + &Self::both_nonzero(flag, down, up),
+            )
+            // Negative numbers wrap `up` around to zero first, and the most
+            // negative number reaches zero in both copies at once
+            + &Self::boolean_not(flag, up)
+            + &Self::both_nonzero(sign, flag, magnitude)
+    }
+
+    /// Combine the magnitudes of `lhs` and `rhs` with `op`, and negate the result
+    /// if exactly one of them is negative.
+    fn signed(
+        dest: StaticLocation,
+        lhs: StaticLocation,
+        rhs: StaticLocation,
+        op: impl Fn(StaticLocation, StaticLocation, StaticLocation) -> String,
+    ) -> String {
+        let (lhs_sign, lhs_magnitude) = (SIGN_TEMP0, SIGN_TEMP1);
+        let (rhs_sign, rhs_magnitude) = (SIGN_TEMP2, SIGN_TEMP3);
+        let result = SIGN_TEMP4;
+        let negative = SIGN_TEMP5;
+
+        Self::sign_and_magnitude(lhs_sign, lhs_magnitude, lhs)
+            + &Self::sign_and_magnitude(rhs_sign, rhs_magnitude, rhs)
+            + &op(result, lhs_magnitude, rhs_magnitude)
+            + &Self::not_equals(negative, lhs_sign, rhs_sign)
+            + &if_stmt(&negative, Self::negate(result, result))
+            + &dest.set_from(result)
+    }
+
+    pub fn signed_times(dest: StaticLocation, lhs: StaticLocation, rhs: StaticLocation) -> String {
+        Self::signed(dest, lhs, rhs, Self::times)
+    }
+
+    /// Divide two's complement numbers, rounding towards zero.
+    pub fn signed_divide(dest: StaticLocation, lhs: StaticLocation, rhs: StaticLocation) -> String {
+        Self::signed(dest, lhs, rhs, Self::divide)
+    }
+
+    pub fn signed_less_than(
+        dest: StaticLocation,
+        lhs: StaticLocation,
+        rhs: StaticLocation,
+    ) -> String {
+        let (lhs_sign, lhs_magnitude) = (SIGN_TEMP0, SIGN_TEMP1);
+        let (rhs_sign, rhs_magnitude) = (SIGN_TEMP2, SIGN_TEMP3);
+        let compared = SIGN_TEMP4;
+        let flag = SIGN_TEMP5;
+
+        Self::sign_and_magnitude(lhs_sign, lhs_magnitude, lhs)
+            + &Self::sign_and_magnitude(rhs_sign, rhs_magnitude, rhs)
+            + &dest.zero()
+            // If neither is negative, the one with the smaller magnitude is less
+            + &Self::less_than(compared, lhs_magnitude, rhs_magnitude)
+            + &Self::either_nonzero(flag, lhs_sign, rhs_sign)
+            + &Self::boolean_not(flag, flag)
+            + &if_stmt(&flag, dest.set_from(compared))
+            // If both are, the one with the larger magnitude is less
+            + &Self::greater_than(compared, lhs_magnitude, rhs_magnitude)
+            + &Self::both_nonzero(flag, lhs_sign, rhs_sign)
+            + &if_stmt(&flag, dest.set_from(compared))
+            // Otherwise, the negative one is less
+            + &Self::boolean_not(compared, rhs_sign)
+            + &Self::both_nonzero(flag, lhs_sign, compared)
+            + &if_stmt(&flag, dest.set_const(1))
+    }
+
+    /// Divide `x` by two in place, and store the bit shifted out in `remainder`.
+    /// This works for any cell width, since it only counts down to zero.
+    fn halve(x: StaticLocation, remainder: StaticLocation) -> String {
+        // q = 0
+        // remainder = 0
+        // x[x- remainder=!remainder t=!remainder t[q+]]
+        // x = q
+        let q = CMP_TEMP0;
+        let t = CMP_TEMP1;
+
+        q.zero()
+            + &remainder.zero()
+            + &while_on(
+                &x,
+                x.dec()
+                    + &Self::boolean_not(remainder, remainder)
+                    + &Self::boolean_not(t, remainder)
+                    + &if_stmt(&t, q.inc()),
+            )
+            + &x.set_from(q)
+    }
+
+    /// Combine `lhs` and `rhs` one bit at a time, starting from the least significant bit.
+    /// `combine(set, a, b)` sets `set` to whether the result bit is set, given the input bits `a` and `b`.
+    fn bitwise(
+        dest: StaticLocation,
+        lhs: StaticLocation,
+        rhs: StaticLocation,
+        combine: impl Fn(StaticLocation, StaticLocation, StaticLocation) -> String,
+    ) -> String {
+        let a = BIT_TEMP0;
+        let b = BIT_TEMP1;
+        let result = BIT_TEMP2;
+        let bit = BIT_TEMP3;
+        let a_bit = BIT_TEMP4;
+        let b_bit = BIT_TEMP5;
+        let set = CMP_TEMP1;
+        let remaining = CMP_TEMP2;
+
+        // Loop until both operands run out of bits, so we never need to know the cell width
+        a.set_from(lhs)
+            + &b.set_from(rhs)
+            + &result.zero()
+            + &bit.set_const(1)
+            + &Self::either_nonzero(remaining, a, b)
+            + &while_on(
+                &remaining,
+                Self::halve(a, a_bit)
+                    + &Self::halve(b, b_bit)
+                    + &combine(set, a_bit, b_bit)
+                    + &if_stmt(&set, Self::plus(result, result, bit))
+                    + &Self::plus(bit, bit, bit)
+                    + &Self::either_nonzero(remaining, a, b),
+            )
+            + &dest.set_from(result)
+    }
+
+    pub fn bitwise_and(dest: StaticLocation, lhs: StaticLocation, rhs: StaticLocation) -> String {
+        Self::bitwise(dest, lhs, rhs, Self::both_nonzero)
+    }
+
+    pub fn bitwise_or(dest: StaticLocation, lhs: StaticLocation, rhs: StaticLocation) -> String {
+        Self::bitwise(dest, lhs, rhs, Self::either_nonzero)
+    }
+
+    pub fn bitwise_xor(dest: StaticLocation, lhs: StaticLocation, rhs: StaticLocation) -> String {
+        Self::bitwise(dest, lhs, rhs, Self::not_equals)
+    }
+
+    pub fn bitwise_not(dest: StaticLocation, src: StaticLocation) -> String {
+        // !x == -x - 1 for any cell width
+        let x = BIT_TEMP0;
+        let result = BIT_TEMP1;
+
+        x.set_from(src)
+            + &result.zero()
+            + &while_on(&x, x.dec() + &result.dec())
+            + &result.dec()
+            + &dest.set_from(result)
+    }
+
+    pub fn shift_left(dest: StaticLocation, lhs: StaticLocation, rhs: StaticLocation) -> String {
+        let x = BIT_TEMP0;
+        let n = BIT_TEMP1;
+
+        x.set_from(lhs)
+            + &n.set_from(rhs)
+            + &while_on(&n, n.dec() + &Self::plus(x, x, x))
+            + &dest.set_from(x)
+    }
+
+    pub fn shift_right(dest: StaticLocation, lhs: StaticLocation, rhs: StaticLocation) -> String {
+        let x = BIT_TEMP0;
+        let n = BIT_TEMP1;
+
+        x.set_from(lhs)
+            + &n.set_from(rhs)
+            + &while_on(&n, n.dec() + &Self::halve(x, BIT_TEMP2))
+            + &dest.set_from(x)
+    }
+
     pub fn putchar(&self) -> String {
         self.to() + "." + &self.from()
     }
@@ -483,10 +743,67 @@ impl StaticLocation {
         + &PUT_INT0.from()
     }
 
+    /// Print the cell as a two's complement number.
+    pub fn putsint(&self) -> String {
+        let (sign, magnitude) = (SIGN_TEMP0, SIGN_TEMP1);
+        let minus = SIGN_TEMP2;
+
+        Self::sign_and_magnitude(sign, magnitude, *self)
+            + &if_stmt(&sign, minus.set_const(b'-' as u64) + &minus.putchar())
+            + &magnitude.putint()
+    }
+
     pub fn getchar(&self) -> String {
         self.to() + "," + &self.from()
     }
 
+    /// Read a number into the cell: any spaces, tabs and newlines, an optional
+    /// `-`, and then as many digits as follow. The character after the number
+    /// is consumed.
+    pub fn getint(&self) -> String {
+        let c = SIGN_TEMP0;
+        let n = SIGN_TEMP1;
+        let negative = SIGN_TEMP2;
+        let flag = SIGN_TEMP3;
+        let test = SIGN_TEMP4;
+        let constant = SIGN_TEMP5;
+
+        let is = |dest: StaticLocation, ch: char| {
+            constant.set_const(ch as u64) + &Self::equals(dest, c, constant)
+        };
+        let is_space = || {
+            let mut result = flag.zero();
+            for ch in [' ', '\t', '\n', '\r'] {
+                result += &(is(test, ch) + &if_stmt(&test, flag.set_const(1)));
+            }
+            result
+        };
+        let is_digit = || {
+            constant.set_const('0' as u64)
+                + &Self::less_than(test, c, constant)
+                + &constant.set_const('9' as u64)
+                + &Self::less_equal(flag, c, constant)
+                + &if_stmt(&test, flag.zero())
+        };
+        // n = n * 10 + (c - '0')
+        let accumulate = constant.set_const(10)
+            + &Self::times(test, n, constant)
+            + &constant.set_const('0' as u64)
+            + &Self::minus(n, c, constant)
+            + &Self::plus(n, n, test);
+
+        c.getchar()
+            + &is_space()
+            + &while_on(&flag, c.getchar() + &is_space())
+            + &is(negative, '-')
+            + &if_stmt(&negative, c.getchar())
+            + &n.zero()
+            + &is_digit()
+            + &while_on(&flag, accumulate + &c.getchar() + &is_digit())
+            + &if_stmt(&negative, Self::negate(n, n))
+            + &self.set_from(n)
+    }
+
     pub fn inc(&self) -> String {
         self.add_const(1)
     }
@@ -526,12 +843,254 @@ impl StaticLocation {
     }
 }
 
+/// The number of cells a wide register spans.
+pub const WIDE_CELLS: usize = 4;
+
+/// A number spread over `WIDE_CELLS` consecutive cells, one byte per cell with the
+/// least significant byte first.
+///
+/// Every cell holds a value below 256 whatever the width of the cells, so wide numbers
+/// have the same 32-bit range on 8, 16 and 32-bit tapes. Carries are detected by comparing
+/// a cell against 256, which wraps around to zero on 8-bit cells just like the cell does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WideLocation {
+    name: &'static str,
+    low: StaticLocation,
+}
+
+impl WideLocation {
+    pub const fn new(name: &'static str, low: StaticLocation) -> Self {
+        Self { name, low }
+    }
+
+    pub fn register(name: &str) -> Option<Self> {
+        WIDE_REGISTERS.iter().find(|reg| reg.name == name).copied()
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The cell holding the byte with the given significance.
+    pub const fn cell(&self, index: usize) -> StaticLocation {
+        self.low.off(index as i64)
+    }
+
+    fn cells(&self) -> impl DoubleEndedIterator<Item = StaticLocation> + '_ {
+        (0..WIDE_CELLS).map(|i| self.cell(i))
+    }
+
+    pub fn zero(&self) -> String {
+        self.cells().map(|cell| cell.zero()).collect()
+    }
+
+    pub fn set_const(&self, value: u64) -> String {
+        self.cells()
+            .enumerate()
+            .map(|(i, cell)| cell.set_const((value >> (8 * i)) & 0xff))
+            .collect()
+    }
+
+    pub fn set_from(&self, src: WideLocation) -> String {
+        self.cells()
+            .zip(src.cells())
+            .map(|(dest, src)| dest.set_from(src))
+            .collect()
+    }
+
+    /// Zero-extend a single cell into the wide number, counting it up one at a time.
+    pub fn set_from_narrow(&self, src: StaticLocation) -> String {
+        let count = WIDE_COUNT;
+        WIDE_BASE.set_const(256)
+            + &self.zero()
+            + &count.set_from(src)
+            + &while_on(&count, count.dec() + &self.carry_into(0))
+    }
+
+    /// Set `dest` to 1 if any byte of the wide number is non-zero, and 0 otherwise.
+    fn nonzero(&self, dest: StaticLocation) -> String {
+        let mut result = dest.zero();
+        for cell in self.cells() {
+            result += &if_stmt(&cell, dest.set_const(1));
+        }
+        result
+    }
+
+    /// Add one to the byte at `index`, carrying into the bytes above it.
+    /// WIDE_BASE must hold 256.
+    fn carry_into(&self, index: usize) -> String {
+        let carry = WIDE_CARRY;
+        let mut result = carry.set_const(1);
+        for cell in self.cells().skip(index) {
+            result += &if_stmt(
+                &carry,
+                cell.inc()
+                    + &StaticLocation::equals(carry, cell, WIDE_BASE)
+                    + &if_stmt(&carry, cell.zero()),
+            );
+        }
+        result
+    }
+
+    /// Subtract one from the byte at `index`, borrowing from the bytes above it.
+    fn borrow_from(&self, index: usize) -> String {
+        let borrow = WIDE_CARRY;
+        let mut result = borrow.set_const(1);
+        for cell in self.cells().skip(index) {
+            result += &if_stmt(
+                &borrow,
+                StaticLocation::boolean_not(borrow, cell)
+                    + &cell.dec()
+                    + &if_stmt(&borrow, cell.set_const(0xff)),
+            );
+        }
+        result
+    }
+
+    /// Add `src` to `self` one byte at a time. The most significant bytes go first,
+    /// so `src` may be `self`: carries only reach bytes that were already counted.
+    fn add_to(&self, src: WideLocation) -> String {
+        let count = WIDE_COUNT;
+        let mut result = String::new();
+        for i in (0..WIDE_CELLS).rev() {
+            result += &(count.set_from(src.cell(i))
+                + &while_on(&count, count.dec() + &self.carry_into(i)));
+        }
+        result
+    }
+
+    pub fn plus(dest: WideLocation, lhs: WideLocation, rhs: WideLocation) -> String {
+        let sum = WIDE_TEMP0;
+        WIDE_BASE.set_const(256) + &sum.set_from(lhs) + &sum.add_to(rhs) + &dest.set_from(sum)
+    }
+
+    pub fn minus(dest: WideLocation, lhs: WideLocation, rhs: WideLocation) -> String {
+        let difference = WIDE_TEMP0;
+        let count = WIDE_COUNT;
+        let mut result = difference.set_from(lhs);
+        for i in 0..WIDE_CELLS {
+            result += &(count.set_from(rhs.cell(i))
+                + &while_on(&count, count.dec() + &difference.borrow_from(i)));
+        }
+        result + &dest.set_from(difference)
+    }
+
+    /// Multiply by shifting and adding: every bit of `lhs` adds `rhs`, shifted left by
+    /// the bit's position, to the product. This stops once the bits left in `lhs` are zero.
+    pub fn times(dest: WideLocation, lhs: WideLocation, rhs: WideLocation) -> String {
+        let product = WIDE_TEMP0;
+        let shifted = WIDE_TEMP1;
+        let multiplier = WIDE_TEMP2;
+        let (byte, bit, bits) = (WIDE_BYTE, WIDE_BIT, WIDE_BITS);
+        let more = WIDE_MORE;
+
+        // Take the bits of the low byte of `multiplier`, then shift its bytes down
+        let mut next_byte = String::new();
+        for i in 1..WIDE_CELLS {
+            next_byte += &multiplier.cell(i - 1).set_from(multiplier.cell(i));
+        }
+        next_byte += &multiplier.cell(WIDE_CELLS - 1).zero();
+
+        WIDE_BASE.set_const(256)
+            + &product.zero()
+            + &shifted.set_from(rhs)
+            + &multiplier.set_from(lhs)
+            + &multiplier.nonzero(more)
+            + &while_on(
+                &more,
+                byte.set_from(multiplier.cell(0))
+                    + &bits.set_const(8)
+                    + &while_on(
+                        &bits,
+                        StaticLocation::halve(byte, bit)
+                            + &if_stmt(&bit, product.add_to(shifted))
+                            + &shifted.add_to(shifted)
+                            + &bits.dec(),
+                    )
+                    + &next_byte
+                    + &multiplier.nonzero(more),
+            )
+            + &dest.set_from(product)
+    }
+
+    /// Divide `self` by ten in place, leaving the remainder in `remainder`.
+    ///
+    /// Each byte is divided with the remainder of the bytes above it, as in long
+    /// division. To keep every intermediate value below 256, `r * 256 + byte` is
+    /// split into `r * 25 + byte / 10` and `r * 6 + byte % 10`.
+    fn divide_by_ten(&self, remainder: StaticLocation) -> String {
+        let r = remainder;
+        let ten = WIDE_CONST;
+        let (quotient, low, sum, high) = (WIDE_QUOT, WIDE_BYTE, WIDE_SUM, WIDE_BIT);
+
+        let mut result = r.zero();
+        for cell in self.cells().rev() {
+            result += &(ten.set_const(10)
+                + &StaticLocation::divide(quotient, cell, ten)
+                + &StaticLocation::modulo(low, cell, ten)
+                // sum = r * 6 + byte % 10
+                + &ten.set_const(6)
+                + &StaticLocation::times(sum, r, ten)
+                + &StaticLocation::plus(sum, sum, low)
+                // high = r * 25 + byte / 10
+                + &ten.set_const(25)
+                + &StaticLocation::times(high, r, ten)
+                + &StaticLocation::plus(high, high, quotient)
+                // byte = high + sum / 10, r = sum % 10
+                + &ten.set_const(10)
+                + &StaticLocation::divide(quotient, sum, ten)
+                + &StaticLocation::plus(cell, high, quotient)
+                + &StaticLocation::modulo(r, sum, ten));
+        }
+        result
+    }
+
+    /// Print the wide number in decimal, without leading zeros.
+    pub fn putint(&self) -> String {
+        let number = WIDE_TEMP0;
+        let digits = WIDE_DIGITS;
+        let count = WIDE_BITS;
+        let nonzero = WIDE_CARRY;
+
+        // The digits come out least significant first, so each one pushes
+        // the ones before it up, leaving the most significant in digits[0]
+        let mut push_digit = String::new();
+        for i in (1..digits.len()).rev() {
+            push_digit += &digits[i].set_from(digits[i - 1]);
+        }
+        push_digit += &number.divide_by_ten(digits[0]);
+
+        let mut pop_digit = digits[0].add_const(b'0' as i64) + &digits[0].putchar();
+        for i in 1..digits.len() {
+            pop_digit += &digits[i - 1].set_from(digits[i]);
+        }
+
+        number.set_from(*self)
+            + &count.zero()
+            + &nonzero.set_const(1)
+            + &while_on(
+                &nonzero,
+                push_digit + &count.inc() + &number.nonzero(nonzero),
+            )
+            + &while_on(&count, pop_digit + &count.dec())
+    }
+}
+
+impl std::fmt::Display for WideLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
 /// A StaticLocation on a brainfuck tape.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DynamicLocation {
     DerefHeap(StaticLocation),
     DerefStack(StaticLocation),
     Static(StaticLocation),
+    /// A cell on the stack at an offset from the frame pointer, like the
+    /// arguments and locals of a function.
+    Frame(i64),
 }
 
 impl DynamicLocation {
@@ -552,7 +1111,19 @@ impl DynamicLocation {
         Self::Static(StaticLocation::Address(addr))
     }
 
-    pub fn set_from(&self, src: impl Into<DynamicLocation>) -> String {
+    /// Point `ptr` at the cell of a frame location, so it can be used like any
+    /// other stack location. Other locations are returned as they are.
+    fn resolve(self, ptr: StaticLocation) -> (String, Self) {
+        match self {
+            Self::Frame(offset) => (
+                ptr.set_from(FP) + &ptr.add_const(offset),
+                Self::DerefStack(ptr),
+            ),
+            loc => (String::new(), loc),
+        }
+    }
+
+    pub fn set_from(&self, layout: &Layout, src: impl Into<DynamicLocation>) -> String {
         let temp0 = DYN_SET_TEMP;
 
         let src = src.into();
@@ -562,157 +1133,348 @@ impl DynamicLocation {
         let dst = self.clone();
         use DynamicLocation::*;
         match (dst, src) {
+            (Frame(_), _) | (_, Frame(_)) => {
+                let (dst_code, dst) = dst.resolve(FRAME_TEMP0);
+                let (src_code, src) = src.resolve(FRAME_TEMP1);
+                dst_code + &src_code + &dst.set_from(layout, src)
+            }
+
             (Static(dst), Static(src)) => dst.set_from(src),
 
-            (Static(dst), DerefStack(src)) => STACK.get(src, dst),
-            (Static(dst), DerefHeap(src)) => HEAP.get(src, dst),
-            (DerefStack(dst), Static(src)) => STACK.set(dst, src),
-            (DerefHeap(dst), Static(src)) => HEAP.set(dst, src),
+            // Reading clears `dst` before it's done with the index, so an index
+            // in `dst` itself has to be read into `temp0` first
+            (Static(dst), DerefStack(src)) if dst == src => {
+                layout.stack().get(src, temp0) + &dst.set_from(temp0)
+            }
+            (Static(dst), DerefHeap(src)) if dst == src => {
+                layout.heap().get(src, temp0) + &dst.set_from(temp0)
+            }
+            (Static(dst), DerefStack(src)) => layout.stack().get(src, dst),
+            (Static(dst), DerefHeap(src)) => layout.heap().get(src, dst),
+            (DerefStack(dst), Static(src)) => layout.stack().set(dst, src),
+            (DerefHeap(dst), Static(src)) => layout.heap().set(dst, src),
 
             (DerefStack(dst), DerefStack(src)) => {
                 // Get the value of `src` into `temp0`
-                STACK.get(src, temp0) + &STACK.set(dst, temp0)
+                layout.stack().get(src, temp0) + &layout.stack().set(dst, temp0)
             }
             (DerefHeap(dst), DerefHeap(src)) => {
                 // Get the value of `src` into `temp0`
-                HEAP.get(src, temp0) + &HEAP.set(dst, temp0)
+                layout.heap().get(src, temp0) + &layout.heap().set(dst, temp0)
             }
 
             (DerefStack(dst), DerefHeap(src)) => {
                 // Get the value of `src` into `temp0`
-                HEAP.get(src, temp0) + &STACK.set(dst, temp0)
+                layout.heap().get(src, temp0) + &layout.stack().set(dst, temp0)
             }
             (DerefHeap(dst), DerefStack(src)) => {
                 // Get the value of `src` into `temp0`
-                STACK.get(src, temp0) + &HEAP.set(dst, temp0)
+                layout.stack().get(src, temp0) + &layout.heap().set(dst, temp0)
             }
         }
     }
 
     /// Get the value at this location
-    pub fn get_from(&self, dst: impl Into<DynamicLocation>) -> String {
-        dst.into().set_from(self.clone())
+    pub fn get_from(&self, layout: &Layout, dst: impl Into<DynamicLocation>) -> String {
+        dst.into().set_from(layout, self.clone())
     }
 
-    pub fn set_const(&self, value: u64) -> String {
+    pub fn set_const(&self, layout: &Layout, value: u64) -> String {
         match self {
             Self::Static(loc) => loc.set_const(value),
-            Self::DerefStack(loc) => VAL_TEMP.set_const(value) + &STACK.set(*loc, VAL_TEMP),
-            Self::DerefHeap(loc) => VAL_TEMP.set_const(value) + &HEAP.set(*loc, VAL_TEMP),
+            Self::DerefStack(loc) => {
+                VAL_TEMP.set_const(value) + &layout.stack().set(*loc, VAL_TEMP)
+            }
+            Self::DerefHeap(loc) => VAL_TEMP.set_const(value) + &layout.heap().set(*loc, VAL_TEMP),
+            Self::Frame(_) => {
+                let (code, loc) = self.resolve(FRAME_TEMP0);
+                code + &loc.set_const(layout, value)
+            }
         }
     }
 
-    pub fn inc(&self) -> String {
-        self.add_const(1)
+    pub fn inc(&self, layout: &Layout) -> String {
+        self.add_const(layout, 1)
     }
 
-    pub fn dec(&self) -> String {
-        self.sub_const(1)
+    pub fn dec(&self, layout: &Layout) -> String {
+        self.sub_const(layout, 1)
     }
 
-    pub fn add_const(&self, value: i64) -> String {
+    pub fn add_const(&self, layout: &Layout, value: i64) -> String {
         if value < 0 {
-            return self.sub_const(-value);
+            return self.sub_const(layout, -value);
         }
         match self {
             Self::Static(loc) => loc.add_const(value),
             Self::DerefStack(loc) => {
-                STACK.get(*loc, VAL_TEMP) + &VAL_TEMP.add_const(value) + &STACK.set(*loc, VAL_TEMP)
+                layout.stack().get(*loc, VAL_TEMP)
+                    + &VAL_TEMP.add_const(value)
+                    + &layout.stack().set(*loc, VAL_TEMP)
             }
             Self::DerefHeap(loc) => {
-                HEAP.get(*loc, VAL_TEMP) + &VAL_TEMP.add_const(value) + &HEAP.set(*loc, VAL_TEMP)
+                layout.heap().get(*loc, VAL_TEMP)
+                    + &VAL_TEMP.add_const(value)
+                    + &layout.heap().set(*loc, VAL_TEMP)
+            }
+            Self::Frame(_) => {
+                let (code, loc) = self.resolve(FRAME_TEMP0);
+                code + &loc.add_const(layout, value)
             }
         }
     }
 
-    pub fn sub_const(&self, value: i64) -> String {
+    pub fn sub_const(&self, layout: &Layout, value: i64) -> String {
         if value < 0 {
-            return self.add_const(-value);
+            return self.add_const(layout, -value);
         }
         match self {
             Self::Static(loc) => loc.sub_const(value),
             Self::DerefStack(loc) => {
-                STACK.get(*loc, VAL_TEMP) + &VAL_TEMP.sub_const(value) + &STACK.set(*loc, VAL_TEMP)
+                layout.stack().get(*loc, VAL_TEMP)
+                    + &VAL_TEMP.sub_const(value)
+                    + &layout.stack().set(*loc, VAL_TEMP)
             }
             Self::DerefHeap(loc) => {
-                HEAP.get(*loc, VAL_TEMP) + &VAL_TEMP.sub_const(value) + &HEAP.set(*loc, VAL_TEMP)
+                layout.heap().get(*loc, VAL_TEMP)
+                    + &VAL_TEMP.sub_const(value)
+                    + &layout.heap().set(*loc, VAL_TEMP)
+            }
+            Self::Frame(_) => {
+                let (code, loc) = self.resolve(FRAME_TEMP0);
+                code + &loc.sub_const(layout, value)
             }
         }
     }
 
     pub fn static_binop(
+        layout: &Layout,
         binop: impl Fn(StaticLocation, StaticLocation, StaticLocation) -> String,
         dest: DynamicLocation,
         lhs: DynamicLocation,
         rhs: DynamicLocation,
     ) -> String {
-        Self::from(DYN_OP_TEMP0).set_from(lhs)
-            + &Self::from(DYN_OP_TEMP1).set_from(rhs)
+        Self::from(DYN_OP_TEMP0).set_from(layout, lhs)
+            + &Self::from(DYN_OP_TEMP1).set_from(layout, rhs)
             + &binop(DYN_OP_TEMP2, DYN_OP_TEMP0, DYN_OP_TEMP1)
-            + &dest.set_from(DYN_OP_TEMP2)
+            + &dest.set_from(layout, DYN_OP_TEMP2)
     }
 
     pub fn static_unop(
+        layout: &Layout,
         unop: impl Fn(StaticLocation, StaticLocation) -> String,
         dest: DynamicLocation,
         src: DynamicLocation,
     ) -> String {
-        Self::from(DYN_OP_TEMP0).set_from(src)
+        Self::from(DYN_OP_TEMP0).set_from(layout, src)
             + &unop(DYN_OP_TEMP1, DYN_OP_TEMP0)
-            + &dest.set_from(DYN_OP_TEMP1)
+            + &dest.set_from(layout, DYN_OP_TEMP1)
     }
 
-    pub fn negate(dest: DynamicLocation, src: DynamicLocation) -> String {
-        Self::static_unop(StaticLocation::negate, dest, src)
+    pub fn negate(layout: &Layout, dest: DynamicLocation, src: DynamicLocation) -> String {
+        Self::static_unop(layout, StaticLocation::negate, dest, src)
     }
 
-    pub fn boolean_not(dest: DynamicLocation, src: DynamicLocation) -> String {
-        Self::static_unop(StaticLocation::boolean_not, dest, src)
+    pub fn boolean_not(layout: &Layout, dest: DynamicLocation, src: DynamicLocation) -> String {
+        Self::static_unop(layout, StaticLocation::boolean_not, dest, src)
     }
 
-    pub fn getchar(&self) -> String {
+    pub fn getchar(&self, layout: &Layout) -> String {
         match self {
             Self::Static(loc) => loc.getchar(),
-            Self::DerefStack(loc) => VAL_TEMP.getchar() + &STACK.set(*loc, VAL_TEMP),
-            Self::DerefHeap(loc) => VAL_TEMP.getchar() + &HEAP.set(*loc, VAL_TEMP),
+            Self::DerefStack(loc) => VAL_TEMP.getchar() + &layout.stack().set(*loc, VAL_TEMP),
+            Self::DerefHeap(loc) => VAL_TEMP.getchar() + &layout.heap().set(*loc, VAL_TEMP),
+            Self::Frame(_) => {
+                let (code, loc) = self.resolve(FRAME_TEMP0);
+                code + &loc.getchar(layout)
+            }
         }
     }
 
-    pub fn putint(&self) -> String {
+    pub fn putint(&self, layout: &Layout) -> String {
         match self {
             Self::Static(loc) => loc.putint(),
-            Self::DerefStack(loc) => STACK.get(*loc, VAL_TEMP) + &VAL_TEMP.putint(),
-            Self::DerefHeap(loc) => HEAP.get(*loc, VAL_TEMP) + &VAL_TEMP.putint(),
+            Self::DerefStack(loc) => layout.stack().get(*loc, VAL_TEMP) + &VAL_TEMP.putint(),
+            Self::DerefHeap(loc) => layout.heap().get(*loc, VAL_TEMP) + &VAL_TEMP.putint(),
+            Self::Frame(_) => {
+                let (code, loc) = self.resolve(FRAME_TEMP0);
+                code + &loc.putint(layout)
+            }
         }
     }
 
-    pub fn putchar(&self) -> String {
+    pub fn putsint(&self, layout: &Layout) -> String {
+        match self {
+            Self::Static(loc) => loc.putsint(),
+            Self::DerefStack(loc) => layout.stack().get(*loc, VAL_TEMP) + &VAL_TEMP.putsint(),
+            Self::DerefHeap(loc) => layout.heap().get(*loc, VAL_TEMP) + &VAL_TEMP.putsint(),
+            Self::Frame(_) => {
+                let (code, loc) = self.resolve(FRAME_TEMP0);
+                code + &loc.putsint(layout)
+            }
+        }
+    }
+
+    pub fn getint(&self, layout: &Layout) -> String {
+        match self {
+            Self::Static(loc) => loc.getint(),
+            Self::DerefStack(loc) => VAL_TEMP.getint() + &layout.stack().set(*loc, VAL_TEMP),
+            Self::DerefHeap(loc) => VAL_TEMP.getint() + &layout.heap().set(*loc, VAL_TEMP),
+            Self::Frame(_) => {
+                let (code, loc) = self.resolve(FRAME_TEMP0);
+                code + &loc.getint(layout)
+            }
+        }
+    }
+
+    pub fn putchar(&self, layout: &Layout) -> String {
         match self {
             Self::Static(loc) => loc.putchar(),
-            Self::DerefStack(loc) => STACK.get(*loc, VAL_TEMP) + &VAL_TEMP.putchar(),
-            Self::DerefHeap(loc) => HEAP.get(*loc, VAL_TEMP) + &VAL_TEMP.putchar(),
+            Self::DerefStack(loc) => layout.stack().get(*loc, VAL_TEMP) + &VAL_TEMP.putchar(),
+            Self::DerefHeap(loc) => layout.heap().get(*loc, VAL_TEMP) + &VAL_TEMP.putchar(),
+            Self::Frame(_) => {
+                let (code, loc) = self.resolve(FRAME_TEMP0);
+                code + &loc.putchar(layout)
+            }
         }
     }
 
-    pub fn plus(dest: DynamicLocation, lhs: DynamicLocation, rhs: DynamicLocation) -> String {
-        Self::static_binop(StaticLocation::plus, dest, lhs, rhs)
+    pub fn plus(
+        layout: &Layout,
+        dest: DynamicLocation,
+        lhs: DynamicLocation,
+        rhs: DynamicLocation,
+    ) -> String {
+        Self::static_binop(layout, StaticLocation::plus, dest, lhs, rhs)
     }
-    pub fn minus(dest: DynamicLocation, lhs: DynamicLocation, rhs: DynamicLocation) -> String {
-        Self::static_binop(StaticLocation::minus, dest, lhs, rhs)
+    pub fn minus(
+        layout: &Layout,
+        dest: DynamicLocation,
+        lhs: DynamicLocation,
+        rhs: DynamicLocation,
+    ) -> String {
+        Self::static_binop(layout, StaticLocation::minus, dest, lhs, rhs)
     }
-    pub fn times(dest: DynamicLocation, lhs: DynamicLocation, rhs: DynamicLocation) -> String {
-        Self::static_binop(StaticLocation::times, dest, lhs, rhs)
+    pub fn times(
+        layout: &Layout,
+        dest: DynamicLocation,
+        lhs: DynamicLocation,
+        rhs: DynamicLocation,
+    ) -> String {
+        Self::static_binop(layout, StaticLocation::times, dest, lhs, rhs)
     }
-    pub fn divide(dest: DynamicLocation, lhs: DynamicLocation, rhs: DynamicLocation) -> String {
-        Self::static_binop(StaticLocation::divide, dest, lhs, rhs)
+    pub fn divide(
+        layout: &Layout,
+        dest: DynamicLocation,
+        lhs: DynamicLocation,
+        rhs: DynamicLocation,
+    ) -> String {
+        Self::static_binop(layout, StaticLocation::divide, dest, lhs, rhs)
     }
 
-    pub fn equals(dest: DynamicLocation, lhs: DynamicLocation, rhs: DynamicLocation) -> String {
-        Self::static_binop(StaticLocation::equals, dest, lhs, rhs)
+    pub fn equals(
+        layout: &Layout,
+        dest: DynamicLocation,
+        lhs: DynamicLocation,
+        rhs: DynamicLocation,
+    ) -> String {
+        Self::static_binop(layout, StaticLocation::equals, dest, lhs, rhs)
     }
 
-    pub fn not_equals(dest: DynamicLocation, lhs: DynamicLocation, rhs: DynamicLocation) -> String {
-        Self::static_binop(StaticLocation::not_equals, dest, lhs, rhs)
+    pub fn not_equals(
+        layout: &Layout,
+        dest: DynamicLocation,
+        lhs: DynamicLocation,
+        rhs: DynamicLocation,
+    ) -> String {
+        Self::static_binop(layout, StaticLocation::not_equals, dest, lhs, rhs)
+    }
+
+    pub fn modulo(
+        layout: &Layout,
+        dest: DynamicLocation,
+        lhs: DynamicLocation,
+        rhs: DynamicLocation,
+    ) -> String {
+        Self::static_binop(layout, StaticLocation::modulo, dest, lhs, rhs)
+    }
+
+    pub fn less_than(
+        layout: &Layout,
+        dest: DynamicLocation,
+        lhs: DynamicLocation,
+        rhs: DynamicLocation,
+    ) -> String {
+        Self::static_binop(layout, StaticLocation::less_than, dest, lhs, rhs)
+    }
+    pub fn less_equal(
+        layout: &Layout,
+        dest: DynamicLocation,
+        lhs: DynamicLocation,
+        rhs: DynamicLocation,
+    ) -> String {
+        Self::static_binop(layout, StaticLocation::less_equal, dest, lhs, rhs)
+    }
+    pub fn greater_than(
+        layout: &Layout,
+        dest: DynamicLocation,
+        lhs: DynamicLocation,
+        rhs: DynamicLocation,
+    ) -> String {
+        Self::static_binop(layout, StaticLocation::greater_than, dest, lhs, rhs)
+    }
+    pub fn greater_equal(
+        layout: &Layout,
+        dest: DynamicLocation,
+        lhs: DynamicLocation,
+        rhs: DynamicLocation,
+    ) -> String {
+        Self::static_binop(layout, StaticLocation::greater_equal, dest, lhs, rhs)
+    }
+
+    pub fn bitwise_and(
+        layout: &Layout,
+        dest: DynamicLocation,
+        lhs: DynamicLocation,
+        rhs: DynamicLocation,
+    ) -> String {
+        Self::static_binop(layout, StaticLocation::bitwise_and, dest, lhs, rhs)
+    }
+    pub fn bitwise_or(
+        layout: &Layout,
+        dest: DynamicLocation,
+        lhs: DynamicLocation,
+        rhs: DynamicLocation,
+    ) -> String {
+        Self::static_binop(layout, StaticLocation::bitwise_or, dest, lhs, rhs)
+    }
+    pub fn bitwise_xor(
+        layout: &Layout,
+        dest: DynamicLocation,
+        lhs: DynamicLocation,
+        rhs: DynamicLocation,
+    ) -> String {
+        Self::static_binop(layout, StaticLocation::bitwise_xor, dest, lhs, rhs)
+    }
+    pub fn bitwise_not(layout: &Layout, dest: DynamicLocation, src: DynamicLocation) -> String {
+        Self::static_unop(layout, StaticLocation::bitwise_not, dest, src)
+    }
+
+    pub fn shift_left(
+        layout: &Layout,
+        dest: DynamicLocation,
+        lhs: DynamicLocation,
+        rhs: DynamicLocation,
+    ) -> String {
+        Self::static_binop(layout, StaticLocation::shift_left, dest, lhs, rhs)
+    }
+    pub fn shift_right(
+        layout: &Layout,
+        dest: DynamicLocation,
+        lhs: DynamicLocation,
+        rhs: DynamicLocation,
+    ) -> String {
+        Self::static_binop(layout, StaticLocation::shift_right, dest, lhs, rhs)
     }
 
     pub fn stack_deref(self) -> Self {
@@ -746,6 +1508,8 @@ impl std::fmt::Display for DynamicLocation {
             DynamicLocation::Static(loc) => write!(f, "{loc}"),
             DynamicLocation::DerefStack(loc) => write!(f, "[{loc}]"),
             DynamicLocation::DerefHeap(loc) => write!(f, "(heap) [{loc}]"),
+            DynamicLocation::Frame(offset) if *offset < 0 => write!(f, "[FP - {}]", -offset),
+            DynamicLocation::Frame(offset) => write!(f, "[FP + {offset}]"),
         }
     }
 }
@@ -759,50 +1523,23 @@ impl From<StaticLocation> for DynamicLocation {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::io::Write;
-
-    lazy_static! {
-        static ref COMPILE_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
-    }
-    fn compile_and_run(filename: &str) {
-        let lock = COMPILE_LOCK.lock().unwrap();
-
-        // // super-bf ./test.b -e; ./main.exe
-        // let mut cmd = std::process::Command::new("super-bf")
-        //     .arg(filename)
-        //     .arg("-e")
-        //     .spawn()
-        //     .expect("Failed to execute command");
-        let mut cmd = std::process::Command::new("./compile")
-            .arg(filename)
-            .arg("output.c")
-            .spawn()
-            .expect("Failed to execute command");
-        let output = cmd.wait_with_output().expect("Failed to read stdout");
-        assert!(
-            output.status.success(),
-            "Command failed with status: {}",
-            output.status
-        );
-        drop(lock);
-        // // Now run the generated file
-        // let mut cmd = std::process::Command::new("./main.exe")
-        //     .spawn()
-        //     .expect("Failed to execute command");
-        // let output = cmd.wait_with_output().expect("Failed to read stdout");
-        // assert!(output.status.success(), "Command failed with status: {}", output.status);
+
+    fn run(bf: String) {
+        let output = interpret_with_input(bf, "", 1).expect("Failed to run brainfuck");
+        println!("{output}");
     }
 
     #[test]
     fn test_table() {
-        let val = global_alloc(1);
-        let idx = global_alloc(1);
-        let dst = global_alloc(1);
+        let mut asm = Assembler::new();
+        let val = asm.global_alloc(1);
+        let idx = asm.global_alloc(1);
+        let dst = asm.global_alloc(1);
         println!("idx: {idx:?}");
         println!("val: {val:?}");
         println!("dst: {dst:?}");
 
-        let table = Table::allocate(10);
+        let table = asm.allocate_table(10);
 
         println!("{table:#?}");
 
@@ -825,12 +1562,81 @@ mod tests {
     }
 
     #[test]
-    fn test_divide() {
-        allocate_registers_and_stack();
+    fn test_load_through_own_register() {
+        let source = "push 5\npush 6\nR1 lea [SP] - 1\nR1 = [R1]\nputint R1\n\
+                      R2 alloc 1\n{R2} = 7\nR2 = {R2}\nputint R2";
+        let program = Program::parse(source).unwrap();
+        assert_eq!(
+            interpret_with_input(program.assemble(), "", 1).unwrap(),
+            "57"
+        );
+    }
 
-        let a = global_alloc(1);
-        let b = global_alloc(1);
-        let c = global_alloc(1);
+    #[test]
+    fn test_signed_arithmetic() {
+        let source = "R0 = 0\nR0 sub 7\nR1 idiv R0, 2\nputsint R1\nputchar ' '\n\
+                      R1 imul R0, 3\nputsint R1\nputchar ' '\n\
+                      R1 imul R0, R0\nputsint R1\nputchar ' '\n\
+                      R1 idiv 100, R0\nputsint R1\nputchar ' '\n\
+                      neg R0, R0\nputsint R0\nputchar ' '\nputsint 128";
+        let program = Program::parse(source).unwrap();
+        assert_eq!(
+            interpret_with_input(program.assemble(), "", 1).unwrap(),
+            "-3 -21 49 -14 7 -128"
+        );
+        assert_eq!(
+            interpret_with_input(program.assemble(), "", 2).unwrap(),
+            "-3 -21 49 -14 7 128"
+        );
+    }
+
+    #[test]
+    fn test_wide_registers() {
+        let source = "W0 = 100000\nW1 = 70000\nW2 add W0, W1\nputint W2\nputchar ' '\n\
+                      W2 sub W1, W0\nputint W2\nputchar ' '\n\
+                      W2 mul W0, 3\nputint W2\nputchar ' '\n\
+                      R0 = 200\nW3 = R0\nW3 mul W3\nputint W3\nputchar ' '\n\
+                      W3 = 0\nputint W3";
+        let program = Program::parse(source).unwrap();
+        for cell_bytes in [1, 2] {
+            assert_eq!(
+                interpret_with_input(program.assemble(), "", cell_bytes).unwrap(),
+                "170000 4294937296 300000 40000 0"
+            );
+        }
+    }
+
+    #[test]
+    fn test_signed_less_than() {
+        let source = "R0 = 0\nR0 sub 5\n\
+                      R1 ilt R0, 3\nputint R1\nR1 ilt 3, R0\nputint R1\n\
+                      R1 ilt R0, 255\nputint R1\nR1 ilt 255, R0\nputint R1\n\
+                      R1 ilt 2, 3\nputint R1\nR1 ilt R0, R0\nputint R1\n\
+                      R1 ilt 128, 127\nputint R1";
+        let program = Program::parse(source).unwrap();
+        assert_eq!(
+            interpret_with_input(program.assemble(), "", 1).unwrap(),
+            "1010101"
+        );
+    }
+
+    #[test]
+    fn test_getint() {
+        let source = "getint R0\ngetint R1\npush 0\ngetint [SP]\nR2 add R0, R1\nputsint R2\n\
+                      putchar ' '\nputsint [SP]";
+        let program = Program::parse(source).unwrap();
+        assert_eq!(
+            interpret_with_input(program.assemble(), " -17\n\t20 -3", 1).unwrap(),
+            "3 -3"
+        );
+    }
+
+    #[test]
+    fn test_divide() {
+        let mut asm = Assembler::new();
+        let a = asm.global_alloc(1);
+        let b = asm.global_alloc(1);
+        let c = asm.global_alloc(1);
 
         let mut result = String::new();
         // result += &a.set_const(0xff);
@@ -843,21 +1649,15 @@ mod tests {
 
         // println!("{result}");
 
-        std::fs::File::create("test-math.b")
-            .expect("Failed to create file")
-            .write_all(result.as_bytes())
-            .unwrap();
-
-        compile_and_run("test-math.b");
+        run(result);
     }
 
     #[test]
     fn test_multiply() {
-        allocate_registers_and_stack();
-
-        let a = global_alloc(1);
-        let b = global_alloc(1);
-        let c = global_alloc(1);
+        let mut asm = Assembler::new();
+        let a = asm.global_alloc(1);
+        let b = asm.global_alloc(1);
+        let c = asm.global_alloc(1);
 
         let mut result = String::new();
         // result += &a.set_const(0xff);
@@ -870,21 +1670,15 @@ mod tests {
 
         // println!("{result}");
 
-        std::fs::File::create("test-math.b")
-            .expect("Failed to create file")
-            .write_all(result.as_bytes())
-            .unwrap();
-
-        compile_and_run("test-math.b");
+        run(result);
     }
 
     #[test]
     fn test_subtract() {
-        allocate_registers_and_stack();
-
-        let a = global_alloc(1);
-        let b = global_alloc(1);
-        let c = global_alloc(1);
+        let mut asm = Assembler::new();
+        let a = asm.global_alloc(1);
+        let b = asm.global_alloc(1);
+        let c = asm.global_alloc(1);
 
         let mut result = String::new();
         // result += &a.set_const(0xff);
@@ -897,21 +1691,16 @@ mod tests {
 
         // println!("{result}");
 
-        std::fs::File::create("test-math.b")
-            .expect("Failed to create file")
-            .write_all(result.as_bytes())
-            .unwrap();
-
-        compile_and_run("test-math.b");
+        run(result);
     }
 
     #[test]
     fn test_putint() {
-        allocate_registers_and_stack();
-        let a = global_alloc(1);
-        let b = global_alloc(1);
-        let c = global_alloc(1);
-        let newline = global_alloc(1);
+        let mut asm = Assembler::new();
+        let a = asm.global_alloc(1);
+        let b = asm.global_alloc(1);
+        let c = asm.global_alloc(1);
+        let newline = asm.global_alloc(1);
 
         let mut result = String::new();
         // result += &a.get(b);
@@ -931,20 +1720,15 @@ mod tests {
         result += &newline.putchar();
         result += &newline.putchar();
 
-        std::fs::File::create("test-putint.b")
-            .expect("Failed to create file")
-            .write_all(result.as_bytes())
-            .unwrap();
-
-        compile_and_run("test-putint.b");
+        run(result);
     }
 
     #[test]
     fn test_equals() {
-        // allocate_registers_and_stack();
-        let a = global_alloc(1);
-        let b = global_alloc(1);
-        let c = global_alloc(1);
+        let mut asm = Assembler::new();
+        let a = asm.global_alloc(1);
+        let b = asm.global_alloc(1);
+        let c = asm.global_alloc(1);
 
         let mut result = String::new();
         // result += &a.set_const(0xff);
@@ -957,20 +1741,15 @@ mod tests {
 
         // println!("{result}");
 
-        std::fs::File::create("test-equals.b")
-            .expect("Failed to create file")
-            .write_all(result.as_bytes())
-            .unwrap();
-
-        compile_and_run("test-equals.b");
+        run(result);
     }
 
     #[test]
     fn test_if_stmt() {
-        allocate_registers_and_stack();
-        let a = global_alloc(1);
-        let b = global_alloc(1);
-        let c = global_alloc(1);
+        let mut asm = Assembler::new();
+        let a = asm.global_alloc(1);
+        let b = asm.global_alloc(1);
+        let c = asm.global_alloc(1);
 
         let mut result = String::new();
         result += &b.set_const(20);
@@ -984,11 +1763,6 @@ mod tests {
 
         // println!("{result}");
 
-        std::fs::File::create("test-if-stmt.b")
-            .expect("Failed to create file")
-            .write_all(result.as_bytes())
-            .unwrap();
-
-        compile_and_run("test-if-stmt.b");
+        run(result);
     }
 }
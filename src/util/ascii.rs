@@ -1,4 +1,4 @@
-use crate::simplify_bf;
+use crate::{simplify_bf, BfError};
 use lazy_static::lazy_static;
 use std::collections::HashMap;
 use std::path::Path;
@@ -76,11 +76,15 @@ fn available_brainfuck_slots(art: &str) -> usize {
     art.chars().filter(|c| !c.is_whitespace()).count()
 }
 
-pub fn apply_ascii_art_template(art_template: &str, bf: String, comment: &str) -> String {
+pub fn apply_ascii_art_template(
+    art_template: &str,
+    bf: String,
+    comment: &str,
+) -> Result<String, BfError> {
     let mut art_template = replace_brainfuck_chars(art_template.to_string());
     let (width, height) = ascii_art_size(art_template.as_str());
     art_template = ascii_art_fill(&art_template, width, height);
-    let mut bf = simplify_bf(bf);
+    let mut bf = simplify_bf(bf)?;
 
     while available_brainfuck_slots(&art_template) < bf.len() {
         // Scale the art
@@ -114,7 +118,7 @@ pub fn apply_ascii_art_template(art_template: &str, bf: String, comment: &str) -
         "Not enough space in art template for brainfuck code"
     );
 
-    art_template
+    Ok(art_template)
 }
 
 lazy_static! {
@@ -178,7 +182,7 @@ pub fn apply_template_from_name_or_file(
         }
     };
 
-    let art = apply_ascii_art_template(&template, bf, comment);
+    let art = apply_ascii_art_template(&template, bf, comment)?;
     Ok(art)
 }
 